@@ -0,0 +1,48 @@
+//! Runs a delivery whose upload worker panics and checks the panic fails
+//! only that file. Exits non-zero otherwise, including when the build
+//! aborts on panic; `tests/release_panics.rs` runs it with `--release`.
+
+use std::io::{Cursor, Write};
+use std::sync::{Arc, Mutex};
+use untar::config::Config;
+use untar::processor::{Processor, RunFailed, TarSource};
+use untar::sink::Sink;
+
+fn main() {
+    let mut manifest = tempfile::NamedTempFile::new().unwrap();
+    write!(
+        manifest,
+        "<transmit-content><file><filename>a.txt</filename><filesize>3</filesize></file></transmit-content>"
+    )
+    .unwrap();
+    let mut builder = tar::Builder::new(Vec::new());
+    let mut header = tar::Header::new_gnu();
+    header.set_size(3);
+    header.set_mode(0o644);
+    header.set_cksum();
+    builder.append_data(&mut header, "a.txt", &b"abc"[..]).unwrap();
+    let source = TarSource { name: "panic.tar".to_string(), reader: Cursor::new(builder.into_inner().unwrap()), top_dir: None };
+
+    // A poisoned in-memory sink: the upload panics when it closes the file.
+    let files = Arc::new(Mutex::new(Default::default()));
+    let poisoner = files.clone();
+    let _ = std::thread::spawn(move || {
+        let _held = poisoner.lock().unwrap();
+        panic!("poisoning the sink");
+    })
+    .join();
+
+    let processor = Processor::new(
+        Sink::Memory(files),
+        Config::from_xml_file(manifest.path()).unwrap(),
+        "/dst".to_string(),
+        manifest.path().to_string_lossy().to_string(),
+    );
+    let runtime = tokio::runtime::Runtime::new().unwrap();
+    let failed = runtime.block_on(processor.process_tars(vec![source])).unwrap_err();
+    let report = &failed.downcast_ref::<RunFailed>().expect("the run fails with its report").report;
+    assert_eq!(report.failures.len(), 1);
+    assert!(report.failures[0].error.contains("worker panicked"), "{}", report.failures[0].error);
+    assert!(report.failures[0].backtrace.is_some());
+    println!("worker panic reported as: {}", report.failures[0].error);
+}
@@ -0,0 +1,10 @@
+#![no_main]
+
+use std::io::Read;
+use libfuzzer_sys::fuzz_target;
+use untar::decompress::{get_format, wrap_decoder};
+
+fuzz_target!(|data: &[u8]| {
+    let mut out = Vec::new();
+    let _ = wrap_decoder(get_format("fuzz.gz"), data).read_to_end(&mut out);
+});
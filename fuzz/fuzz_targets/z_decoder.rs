@@ -0,0 +1,11 @@
+#![no_main]
+
+use std::io::Read;
+use libfuzzer_sys::fuzz_target;
+use untar::decompress::ZDecoder;
+
+// Arbitrary input may be rejected with an error, but must never panic or hang.
+fuzz_target!(|data: &[u8]| {
+    let mut out = Vec::new();
+    let _ = ZDecoder::new(data).read_to_end(&mut out);
+});
@@ -0,0 +1,423 @@
+use std::fs::File;
+use std::io::{self, Read, Seek};
+use std::ops::Deref;
+use std::os::fd::AsRawFd;
+use std::os::unix::fs::FileExt;
+use std::path::Component;
+use std::sync::mpsc::{channel, Receiver};
+use std::sync::Arc;
+use anyhow::{Context, Result};
+use tar::Archive;
+use tokio::io::{AsyncRead, AsyncReadExt};
+use tokio::sync::{OwnedSemaphorePermit, Semaphore};
+use tokio::task::AbortHandle;
+
+use crate::decompress::strip_suffix;
+
+/// Scans the tar headers (seeking over entry data) and returns the name of the
+/// single top-level directory that wraps every entry, if there is one.
+pub fn detect_top_dir<R: Read + Seek>(reader: R) -> Result<Option<String>> {
+    let mut archive = Archive::new(reader);
+    let entries = archive.entries_with_seek().context("Failed to read tar entries")?;
+
+    let mut top_dir: Option<String> = None;
+    let mut seen = false;
+    for entry_res in entries {
+        let entry = match entry_res {
+            Ok(entry) => entry,
+            // Junk after the last member; extraction reports it.
+            Err(e) if seen && is_garbage_header(&e) => break,
+            Err(e) => return Err(e).context("Failed to get tar entry"),
+        };
+        seen = true;
+        let path = entry.path()?.into_owned();
+        let mut components = path
+            .components()
+            .filter(|c| !matches!(c, Component::CurDir));
+
+        let first = match components.next() {
+            Some(Component::Normal(name)) => name.to_string_lossy().to_string(),
+            Some(_) => return Ok(None),
+            None => continue,
+        };
+        // A regular file at the top level means there is no wrapping directory.
+        if components.next().is_none() && !entry.header().entry_type().is_dir() {
+            return Ok(None);
+        }
+
+        match &top_dir {
+            Some(dir) if *dir != first => return Ok(None),
+            Some(_) => {}
+            None => top_dir = Some(first),
+        }
+    }
+
+    Ok(top_dir)
+}
+
+/// Removes `top_dir/` (and any leading `./`) from a tar entry path.
+/// Returns an empty string for the directory entry itself.
+pub fn strip_top_dir(path: &str, top_dir: &str) -> String {
+    let path = path.trim_start_matches("./");
+    match path.strip_prefix(top_dir) {
+        Some(rest) if rest.is_empty() || rest.starts_with('/') => {
+            rest.trim_start_matches('/').to_string()
+        }
+        _ => path.to_string(),
+    }
+}
+
+/// Scans the tar headers (seeking over entry data) for the member `name`,
+/// matched with or without its compression suffix after stripping `top_dir`.
+/// Returns the offset where the member's headers start, including any long
+/// name/PAX headers, so a reader positioned there yields that member first.
+pub fn find_entry<R: Read + Seek>(reader: R, name: &str, top_dir: Option<&str>) -> Result<Option<u64>> {
+    let mut archive = Archive::new(reader);
+    let entries = archive.entries_with_seek().context("Failed to read tar entries")?;
+
+    let mut start = 0u64;
+    for entry_res in entries {
+        let entry = match entry_res {
+            Ok(entry) => entry,
+            Err(e) if start > 0 && is_garbage_header(&e) => break,
+            Err(e) => return Err(e).context("Failed to get tar entry"),
+        };
+        let mut path = entry.path()?.to_string_lossy().to_string();
+        if let Some(top_dir) = top_dir {
+            path = strip_top_dir(&path, top_dir);
+        }
+        if path == name || strip_suffix(&path) == name {
+            return Ok(Some(start));
+        }
+        start = entry.raw_file_position() + entry.size().div_ceil(512) * 512;
+    }
+
+    Ok(None)
+}
+
+/// A member found by `index_entries`.
+pub struct IndexedEntry {
+    pub path: String,
+    /// Where the member's data starts in the tar, past its headers.
+    pub offset: u64,
+    pub size: u64,
+}
+
+/// The members of a tar, from one pass over its headers.
+pub struct TarIndex {
+    pub entries: Vec<IndexedEntry>,
+    /// Offset just past the last member's data, where the EOF marker should start.
+    pub end_of_members: u64,
+    /// Whether junk was found where the EOF marker should be.
+    pub garbage: bool,
+}
+
+/// Scans the tar headers (seeking over entry data) and records where every
+/// member's data is, so the members can then be read in any order.
+pub fn index_entries<R: Read + Seek>(reader: R) -> Result<TarIndex> {
+    let mut archive = Archive::new(reader);
+    let entries = archive.entries_with_seek().context("Failed to read tar entries")?;
+
+    let mut index = TarIndex { entries: Vec::new(), end_of_members: 0, garbage: false };
+    for entry_res in entries {
+        let entry = match entry_res {
+            Ok(entry) => entry,
+            Err(e) if index.end_of_members > 0 && is_garbage_header(&e) => {
+                index.garbage = true;
+                break;
+            }
+            Err(e) => return Err(e).context("Failed to get tar entry"),
+        };
+        let offset = entry.raw_file_position();
+        index.end_of_members = offset + entry.size().div_ceil(512) * 512;
+        index.entries.push(IndexedEntry {
+            path: entry.path()?.to_string_lossy().to_string(),
+            offset,
+            size: entry.size(),
+        });
+    }
+
+    Ok(index)
+}
+
+/// A tar on local disk whose members can be read at any offset.
+pub enum LocalTar {
+    File(File),
+    Mapped(MappedFile),
+}
+
+impl LocalTar {
+    fn read_at(&self, buf: &mut [u8], offset: u64) -> io::Result<usize> {
+        match self {
+            LocalTar::File(file) => file.read_at(buf, offset),
+            LocalTar::Mapped(mapped) => {
+                let start = usize::try_from(offset).unwrap_or(usize::MAX).min(mapped.len());
+                let n = buf.len().min(mapped.len() - start);
+                buf[..n].copy_from_slice(&mapped[start..start + n]);
+                Ok(n)
+            }
+        }
+    }
+
+    pub fn size(&self) -> io::Result<u64> {
+        match self {
+            LocalTar::File(file) => Ok(file.metadata()?.len()),
+            LocalTar::Mapped(mapped) => Ok(mapped.len() as u64),
+        }
+    }
+}
+
+/// A file mapped read-only into memory, so it is read without syscalls and
+/// any part of it can be read again for free. The file must not shrink
+/// while it is mapped.
+pub struct MappedFile {
+    ptr: *const u8,
+    len: usize,
+}
+
+// The mapping is read-only and lives until the value is dropped.
+unsafe impl Send for MappedFile {}
+unsafe impl Sync for MappedFile {}
+
+impl MappedFile {
+    pub fn map(file: &File) -> io::Result<Self> {
+        let len = usize::try_from(file.metadata()?.len()).map_err(io::Error::other)?;
+        if len == 0 {
+            // mmap refuses empty mappings.
+            return Ok(Self { ptr: std::ptr::NonNull::dangling().as_ptr(), len });
+        }
+        let ptr = unsafe {
+            libc::mmap(std::ptr::null_mut(), len, libc::PROT_READ, libc::MAP_PRIVATE, file.as_raw_fd(), 0)
+        };
+        if ptr == libc::MAP_FAILED {
+            return Err(io::Error::last_os_error());
+        }
+        Ok(Self { ptr: ptr as *const u8, len })
+    }
+}
+
+impl Deref for MappedFile {
+    type Target = [u8];
+
+    fn deref(&self) -> &[u8] {
+        unsafe { std::slice::from_raw_parts(self.ptr, self.len) }
+    }
+}
+
+impl AsRef<[u8]> for MappedFile {
+    fn as_ref(&self) -> &[u8] {
+        self
+    }
+}
+
+impl Drop for MappedFile {
+    fn drop(&mut self) {
+        if self.len > 0 {
+            unsafe { libc::munmap(self.ptr as *mut libc::c_void, self.len) };
+        }
+    }
+}
+
+/// Reads `len` bytes of a local tar from `offset` on, independently of any
+/// other reader of the same tar, so members can be read at once.
+#[derive(Clone)]
+pub struct EntryReader {
+    tar: Arc<LocalTar>,
+    offset: u64,
+    remaining: u64,
+}
+
+impl EntryReader {
+    pub fn new(tar: Arc<LocalTar>, offset: u64, len: u64) -> Self {
+        Self { tar, offset, remaining: len }
+    }
+}
+
+impl Read for EntryReader {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        let len = buf.len().min(usize::try_from(self.remaining).unwrap_or(usize::MAX));
+        if len == 0 {
+            return Ok(0);
+        }
+        let n = self.tar.read_at(&mut buf[..len], self.offset)?;
+        if n == 0 {
+            return Err(io::Error::new(io::ErrorKind::UnexpectedEof, "tar ends inside a member"));
+        }
+        self.offset += n as u64;
+        self.remaining -= n as u64;
+        Ok(n)
+    }
+}
+
+/// Chunks an `AsyncTarReader` reads ahead of the tar parser.
+const READ_AHEAD_CHUNKS: usize = 4;
+
+/// Reads a tar through an `AsyncRead` on a runtime task, a few chunks ahead
+/// of the tar parser, which takes the bytes through `Read`. Local files are
+/// read with `tokio::fs` this way, so disk latency stalls neither the parser
+/// nor a runtime worker, and remote sources can be plugged in the same way.
+///
+/// Needs a multi-thread runtime: reads block while the next chunk is fetched.
+pub struct AsyncTarReader {
+    chunks: Receiver<io::Result<ReadChunk>>,
+    current: Option<ReadChunk>,
+    task: AbortHandle,
+}
+
+struct ReadChunk {
+    data: Vec<u8>,
+    consumed: usize,
+    /// Lets the task read another chunk once this one is used up.
+    _slot: OwnedSemaphorePermit,
+}
+
+impl AsyncTarReader {
+    /// Starts reading `reader` in chunks of up to `chunk_size` bytes.
+    pub fn spawn<R: AsyncRead + Send + 'static>(reader: R, chunk_size: usize) -> Self {
+        let (tx, rx) = channel();
+        let slots = Arc::new(Semaphore::new(READ_AHEAD_CHUNKS));
+        let task = tokio::spawn(async move {
+            let mut reader = std::pin::pin!(reader);
+            loop {
+                let slot = slots.clone().acquire_owned().await.expect("read slots are never closed");
+                let mut data = vec![0u8; chunk_size];
+                let chunk = match reader.read(&mut data).await {
+                    Ok(0) => return,
+                    Ok(n) => {
+                        data.truncate(n);
+                        Ok(ReadChunk { data, consumed: 0, _slot: slot })
+                    }
+                    Err(e) => Err(e),
+                };
+                let failed = chunk.is_err();
+                // The reader hangs up when it is dropped.
+                if tx.send(chunk).is_err() || failed {
+                    return;
+                }
+            }
+        });
+        Self { chunks: rx, current: None, task: task.abort_handle() }
+    }
+
+    /// Reads a local file with `tokio::fs`, `chunk_size` bytes at a time.
+    pub fn open(file: File, chunk_size: usize) -> Self {
+        let mut file = tokio::fs::File::from_std(file);
+        file.set_max_buf_size(chunk_size);
+        Self::spawn(file, chunk_size)
+    }
+}
+
+impl Read for AsyncTarReader {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        loop {
+            if let Some(chunk) = &mut self.current
+                && chunk.consumed < chunk.data.len()
+            {
+                let n = buf.len().min(chunk.data.len() - chunk.consumed);
+                buf[..n].copy_from_slice(&chunk.data[chunk.consumed..chunk.consumed + n]);
+                chunk.consumed += n;
+                return Ok(n);
+            }
+            // Frees the slot of the chunk used up.
+            self.current = None;
+            match self.chunks.recv() {
+                Ok(Ok(chunk)) => self.current = Some(chunk),
+                Ok(Err(e)) => return Err(e),
+                // The task is done: the end of the tar.
+                Err(_) => return Ok(0),
+            }
+        }
+    }
+}
+
+impl Drop for AsyncTarReader {
+    fn drop(&mut self) {
+        self.task.abort();
+    }
+}
+
+/// Counts the bytes read through it, so the position in a non-seekable tar
+/// stream is known after the archive reader is done with it.
+pub struct CountingReader<R> {
+    inner: R,
+    position: u64,
+}
+
+impl<R: Read> CountingReader<R> {
+    pub fn new(inner: R) -> Self {
+        Self { inner, position: 0 }
+    }
+
+    pub fn position(&self) -> u64 {
+        self.position
+    }
+}
+
+impl<R: Read> Read for CountingReader<R> {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        let n = self.inner.read(buf)?;
+        self.position += n as u64;
+        Ok(n)
+    }
+}
+
+/// Whether a tar iteration error means the next header block is junk or a
+/// truncated block, rather than an I/O failure. The tar crate only exposes
+/// these as messages.
+pub fn is_garbage_header(err: &io::Error) -> bool {
+    let message = err.to_string();
+    message.contains("checksum mismatch")
+        || message.contains("failed to read entire block")
+        || message.contains("numeric field")
+}
+
+/// Reads everything left in `reader`, returning the number of non-zero bytes.
+pub fn count_non_zero<R: Read>(mut reader: R) -> io::Result<u64> {
+    let mut buf = vec![0u8; 64 * 1024];
+    let mut non_zero = 0u64;
+    loop {
+        let n = reader.read(&mut buf)?;
+        if n == 0 {
+            return Ok(non_zero);
+        }
+        non_zero += buf[..n].iter().filter(|&&b| b != 0).count() as u64;
+    }
+}
+
+/// Regex for a shell glob over a single file name. `[!...]` negates a class,
+/// and a `[` without a closing `]` matches itself.
+pub fn glob_regex(glob: &str) -> Result<regex::Regex> {
+    let chars: Vec<char> = glob.chars().collect();
+    let mut pattern = String::from("^");
+    let mut i = 0;
+    while i < chars.len() {
+        match chars[i] {
+            '*' => pattern.push_str(".*"),
+            '?' => pattern.push('.'),
+            '[' => {
+                let negated = chars.get(i + 1) == Some(&'!');
+                let start = i + 1 + negated as usize;
+                // A `]` right after the bracket belongs to the class.
+                match chars.iter().skip(start + 1).position(|&c| c == ']') {
+                    Some(len) => {
+                        let end = start + 1 + len;
+                        pattern.push_str(if negated { "[^" } else { "[" });
+                        for &c in &chars[start..end] {
+                            match c {
+                                '-' => pattern.push('-'),
+                                c => pattern.push_str(&regex::escape(c.encode_utf8(&mut [0; 4]))),
+                            }
+                        }
+                        pattern.push(']');
+                        i = end;
+                    }
+                    None => pattern.push_str(r"\["),
+                }
+            }
+            c => pattern.push_str(&regex::escape(c.encode_utf8(&mut [0; 4]))),
+        }
+        i += 1;
+    }
+    pattern.push('$');
+    Ok(regex::Regex::new(&pattern)?)
+}
@@ -0,0 +1,256 @@
+use std::collections::HashMap;
+use std::io::{self, Write};
+use flate2::write::GzEncoder;
+use flate2::Compression;
+
+/// Code that makes the decoder start over with an empty table.
+const CLEAR: u32 = 256;
+/// Input bytes between compression ratio checks, as in `compress`.
+const CHECK_GAP: u64 = 10_000;
+
+/// Writes data in the .Z format of Unix `compress`, e.g. to build test
+/// fixtures that `ZDecoder`, `uncompress` and `gzip -d` all read back.
+///
+/// ```
+/// use std::io::{Read, Write};
+/// use untar::compress::ZEncoder;
+/// use untar::decompress::ZDecoder;
+///
+/// let mut encoder = ZEncoder::new(Vec::new(), 16);
+/// encoder.write_all(b"to be or not to be, that is the question").unwrap();
+/// let compressed = encoder.finish().unwrap();
+///
+/// let mut out = Vec::new();
+/// ZDecoder::new(&compressed[..]).read_to_end(&mut out).unwrap();
+/// assert_eq!(out, b"to be or not to be, that is the question");
+/// ```
+///
+/// Like `compress`, the encoder works in block mode: once the table is full,
+/// it is cleared whenever the compression ratio starts to drop.
+pub struct ZEncoder<W: Write> {
+    inner: W,
+    max_bits: u8,
+    bits: u8,
+    /// (prefix code, next byte) -> code
+    table: HashMap<(u32, u8), u32>,
+    next_code: u32,
+    prefix: Option<u32>,
+    /// Codes written at the current width since its group of eight began.
+    group_codes: u32,
+    buffer: u64,
+    bits_in_buffer: u8,
+    out: Vec<u8>,
+    bytes_in: u64,
+    bytes_out: u64,
+    /// Input position of the next ratio check once the table is full.
+    checkpoint: u64,
+    ratio: f64,
+}
+
+impl<W: Write> ZEncoder<W> {
+    /// `max_bits` is clamped to 10 to 16 bits: `compress -b` also allows 9,
+    /// but gzip misreads such files.
+    pub fn new(inner: W, max_bits: u8) -> Self {
+        let max_bits = max_bits.clamp(10, 16);
+        Self {
+            inner,
+            max_bits,
+            bits: 9,
+            table: HashMap::new(),
+            // 256 is the CLEAR code of block mode.
+            next_code: 257,
+            prefix: None,
+            group_codes: 0,
+            buffer: 0,
+            bits_in_buffer: 0,
+            out: vec![0x1f, 0x9d, 0x80 | max_bits],
+            bytes_in: 0,
+            bytes_out: 3,
+            checkpoint: CHECK_GAP,
+            ratio: 0.0,
+        }
+    }
+
+    fn put_bits(&mut self, value: u32, bits: u8) {
+        self.buffer |= (value as u64) << self.bits_in_buffer;
+        self.bits_in_buffer += bits;
+        while self.bits_in_buffer >= 8 {
+            self.out.push(self.buffer as u8);
+            self.bytes_out += 1;
+            self.buffer >>= 8;
+            self.bits_in_buffer -= 8;
+        }
+    }
+
+    /// Pads out the current group of eight codes.
+    fn end_group(&mut self) {
+        while !self.group_codes.is_multiple_of(8) {
+            self.put_bits(0, self.bits);
+            self.group_codes += 1;
+        }
+        self.group_codes = 0;
+    }
+
+    /// Starts over with an empty table if the ratio dropped since the last check.
+    fn check_ratio(&mut self) {
+        if self.bytes_in < self.checkpoint {
+            return;
+        }
+        self.checkpoint = self.bytes_in + CHECK_GAP;
+        let ratio = self.bytes_in as f64 / self.bytes_out as f64;
+        if ratio > self.ratio {
+            self.ratio = ratio;
+            return;
+        }
+        self.emit(CLEAR);
+        self.end_group();
+        self.table.clear();
+        self.next_code = 257;
+        self.bits = 9;
+        self.ratio = 0.0;
+    }
+
+    fn emit(&mut self, code: u32) {
+        // compress reads codes in groups of eight and skips the rest of a
+        // group when the width grows, so the group is padded out first.
+        if self.next_code > 1 << self.bits && self.bits < self.max_bits {
+            self.end_group();
+            self.bits += 1;
+        }
+        self.put_bits(code, self.bits);
+        self.group_codes += 1;
+    }
+
+    fn flush_output(&mut self) -> io::Result<()> {
+        self.inner.write_all(&self.out)?;
+        self.out.clear();
+        Ok(())
+    }
+
+    /// Writes the last code and returns the inner writer.
+    pub fn finish(mut self) -> io::Result<W> {
+        if let Some(prefix) = self.prefix.take() {
+            self.emit(prefix);
+        }
+        if self.bits_in_buffer > 0 {
+            self.out.push(self.buffer as u8);
+            self.bits_in_buffer = 0;
+        }
+        self.flush_output()?;
+        self.inner.flush()?;
+        Ok(self.inner)
+    }
+}
+
+impl<W: Write> Write for ZEncoder<W> {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        for &byte in buf {
+            self.bytes_in += 1;
+            let Some(prefix) = self.prefix else {
+                self.prefix = Some(byte as u32);
+                continue;
+            };
+            if let Some(&code) = self.table.get(&(prefix, byte)) {
+                self.prefix = Some(code);
+                continue;
+            }
+            self.emit(prefix);
+            if self.next_code < 1 << self.max_bits {
+                self.table.insert((prefix, byte), self.next_code);
+                self.next_code += 1;
+            } else {
+                self.check_ratio();
+            }
+            self.prefix = Some(byte as u32);
+        }
+        if self.out.len() >= 64 * 1024 {
+            self.flush_output()?;
+        }
+        Ok(buf.len())
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.flush_output()?;
+        self.inner.flush()
+    }
+}
+
+/// Format `--recompress` stores files in on HDFS.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Recompression {
+    Gzip { level: u32 },
+    Zstd { level: i32 },
+}
+
+impl Recompression {
+    /// Parses `gzip` or `zstd`, optionally with a level, e.g. `zstd:19`.
+    pub fn parse(value: &str) -> Result<Self, String> {
+        let (format, level) = match value.split_once(':') {
+            Some((format, level)) => (format, Some(level)),
+            None => (value, None),
+        };
+        let invalid_level = |range: &str| format!("invalid {} level '{}', expected {}", format, level.unwrap_or_default(), range);
+        match format {
+            "gzip" => match level.map(str::parse::<u32>) {
+                None => Ok(Recompression::Gzip { level: 6 }),
+                Some(Ok(level)) if level <= 9 => Ok(Recompression::Gzip { level }),
+                Some(_) => Err(invalid_level("0 to 9")),
+            },
+            "zstd" => match level.map(str::parse::<i32>) {
+                None => Ok(Recompression::Zstd { level: zstd::DEFAULT_COMPRESSION_LEVEL }),
+                Some(Ok(level)) if zstd::compression_level_range().contains(&level) => {
+                    Ok(Recompression::Zstd { level })
+                }
+                Some(_) => Err(invalid_level("-7 to 22")),
+            },
+            _ => Err(format!("invalid --recompress '{}', expected gzip or zstd[:level]", value)),
+        }
+    }
+
+    /// Appended to the names of the stored files.
+    pub fn suffix(&self) -> &'static str {
+        match self {
+            Recompression::Gzip { .. } => ".gz",
+            Recompression::Zstd { .. } => ".zst",
+        }
+    }
+
+    pub fn compressor(&self) -> io::Result<Recompressor> {
+        Ok(match *self {
+            Recompression::Gzip { level } => {
+                Recompressor::Gzip(GzEncoder::new(Vec::new(), Compression::new(level)))
+            }
+            Recompression::Zstd { level } => Recompressor::Zstd(zstd::stream::write::Encoder::new(Vec::new(), level)?),
+        })
+    }
+}
+
+/// Compresses one file chunk by chunk for `--recompress`.
+pub enum Recompressor {
+    Gzip(GzEncoder<Vec<u8>>),
+    Zstd(zstd::stream::write::Encoder<'static, Vec<u8>>),
+}
+
+impl Recompressor {
+    /// Compresses `data` and returns whatever compressed output is ready, possibly nothing.
+    pub fn compress(&mut self, data: &[u8]) -> io::Result<Vec<u8>> {
+        Ok(match self {
+            Recompressor::Gzip(encoder) => {
+                encoder.write_all(data)?;
+                std::mem::take(encoder.get_mut())
+            }
+            Recompressor::Zstd(encoder) => {
+                encoder.write_all(data)?;
+                std::mem::take(encoder.get_mut())
+            }
+        })
+    }
+
+    /// Ends the stream and returns the rest of the output.
+    pub fn finish(self) -> io::Result<Vec<u8>> {
+        match self {
+            Recompressor::Gzip(encoder) => encoder.finish(),
+            Recompressor::Zstd(encoder) => encoder.finish(),
+        }
+    }
+}
@@ -1,42 +1,384 @@
-use serde::{Deserialize, Serialize};
-use std::collections::HashMap;
+use std::collections::{HashMap, HashSet};
+use std::fs::File;
+use std::io::BufReader;
 use std::path::Path;
-use anyhow::{Context, Result};
-use quick_xml::de::from_str;
+use anyhow::{anyhow, Context, Result};
+use quick_xml::events::Event;
+use quick_xml::Reader;
+use tracing::info;
 
-#[derive(Debug, Serialize, Deserialize, PartialEq)]
-#[serde(rename = "transmit-content")]
-pub struct Manifest {
-    #[serde(rename = "file", default)]
-    pub file: Vec<FileEntry>,
+use crate::workdir::WorkDir;
+
+/// Log parse progress every this many manifest entries.
+const PROGRESS_INTERVAL: usize = 1_000_000;
+
+pub struct Config {
+    pub file_map: FileMap,
+    /// Number of files and sum of their sizes.
+    pub entries: usize,
+    pub total_size: u64,
+    /// `<deleted-file>` entries: paths this delivery removes.
+    pub deleted: Vec<DeletedFile>,
 }
 
-#[derive(Debug, Serialize, Deserialize, PartialEq)]
-pub struct FileEntry {
-    #[serde(rename = "filename")]
+/// A file a differential delivery removes from the destination.
+#[derive(Debug, Clone)]
+pub struct DeletedFile {
     pub filename: String,
-    #[serde(rename = "filesize")]
-    pub filesize: u64,
+    pub group: Option<String>,
 }
 
-pub struct Config {
-    pub file_map: HashMap<String, u64>,
+/// Filename -> expected size (and manifest group) lookup, in memory or backed
+/// by an on-disk index for manifests too large to hold in RAM.
+pub enum FileMap {
+    Memory {
+        sizes: HashMap<String, u64>,
+        /// Only files with a `<group>` are listed.
+        groups: HashMap<String, String>,
+    },
+    /// Values are the size, followed by the group if there is one.
+    Disk(sled::Db),
+}
+
+impl FileMap {
+    pub fn in_memory() -> Self {
+        FileMap::Memory { sizes: HashMap::new(), groups: HashMap::new() }
+    }
+
+    /// Opens a temporary on-disk index at `path` (removed when dropped).
+    pub fn on_disk<P: AsRef<Path>>(path: P) -> Result<Self> {
+        let db = sled::Config::new()
+            .path(path.as_ref())
+            .temporary(true)
+            .open()
+            .context("Failed to create on-disk manifest index")?;
+        Ok(FileMap::Disk(db))
+    }
+
+    pub fn get(&self, filename: &str) -> Result<Option<u64>> {
+        Ok(match self {
+            FileMap::Memory { sizes, .. } => sizes.get(filename).copied(),
+            FileMap::Disk(db) => db
+                .get(filename)
+                .context("Failed to read on-disk manifest index")?
+                .map(|value| decode_size(&value)),
+        })
+    }
+
+    /// The manifest `<group>` of `filename`, if it has one.
+    pub fn group(&self, filename: &str) -> Result<Option<String>> {
+        Ok(match self {
+            FileMap::Memory { groups, .. } => groups.get(filename).cloned(),
+            FileMap::Disk(db) => db
+                .get(filename)
+                .context("Failed to read on-disk manifest index")?
+                .filter(|value| value.len() > 8)
+                .map(|value| String::from_utf8_lossy(&value[8..]).to_string()),
+        })
+    }
+
+    pub fn contains_key(&self, filename: &str) -> Result<bool> {
+        Ok(self.get(filename)?.is_some())
+    }
+
+    pub fn iter(&self) -> Box<dyn Iterator<Item = Result<(String, u64)>> + '_> {
+        match self {
+            FileMap::Memory { sizes, .. } => Box::new(sizes.iter().map(|(k, v)| Ok((k.clone(), *v)))),
+            FileMap::Disk(db) => Box::new(db.iter().map(|item| {
+                let (key, value) = item.context("Failed to read on-disk manifest index")?;
+                Ok((String::from_utf8_lossy(&key).to_string(), decode_size(&value)))
+            })),
+        }
+    }
+
+    pub fn keys(&self) -> impl Iterator<Item = Result<String>> + '_ {
+        self.iter().map(|item| item.map(|(k, _)| k))
+    }
+
+    fn insert(&mut self, filename: String, size: u64, group: Option<String>) -> Result<()> {
+        match self {
+            FileMap::Memory { sizes, groups } => {
+                if let Some(group) = group {
+                    groups.insert(filename.clone(), group);
+                }
+                sizes.insert(filename, size);
+            }
+            FileMap::Disk(db) => {
+                let mut value = size.to_be_bytes().to_vec();
+                value.extend_from_slice(group.unwrap_or_default().as_bytes());
+                db.insert(filename.as_bytes(), value)
+                    .context("Failed to write on-disk manifest index")?;
+            }
+        }
+        Ok(())
+    }
+}
+
+fn decode_size(value: &[u8]) -> u64 {
+    let mut bytes = [0u8; 8];
+    bytes.copy_from_slice(&value[..8]);
+    u64::from_be_bytes(bytes)
 }
 
 impl Config {
     pub fn from_xml_file<P: AsRef<Path>>(path: P) -> Result<Self> {
-        let content = std::fs::read_to_string(path).context("Failed to read XML file")?;
-        let manifest: Manifest = from_str(&content).context("Failed to parse XML")?;
-        
-        let mut file_map = HashMap::new();
-        for entry in manifest.file {
-            file_map.insert(entry.filename, entry.filesize);
+        Self::parse(path, FileMap::in_memory(), None)
+    }
+
+    /// Like `from_xml_file`, but manifests larger than `threshold` bytes are
+    /// indexed on disk in the work directory instead of in memory.
+    pub fn from_xml_file_indexed<P: AsRef<Path>>(path: P, threshold: u64, work_dir: &WorkDir) -> Result<Self> {
+        let xml_size = std::fs::metadata(&path).context("Failed to read XML file")?.len();
+        if xml_size > threshold {
+            info!("Manifest is {} bytes, building on-disk index under {}", xml_size, work_dir.path().display());
+            work_dir.ensure_free_space()?;
+            Self::parse(path, FileMap::on_disk(work_dir.temp_path("manifest"))?, Some(work_dir))
+        } else {
+            Self::parse(path, FileMap::in_memory(), None)
         }
-        
-        Ok(Config { file_map })
     }
 
-    pub fn get_expected_size(&self, filename: &str) -> Option<u64> {
-        self.file_map.get(filename).copied()
+    fn parse<P: AsRef<Path>>(path: P, mut file_map: FileMap, work_dir: Option<&WorkDir>) -> Result<Self> {
+        let mut inserted = 0usize;
+        let mut total_size = 0u64;
+        let mut deleted = Vec::new();
+        let entries = for_each_entry(path, |entry| {
+            if entry.deleted {
+                check_deleted_name(&entry.filename)?;
+                deleted.push(DeletedFile { filename: entry.filename, group: entry.group });
+                return Ok(());
+            }
+            let size = entry.filesize.ok_or_else(|| {
+                anyhow!("Failed to parse XML: <file> {} without <filesize>", entry.filename)
+            })?;
+            file_map.insert(entry.filename, size, entry.group)?;
+            inserted += 1;
+            total_size += size;
+            match work_dir {
+                Some(work_dir) if inserted.is_multiple_of(PROGRESS_INTERVAL) => work_dir.ensure_free_space(),
+                _ => Ok(()),
+            }
+        })?;
+
+        if let FileMap::Memory { sizes, groups } = &mut file_map {
+            sizes.shrink_to_fit();
+            groups.shrink_to_fit();
+        }
+        info!("Loaded {} manifest entries", entries);
+        if !deleted.is_empty() {
+            info!("Manifest deletes {} files", deleted.len());
+        }
+        Ok(Config { file_map, entries, total_size, deleted })
+    }
+
+    pub fn get_expected_size(&self, filename: &str) -> Result<Option<u64>> {
+        self.file_map.get(filename)
+    }
+
+    pub fn group(&self, filename: &str) -> Result<Option<String>> {
+        self.file_map.group(filename)
+    }
+
+    /// Maps each file name (last path component) to its manifest path, or to
+    /// `None` when several directories hold a file of that name.
+    pub fn paths_by_file_name(&self) -> Result<HashMap<String, Option<String>>> {
+        let mut paths: HashMap<String, Option<String>> = HashMap::new();
+        for filename in self.file_map.keys() {
+            let filename = filename?;
+            let name = filename.rsplit('/').next().unwrap_or(&filename).to_string();
+            paths
+                .entry(name)
+                .and_modify(|path| *path = None)
+                .or_insert(Some(filename));
+        }
+        Ok(paths)
+    }
+
+    /// Compares this manifest against the one from a previous delivery.
+    pub fn diff(&self, previous: &Config) -> Result<ManifestDelta> {
+        let mut delta = ManifestDelta::default();
+
+        for item in self.file_map.iter() {
+            let (filename, size) = item?;
+            match previous.file_map.get(&filename)? {
+                None => delta.added.push((filename, size)),
+                Some(old_size) if old_size != size => {
+                    delta.changed.push((filename, old_size, size))
+                }
+                Some(_) => delta.unchanged += 1,
+            }
+        }
+        for filename in previous.file_map.keys() {
+            let filename = filename?;
+            if !self.file_map.contains_key(&filename)? {
+                delta.removed.push(filename);
+            }
+        }
+
+        delta.added.sort();
+        delta.changed.sort();
+        delta.removed.sort();
+        Ok(delta)
+    }
+}
+
+/// Rejects `<deleted-file>` names that would reach the destination root
+/// itself or outside it once joined to the root: empty or absolute ones,
+/// and ones with an empty, `.` or `..` component.
+fn check_deleted_name(filename: &str) -> Result<()> {
+    if filename.starts_with('/') || filename.split('/').any(|part| matches!(part, "" | "." | "..")) {
+        return Err(anyhow!(
+            "Failed to parse XML: <deleted-file> {:?} is not a relative path below the destination",
+            filename
+        ));
+    }
+    Ok(())
+}
+
+/// Loads only the filenames of a manifest, e.g. an exclusion list.
+pub fn load_filenames<P: AsRef<Path>>(path: P) -> Result<HashSet<String>> {
+    let mut names = HashSet::new();
+    for_each_entry(path, |entry| {
+        if !entry.deleted {
+            names.insert(entry.filename);
+        }
+        Ok(())
+    })?;
+    Ok(names)
+}
+
+/// Maps every file of a manifest to its position in it, counting from 0.
+pub fn load_positions<P: AsRef<Path>>(path: P) -> Result<HashMap<String, usize>> {
+    let mut positions = HashMap::new();
+    for_each_entry(path, |entry| {
+        if !entry.deleted {
+            let position = positions.len();
+            positions.entry(entry.filename).or_insert(position);
+        }
+        Ok(())
+    })?;
+    Ok(positions)
+}
+
+/// One `<file>` or `<deleted-file>` element of a manifest.
+struct ManifestEntry {
+    deleted: bool,
+    filename: String,
+    filesize: Option<u64>,
+    /// Optional `<group>`, e.g. `logs`, used to pick the destination root.
+    group: Option<String>,
+}
+
+/// Streams the `<transmit-content>` manifest, so memory use is bounded by
+/// what the caller keeps rather than the size of the XML document.
+/// Returns the number of `<file>` entries seen.
+fn for_each_entry<P, F>(path: P, mut on_entry: F) -> Result<usize>
+where
+    P: AsRef<Path>,
+    F: FnMut(ManifestEntry) -> Result<()>,
+{
+    let file = File::open(path).context("Failed to read XML file")?;
+    let mut reader = Reader::from_reader(BufReader::with_capacity(1 << 20, file));
+    reader.trim_text(true);
+
+    let mut buf = Vec::new();
+    let mut entries = 0usize;
+    // Inside a <file> (false) or <deleted-file> (true).
+    let mut in_entry: Option<bool> = None;
+    let mut field: Option<Vec<u8>> = None;
+    let mut text = String::new();
+    let mut filename: Option<String> = None;
+    let mut filesize: Option<u64> = None;
+    let mut group: Option<String> = None;
+
+    loop {
+        match reader.read_event_into(&mut buf).context("Failed to parse XML")? {
+            Event::Start(e) => {
+                let name = e.name().as_ref().to_vec();
+                if name == b"file" || name == b"deleted-file" {
+                    in_entry = Some(name == b"deleted-file");
+                    filename = None;
+                    filesize = None;
+                    group = None;
+                } else if in_entry.is_some() {
+                    field = Some(name);
+                    text.clear();
+                }
+            }
+            Event::Text(t) if field.is_some() => {
+                text.push_str(&t.unescape().context("Failed to parse XML")?);
+            }
+            Event::CData(t) if field.is_some() => {
+                text.push_str(&String::from_utf8_lossy(&t.into_inner()));
+            }
+            Event::End(e) => {
+                let name = e.name();
+                if name.as_ref() == b"deleted-file" {
+                    in_entry = None;
+                    let filename = filename
+                        .take()
+                        .ok_or_else(|| anyhow!("Failed to parse XML: <deleted-file> without <filename>"))?;
+                    on_entry(ManifestEntry { deleted: true, filename, filesize: None, group: group.take() })?;
+                } else if name.as_ref() == b"file" {
+                    in_entry = None;
+                    let filename = filename
+                        .take()
+                        .ok_or_else(|| anyhow!("Failed to parse XML: <file> without <filename>"))?;
+                    on_entry(ManifestEntry { deleted: false, filename, filesize: filesize.take(), group: group.take() })?;
+                    entries += 1;
+                    if entries.is_multiple_of(PROGRESS_INTERVAL) {
+                        info!("Parsed {} manifest entries", entries);
+                    }
+                } else if field.as_deref() == Some(name.as_ref()) {
+                    match name.as_ref() {
+                        b"filename" => filename = Some(std::mem::take(&mut text)),
+                        b"group" => group = Some(text.trim().to_string()).filter(|g| !g.is_empty()),
+                        b"filesize" => {
+                            filesize = Some(text.trim().parse().context(format!(
+                                "Failed to parse XML: invalid filesize '{}'",
+                                text
+                            ))?)
+                        }
+                        _ => {}
+                    }
+                    field = None;
+                }
+            }
+            Event::Eof => break,
+            _ => {}
+        }
+        buf.clear();
+    }
+
+    Ok(entries)
+}
+
+/// Added/changed/removed files between two deliveries of the same feed.
+#[derive(Debug, Default)]
+pub struct ManifestDelta {
+    pub added: Vec<(String, u64)>,
+    pub changed: Vec<(String, u64, u64)>,
+    pub removed: Vec<String>,
+    pub unchanged: usize,
+}
+
+impl ManifestDelta {
+    pub fn print(&self) {
+        println!(
+            "Delta against previous delivery: {} added, {} changed, {} removed, {} unchanged",
+            self.added.len(),
+            self.changed.len(),
+            self.removed.len(),
+            self.unchanged
+        );
+        for (filename, size) in &self.added {
+            println!("  + {} ({} bytes)", filename, size);
+        }
+        for (filename, old_size, new_size) in &self.changed {
+            println!("  ~ {} ({} -> {} bytes)", filename, old_size, new_size);
+        }
+        for filename in &self.removed {
+            println!("  - {}", filename);
+        }
     }
 }
@@ -0,0 +1,208 @@
+use std::path::Path;
+use std::time::Duration;
+use anyhow::{anyhow, Context, Result};
+use serde::{Deserialize, Serialize};
+use tokio::io::{AsyncBufReadExt, AsyncWriteExt, BufReader};
+use tokio::net::{UnixListener, UnixStream};
+use tokio::sync::watch;
+use tokio::task::JoinHandle;
+use tracing::{info, warn};
+
+use crate::telemetry::{ResourceMonitor, ResourceStatus};
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "lowercase")]
+pub enum RunState {
+    Running,
+    /// No new files are started; in-flight writes also wait when `freeze_writes` is set.
+    Paused { freeze_writes: bool },
+    /// Past the deadline: no new files are started, and once `abort_writes`
+    /// is set, in-flight writes fail too.
+    Expired { abort_writes: bool },
+    Cancelled,
+}
+
+/// Returned once the run's deadline has stopped it.
+#[derive(Debug, Clone, Copy, thiserror::Error)]
+#[error("Run deadline passed")]
+pub struct DeadlinePassed;
+
+/// Request accepted on the control socket, one JSON object per line,
+/// e.g. `{"command": "pause", "freeze_writes": true}`.
+#[derive(Debug, Deserialize)]
+struct ControlRequest {
+    command: String,
+    #[serde(default)]
+    freeze_writes: bool,
+}
+
+#[derive(Debug, Serialize)]
+struct ControlResponse {
+    state: RunState,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    resources: Option<ResourceStatus>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    error: Option<String>,
+}
+
+/// Shared pause/resume/cancel switch for a running job.
+#[derive(Clone)]
+pub struct Control {
+    state: watch::Sender<RunState>,
+    resources: Option<ResourceMonitor>,
+}
+
+impl Default for Control {
+    fn default() -> Self {
+        Self { state: watch::Sender::new(RunState::Running), resources: None }
+    }
+}
+
+impl Control {
+    /// Include memory and open file counts in every response.
+    pub fn with_resources(mut self, monitor: ResourceMonitor) -> Self {
+        self.resources = Some(monitor);
+        self
+    }
+
+    pub fn state(&self) -> RunState {
+        *self.state.borrow()
+    }
+
+    pub fn set_state(&self, state: RunState) {
+        // A cancelled run stays cancelled, an expired one can only end sooner.
+        self.state.send_if_modified(|current| {
+            let allowed = match (*current, state) {
+                (RunState::Cancelled, _) => false,
+                (RunState::Expired { .. }, RunState::Expired { .. } | RunState::Cancelled) => true,
+                (RunState::Expired { .. }, _) => false,
+                _ => true,
+            };
+            if !allowed || *current == state {
+                return false;
+            }
+            *current = state;
+            true
+        });
+    }
+
+    /// Waits while the run is paused. Fails once the run is cancelled or
+    /// past its deadline.
+    pub async fn wait_to_start(&self) -> Result<()> {
+        self.wait_while(
+            |state| matches!(state, RunState::Paused { .. }),
+            |state| matches!(state, RunState::Expired { .. }),
+        )
+        .await
+    }
+
+    /// Waits while the run is paused with frozen writes. Fails once the run
+    /// is cancelled or its writes are aborted.
+    pub async fn wait_to_write(&self) -> Result<()> {
+        self.wait_while(
+            |state| matches!(state, RunState::Paused { freeze_writes: true }),
+            |state| matches!(state, RunState::Expired { abort_writes: true }),
+        )
+        .await
+    }
+
+    async fn wait_while(&self, blocked: impl Fn(&RunState) -> bool, expired: impl Fn(&RunState) -> bool) -> Result<()> {
+        let mut rx = self.state.subscribe();
+        let state = *rx
+            .wait_for(|state| *state == RunState::Cancelled || expired(state) || !blocked(state))
+            .await
+            .map_err(|_| anyhow!("Control channel closed"))?;
+        if state == RunState::Cancelled {
+            return Err(anyhow!("Run cancelled by operator"));
+        }
+        if expired(&state) {
+            return Err(DeadlinePassed.into());
+        }
+        Ok(())
+    }
+
+    /// Stops new files from being started once `remaining` has passed, and
+    /// aborts the writes still in flight `grace` later.
+    pub fn expire_after(&self, remaining: Duration, grace: Duration) -> JoinHandle<()> {
+        let control = self.clone();
+        tokio::spawn(async move {
+            tokio::time::sleep(remaining).await;
+            warn!("Run deadline reached, starting no new files; uploads in flight have {:?} to finish", grace);
+            control.set_state(RunState::Expired { abort_writes: false });
+            tokio::time::sleep(grace).await;
+            warn!("Aborting the uploads still in flight past the deadline");
+            control.set_state(RunState::Expired { abort_writes: true });
+        })
+    }
+
+    /// Listens on a unix socket for pause/resume/cancel/status requests,
+    /// until the returned task is aborted.
+    pub fn serve<P: AsRef<Path>>(&self, path: P) -> Result<JoinHandle<()>> {
+        let path = path.as_ref();
+        if path.exists() {
+            std::fs::remove_file(path)
+                .context(format!("Failed to remove stale control socket {}", path.display()))?;
+        }
+        let listener = UnixListener::bind(path)
+            .context(format!("Failed to bind control socket {}", path.display()))?;
+        info!("Listening for control commands on {}", path.display());
+
+        let control = self.clone();
+        Ok(tokio::spawn(async move {
+            loop {
+                match listener.accept().await {
+                    Ok((stream, _)) => {
+                        let control = control.clone();
+                        tokio::spawn(async move {
+                            if let Err(e) = control.handle_connection(stream).await {
+                                warn!("Control connection error: {}", e);
+                            }
+                        });
+                    }
+                    Err(e) => warn!("Failed to accept control connection: {}", e),
+                }
+            }
+        }))
+    }
+
+    async fn handle_connection(&self, stream: UnixStream) -> Result<()> {
+        let (reader, mut writer) = stream.into_split();
+        let mut lines = BufReader::new(reader).lines();
+
+        while let Some(line) = lines.next_line().await? {
+            let error = match serde_json::from_str::<ControlRequest>(&line) {
+                Ok(request) => self.apply(request).err().map(|e| e.to_string()),
+                Err(e) => Some(format!("Invalid request: {}", e)),
+            };
+            let response = ControlResponse {
+                state: self.state(),
+                resources: self.resources.as_ref().map(ResourceMonitor::status),
+                error,
+            };
+            let mut out = serde_json::to_string(&response)?;
+            out.push('\n');
+            writer.write_all(out.as_bytes()).await?;
+        }
+        Ok(())
+    }
+
+    fn apply(&self, request: ControlRequest) -> Result<()> {
+        match request.command.as_str() {
+            "pause" => {
+                info!("Pausing run (freeze writes: {})", request.freeze_writes);
+                self.set_state(RunState::Paused { freeze_writes: request.freeze_writes });
+            }
+            "resume" => {
+                info!("Resuming run");
+                self.set_state(RunState::Running);
+            }
+            "cancel" => {
+                warn!("Cancelling run on operator request");
+                self.set_state(RunState::Cancelled);
+            }
+            "status" => {}
+            other => return Err(anyhow!("Unknown command: {}", other)),
+        }
+        Ok(())
+    }
+}
@@ -1,37 +1,281 @@
-use std::io::{self, Read};
-use flate2::read::GzDecoder;
+use std::borrow::Cow;
+use std::io::{self, BufRead, BufReader, Read, Write};
+use std::process::{Child, ChildStdin, Command, Stdio};
+use std::sync::mpsc::{self, Receiver};
+use std::sync::OnceLock;
+use std::thread;
+use bzip2::bufread::MultiBzDecoder;
+use xz2::bufread::XzDecoder;
+use xz2::stream::{Action, Status, Stream};
+use zstd::dict::DecoderDictionary;
 
+#[derive(Debug, Clone, Copy, PartialEq, Eq, clap::ValueEnum)]
 pub enum DecompressionFormat {
     Gzip,
     UnixCompress, // .Z
+    Pack, // .z, the pre-compress Huffman packer
+    Bzip2,
+    Xz,
+    Lzma, // legacy .lzma (LZMA-alone)
+    Zstd,
+    Lz4, // LZ4 frame format
+    HadoopSnappy, // .snappy written by Hadoop's SnappyCodec
+    Brotli,
+    Lzip,
+    Lzop,
     None,
 }
 
+impl DecompressionFormat {
+    /// File name suffix of the format, removed from the uploaded name.
+    pub fn suffix(&self) -> &'static str {
+        match self {
+            DecompressionFormat::Gzip => ".gz",
+            DecompressionFormat::UnixCompress => ".Z",
+            DecompressionFormat::Pack => ".z",
+            DecompressionFormat::Bzip2 => ".bz2",
+            DecompressionFormat::Xz => ".xz",
+            DecompressionFormat::Lzma => ".lzma",
+            DecompressionFormat::Zstd => ".zst",
+            DecompressionFormat::Lz4 => ".lz4",
+            DecompressionFormat::HadoopSnappy => ".snappy",
+            DecompressionFormat::Brotli => ".br",
+            DecompressionFormat::Lzip => ".lz",
+            DecompressionFormat::Lzop => ".lzo",
+            DecompressionFormat::None => "",
+        }
+    }
+}
+
+/// How files with one suffix are decompressed and renamed.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct SuffixRule {
+    pub suffix: String,
+    pub format: DecompressionFormat,
+    /// Replaces the suffix in the decompressed name, e.g. `.tar` for `.tgz`.
+    pub replacement: String,
+}
+
+impl SuffixRule {
+    fn new(suffix: &str, format: DecompressionFormat, replacement: &str) -> Self {
+        Self { suffix: suffix.to_string(), format, replacement: replacement.to_string() }
+    }
+
+    /// Parses a `--suffix-map` value such as `.gzip=gzip` or `.taz=unix-compress:.tar`.
+    pub fn parse(spec: &str) -> Result<Self, String> {
+        let invalid = || format!("invalid --suffix-map '{}', expected .ext=format[:replacement]", spec);
+        let (suffix, rest) = spec.split_once('=').ok_or_else(invalid)?;
+        let (format, replacement) = rest.split_once(':').unwrap_or((rest, ""));
+        if !suffix.starts_with('.') || suffix.len() < 2 {
+            return Err(invalid());
+        }
+        let format = <DecompressionFormat as clap::ValueEnum>::from_str(format, true)
+            .map_err(|_| format!("unknown format '{}' in --suffix-map '{}'", format, spec))?;
+        Ok(Self::new(suffix, format, replacement))
+    }
+}
+
+/// Maps file name suffixes to formats, for both picking the decoder and
+/// naming the decompressed file. The longest matching suffix wins.
+#[derive(Debug, Clone)]
+pub struct FormatMap {
+    rules: Vec<SuffixRule>,
+}
+
+impl Default for FormatMap {
+    fn default() -> Self {
+        use DecompressionFormat::*;
+        let rules = [
+            (".gz", Gzip, ""),
+            (".gzip", Gzip, ""),
+            (".tgz", Gzip, ".tar"),
+            (".Z", UnixCompress, ""),
+            (".taz", UnixCompress, ".tar"),
+            (".z", Pack, ""),
+            (".bz2", Bzip2, ""),
+            (".tbz2", Bzip2, ".tar"),
+            (".tbz", Bzip2, ".tar"),
+            (".xz", Xz, ""),
+            (".txz", Xz, ".tar"),
+            (".lzma", Lzma, ""),
+            (".zst", Zstd, ""),
+            (".tzst", Zstd, ".tar"),
+            (".lz4", Lz4, ""),
+            (".snappy", HadoopSnappy, ""),
+            (".br", Brotli, ""),
+            (".lz", Lzip, ""),
+            (".lzo", Lzop, ""),
+        ];
+        let rules = rules.map(|(suffix, format, replacement)| SuffixRule::new(suffix, format, replacement));
+        Self { rules: Vec::new() }.with_rules(rules)
+    }
+}
+
+impl FormatMap {
+    /// Adds rules, replacing any for the same suffix.
+    pub fn with_rules(mut self, rules: impl IntoIterator<Item = SuffixRule>) -> Self {
+        for rule in rules {
+            self.rules.retain(|existing| existing.suffix != rule.suffix);
+            self.rules.push(rule);
+        }
+        self.rules.sort_by_key(|rule| std::cmp::Reverse(rule.suffix.len()));
+        self
+    }
+
+    fn rule(&self, filename: &str) -> Option<&SuffixRule> {
+        self.rules.iter().find(|rule| filename.ends_with(&rule.suffix))
+    }
+
+    pub fn format(&self, filename: &str) -> DecompressionFormat {
+        self.rule(filename).map_or(DecompressionFormat::None, |rule| rule.format)
+    }
+
+    /// Name of the decompressed file, e.g. `logs.tar` for `logs.tgz`.
+    pub fn decompressed_name<'f>(&self, filename: &'f str) -> Cow<'f, str> {
+        match self.rule(filename) {
+            Some(rule) if rule.format != DecompressionFormat::None || !rule.replacement.is_empty() => {
+                let stem = &filename[..filename.len() - rule.suffix.len()];
+                match rule.replacement.as_str() {
+                    "" => Cow::Borrowed(stem),
+                    replacement => Cow::Owned(format!("{}{}", stem, replacement)),
+                }
+            }
+            _ => Cow::Borrowed(filename),
+        }
+    }
+}
+
+fn default_formats() -> &'static FormatMap {
+    static DEFAULT: OnceLock<FormatMap> = OnceLock::new();
+    DEFAULT.get_or_init(FormatMap::default)
+}
+
+/// Format of `filename` by the default suffix mapping.
 pub fn get_format(filename: &str) -> DecompressionFormat {
-    if filename.ends_with(".gz") {
-        DecompressionFormat::Gzip
-    } else if filename.ends_with(".Z") {
-        DecompressionFormat::UnixCompress
-    } else {
-        DecompressionFormat::None
+    default_formats().format(filename)
+}
+
+/// Bytes `sniff_format` needs from the start of a file.
+pub const MAGIC_LEN: usize = 9;
+
+/// Format identified by the leading magic bytes of `head`. Formats without a
+/// reliable magic (brotli, LZMA-alone, Hadoop snappy) are never sniffed.
+pub fn sniff_format(head: &[u8]) -> Option<DecompressionFormat> {
+    const MAGICS: &[(&[u8], DecompressionFormat)] = &[
+        (&[0x1f, 0x8b], DecompressionFormat::Gzip),
+        (&[0x1f, 0x9d], DecompressionFormat::UnixCompress),
+        (&[0x1f, 0x1e], DecompressionFormat::Pack),
+        (b"BZh", DecompressionFormat::Bzip2),
+        (&[0xfd, b'7', b'z', b'X', b'Z', 0x00], DecompressionFormat::Xz),
+        (&[0x28, 0xb5, 0x2f, 0xfd], DecompressionFormat::Zstd),
+        (&[0x04, 0x22, 0x4d, 0x18], DecompressionFormat::Lz4),
+        (b"LZIP", DecompressionFormat::Lzip),
+        (&LZOP_MAGIC, DecompressionFormat::Lzop),
+    ];
+    MAGICS
+        .iter()
+        .find(|(magic, _)| head.starts_with(magic))
+        .map(|&(_, format)| format)
+}
+
+/// Name of the decompressed file by the default suffix mapping, i.e.
+/// `filename` without its compression suffix.
+pub fn strip_suffix(filename: &str) -> Cow<'_, str> {
+    default_formats().decompressed_name(filename)
+}
+
+/// Compressed bytes decoders read from their input at a time, unless
+/// `--read-buffer-size` says otherwise.
+pub const DEFAULT_BUFFER_SIZE: usize = 64 * 1024;
+
+/// Smallest input buffer; headers and trailers must fit in it.
+pub const MIN_BUFFER_SIZE: usize = 4 * 1024;
+
+/// Settings for decoders that need more than the compressed data itself.
+#[derive(Clone, Copy)]
+pub struct DecoderOptions<'d> {
+    /// Prepared `--zstd-dict` for frames compressed with a shared dictionary.
+    pub zstd_dict: Option<&'d DecoderDictionary<'static>>,
+    /// Size of the input buffer of each decoder.
+    pub buffer_size: usize,
+    /// Decode `.Z` input without the compress magic as empty, as older releases did.
+    pub lenient_z_header: bool,
+}
+
+impl Default for DecoderOptions<'_> {
+    fn default() -> Self {
+        Self { zstd_dict: None, buffer_size: DEFAULT_BUFFER_SIZE, lenient_z_header: false }
     }
 }
 
 pub fn wrap_decoder<'a, R: Read + 'a>(
     format: DecompressionFormat,
     reader: R,
-) -> Box<dyn Read + 'a> {
-    match format {
-        DecompressionFormat::Gzip => Box::new(GzDecoder::new(reader)),
-        DecompressionFormat::UnixCompress => Box::new(ZDecoder::new(reader)),
+) -> io::Result<Box<dyn Read + 'a>> {
+    wrap_decoder_with(format, reader, DecoderOptions::default())
+}
+
+/// Decoder for `format` reading from `reader`. Fails only if the codec
+/// library cannot set up a decoder, e.g. for lack of memory.
+pub fn wrap_decoder_with<'a, R: Read + 'a>(
+    format: DecompressionFormat,
+    reader: R,
+    options: DecoderOptions<'a>,
+) -> io::Result<Box<dyn Read + 'a>> {
+    let buffer_size = options.buffer_size;
+    Ok(match format {
+        DecompressionFormat::Gzip => Box::new(GzipDecoder::with_buffer_size(reader, buffer_size)),
+        DecompressionFormat::UnixCompress => {
+            let decoder = ZDecoder::with_buffer_size(reader, buffer_size);
+            if options.lenient_z_header { Box::new(decoder.lenient()) } else { Box::new(decoder) }
+        }
+        DecompressionFormat::Pack => Box::new(PackDecoder::with_buffer_size(reader, buffer_size)),
+        // Multi-stream, so parallel compressors like pbzip2 decode completely.
+        DecompressionFormat::Bzip2 => {
+            Box::new(MultiBzDecoder::new(BufReader::with_capacity(buffer_size, reader)))
+        }
+        DecompressionFormat::Xz => {
+            Box::new(XzDecoder::new_multi_decoder(BufReader::with_capacity(buffer_size, reader)))
+        }
+        DecompressionFormat::Lzma => {
+            let stream = Stream::new_lzma_decoder(u64::MAX)
+                .map_err(|e| io::Error::other(format!("liblzma failed to create an LZMA decoder: {}", e)))?;
+            Box::new(XzDecoder::new_stream(BufReader::with_capacity(buffer_size, reader), stream))
+        }
+        DecompressionFormat::Zstd => {
+            let reader = BufReader::with_capacity(buffer_size, reader);
+            let decoder = match options.zstd_dict {
+                Some(dict) => zstd::stream::read::Decoder::with_prepared_dictionary(reader, dict),
+                None => zstd::stream::read::Decoder::with_buffer(reader),
+            };
+            Box::new(decoder.map_err(|e| io::Error::new(e.kind(), format!("failed to create a zstd decoder: {}", e)))?)
+        }
+        DecompressionFormat::Lz4 => Box::new(lz4_flex::frame::FrameDecoder::new(reader)),
+        DecompressionFormat::HadoopSnappy => {
+            Box::new(HadoopSnappyDecoder::new(BufReader::with_capacity(buffer_size, reader)))
+        }
+        DecompressionFormat::Brotli => Box::new(brotli::Decompressor::new(reader, buffer_size)),
+        DecompressionFormat::Lzip => Box::new(LzipDecoder::with_buffer_size(reader, buffer_size)),
+        DecompressionFormat::Lzop => Box::new(LzopDecoder::new(BufReader::with_capacity(buffer_size, reader))),
         DecompressionFormat::None => Box::new(reader),
-    }
+    })
 }
 
+/// Code sizes a .Z header may declare; compress itself never writes more than 16.
+const Z_MIN_BITS: u8 = 9;
+const Z_MAX_BITS: u8 = 16;
+
+/// Decompressed bytes `ZDecoder` produces per batch of codes.
+const Z_OUTPUT_BATCH: usize = 64 * 1024;
+
 /// Optimized .Z (Unix Compress) Decoder implementation
 pub struct ZDecoder<R: Read> {
-    inner: R,
+    inner: BufReader<R>,
     eof: bool,
+    /// The input did not start with the compress magic.
+    invalid_magic: bool,
+    /// Reported by every read, e.g. for a header this decoder cannot handle.
+    header_error: Option<String>,
     max_bits: u8,
     block_mode: bool,
     current_bits: u8,
@@ -45,20 +289,41 @@ pub struct ZDecoder<R: Read> {
     prefix: u32,
     buffer: u64,
     bits_in_buffer: u8,
+    /// Codes read at the current width since its group of eight began.
+    group_codes: u32,
     
     output_buffer: Vec<u8>,
     output_pos: usize,
+    /// Hit while decoding a batch, reported once its output has been read.
+    pending_error: Option<io::Error>,
 }
 
 impl<R: Read> ZDecoder<R> {
-    pub fn new(mut inner: R) -> Self {
+    pub fn new(inner: R) -> Self {
+        Self::with_buffer_size(inner, DEFAULT_BUFFER_SIZE)
+    }
+
+    /// Codes are read a byte at a time, so the input is buffered.
+    pub fn with_buffer_size(inner: R, buffer_size: usize) -> Self {
+        let mut inner = BufReader::with_capacity(buffer_size, inner);
         let mut header = [0u8; 3];
         if inner.read_exact(&mut header).is_err() || header[0] != 0x1f || header[1] != 0x9d {
-            return Self::empty(inner);
+            let mut decoder = Self::empty(inner);
+            decoder.invalid_magic = true;
+            decoder.header_error = Some("invalid compress header".to_string());
+            return decoder;
         }
 
         let max_bits = header[2] & 0x1f;
         let block_mode = (header[2] & 0x80) != 0;
+        if !(Z_MIN_BITS..=Z_MAX_BITS).contains(&max_bits) {
+            let mut decoder = Self::empty(inner);
+            decoder.header_error = Some(format!(
+                "unsupported compress code size of {} bits (expected {} to {})",
+                max_bits, Z_MIN_BITS, Z_MAX_BITS
+            ));
+            return decoder;
+        }
 
         let table_size = 1 << max_bits;
         let mut prefixes = Vec::with_capacity(table_size);
@@ -76,6 +341,8 @@ impl<R: Read> ZDecoder<R> {
         Self {
             inner,
             eof: false,
+            invalid_magic: false,
+            header_error: None,
             max_bits,
             block_mode,
             current_bits: 9,
@@ -85,15 +352,19 @@ impl<R: Read> ZDecoder<R> {
             prefix: u32::MAX,
             buffer: 0,
             bits_in_buffer: 0,
-            output_buffer: Vec::with_capacity(1024),
+            group_codes: 0,
+            output_buffer: Vec::with_capacity(Z_OUTPUT_BATCH + (1 << max_bits)),
             output_pos: 0,
+            pending_error: None,
         }
     }
 
-    fn empty(inner: R) -> Self {
+    fn empty(inner: BufReader<R>) -> Self {
         Self {
             inner,
             eof: true,
+            invalid_magic: false,
+            header_error: None,
             max_bits: 0,
             block_mode: false,
             current_bits: 0,
@@ -103,96 +374,1167 @@ impl<R: Read> ZDecoder<R> {
             prefix: u32::MAX,
             buffer: 0,
             bits_in_buffer: 0,
+            group_codes: 0,
             output_buffer: vec![],
             output_pos: 0,
+            pending_error: None,
+        }
+    }
+
+    /// Decode input without the compress magic as empty instead of failing.
+    pub fn lenient(mut self) -> Self {
+        if self.invalid_magic {
+            self.header_error = None;
         }
+        self
     }
 
     fn read_code(&mut self) -> io::Result<Option<u32>> {
         while self.bits_in_buffer < self.current_bits {
-            let mut byte = [0u8; 1];
-            if self.inner.read_exact(&mut byte).is_err() {
-                return Ok(None);
-            }
-            self.buffer |= (byte[0] as u64) << self.bits_in_buffer;
+            let byte = match self.inner.fill_buf() {
+                Ok(&[byte, ..]) => byte,
+                // Leftover bits at the end are padding.
+                Ok(_) => return Ok(None),
+                Err(e) if e.kind() == io::ErrorKind::Interrupted => continue,
+                Err(e) => return Err(e),
+            };
+            self.inner.consume(1);
+            self.buffer |= (byte as u64) << self.bits_in_buffer;
             self.bits_in_buffer += 8;
         }
 
         let code = (self.buffer & ((1 << self.current_bits) - 1)) as u32;
         self.buffer >>= self.current_bits;
         self.bits_in_buffer -= self.current_bits;
+        self.group_codes += 1;
         Ok(Some(code))
     }
 
-    fn expand_code(prefixes: &[u32], chars: &[u8], code: u32, out: &mut Vec<u8>) {
+    /// compress writes codes in groups of eight and pads out the group
+    /// whenever the code width changes, so the rest of it is skipped.
+    fn skip_group(&mut self) -> io::Result<()> {
+        while !self.group_codes.is_multiple_of(8) {
+            if self.read_code()?.is_none() {
+                break;
+            }
+        }
+        self.group_codes = 0;
+        Ok(())
+    }
+
+    /// Decodes codes into `output_buffer` until it holds `Z_OUTPUT_BATCH`
+    /// bytes or the input ends.
+    fn decode_batch(&mut self) -> io::Result<()> {
+        while !self.eof && self.output_buffer.len() < Z_OUTPUT_BATCH {
+            let Some(code) = self.read_code()? else {
+                self.eof = true;
+                break;
+            };
+            if self.block_mode && code == 256 {
+                self.skip_group()?;
+                self.prefixes.truncate(257);
+                self.chars.truncate(257);
+                self.current_bits = 9;
+                self.max_code = (1 << 9) - 1;
+                self.prefix = u32::MAX;
+                continue;
+            }
+
+            let start = self.output_buffer.len();
+            if (code as usize) < self.prefixes.len() {
+                Self::expand_code(&self.prefixes, &self.chars, code, &mut self.output_buffer)?;
+            } else if code == self.prefixes.len() as u32 && self.prefix != u32::MAX {
+                Self::expand_code(&self.prefixes, &self.chars, self.prefix, &mut self.output_buffer)?;
+                let first_char = self.output_buffer[start];
+                self.output_buffer.push(first_char);
+            } else {
+                return Err(invalid_data(&format!(
+                    "invalid LZW code {} (table has {} entries)",
+                    code,
+                    self.prefixes.len()
+                )));
+            }
+
+            if self.prefix != u32::MAX && self.prefixes.len() < (1 << self.max_bits) {
+                self.prefixes.push(self.prefix);
+                self.chars.push(self.output_buffer[start]);
+
+                if self.prefixes.len() > self.max_code as usize && self.current_bits < self.max_bits {
+                    self.skip_group()?;
+                    self.current_bits += 1;
+                    self.max_code = (1 << self.current_bits) - 1;
+                }
+            }
+            self.prefix = code;
+        }
+        Ok(())
+    }
+
+    fn expand_code(prefixes: &[u32], chars: &[u8], code: u32, out: &mut Vec<u8>) -> io::Result<()> {
         let mut curr = code;
         let start_idx = out.len();
         while curr != u32::MAX {
-            out.push(chars[curr as usize]);
-            curr = prefixes[curr as usize];
+            // Entries only point at earlier ones, so a chain is never longer than the table.
+            let (Some(&byte), Some(&prefix)) = (chars.get(curr as usize), prefixes.get(curr as usize)) else {
+                return Err(invalid_data("LZW code refers past the end of the table"));
+            };
+            if out.len() - start_idx >= prefixes.len() {
+                return Err(invalid_data("LZW code chain loops"));
+            }
+            out.push(byte);
+            curr = prefix;
         }
         // Reverse the newly added sequence
         out[start_idx..].reverse();
+        Ok(())
     }
 }
 
 impl<R: Read> Read for ZDecoder<R> {
     fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        if let Some(error) = &self.header_error {
+            return Err(invalid_data(error));
+        }
+        if self.output_pos == self.output_buffer.len() {
+            if let Some(error) = self.pending_error.take() {
+                return Err(error);
+            }
+            self.output_buffer.clear();
+            self.output_pos = 0;
+            // Data decoded before an error is handed out first.
+            if let Err(e) = self.decode_batch() {
+                if self.output_buffer.is_empty() {
+                    return Err(e);
+                }
+                self.pending_error = Some(e);
+            }
+        }
+        let n = buf.len().min(self.output_buffer.len() - self.output_pos);
+        buf[..n].copy_from_slice(&self.output_buffer[self.output_pos..self.output_pos + n]);
+        self.output_pos += n;
+        Ok(n)
+    }
+}
+
+/// Upper bound on one hadoop-snappy chunk, compressed or not; Hadoop's own
+/// buffers are 256 KiB, so anything near this is corrupt input.
+const HADOOP_SNAPPY_MAX_CHUNK: usize = 64 << 20;
+
+/// Decoder for Hadoop's block-framed Snappy (`BlockCompressorStream`).
+///
+/// The stream is a sequence of blocks, each a big-endian u32 uncompressed
+/// length followed by one or more chunks of big-endian u32 compressed length
+/// and raw Snappy data, until the chunks add up to the block length.
+pub struct HadoopSnappyDecoder<R: Read> {
+    inner: R,
+    decoder: snap::raw::Decoder,
+    compressed: Vec<u8>,
+    output: Vec<u8>,
+    output_pos: usize,
+    /// Uncompressed bytes still expected from the current block.
+    block_remaining: u64,
+}
+
+impl<R: Read> HadoopSnappyDecoder<R> {
+    pub fn new(inner: R) -> Self {
+        Self {
+            inner,
+            decoder: snap::raw::Decoder::new(),
+            compressed: Vec::new(),
+            output: Vec::new(),
+            output_pos: 0,
+            block_remaining: 0,
+        }
+    }
+
+    /// Reads a big-endian u32; `None` on a clean end of stream.
+    fn read_u32(&mut self) -> io::Result<Option<u32>> {
+        let mut bytes = [0u8; 4];
+        let mut filled = 0;
+        while filled < 4 {
+            match self.inner.read(&mut bytes[filled..])? {
+                0 if filled == 0 => return Ok(None),
+                0 => return Err(io::Error::new(io::ErrorKind::UnexpectedEof, "truncated hadoop-snappy length")),
+                n => filled += n,
+            }
+        }
+        Ok(Some(u32::from_be_bytes(bytes)))
+    }
+
+    /// Decodes the next chunk into `output`; false at the end of the stream.
+    fn next_chunk(&mut self) -> io::Result<bool> {
+        while self.block_remaining == 0 {
+            match self.read_u32()? {
+                Some(len) => self.block_remaining = len as u64,
+                None => return Ok(false),
+            }
+        }
+        let len = self.read_u32()?.ok_or_else(|| {
+            io::Error::new(io::ErrorKind::UnexpectedEof, "hadoop-snappy block ended before its chunks")
+        })?;
+        if len as usize > HADOOP_SNAPPY_MAX_CHUNK {
+            return Err(io::Error::new(io::ErrorKind::InvalidData, "hadoop-snappy chunk length is implausibly large"));
+        }
+        self.compressed.resize(len as usize, 0);
+        self.inner.read_exact(&mut self.compressed)?;
+
+        let decompressed_len = snap::raw::decompress_len(&self.compressed)
+            .map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e))?;
+        if decompressed_len as u64 > self.block_remaining || decompressed_len > HADOOP_SNAPPY_MAX_CHUNK {
+            return Err(io::Error::new(io::ErrorKind::InvalidData, "hadoop-snappy chunk overruns its block"));
+        }
+        self.output.resize(decompressed_len, 0);
+        self.decoder
+            .decompress(&self.compressed, &mut self.output)
+            .map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e))?;
+        self.output_pos = 0;
+        self.block_remaining -= decompressed_len as u64;
+        Ok(true)
+    }
+}
+
+impl<R: Read> Read for HadoopSnappyDecoder<R> {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        while self.output_pos == self.output.len() {
+            if !self.next_chunk()? {
+                return Ok(0);
+            }
+        }
+        let n = buf.len().min(self.output.len() - self.output_pos);
+        buf[..n].copy_from_slice(&self.output[self.output_pos..self.output_pos + n]);
+        self.output_pos += n;
+        Ok(n)
+    }
+}
+
+const LZIP_MAGIC: &[u8; 4] = b"LZIP";
+const LZIP_HEADER_LEN: usize = 6;
+const LZIP_TRAILER_LEN: usize = 20;
+
+fn invalid_data(message: &str) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, message.to_string())
+}
+
+/// Streaming decoder for lzip (`.lz`), including multi-member files as
+/// written by plzip. Each member's CRC32 and size are checked.
+///
+/// An lzip member is LZMA-alone data without its 13-byte header, so each
+/// member is fed to liblzma's LZMA-alone decoder behind a synthesized header
+/// (lc=3, lp=0, pb=2, the member's dictionary size, unknown length).
+pub struct LzipDecoder<R: Read> {
+    inner: R,
+    input: Vec<u8>,
+    in_pos: usize,
+    in_len: usize,
+    input_eof: bool,
+    stream: Option<Stream>,
+    crc: flate2::Crc,
+    members: u64,
+    done: bool,
+}
+
+impl<R: Read> LzipDecoder<R> {
+    pub fn new(inner: R) -> Self {
+        Self::with_buffer_size(inner, DEFAULT_BUFFER_SIZE)
+    }
+
+    pub fn with_buffer_size(inner: R, buffer_size: usize) -> Self {
+        Self {
+            inner,
+            input: vec![0u8; buffer_size.max(MIN_BUFFER_SIZE)],
+            in_pos: 0,
+            in_len: 0,
+            input_eof: false,
+            stream: None,
+            crc: flate2::Crc::new(),
+            members: 0,
+            done: false,
+        }
+    }
+
+    /// Buffers at least `need` unread bytes; false if the input ends first.
+    fn fill(&mut self, need: usize) -> io::Result<bool> {
+        if self.in_len - self.in_pos >= need {
+            return Ok(true);
+        }
+        self.input.copy_within(self.in_pos..self.in_len, 0);
+        self.in_len -= self.in_pos;
+        self.in_pos = 0;
+        while self.in_len < need && !self.input_eof {
+            match self.inner.read(&mut self.input[self.in_len..])? {
+                0 => self.input_eof = true,
+                n => self.in_len += n,
+            }
+        }
+        Ok(self.in_len >= need)
+    }
+
+    /// Starts the next member; false at the end of the file.
+    fn start_member(&mut self) -> io::Result<bool> {
+        if !self.fill(LZIP_HEADER_LEN)? {
+            if self.members > 0 {
+                return Ok(false);
+            }
+            return Err(invalid_data("truncated lzip header"));
+        }
+        let header = &self.input[self.in_pos..self.in_pos + LZIP_HEADER_LEN];
+        if &header[..4] != LZIP_MAGIC {
+            // Like lzip itself, ignore trailing data after the last member.
+            if self.members > 0 {
+                return Ok(false);
+            }
+            return Err(invalid_data("not an lzip file"));
+        }
+        if header[4] != 1 {
+            return Err(invalid_data("unsupported lzip version"));
+        }
+        let base = 1u32 << (header[5] & 0x1f);
+        let dict_size = base - (base / 16) * (header[5] >> 5) as u32;
+        self.in_pos += LZIP_HEADER_LEN;
+
+        let mut stream = Stream::new_lzma_decoder(u64::MAX).map_err(|e| invalid_data(&e.to_string()))?;
+        let mut alone_header = [0u8; 13];
+        alone_header[0] = 0x5d; // (pb * 5 + lp) * 9 + lc
+        alone_header[1..5].copy_from_slice(&dict_size.to_le_bytes());
+        alone_header[5..].copy_from_slice(&u64::MAX.to_le_bytes());
+        // liblzma makes no progress without output space, even for the header.
+        stream
+            .process(&alone_header, &mut [0u8; 1], Action::Run)
+            .map_err(|e| invalid_data(&e.to_string()))?;
+        self.stream = Some(stream);
+        self.crc.reset();
+        Ok(true)
+    }
+
+    /// Checks the trailer of the member that just ended.
+    fn finish_member(&mut self, data_size: u64) -> io::Result<()> {
+        if !self.fill(LZIP_TRAILER_LEN)? {
+            return Err(io::Error::new(io::ErrorKind::UnexpectedEof, "truncated lzip trailer"));
+        }
+        let trailer = &self.input[self.in_pos..self.in_pos + LZIP_TRAILER_LEN];
+        let crc = u32::from_le_bytes(trailer[0..4].try_into().unwrap());
+        let size = u64::from_le_bytes(trailer[4..12].try_into().unwrap());
+        if crc != self.crc.sum() {
+            return Err(invalid_data("lzip CRC mismatch"));
+        }
+        if size != data_size {
+            return Err(invalid_data("lzip data size mismatch"));
+        }
+        self.in_pos += LZIP_TRAILER_LEN;
+        self.stream = None;
+        self.members += 1;
+        Ok(())
+    }
+}
+
+impl<R: Read> Read for LzipDecoder<R> {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        if buf.is_empty() {
+            return Ok(0);
+        }
+        loop {
+            if self.done {
+                return Ok(0);
+            }
+            if self.stream.is_none() && !self.start_member()? {
+                self.done = true;
+                return Ok(0);
+            }
+            if self.in_pos == self.in_len && !self.fill(1)? {
+                return Err(io::Error::new(io::ErrorKind::UnexpectedEof, "truncated lzip member"));
+            }
+
+            let stream = self.stream.as_mut().unwrap();
+            let (in_before, out_before) = (stream.total_in(), stream.total_out());
+            let status = stream
+                .process(&self.input[self.in_pos..self.in_len], buf, Action::Run)
+                .map_err(|e| invalid_data(&e.to_string()))?;
+            let consumed = (stream.total_in() - in_before) as usize;
+            let produced = (stream.total_out() - out_before) as usize;
+            // total_out counts this member only; the synthesized header produced nothing.
+            let member_size = stream.total_out();
+            self.in_pos += consumed;
+            self.crc.update(&buf[..produced]);
+
+            if status == Status::StreamEnd {
+                self.finish_member(member_size)?;
+            }
+            if produced > 0 {
+                return Ok(produced);
+            }
+        }
+    }
+}
+
+const GZIP_MAGIC: [u8; 2] = [0x1f, 0x8b];
+const GZIP_HEADER_LEN: usize = 10;
+const GZIP_TRAILER_LEN: usize = 8;
+const GZIP_FHCRC: u8 = 0x02;
+const GZIP_FEXTRA: u8 = 0x04;
+const GZIP_FNAME: u8 = 0x08;
+const GZIP_FCOMMENT: u8 = 0x10;
+
+/// Streaming decoder for gzip (`.gz`), including files built by appending
+/// gzip streams. Each member's CRC32 and ISIZE trailer is checked as it
+/// ends, so corrupt data fails with "CRC mismatch" rather than only at the
+/// size check. Zero bytes after the last member, as tape and block-device
+/// writers pad files with, are ignored like gzip itself does.
+pub struct GzipDecoder<R: Read> {
+    inner: R,
+    input: Vec<u8>,
+    in_pos: usize,
+    in_len: usize,
+    input_eof: bool,
+    inflate: Option<flate2::Decompress>,
+    crc: flate2::Crc,
+    members: u64,
+    done: bool,
+}
+
+impl<R: Read> GzipDecoder<R> {
+    pub fn new(inner: R) -> Self {
+        Self::with_buffer_size(inner, DEFAULT_BUFFER_SIZE)
+    }
+
+    pub fn with_buffer_size(inner: R, buffer_size: usize) -> Self {
+        Self {
+            inner,
+            input: vec![0u8; buffer_size.max(MIN_BUFFER_SIZE)],
+            in_pos: 0,
+            in_len: 0,
+            input_eof: false,
+            inflate: None,
+            crc: flate2::Crc::new(),
+            members: 0,
+            done: false,
+        }
+    }
+
+    /// For input following `members` members already decoded elsewhere, which
+    /// may then also be nothing but the padding after the last of them.
+    pub(crate) fn after_members(mut self, members: u64) -> Self {
+        self.members = members;
+        self
+    }
+
+    /// Buffers at least `need` unread bytes; false if the input ends first.
+    fn fill(&mut self, need: usize) -> io::Result<bool> {
+        if self.in_len - self.in_pos >= need {
+            return Ok(true);
+        }
+        self.input.copy_within(self.in_pos..self.in_len, 0);
+        self.in_len -= self.in_pos;
+        self.in_pos = 0;
+        while self.in_len < need && !self.input_eof {
+            match self.inner.read(&mut self.input[self.in_len..])? {
+                0 => self.input_eof = true,
+                n => self.in_len += n,
+            }
+        }
+        Ok(self.in_len >= need)
+    }
+
+    fn header_byte(&mut self) -> io::Result<u8> {
+        if !self.fill(1)? {
+            return Err(io::Error::new(io::ErrorKind::UnexpectedEof, "truncated gzip header"));
+        }
+        self.in_pos += 1;
+        Ok(self.input[self.in_pos - 1])
+    }
+
+    /// Parses the next member's header; false at the end of the file.
+    fn start_member(&mut self) -> io::Result<bool> {
+        if self.members > 0 {
+            if !self.fill(1)? {
+                return Ok(false);
+            }
+            if self.input[self.in_pos] == 0 {
+                self.skip_padding()?;
+                return Ok(false);
+            }
+        }
+        if !self.fill(GZIP_HEADER_LEN)? {
+            return Err(io::Error::new(io::ErrorKind::UnexpectedEof, "truncated gzip header"));
+        }
+        let header = &self.input[self.in_pos..self.in_pos + GZIP_HEADER_LEN];
+        if header[..2] != GZIP_MAGIC {
+            if self.members > 0 {
+                return Err(invalid_data("trailing garbage after gzip member"));
+            }
+            return Err(invalid_data("not a gzip file"));
+        }
+        if header[2] != 8 {
+            return Err(invalid_data("unsupported gzip compression method"));
+        }
+        let flags = header[3];
+        self.in_pos += GZIP_HEADER_LEN;
+
+        if flags & GZIP_FEXTRA != 0 {
+            let len = u16::from_le_bytes([self.header_byte()?, self.header_byte()?]);
+            for _ in 0..len {
+                self.header_byte()?;
+            }
+        }
+        for field in [GZIP_FNAME, GZIP_FCOMMENT] {
+            if flags & field != 0 {
+                while self.header_byte()? != 0 {}
+            }
+        }
+        if flags & GZIP_FHCRC != 0 {
+            self.header_byte()?;
+            self.header_byte()?;
+        }
+
+        self.inflate = Some(flate2::Decompress::new(false));
+        self.crc.reset();
+        Ok(true)
+    }
+
+    /// Consumes the zeros padding the file after its last member, failing
+    /// on anything else there.
+    fn skip_padding(&mut self) -> io::Result<()> {
+        loop {
+            if self.input[self.in_pos..self.in_len].iter().any(|&byte| byte != 0) {
+                return Err(invalid_data("trailing garbage after gzip member"));
+            }
+            self.in_pos = self.in_len;
+            if !self.fill(1)? {
+                return Ok(());
+            }
+        }
+    }
+
+    /// Checks the trailer of the member that just ended.
+    fn finish_member(&mut self) -> io::Result<()> {
+        if !self.fill(GZIP_TRAILER_LEN)? {
+            return Err(io::Error::new(io::ErrorKind::UnexpectedEof, "truncated gzip trailer"));
+        }
+        let trailer = &self.input[self.in_pos..self.in_pos + GZIP_TRAILER_LEN];
+        let crc = u32::from_le_bytes(trailer[0..4].try_into().unwrap());
+        let size = u32::from_le_bytes(trailer[4..8].try_into().unwrap());
+        let member = self.members + 1;
+        if crc != self.crc.sum() {
+            return Err(invalid_data(&format!(
+                "gzip CRC mismatch in member {}: trailer has {:08x}, data has {:08x}",
+                member,
+                crc,
+                self.crc.sum()
+            )));
+        }
+        // ISIZE is the length modulo 2^32, as is Crc::amount.
+        if size != self.crc.amount() {
+            return Err(invalid_data(&format!(
+                "gzip length mismatch in member {}: trailer has {} bytes, data has {}",
+                member,
+                size,
+                self.crc.amount()
+            )));
+        }
+        self.in_pos += GZIP_TRAILER_LEN;
+        self.inflate = None;
+        self.members += 1;
+        Ok(())
+    }
+}
+
+impl<R: Read> Read for GzipDecoder<R> {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        if buf.is_empty() {
+            return Ok(0);
+        }
+        loop {
+            if self.done {
+                return Ok(0);
+            }
+            if self.inflate.is_none() && !self.start_member()? {
+                self.done = true;
+                return Ok(0);
+            }
+            if self.in_pos == self.in_len && !self.fill(1)? {
+                return Err(io::Error::new(io::ErrorKind::UnexpectedEof, "truncated gzip member"));
+            }
+
+            let inflate = self.inflate.as_mut().unwrap();
+            let (in_before, out_before) = (inflate.total_in(), inflate.total_out());
+            let status = inflate
+                .decompress(&self.input[self.in_pos..self.in_len], buf, flate2::FlushDecompress::None)
+                .map_err(|e| invalid_data(&format!("corrupt gzip data: {}", e)))?;
+            let consumed = (inflate.total_in() - in_before) as usize;
+            let produced = (inflate.total_out() - out_before) as usize;
+            self.in_pos += consumed;
+            self.crc.update(&buf[..produced]);
+
+            if status == flate2::Status::StreamEnd {
+                self.finish_member()?;
+            }
+            if produced > 0 {
+                return Ok(produced);
+            }
+        }
+    }
+}
+
+const LZOP_MAGIC: [u8; 9] = [0x89, b'L', b'Z', b'O', 0x00, 0x0d, 0x0a, 0x1a, 0x0a];
+const LZOP_F_ADLER32_D: u32 = 0x1;
+const LZOP_F_ADLER32_C: u32 = 0x2;
+const LZOP_F_H_EXTRA_FIELD: u32 = 0x40;
+const LZOP_F_CRC32_D: u32 = 0x100;
+const LZOP_F_CRC32_C: u32 = 0x200;
+const LZOP_F_H_FILTER: u32 = 0x800;
+const LZOP_F_H_CRC32: u32 = 0x1000;
+/// lzop never writes blocks above 64 MiB; larger lengths mean a corrupt file.
+const LZOP_MAX_BLOCK: usize = 64 << 20;
+
+fn adler32(adler: u32, data: &[u8]) -> u32 {
+    let (mut a, mut b) = (adler & 0xffff, adler >> 16);
+    for chunk in data.chunks(5552) {
+        for &byte in chunk {
+            a += byte as u32;
+            b += a;
+        }
+        a %= 65521;
+        b %= 65521;
+    }
+    (b << 16) | a
+}
+
+fn crc32(data: &[u8]) -> u32 {
+    let mut crc = flate2::Crc::new();
+    crc.update(data);
+    crc.sum()
+}
+
+/// Streaming decoder for lzop (`.lzo`) files with LZO1X blocks. Block
+/// checksums (Adler-32 or CRC32, as flagged in the header) are verified.
+pub struct LzopDecoder<R: Read> {
+    inner: R,
+    /// Header flags; `None` until the header has been read.
+    flags: Option<u32>,
+    compressed: Vec<u8>,
+    output: Vec<u8>,
+    output_pos: usize,
+    done: bool,
+}
+
+impl<R: Read> LzopDecoder<R> {
+    pub fn new(inner: R) -> Self {
+        Self {
+            inner,
+            flags: None,
+            compressed: Vec::new(),
+            output: Vec::new(),
+            output_pos: 0,
+            done: false,
+        }
+    }
+
+    /// Reads `len` bytes, adding them to `header` for the header checksum.
+    fn read_header_bytes(&mut self, header: &mut Vec<u8>, len: usize) -> io::Result<()> {
+        let start = header.len();
+        header.resize(start + len, 0);
+        self.inner.read_exact(&mut header[start..])
+    }
+
+    fn read_u32(&mut self) -> io::Result<u32> {
+        let mut bytes = [0u8; 4];
+        self.inner.read_exact(&mut bytes)?;
+        Ok(u32::from_be_bytes(bytes))
+    }
+
+    fn read_header(&mut self) -> io::Result<u32> {
+        let mut magic = [0u8; 9];
+        self.inner.read_exact(&mut magic)?;
+        if magic != LZOP_MAGIC {
+            return Err(invalid_data("not an lzop file"));
+        }
+        let mut header = Vec::new();
+        self.read_header_bytes(&mut header, 4)?;
+        let version = u16::from_be_bytes([header[0], header[1]]);
+        if version >= 0x0940 {
+            self.read_header_bytes(&mut header, 2)?; // version needed to extract
+        }
+        self.read_header_bytes(&mut header, 1)?;
+        let method = header[header.len() - 1];
+        if !(1..=3).contains(&method) {
+            return Err(invalid_data("unsupported lzop method (only LZO1X is supported)"));
+        }
+        if version >= 0x0940 {
+            self.read_header_bytes(&mut header, 1)?; // level
+        }
+        self.read_header_bytes(&mut header, 4)?;
+        let flags = u32::from_be_bytes(header[header.len() - 4..].try_into().unwrap());
+        if flags & LZOP_F_H_FILTER != 0 {
+            return Err(invalid_data("lzop filters are not supported"));
+        }
+        // mode, mtime and, from 0.9.40 on, the high mtime word
+        self.read_header_bytes(&mut header, if version >= 0x0940 { 12 } else { 8 })?;
+        self.read_header_bytes(&mut header, 1)?;
+        let name_len = header[header.len() - 1] as usize;
+        self.read_header_bytes(&mut header, name_len)?;
+
+        let checksum = self.read_u32()?;
+        let expected = if flags & LZOP_F_H_CRC32 != 0 { crc32(&header) } else { adler32(1, &header) };
+        if checksum != expected {
+            return Err(invalid_data("lzop header checksum mismatch"));
+        }
+        if flags & LZOP_F_H_EXTRA_FIELD != 0 {
+            let len = self.read_u32()? as usize;
+            io::copy(&mut (&mut self.inner).take(len as u64 + 4), &mut io::sink())?;
+        }
+        Ok(flags)
+    }
+
+    /// Decodes the next block into `output`; false at the end of the file.
+    fn next_block(&mut self) -> io::Result<bool> {
+        let flags = match self.flags {
+            Some(flags) => flags,
+            None => {
+                let flags = self.read_header()?;
+                self.flags = Some(flags);
+                flags
+            }
+        };
+        let dst_len = self.read_u32()? as usize;
+        if dst_len == 0 {
+            return Ok(false);
+        }
+        let src_len = self.read_u32()? as usize;
+        if dst_len > LZOP_MAX_BLOCK || src_len > dst_len {
+            return Err(invalid_data("lzop block length is implausibly large"));
+        }
+        let d_adler = if flags & LZOP_F_ADLER32_D != 0 { Some(self.read_u32()?) } else { None };
+        let d_crc = if flags & LZOP_F_CRC32_D != 0 { Some(self.read_u32()?) } else { None };
+        let stored = src_len == dst_len;
+        let c_adler = if !stored && flags & LZOP_F_ADLER32_C != 0 { Some(self.read_u32()?) } else { None };
+        let c_crc = if !stored && flags & LZOP_F_CRC32_C != 0 { Some(self.read_u32()?) } else { None };
+
+        self.compressed.resize(src_len, 0);
+        self.inner.read_exact(&mut self.compressed)?;
+        if c_adler.is_some_and(|sum| sum != adler32(1, &self.compressed))
+            || c_crc.is_some_and(|sum| sum != crc32(&self.compressed))
+        {
+            return Err(invalid_data("lzop compressed block checksum mismatch"));
+        }
+        if stored {
+            std::mem::swap(&mut self.output, &mut self.compressed);
+        } else {
+            self.output.clear();
+            lzo1x_decompress(&self.compressed, &mut self.output, dst_len)?;
+            if self.output.len() != dst_len {
+                return Err(invalid_data("lzop block decompressed to the wrong length"));
+            }
+        }
+        if d_adler.is_some_and(|sum| sum != adler32(1, &self.output))
+            || d_crc.is_some_and(|sum| sum != crc32(&self.output))
+        {
+            return Err(invalid_data("lzop block checksum mismatch"));
+        }
+        self.output_pos = 0;
+        Ok(true)
+    }
+}
+
+impl<R: Read> Read for LzopDecoder<R> {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        while self.output_pos == self.output.len() {
+            if self.done || !self.next_block()? {
+                self.done = true;
+                return Ok(0);
+            }
+        }
+        let n = buf.len().min(self.output.len() - self.output_pos);
+        buf[..n].copy_from_slice(&self.output[self.output_pos..self.output_pos + n]);
+        self.output_pos += n;
+        Ok(n)
+    }
+}
+
+/// Decompresses one LZO1X block into `out`, never growing it past `max_len`.
+fn lzo1x_decompress(src: &[u8], out: &mut Vec<u8>, max_len: usize) -> io::Result<()> {
+    fn corrupt() -> io::Error {
+        invalid_data("corrupt LZO1X block")
+    }
+    let mut ip = 0;
+    let byte = |ip: &mut usize| -> io::Result<usize> {
+        let b = *src.get(*ip).ok_or_else(corrupt)?;
+        *ip += 1;
+        Ok(b as usize)
+    };
+    // Run of zero bytes then a non-zero byte, each zero worth 255.
+    let extended = |ip: &mut usize| -> io::Result<usize> {
+        let mut len = 0;
+        loop {
+            match byte(ip)? {
+                0 => len += 255,
+                b => return Ok(len + b),
+            }
+        }
+    };
+    let literals = |ip: &mut usize, out: &mut Vec<u8>, len: usize| -> io::Result<()> {
+        let bytes = src.get(*ip..*ip + len).ok_or_else(corrupt)?;
+        if out.len() + len > max_len {
+            return Err(corrupt());
+        }
+        out.extend_from_slice(bytes);
+        *ip += len;
+        Ok(())
+    };
+
+    // State: literals copied by the last instruction (4 meaning a long run).
+    let mut state = 0;
+    if src.first().is_some_and(|&b| b > 17) {
+        let len = byte(&mut ip)? - 17;
+        literals(&mut ip, out, len)?;
+        state = len.min(4);
+    }
+    loop {
+        let t = byte(&mut ip)?;
+        let (dist, len, next);
+        if t < 16 {
+            if state == 0 {
+                let len = if t == 0 { 15 + extended(&mut ip)? } else { t };
+                literals(&mut ip, out, len + 3)?;
+                state = 4;
+                continue;
+            }
+            next = t & 3;
+            if state < 4 {
+                dist = 1 + (t >> 2) + (byte(&mut ip)? << 2);
+                len = 2;
+            } else {
+                dist = 0x801 + (t >> 2) + (byte(&mut ip)? << 2);
+                len = 3;
+            }
+        } else if t >= 64 {
+            next = t & 3;
+            dist = 1 + ((t >> 2) & 7) + (byte(&mut ip)? << 3);
+            len = (t >> 5) + 1;
+        } else if t >= 32 {
+            len = if t & 31 == 0 { 33 + extended(&mut ip)? } else { (t & 31) + 2 };
+            let v = byte(&mut ip)? | (byte(&mut ip)? << 8);
+            dist = 1 + (v >> 2);
+            next = v & 3;
+        } else {
+            len = if t & 7 == 0 { 9 + extended(&mut ip)? } else { (t & 7) + 2 };
+            let v = byte(&mut ip)? | (byte(&mut ip)? << 8);
+            let far = ((t & 8) << 11) + (v >> 2);
+            if far == 0 {
+                // End-of-stream marker.
+                return if ip == src.len() { Ok(()) } else { Err(corrupt()) };
+            }
+            dist = far + 0x4000;
+            next = v & 3;
+        }
+        if dist > out.len() || out.len() + len > max_len {
+            return Err(corrupt());
+        }
+        // Byte by byte, since the match may overlap its own output.
+        let start = out.len() - dist;
+        for i in 0..len {
+            out.push(out[start + i]);
+        }
+        literals(&mut ip, out, next)?;
+        state = next;
+    }
+}
+
+const PACK_MAGIC: [u8; 2] = [0x1f, 0x1e];
+const PACK_MAX_BITS: usize = 24;
+
+/// Huffman tree of a pack file. At each code length the internal nodes take
+/// the lowest codes and the leaves the ones after them.
+struct PackTree {
+    orig_len: u32,
+    max_len: usize,
+    /// Internal nodes, leaves and first literal index per code length.
+    parents: [u32; PACK_MAX_BITS + 1],
+    leaves: [u32; PACK_MAX_BITS + 1],
+    lit_base: [usize; PACK_MAX_BITS + 1],
+    literals: Vec<u8>,
+}
+
+/// MSB-first bit reader over the pack data.
+struct PackBits<R: Read> {
+    inner: BufReader<R>,
+    byte: u8,
+    left: u8,
+}
+
+impl<R: Read> PackBits<R> {
+    fn read_byte(&mut self) -> io::Result<u8> {
+        let mut byte = [0u8; 1];
+        self.inner.read_exact(&mut byte)?;
+        Ok(byte[0])
+    }
+
+    fn bit(&mut self) -> io::Result<u32> {
+        if self.left == 0 {
+            self.byte = self.read_byte()?;
+            self.left = 8;
+        }
+        self.left -= 1;
+        Ok(((self.byte >> self.left) & 1) as u32)
+    }
+}
+
+/// Decoder for files written by the old Unix `pack` (`.z`): a static
+/// Huffman code stored up front, ending with an end-of-block code. The
+/// output is checked against the original length in the header.
+pub struct PackDecoder<R: Read> {
+    input: PackBits<R>,
+    tree: Option<PackTree>,
+    produced: u64,
+    done: bool,
+}
+
+impl<R: Read> PackDecoder<R> {
+    pub fn new(inner: R) -> Self {
+        Self::with_buffer_size(inner, DEFAULT_BUFFER_SIZE)
+    }
+
+    pub fn with_buffer_size(inner: R, buffer_size: usize) -> Self {
+        Self {
+            input: PackBits { inner: BufReader::with_capacity(buffer_size, inner), byte: 0, left: 0 },
+            tree: None,
+            produced: 0,
+            done: false,
+        }
+    }
+
+    fn read_tree(&mut self) -> io::Result<PackTree> {
+        let mut header = [0u8; 7];
+        self.input.inner.read_exact(&mut header)?;
+        if header[..2] != PACK_MAGIC {
+            return Err(invalid_data("not a pack file"));
+        }
+        let orig_len = u32::from_be_bytes(header[2..6].try_into().unwrap());
+        let max_len = header[6] as usize;
+        if max_len == 0 || max_len > PACK_MAX_BITS {
+            return Err(invalid_data("invalid pack code length"));
+        }
+
+        let mut tree = PackTree {
+            orig_len,
+            max_len,
+            parents: [0; PACK_MAX_BITS + 1],
+            leaves: [0; PACK_MAX_BITS + 1],
+            lit_base: [0; PACK_MAX_BITS + 1],
+            literals: Vec::new(),
+        };
+        let mut max_leaves = 1u32;
+        let mut total = 0u32;
+        for len in 1..=max_len {
+            let leaves = self.input.read_byte()? as u32;
+            if leaves + (len == max_len) as u32 > max_leaves {
+                return Err(invalid_data("too many leaves in pack Huffman tree"));
+            }
+            max_leaves = (max_leaves - leaves + 1) * 2 - 1;
+            tree.leaves[len] = leaves;
+            total += leaves;
+        }
+        if total >= 256 {
+            return Err(invalid_data("too many leaves in pack Huffman tree"));
+        }
+        // The last count is stored minus 2: one more literal, plus the
+        // end-of-block code, which is not transmitted.
+        tree.leaves[max_len] += 1;
+        for len in 1..=max_len {
+            tree.lit_base[len] = tree.literals.len();
+            for _ in 0..tree.leaves[len] {
+                let literal = self.input.read_byte()?;
+                tree.literals.push(literal);
+            }
+        }
+        tree.leaves[max_len] += 1;
+
+        let mut nodes = 0u32;
+        for len in (1..=max_len).rev() {
+            nodes >>= 1;
+            tree.parents[len] = nodes;
+            nodes += tree.leaves[len];
+        }
+        Ok(tree)
+    }
+}
+
+impl<R: Read> Read for PackDecoder<R> {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        if self.done || buf.is_empty() {
+            return Ok(0);
+        }
+        if self.tree.is_none() {
+            self.tree = Some(self.read_tree()?);
+        }
+        let tree = self.tree.as_ref().unwrap();
         let mut written = 0;
-        
         while written < buf.len() {
-            if self.output_pos < self.output_buffer.len() {
-                let n = std::cmp::min(buf.len() - written, self.output_buffer.len() - self.output_pos);
-                buf[written..written + n].copy_from_slice(&self.output_buffer[self.output_pos..self.output_pos + n]);
-                written += n;
-                self.output_pos += n;
-                continue;
+            let mut len = 1;
+            let mut code = self.input.bit()?;
+            while code < tree.parents[len] {
+                code = (code << 1) | self.input.bit()?;
+                len += 1;
+            }
+            let leaf = code - tree.parents[len];
+            if leaf >= tree.leaves[len] {
+                return Err(invalid_data("corrupt pack data"));
+            }
+            if len == tree.max_len && leaf == tree.leaves[len] - 1 {
+                self.done = true;
+                // The header holds the length modulo 2^32.
+                if (self.produced + written as u64) & 0xffff_ffff != tree.orig_len as u64 {
+                    return Err(invalid_data("pack data length does not match its header"));
+                }
+                break;
             }
+            buf[written] = tree.literals[tree.lit_base[len] + leaf as usize];
+            written += 1;
+        }
+        self.produced += written as u64;
+        Ok(written)
+    }
+}
 
-            if self.eof { break; }
+/// A `--decompressor` command for files with a suffix this crate cannot
+/// decode itself.
+#[derive(Debug, Clone)]
+pub struct ExternalDecompressor {
+    /// Suffix including the dot, e.g. `.pz`; removed from the uploaded name.
+    pub suffix: String,
+    /// Run with `sh -c`; reads compressed data on stdin and writes it decompressed to stdout.
+    pub command: String,
+}
 
-            match self.read_code()? {
-                Some(code) => {
-                    if self.block_mode && code == 256 {
-                        self.prefixes.truncate(257);
-                        self.chars.truncate(257);
-                        self.current_bits = 9;
-                        self.max_code = (1 << 9) - 1;
-                        self.prefix = u32::MAX;
-                        continue;
-                    }
+impl ExternalDecompressor {
+    /// Parses `ext=command`, with or without the dot before `ext`.
+    pub fn parse(spec: &str) -> Result<Self, String> {
+        match spec.split_once('=') {
+            Some((ext, command)) if !ext.trim_start_matches('.').is_empty() && !command.trim().is_empty() => {
+                Ok(Self {
+                    suffix: format!(".{}", ext.trim_start_matches('.')),
+                    command: command.to_string(),
+                })
+            }
+            _ => Err(format!("invalid --decompressor '{}', expected ext=command", spec)),
+        }
+    }
 
-                    self.output_buffer.clear();
-                    self.output_pos = 0;
-
-                    if (code as usize) < self.prefixes.len() {
-                        Self::expand_code(&self.prefixes, &self.chars, code, &mut self.output_buffer);
-                    } else if code == self.prefixes.len() as u32 && self.prefix != u32::MAX {
-                        Self::expand_code(&self.prefixes, &self.chars, self.prefix, &mut self.output_buffer);
-                        let first_char = self.output_buffer[0];
-                        self.output_buffer.push(first_char);
-                    } else {
-                        return Err(io::Error::new(io::ErrorKind::InvalidData, "Invalid LZW code"));
-                    }
+    /// Name of the decompressed file, if `filename` has this suffix.
+    pub fn strip<'f>(&self, filename: &'f str) -> Option<&'f str> {
+        filename.strip_suffix(self.suffix.as_str())
+    }
+}
 
-                    if self.prefix != u32::MAX && self.prefixes.len() < (1 << self.max_bits) {
-                        let first_char_of_current = self.output_buffer[0];
-                        self.prefixes.push(self.prefix);
-                        self.chars.push(first_char_of_current);
-                        
-                        if self.prefixes.len() > self.max_code as usize && self.current_bits < self.max_bits {
-                            self.current_bits += 1;
-                            self.max_code = (1 << self.current_bits) - 1;
-                        }
+/// Output of an external decompressor that `inner` is piped into.
+///
+/// Stdout is drained by a thread, so the child never blocks writing while
+/// this end writes its stdin; input is only fed once that output is
+/// consumed, which bounds what is buffered to the output of one input chunk.
+pub struct ExternalDecoder<R: Read> {
+    inner: R,
+    command: String,
+    child: Child,
+    stdin: Option<ChildStdin>,
+    output: Receiver<io::Result<Vec<u8>>>,
+    chunk: Vec<u8>,
+    pos: usize,
+}
+
+impl<R: Read> ExternalDecoder<R> {
+    pub fn new(command: &str, inner: R) -> io::Result<Self> {
+        let mut child = Command::new("sh")
+            .arg("-c")
+            .arg(command)
+            .stdin(Stdio::piped())
+            .stdout(Stdio::piped())
+            .spawn()?;
+        let stdin = child.stdin.take();
+        let mut stdout = child.stdout.take().expect("stdout is piped");
+        let (tx, output) = mpsc::channel();
+        thread::spawn(move || loop {
+            let mut chunk = vec![0u8; 64 * 1024];
+            match stdout.read(&mut chunk) {
+                Ok(0) => return,
+                Ok(n) => {
+                    chunk.truncate(n);
+                    if tx.send(Ok(chunk)).is_err() {
+                        return;
                     }
-                    self.prefix = code;
                 }
-                None => {
-                    self.eof = true;
-                    break;
+                Err(e) if e.kind() == io::ErrorKind::Interrupted => {}
+                Err(e) => {
+                    let _ = tx.send(Err(e));
+                    return;
                 }
             }
+        });
+        Ok(Self { inner, command: command.to_string(), child, stdin, output, chunk: Vec::new(), pos: 0 })
+    }
+
+    /// Writes the next chunk of input to the child, closing its stdin at the
+    /// end of the input or once it stops reading.
+    fn feed(&mut self) -> io::Result<()> {
+        let Some(stdin) = &mut self.stdin else {
+            return Ok(());
+        };
+        let mut chunk = [0u8; 64 * 1024];
+        let n = self.inner.read(&mut chunk)?;
+        if n == 0 {
+            self.stdin = None;
+            return Ok(());
+        }
+        match stdin.write_all(&chunk[..n]) {
+            Ok(()) => Ok(()),
+            // The exit status tells whether it failed.
+            Err(e) if e.kind() == io::ErrorKind::BrokenPipe => {
+                self.stdin = None;
+                Ok(())
+            }
+            Err(e) => Err(e),
+        }
+    }
+}
+
+impl<R: Read> Read for ExternalDecoder<R> {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        loop {
+            if self.pos < self.chunk.len() {
+                let n = buf.len().min(self.chunk.len() - self.pos);
+                buf[..n].copy_from_slice(&self.chunk[self.pos..self.pos + n]);
+                self.pos += n;
+                return Ok(n);
+            }
+            let next = if self.stdin.is_some() {
+                match self.output.try_recv() {
+                    Ok(next) => next,
+                    Err(_) => {
+                        self.feed()?;
+                        continue;
+                    }
+                }
+            } else {
+                match self.output.recv() {
+                    Ok(next) => next,
+                    Err(_) => {
+                        let status = self.child.wait()?;
+                        if !status.success() {
+                            return Err(io::Error::other(format!("`{}` failed: {}", self.command, status)));
+                        }
+                        return Ok(0);
+                    }
+                }
+            };
+            self.chunk = next?;
+            self.pos = 0;
+        }
+    }
+}
+
+impl<R: Read> Drop for ExternalDecoder<R> {
+    fn drop(&mut self) {
+        self.stdin = None;
+        if let Ok(None) = self.child.try_wait() {
+            let _ = self.child.kill();
+            let _ = self.child.wait();
         }
-        Ok(written)
     }
 }
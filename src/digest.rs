@@ -0,0 +1,144 @@
+use std::fs::File;
+use std::io::{self, Read};
+use std::path::Path;
+use md5::Md5;
+use serde::Serialize;
+use sha2::{Digest, Sha256};
+
+/// HDFS defaults: dfs.bytes-per-checksum and dfs.checksum.type.
+const BYTES_PER_CRC: u64 = 512;
+static CRC32C: crc::Crc<u32> = crc::Crc::<u32>::new(&crc::CRC_32_ISCSI);
+
+/// Passes reads through while hashing every byte, e.g. to digest a
+/// compressed tar member on its way into the decoder.
+pub struct HashingReader<R> {
+    inner: R,
+    hasher: Sha256,
+    bytes: u64,
+}
+
+impl<R: Read> HashingReader<R> {
+    pub fn new(inner: R) -> Self {
+        Self { inner, hasher: Sha256::new(), bytes: 0 }
+    }
+
+    pub fn get_ref(&self) -> &R {
+        &self.inner
+    }
+
+    /// Hashes whatever the consumer left unread, then returns (bytes, hex digest).
+    pub fn finish(mut self) -> io::Result<(u64, String)> {
+        io::copy(&mut self, &mut io::sink())?;
+        Ok((self.bytes, hex::encode(self.hasher.finalize())))
+    }
+}
+
+impl<R: Read> Read for HashingReader<R> {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        let n = self.inner.read(buf)?;
+        self.hasher.update(&buf[..n]);
+        self.bytes += n as u64;
+        Ok(n)
+    }
+}
+
+/// Deterministic id for a delivery: the same manifest going to the same
+/// destination always gets the same id, so a retried run is recognizable.
+pub fn default_run_id<P: AsRef<Path>>(manifest: P, dst: &str) -> io::Result<String> {
+    let mut reader = HashingReader::new(File::open(manifest)?);
+    io::copy(&mut reader, &mut io::sink())?;
+    reader.hasher.update(b"\0");
+    reader.hasher.update(dst.as_bytes());
+    Ok(hex::encode(reader.hasher.finalize())[..16].to_string())
+}
+
+/// A file checksum as `hdfs dfs -checksum` prints it.
+#[derive(Debug, Clone, Serialize)]
+pub struct FileChecksum {
+    pub algorithm: String,
+    pub checksum: String,
+}
+
+/// Computes HDFS's default file checksum (`MD5-of-<n>MD5-of-512CRC32C`) from
+/// the data as it streams past, for a file written with `block_size` blocks.
+pub struct HdfsChecksum {
+    block_size: u64,
+    chunk_crc: crc::Digest<'static, u32>,
+    chunk_len: u64,
+    block_len: u64,
+    /// MD5 of the current block's chunk CRCs.
+    block_md5: Md5,
+    block_md5s: Vec<u8>,
+    len: u64,
+}
+
+impl HdfsChecksum {
+    pub fn new(block_size: u64) -> Self {
+        Self {
+            block_size,
+            chunk_crc: CRC32C.digest(),
+            chunk_len: 0,
+            block_len: 0,
+            block_md5: Md5::new(),
+            block_md5s: Vec::new(),
+            len: 0,
+        }
+    }
+
+    pub fn update(&mut self, mut data: &[u8]) {
+        while !data.is_empty() {
+            let take = data.len().min((BYTES_PER_CRC - self.chunk_len) as usize);
+            self.chunk_crc.update(&data[..take]);
+            self.chunk_len += take as u64;
+            self.block_len += take as u64;
+            self.len += take as u64;
+            data = &data[take..];
+            if self.chunk_len == BYTES_PER_CRC {
+                self.end_chunk();
+            }
+            if self.block_len == self.block_size {
+                self.end_block();
+            }
+        }
+    }
+
+    fn end_chunk(&mut self) {
+        let crc = std::mem::replace(&mut self.chunk_crc, CRC32C.digest()).finalize();
+        self.block_md5.update(crc.to_be_bytes());
+        self.chunk_len = 0;
+    }
+
+    fn end_block(&mut self) {
+        if self.chunk_len > 0 {
+            self.end_chunk();
+        }
+        self.block_md5s.extend(std::mem::take(&mut self.block_md5).finalize());
+        self.block_len = 0;
+    }
+
+    pub fn finish(mut self) -> FileChecksum {
+        if self.block_len > 0 {
+            self.end_block();
+        }
+        let blocks = self.block_md5s.len() / 16;
+        // Only multi-block files report their CRCs per block; an empty file has no CRC type.
+        let crc_per_block = if blocks > 1 { self.block_size.div_ceil(BYTES_PER_CRC) } else { 0 };
+        let (bytes_per_crc, crc_type) = if self.len == 0 { (0, "CRC32") } else { (BYTES_PER_CRC, "CRC32C") };
+
+        // Hadoop digests the whole backing array of its DataOutputBuffer,
+        // which starts at 32 bytes and doubles, so pad the same way.
+        let mut capacity = 32;
+        while capacity < self.block_md5s.len() {
+            capacity *= 2;
+        }
+        self.block_md5s.resize(capacity, 0);
+
+        let mut bytes = (bytes_per_crc as u32).to_be_bytes().to_vec();
+        bytes.extend_from_slice(&crc_per_block.to_be_bytes());
+        bytes.extend(Md5::digest(&self.block_md5s));
+        FileChecksum {
+            algorithm: format!("MD5-of-{}MD5-of-{}{}", crc_per_block, bytes_per_crc, crc_type),
+            checksum: hex::encode(bytes),
+        }
+    }
+}
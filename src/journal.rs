@@ -0,0 +1,123 @@
+use anyhow::{anyhow, Result};
+use bytes::Bytes;
+use hdfs_native::client::WriteOptions;
+use sha2::{Digest, Sha256};
+use tracing::debug;
+
+use crate::report::FileRecord;
+use crate::sink::Sink;
+
+/// Directory under the destination root that holds the journal.
+pub const JOURNAL_DIR: &str = "_untar_journal";
+
+/// Start and commit records for the files a run writes, kept next to them
+/// so a rerun of the same run on any host can tell the targets an earlier
+/// attempt delivered from the ones it left partly written. Unlike a state
+/// file, it survives losing the host.
+///
+/// Each record is a small file under `<root>/_untar_journal/<run id>/`,
+/// named by the hash of its target: `<hash>.start` is written before the
+/// target is created and `<hash>.commit` once it is closed and verified.
+pub struct Journal {
+    sink: Sink,
+    dir: String,
+}
+
+/// What the journal says about a target.
+#[derive(Debug, PartialEq, Eq)]
+pub enum Recorded {
+    /// Delivered with this size and SHA-256.
+    Committed { size: u64, sha256: String },
+    /// Started but never committed; whatever is at `path`, where the target
+    /// was being written, is incomplete.
+    Partial { path: String },
+    /// Never started.
+    Absent,
+}
+
+impl Journal {
+    /// The journal of `run_id` under `<root>/_untar_journal`.
+    pub fn new(sink: Sink, root: &str, run_id: &str) -> Self {
+        Self { sink, dir: format!("{}/{}/{}", root.trim_end_matches('/'), JOURNAL_DIR, run_id) }
+    }
+
+    fn record_path(&self, target: &str, kind: &str) -> String {
+        format!("{}/{}.{}", self.dir, hex::encode(Sha256::digest(target.as_bytes())), kind)
+    }
+
+    /// Looks up what an earlier attempt at the run did with `target`.
+    pub async fn lookup(&self, target: &str) -> Result<Recorded> {
+        let path = self.record_path(target, "commit");
+        let commit = self.sink.read(&path)
+            .await
+            .map_err(|e| anyhow!("Failed to read journal record {} for {}: {}", path, target, e))?;
+        // A commit record still being written when its run died does not count.
+        if let Some(committed) = commit.as_deref().and_then(|data| parse_commit(data, target)) {
+            return Ok(committed);
+        }
+        let path = self.record_path(target, "start");
+        let started = self.sink.read(&path)
+            .await
+            .map_err(|e| anyhow!("Failed to read journal record {} for {}: {}", path, target, e))?;
+        Ok(match started {
+            // A start record cut short still names the target itself.
+            Some(data) => Recorded::Partial { path: parse_start(&data).unwrap_or_else(|| target.to_string()) },
+            None => Recorded::Absent,
+        })
+    }
+
+    /// Records that `target` is about to be written to `path`, which differs
+    /// from it for a versioned file, dropping any commit record of an
+    /// earlier write.
+    pub async fn start(&self, target: &str, path: &str) -> Result<()> {
+        let commit = self.record_path(target, "commit");
+        self.sink.delete(&commit)
+            .await
+            .map_err(|e| anyhow!("Failed to remove journal record {} for {}: {}", commit, target, e))?;
+        let start = self.record_path(target, "start");
+        self.write(&start, Bytes::from(format!("target={}\npath={}\n", target, path)))
+            .await
+            .map_err(|e| anyhow!("Failed to write journal record {} for {}: {}", start, target, e))
+    }
+
+    /// Records that `target` is delivered, as `file`.
+    pub async fn commit(&self, target: &str, file: &FileRecord) -> Result<()> {
+        let path = self.record_path(target, "commit");
+        let contents = format!("target={}\npath={}\nsize={}\nsha256={}\n", target, file.target, file.size, file.sha256);
+        self.write(&path, Bytes::from(contents))
+            .await
+            .map_err(|e| anyhow!("Failed to write journal record {} for {}: {}", path, target, e))?;
+        debug!("Committed {} in the journal", target);
+        Ok(())
+    }
+
+    async fn write(&self, path: &str, contents: Bytes) -> hdfs_native::Result<()> {
+        let mut writer = self.sink.create(path, WriteOptions::default().overwrite(true)).await?;
+        writer.write(contents).await?;
+        writer.close().await
+    }
+}
+
+/// The path a start record names, if it got that far.
+fn parse_start(data: &[u8]) -> Option<String> {
+    let text = std::str::from_utf8(data).ok()?;
+    text.lines().find_map(|line| line.strip_prefix("path=")).map(str::to_string)
+}
+
+/// A commit record, if it is complete and for `target` rather than another
+/// target with the same hash.
+fn parse_commit(data: &[u8], target: &str) -> Option<Recorded> {
+    let text = std::str::from_utf8(data).ok()?;
+    let mut recorded_target = None;
+    let mut size = None;
+    let mut sha256 = None;
+    for line in text.lines() {
+        match line.split_once('=') {
+            Some(("target", value)) => recorded_target = Some(value),
+            Some(("size", value)) => size = value.parse().ok(),
+            Some(("sha256", value)) if value.len() == 64 => sha256 = Some(value.to_string()),
+            _ => {}
+        }
+    }
+    (recorded_target? == target).then_some(Recorded::Committed { size: size?, sha256: sha256? })
+}
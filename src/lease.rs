@@ -0,0 +1,285 @@
+use std::collections::HashSet;
+use std::sync::Arc;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+use anyhow::{anyhow, Result};
+use bytes::Bytes;
+use sha2::{Digest, Sha256};
+use tokio::sync::Mutex;
+use tokio::task::JoinHandle;
+use tracing::{debug, info, warn};
+
+use crate::sink::Sink;
+
+/// Directory under the destination root that holds the leases.
+pub const LEASE_DIR: &str = ".untar-locks";
+
+/// Leases on the files a run writes, kept as small files in a directory
+/// every run on the same destination shares, so two runs started on the
+/// same delivery split its files instead of both writing them.
+///
+/// A lease names its holder and when it expires. Holders renew their leases
+/// well before then; a lease past its expiry belongs to a run that died and
+/// is taken over. Expiry is wall-clock time, so hosts need synced clocks.
+pub struct Leases {
+    sink: Sink,
+    dir: String,
+    owner: String,
+    /// Tells this `Leases` from others with the same owner, e.g. a restarted
+    /// run that got the same pid.
+    token: String,
+    ttl: Duration,
+    /// Targets this run holds leases on. Locked while they are renewed, so
+    /// a lease is not written back just after it was released.
+    held: Mutex<HashSet<String>>,
+}
+
+/// Outcome of trying to lease a target.
+pub enum Claim {
+    Acquired,
+    /// Another live run holds it; names that run.
+    HeldBy(String),
+}
+
+/// What a lease file says.
+struct LeaseInfo {
+    owner: String,
+    token: Option<String>,
+    expires_ms: u64,
+}
+
+impl LeaseInfo {
+    fn parse(data: &[u8]) -> Option<Self> {
+        let text = std::str::from_utf8(data).ok()?;
+        let mut owner = None;
+        let mut token = None;
+        let mut expires_ms = None;
+        for line in text.lines() {
+            match line.split_once('=') {
+                Some(("owner", value)) => owner = Some(value.to_string()),
+                Some(("token", value)) => token = Some(value.to_string()),
+                Some(("expires", value)) => expires_ms = value.parse().ok(),
+                _ => {}
+            }
+        }
+        Some(Self { owner: owner?, token, expires_ms: expires_ms? })
+    }
+
+    fn expired(&self) -> bool {
+        self.expires_ms < now_ms()
+    }
+}
+
+impl Leases {
+    /// Leases under `<root>/.untar-locks`, held for `ttl` between renewals.
+    pub fn new(sink: Sink, root: &str, ttl: Duration) -> Self {
+        Self {
+            sink,
+            dir: format!("{}/{}", root.trim_end_matches('/'), LEASE_DIR),
+            owner: format!("{}:{}", hostname(), std::process::id()),
+            token: format!("{:016x}", rand::random::<u64>()),
+            ttl,
+            held: Mutex::new(HashSet::new()),
+        }
+    }
+
+    /// Names this run in the leases it holds.
+    pub fn owner(&self) -> &str {
+        &self.owner
+    }
+
+    /// One flat file per target, named by the hash of its path.
+    fn lease_path(&self, target: &str) -> String {
+        format!("{}/{}", self.dir, hex::encode(Sha256::digest(target.as_bytes())))
+    }
+
+    fn contents(&self, target: &str) -> Bytes {
+        let expires_ms = now_ms() + self.ttl.as_millis() as u64;
+        Bytes::from(format!("owner={}\ntoken={}\nexpires={}\ntarget={}\n", self.owner, self.token, expires_ms, target))
+    }
+
+    /// Leases `target` for this run, unless another live run holds it.
+    pub async fn acquire(&self, target: &str) -> Result<Claim> {
+        let path = self.lease_path(target);
+        // A second attempt follows a lease that vanished or was stale.
+        for _ in 0..2 {
+            let created = self.sink.create_new(&path, self.contents(target))
+                .await
+                .map_err(|e| anyhow!("Failed to create lease {} for {}: {}", path, target, e))?;
+            if created {
+                debug!("Leased {} as {}", target, path);
+                self.held.lock().await.insert(target.to_string());
+                return Ok(Claim::Acquired);
+            }
+            let existing = self.sink.read(&path)
+                .await
+                .map_err(|e| anyhow!("Failed to read lease {} for {}: {}", path, target, e))?;
+            let Some(existing) = existing else {
+                continue;
+            };
+            match LeaseInfo::parse(&existing) {
+                Some(lease) if !lease.expired() => return Ok(Claim::HeldBy(lease.owner)),
+                Some(lease) => warn!("Lease on {} held by {} has expired, taking it over", target, lease.owner),
+                // Still being written, or garbage; treated as held until it expires.
+                None => return Ok(Claim::HeldBy("an unknown run".to_string())),
+            }
+            if let Some(holder) = self.remove_stale(&path, &existing).await? {
+                return Ok(Claim::HeldBy(holder));
+            }
+        }
+        Ok(Claim::HeldBy("a racing run".to_string()))
+    }
+
+    /// Removes the lease file at `path` if it still holds `stale`. It is
+    /// first moved to a path of this run's own, in one step, so of several
+    /// runs taking over the same lease only one removes it, and none removes
+    /// the fresh lease the winner puts in its place. Returns the holder of a
+    /// lease that replaced `stale` in the meantime.
+    async fn remove_stale(&self, path: &str, stale: &[u8]) -> Result<Option<String>> {
+        let claimed = format!("{}.takeover-{}", path, self.token);
+        if let Err(e) = self.sink.rename(path, &claimed, false).await {
+            // Most likely another run moved it first; anything else shows
+            // up again when the lease is created.
+            debug!("Could not move stale lease {} aside: {}", path, e);
+            return Ok(None);
+        }
+        let moved = self.sink.read(&claimed)
+            .await
+            .map_err(|e| anyhow!("Failed to read lease {}: {}", claimed, e))?;
+        let Some(moved) = moved else {
+            // Another run moved it first.
+            return Ok(None);
+        };
+        if moved.as_ref() != stale {
+            // A racing run's fresh lease: give it back, unless yet another run leased the target since.
+            let holder = LeaseInfo::parse(&moved).map_or_else(|| "a racing run".to_string(), |lease| lease.owner);
+            let restored = self.sink.create_new(path, moved)
+                .await
+                .map_err(|e| anyhow!("Failed to restore lease {}: {}", path, e))?;
+            if !restored {
+                warn!("Lease {} of {} was replaced while it was being restored", path, holder);
+            }
+            self.remove(&claimed).await;
+            return Ok(Some(holder));
+        }
+        self.remove(&claimed).await;
+        Ok(None)
+    }
+
+    async fn remove(&self, path: &str) {
+        if let Err(e) = self.sink.delete(path).await {
+            warn!("Failed to remove {}: {}", path, e);
+        }
+    }
+
+    /// Whether the lease file on `target` is still this run's.
+    async fn still_held(&self, target: &str) -> Result<bool> {
+        let path = self.lease_path(target);
+        let existing = self.sink.read(&path)
+            .await
+            .map_err(|e| anyhow!("Failed to read lease {} for {}: {}", path, target, e))?;
+        Ok(existing
+            .as_deref()
+            .and_then(LeaseInfo::parse)
+            .is_some_and(|lease| lease.token.as_deref() == Some(self.token.as_str())))
+    }
+
+    /// Gives up the lease on `target`, whether or not its upload landed, so
+    /// another run may retry a failed one.
+    /// Leases another run took over are left to it.
+    pub async fn release(&self, target: &str) {
+        let mut held = self.held.lock().await;
+        held.remove(target);
+        let path = self.lease_path(target);
+        match self.still_held(target).await {
+            Ok(true) => {}
+            Ok(false) => {
+                warn!("Lease {} on {} was taken over by another run, leaving it", path, target);
+                return;
+            }
+            Err(e) => {
+                warn!("{:#}, leaving it to expire in {:?}", e, self.ttl);
+                return;
+            }
+        }
+        if let Err(e) = self.sink.delete(&path).await {
+            warn!("Failed to release lease {} on {}, it expires in {:?}: {}", path, target, self.ttl, e);
+        }
+    }
+
+    /// Waits until nobody holds a lease on `target`. False if the holder's
+    /// lease expired rather than being released, i.e. it died mid-upload.
+    pub async fn wait_released(&self, target: &str) -> Result<bool> {
+        let path = self.lease_path(target);
+        let poll = (self.ttl / 10).clamp(Duration::from_millis(10), Duration::from_secs(5));
+        loop {
+            let existing = self.sink.read(&path)
+                .await
+                .map_err(|e| anyhow!("Failed to read lease {} for {}: {}", path, target, e))?;
+            match existing.as_deref().map(LeaseInfo::parse) {
+                None => return Ok(true),
+                Some(Some(lease)) if lease.expired() => return Ok(false),
+                _ => tokio::time::sleep(poll).await,
+            }
+        }
+    }
+
+    /// Rewrites every held lease with a fresh expiry a few times per ttl,
+    /// until aborted.
+    pub fn start_renewal(self: &Arc<Self>) -> JoinHandle<()> {
+        let leases = self.clone();
+        tokio::spawn(async move {
+            let mut ticker = tokio::time::interval(leases.ttl / 3);
+            ticker.tick().await;
+            loop {
+                ticker.tick().await;
+                let mut held = leases.held.lock().await;
+                let mut lost = Vec::new();
+                for target in held.iter() {
+                    match leases.renew(target).await {
+                        Ok(true) => {}
+                        Ok(false) => lost.push(target.clone()),
+                        Err(e) => warn!("Failed to renew lease on {}: {:#}", target, e),
+                    }
+                }
+                for target in lost {
+                    warn!("Lease on {} was taken over by another run, no longer renewing it", target);
+                    held.remove(&target);
+                }
+                debug!("Renewed {} lease(s) as {}", held.len(), leases.owner);
+            }
+        })
+    }
+
+    /// Rewrites the lease on `target` with a fresh expiry. False if it is no
+    /// longer this run's, which then leaves it alone.
+    pub async fn renew(&self, target: &str) -> Result<bool> {
+        if !self.still_held(target).await? {
+            return Ok(false);
+        }
+        let path = self.lease_path(target);
+        let write_options = hdfs_native::client::WriteOptions::default().overwrite(true);
+        let mut writer = self.sink.create(&path, write_options).await?;
+        writer.write(self.contents(target)).await?;
+        writer.close().await?;
+        Ok(true)
+    }
+}
+
+impl Drop for Leases {
+    fn drop(&mut self) {
+        let held = self.held.get_mut();
+        if !held.is_empty() {
+            info!("{} lease(s) left behind by {} expire after {:?}", held.len(), self.owner, self.ttl);
+        }
+    }
+}
+
+fn now_ms() -> u64 {
+    SystemTime::now().duration_since(UNIX_EPOCH).unwrap_or_default().as_millis() as u64
+}
+
+fn hostname() -> String {
+    std::fs::read_to_string("/proc/sys/kernel/hostname")
+        .map(|name| name.trim().to_string())
+        .unwrap_or_else(|_| "unknown".to_string())
+}
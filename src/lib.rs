@@ -0,0 +1,28 @@
+pub mod archive;
+pub mod compress;
+pub mod config;
+pub mod control;
+pub mod decompress;
+pub mod digest;
+pub mod journal;
+pub mod lease;
+pub mod matcher;
+pub mod namenode;
+pub mod naming;
+pub mod panics;
+pub mod parallel_gzip;
+pub mod plan;
+#[cfg(feature = "wasm-plugins")]
+pub mod plugin;
+pub mod pool;
+pub mod processor;
+pub mod report;
+pub mod resolver;
+pub mod retry;
+pub mod schedule;
+pub mod sink;
+pub mod spill;
+pub mod state;
+pub mod telemetry;
+pub mod units;
+pub mod workdir;
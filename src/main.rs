@@ -1,24 +1,195 @@
-mod config;
-mod decompress;
-mod processor;
-
-use anyhow::{Context, Result};
-use clap::Parser;
-use hdfs_native::client::ClientBuilder;
+use anyhow::{anyhow, bail, Context, Result};
+use clap::error::ErrorKind;
+use clap::{CommandFactory, Parser, Subcommand};
+use std::collections::HashSet;
 use std::fs::File;
+use std::io::{Cursor, Seek, SeekFrom};
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, OnceLock};
+use std::time::{Duration, Instant};
+use tracing::{error, info, info_span, warn, Instrument};
 use tracing_subscriber::fmt;
 use tracing_subscriber::EnvFilter;
 
-use crate::config::Config;
-use crate::processor::Processor;
+use untar::compress::Recompression;
+use untar::config::{self, Config};
+use untar::journal::Journal;
+use untar::lease::Leases;
+use untar::decompress::{ExternalDecompressor, FormatMap, SuffixRule, MIN_BUFFER_SIZE};
+use untar::control::{Control, DeadlinePassed};
+use untar::matcher::{self, MatchStrategy};
+use untar::naming::{NameTemplate, TemplateContext};
+use untar::processor::{
+    ChunkSizing, CollisionPolicy, ExpansionLimit, FileTimeout, IfExists, MemberOrder, Processor, RatioCheck, RunFailed,
+    Shard, Speculation, TarSource, ThroughputFloor, TimeoutPolicy,
+};
+use untar::report::{Sla, SlaBreached};
+use untar::resolver::{DstResolver, ResolveRequest};
+use untar::retry::RetryPolicy;
+use untar::schedule::{self, CronSchedule};
+use untar::sink::{BlockSizing, Sink, SinkKind, Throttle, WriteOpt};
+use untar::spill::Spill;
+use untar::state::StateFile;
+use untar::telemetry::{self, ProfileSpec, Profiler, ResourceLimits, ResourceMonitor, StageCounters};
+use untar::workdir::WorkDir;
+use untar::namenode::{self, ConnectOptions};
+use untar::panics::catch_panics;
+use untar::{archive, digest, units};
 
 #[derive(Parser, Debug)]
 #[command(author, version, about = "Untar files from tar to HDFS with decompression and verification")]
-struct Args {
+#[command(args_conflicts_with_subcommands = true)]
+struct Cli {
+    #[command(subcommand)]
+    command: Option<Command>,
+
+    #[command(flatten)]
+    run: Option<Args>,
+}
+
+#[derive(Subcommand, Debug)]
+enum Command {
+    /// Extract, verify and upload a single tar member, e.g. for a spot restore
+    ExtractOne(ExtractOneArgs),
+    /// Stay running and start a batch of runs on a cron schedule
+    Schedule(ScheduleArgs),
+    /// Decompress every manifest member and check it against the manifest without writing
+    /// anything, like `gzip -t`
+    Test(TestArgs),
+}
+
+#[derive(clap::Args, Debug)]
+struct TestArgs {
+    /// Path to the source TAR file (repeat, or use a glob, for several archives sharing one manifest)
+    #[arg(short, long, required = true)]
+    tar: Vec<String>,
+
+    /// Path to the XML manifest file
+    #[arg(short, long)]
+    xml: String,
+
+    #[command(flatten)]
+    extraction: ExtractionArgs,
+
+    /// Write an XML run report to this local path
+    #[arg(long)]
+    report: Option<String>,
+}
+
+/// How tar entries are matched to the manifest and decompressed, shared by
+/// runs and `test` so both read an archive the same way.
+#[derive(clap::Args, Debug)]
+struct ExtractionArgs {
+    /// Keep a single top-level directory wrapping all tar entries instead of stripping it
+    #[arg(long)]
+    keep_top_dir: bool,
+
+    /// Open tars nested inside the archive (e.g. inner.tar.gz) that the manifest does not list,
+    /// and extract and verify their members as if unpacked in place
+    #[arg(long)]
+    recurse_archives: bool,
+
+    /// Fail the run on a tar entry found under another directory than its manifest path; by
+    /// default it is skipped (reported as misplaced either way)
+    #[arg(long, conflicts_with = "relocate_misplaced")]
+    strict_paths: bool,
+
+    /// Deliver a tar entry found under another directory to the path the manifest lists, unless
+    /// an entry at that path turns up too
+    #[arg(long)]
+    relocate_misplaced: bool,
+
+    /// How tar entry paths are matched to manifest paths
+    #[arg(long = "match", value_enum, default_value_t = MatchStrategy::Exact)]
+    match_strategy: MatchStrategy,
+
+    /// With --match regex: pattern applied to each tar entry path, e.g. '^vendor_[0-9]+/'
+    #[arg(long)]
+    match_regex: Option<String>,
+
+    /// With --match regex: replacement for the matched part ($1, ${name} refer to groups)
+    #[arg(long, default_value = "")]
+    match_replace: String,
+
+    /// XML manifest of files that must never be uploaded; they are skipped and reported as excluded
+    #[arg(long)]
+    exclude_manifest: Option<String>,
+
+    /// Pipe entries ending in `.ext` through a command that reads stdin and writes the decompressed
+    /// data to stdout, e.g. `pz=pzcat -d` (repeatable)
+    #[arg(long = "decompressor", value_name = "EXT=COMMAND", value_parser = ExternalDecompressor::parse)]
+    decompressors: Vec<ExternalDecompressor>,
+
+    /// Decompress entries ending in `.ext` as a format and rename them, e.g. `.tgz=gzip:.tar`
+    /// or `.z=none` (repeatable; overrides the built-in suffixes)
+    #[arg(long = "suffix-map", value_name = ".EXT=FORMAT[:REPLACEMENT]", value_parser = SuffixRule::parse)]
+    suffix_map: Vec<SuffixRule>,
+
+    /// Dictionary the .zst members were compressed with (as made by `zstd --train`)
+    #[arg(long)]
+    zstd_dict: Option<PathBuf>,
+
+    /// Treat .Z members that lack the compress magic as empty instead of failing them
+    #[arg(long)]
+    lenient_z_header: bool,
+
+    /// Bytes read from the tar and fed to decoders at a time (e.g. 1m)
+    #[arg(long, value_parser = units::parse_size, default_value = "64k")]
+    read_buffer_size: u64,
+
+    /// Inflate .gz members of 64 MiB and more on this many threads: BGZF files (bgzip) are
+    /// decompressed in parallel, other gzip on a background thread alongside reading the tar
+    #[arg(long)]
+    decompress_threads: Option<usize>,
+}
+
+#[derive(clap::Args, Debug)]
+struct ScheduleArgs {
+    /// When to start the batch, as a five-field cron expression in the system timezone, e.g. '0 2 * * *'
+    #[arg(long)]
+    cron: String,
+
+    /// YAML file listing the runs of each batch, each with a name and its untar arguments;
+    /// re-read before every batch
+    #[arg(long)]
+    jobs: PathBuf,
+
+    /// Directory for one run report per job and batch, named `<job>-<time>.xml`,
+    /// for jobs that do not pass --report themselves
+    #[arg(long)]
+    receipts_dir: Option<PathBuf>,
+}
+
+#[derive(clap::Args, Debug)]
+struct ExtractOneArgs {
     /// Path to the source TAR file
     #[arg(short, long)]
     tar: String,
 
+    /// Member to extract, with or without its compression suffix
+    #[arg(short, long)]
+    entry: String,
+
+    /// Path to the XML manifest the member is verified against
+    #[arg(short, long)]
+    xml: String,
+
+    /// Directory to restore into, keeping the member's path: an HDFS URL (hdfs://nn:8020/path) or a local path
+    #[arg(short, long)]
+    out: String,
+
+    /// Keep a single top-level directory wrapping all tar entries instead of stripping it
+    #[arg(long)]
+    keep_top_dir: bool,
+}
+
+#[derive(clap::Args, Debug)]
+struct Args {
+    /// Path to the source TAR file (repeat, or use a glob such as 'batch/*.tar', for several
+    /// archives sharing one manifest)
+    #[arg(short, long, required = true)]
+    tar: Vec<String>,
+
     /// Path to the XML manifest file
     #[arg(short, long)]
     xml: String,
@@ -27,38 +198,853 @@ struct Args {
     #[arg(short, long)]
     namenode: Option<String>,
 
-    /// Target path on HDFS
-    #[arg(short, long)]
-    dst: String,
+    /// Retries of NameNode name resolution and connection at run start
+    #[arg(long, default_value_t = 5)]
+    connect_retries: u32,
+
+    /// First delay between connection retries; doubles per attempt, with jitter (e.g. 1s)
+    #[arg(long, value_parser = units::parse_duration, default_value = "1s")]
+    connect_backoff: Duration,
+
+    /// Retries of creating or closing a file after a transient HDFS error
+    /// (NameNode failover, safe mode, DataNode pipeline errors). A write that
+    /// fails partway is not retried, as its buffered data cannot be replayed
+    #[arg(long, default_value_t = 3)]
+    write_retries: u32,
+
+    /// First delay between write retries; doubles per attempt, with jitter (e.g. 1s)
+    #[arg(long, value_parser = units::parse_duration, default_value = "1s")]
+    write_backoff: Duration,
+
+    /// Resolve the --namenode host once and use that address for the whole run
+    /// (breaks Kerberos principals that use the host name)
+    #[arg(long)]
+    pin_namenode: bool,
+
+    /// Target path on HDFS (the fallback when --dst-resolver is given)
+    #[arg(short, long, required_unless_present = "dst_resolver")]
+    dst: Option<String>,
+
+    /// Send files of a manifest <group> to their own root, e.g. `logs=/raw/logs` (repeatable);
+    /// other files go to --dst
+    #[arg(long, value_parser = parse_dst_map)]
+    dst_map: Vec<(String, String)>,
+
+    /// Routing service URL that is POSTed the manifest metadata and answers `{"dst": "/hdfs/path"}`
+    #[arg(long)]
+    dst_resolver: Option<String>,
+
+    /// How long a destination answered by --dst-resolver is reused from the local cache
+    #[arg(long, value_parser = units::parse_duration, default_value = "1h")]
+    dst_cache_ttl: Duration,
+
+    /// After a complete delivery, move files listed as <deleted-file> in the manifest to the trash
+    #[arg(long)]
+    apply_deletes: bool,
+
+    /// Trash directory for --apply-deletes (defaults to /user/<user>/.Trash/Current)
+    #[arg(long)]
+    trash_dir: Option<String>,
+
+    /// Only compare what this delivery would write (and delete) with what is at the destination,
+    /// listing files to create, overwrite or delete and conflicts; nothing is written
+    #[arg(long)]
+    dry_run: bool,
+
+    /// XML manifest of the previous delivery; prints what this delivery adds, changes and removes
+    #[arg(long)]
+    previous_xml: Option<String>,
+
+    #[command(flatten)]
+    extraction: ExtractionArgs,
+
+    /// Index the tar headers first, then read members in parallel on the decompress workers;
+    /// for uncompressed tars on local disk, where seeking is cheap
+    #[arg(long)]
+    indexed: bool,
+
+    /// With --indexed: order in which each tar's members are matched and uploaded, and so
+    /// targets created; size (largest first) keeps one huge member from running alone at the end
+    #[arg(long, value_enum, default_value_t = MemberOrder::Archive)]
+    order: MemberOrder,
+
+    /// Map the tars into memory and read members from the mapping instead of with read
+    /// calls; for tars on fast local disks (e.g. NVMe) that nothing truncates during the run
+    #[arg(long)]
+    mmap: bool,
+
+    /// Read tars with async file I/O on the runtime, a few --read-buffer-size chunks ahead of
+    /// the tar parser, instead of with blocking reads; for slow or high-latency disks
+    #[arg(long, conflicts_with = "mmap")]
+    async_read: bool,
+
+    /// What to do when two tar entries map to the same HDFS path
+    #[arg(long, value_enum, default_value_t = CollisionPolicy::Skip)]
+    on_collision: CollisionPolicy,
+
+    /// What to do when a target is already on HDFS, e.g. from an earlier delivery to the same --dst
+    #[arg(long, value_enum, default_value_t = IfExists::Overwrite)]
+    if_exists: IfExists,
+
+    /// Append each file to the existing file at its target, e.g. the daily increment of a continuing
+    /// feed; manifest sizes are the increments', and only the appended data is verified
+    #[arg(long)]
+    append: bool,
+
+    /// Write an XML run report to this local path
+    #[arg(long)]
+    report: Option<String>,
+
+    /// Flag the run as an SLA breach, logged under untar::sla and exiting with status 4,
+    /// if it takes longer than this (e.g. 45m, 2h)
+    #[arg(long, value_parser = units::parse_duration)]
+    sla_max_duration: Option<Duration>,
+
+    /// Flag the run as an SLA breach if average throughput falls below this many MB/s
+    #[arg(long)]
+    sla_min_throughput: Option<f64>,
+
+    /// Stop starting new files at this local time of day (HH:MM, the next time it comes round),
+    /// fail the run with a partial report and exit with status 3
+    #[arg(long, value_parser = units::parse_time_of_day)]
+    deadline: Option<time::Time>,
+
+    /// Like --deadline, this long after the run starts (e.g. 4h)
+    #[arg(long, value_parser = units::parse_duration)]
+    max_runtime: Option<Duration>,
+
+    /// Past --deadline or --max-runtime, how long uploads in flight may take to finish before
+    /// they are aborted
+    #[arg(long, value_parser = units::parse_duration, default_value = "5m")]
+    deadline_grace: Duration,
+
+    /// Unix socket accepting JSON pause/resume/cancel/status commands while the run is in progress
+    #[arg(long)]
+    control_socket: Option<String>,
+
+    /// How often bytes read, decompressed and written per second are logged, with an ETA from the
+    /// manifest's total size (0 turns this off)
+    #[arg(long, value_parser = units::parse_duration, default_value = "1m")]
+    progress_interval: Duration,
+
+    /// Account the CPU time spent reading, decompressing (per format) and writing, for the whole
+    /// run or its first SECONDS, and write it as folded stacks (flamegraph.pl, inferno, speedscope)
+    /// next to the --report as <report>.cpu.folded, or to untar-<run id>.cpu.folded. This is
+    /// per-stage accounting, not a sampling profiler: it shows which stage and format the CPU went
+    /// to, not which functions within them
+    #[arg(long, value_name = "cpu[:SECONDS]", value_parser = ProfileSpec::parse)]
+    profile: Option<ProfileSpec>,
+
+    /// How often resident memory and open file descriptors are sampled (e.g. 30s)
+    #[arg(long, value_parser = units::parse_duration, default_value = "30s")]
+    resource_interval: Duration,
+
+    /// Warn when resident memory approaches this size (e.g. 8g)
+    #[arg(long, value_parser = units::parse_size)]
+    max_rss: Option<u64>,
+
+    /// Warn when open file descriptors approach this count (defaults to the soft `ulimit -n`)
+    #[arg(long)]
+    max_open_files: Option<u64>,
+
+    /// Near --max-rss or --max-open-files, wait for in-flight uploads to close their writers
+    /// before starting new files
+    #[arg(long)]
+    recycle_writers: bool,
+
+    /// Abort a file whose HDFS write throughput stays below this many MB/s
+    #[arg(long)]
+    min_file_throughput: Option<f64>,
+
+    /// Abort a file whose upload takes longer than this (e.g. 30m) and mark it failed
+    #[arg(long, value_parser = units::parse_duration)]
+    file_timeout: Option<Duration>,
+
+    /// Abort a file whose upload moves no data for this long (e.g. 10m) and mark it failed;
+    /// with --retries-per-file it is extracted again
+    #[arg(long, value_parser = units::parse_duration)]
+    stall_timeout: Option<Duration>,
+
+    /// Once a file has timed out or stalled: stop starting new files, or carry on with the rest
+    #[arg(long, value_enum, default_value_t = TimeoutPolicy::Stop)]
+    on_file_timeout: TimeoutPolicy,
+
+    /// Window over which --min-file-throughput and --speculate-below are measured (e.g. 60s, 5m)
+    #[arg(long, value_parser = units::parse_duration, default_value = "60s")]
+    throughput_window: Duration,
+
+    /// With --indexed: when a file's write throughput falls below this share of the run's median
+    /// (e.g. 0.1), race a second copy of it through a fresh writer and keep whichever finishes first
+    #[arg(long)]
+    speculate_below: Option<f64>,
+
+    /// With --indexed: when a file's upload fails, read its member again from the tar and
+    /// upload it afresh, up to this many times
+    #[arg(long, default_value_t = 0)]
+    retries_per_file: u32,
+
+    /// Abort a compressed file that decompresses to more than this many times its size in the tar
+    #[arg(long)]
+    max_expansion_ratio: Option<f64>,
+
+    /// Abort a compressed file that decompresses to more than this (e.g. 500g)
+    #[arg(long, value_parser = units::parse_size)]
+    max_decompressed_size: Option<u64>,
+
+    /// Warn when a compressed file decompresses to within this fraction of its size in the tar,
+    /// as corrupt feeds that store data uncompressed do
+    #[arg(long, default_value_t = 0.001)]
+    ratio_warn_tolerance: f64,
+
+    /// Warn when a compressed file decompresses to more than this many times its size in the tar
+    #[arg(long)]
+    ratio_warn_max: Option<f64>,
+
+    /// Manifests larger than this (e.g. 1g) are indexed on local disk instead of in memory
+    #[arg(long, value_parser = units::parse_size, default_value = "1g")]
+    manifest_index_threshold: u64,
+
+    /// Local directory for temporary files such as the on-disk manifest index (defaults to the system temp directory)
+    #[arg(long)]
+    work_dir: Option<PathBuf>,
+
+    /// Fail instead of writing to the work directory when less than this much disk is free (e.g. 5g)
+    #[arg(long, value_parser = units::parse_size, default_value = "1g")]
+    min_free_disk: u64,
+
+    /// Let decompression run ahead of a slow HDFS by spilling up to this much (e.g. 20g) to the work directory
+    #[arg(long, value_parser = units::parse_size)]
+    spill_max_bytes: Option<u64>,
+
+    /// Keep reading the tar while every upload worker is busy or HDFS stalls: new files are
+    /// staged through the spill in the work directory and uploaded in tar order as workers free
+    /// up; set --max-in-flight to bound the memory they queue
+    #[arg(long)]
+    store_and_forward: bool,
+
+    /// Skip files already on HDFS with the size the manifest gives them, e.g. when re-running
+    /// after a partial failure; the manifest carries no checksums, so only sizes are compared
+    #[arg(long)]
+    skip_existing: bool,
+
+    /// Local file recording each file as it is delivered; rerun with the same file (and run id)
+    /// to skip the files it lists without checking HDFS; streamed tars also resume reading after
+    /// the last member delivered in order
+    #[arg(long)]
+    state_file: Option<PathBuf>,
+
+    /// Write each file under `<dst>/.untar-tmp/` first and move it to its target only once its size
+    /// and checksum are verified, so readers never see a half-written file; failed uploads are removed
+    #[arg(long)]
+    atomic: bool,
+
+    /// Record each file's start and commit under `<dst>/_untar_journal/<run id>/`, so a rerun of
+    /// the run on any host skips the files committed and rewrites the ones left partly written
+    #[arg(long)]
+    journal: bool,
+
+    /// Deliver only shard I of N (counting from 0): the manifest files whose name hashes to I
+    /// modulo N, so N hosts given the same tars and manifest split it without overlap. Each
+    /// shard writes its receipts and CHECKSUMS files suffixed `.shard-I-of-N`
+    #[arg(long, value_name = "I/N", value_parser = Shard::parse)]
+    shard: Option<Shard>,
+
+    /// Lease each file under `<dst>/.untar-locks/` before writing it, so runs started on the
+    /// same delivery at once split its files instead of both writing them; files another run
+    /// holds are waited for and checked before the receipt is written
+    #[arg(long)]
+    coordinate: bool,
+
+    /// How long a lease outlives its run if the run dies; live runs renew theirs well before
+    #[arg(long, value_parser = units::parse_duration, default_value = "10m")]
+    lease_ttl: Duration,
+
+    /// Where extracted files go; `null` runs the full read/decompress/verify path without touching HDFS,
+    /// so the stage timings tell decompression from cluster bottlenecks
+    #[arg(long, value_enum, default_value_t = SinkKind::Hdfs)]
+    sink: SinkKind,
+
+    /// With --sink null, throttle output to this many bytes per second (e.g. 500m) to project run time
+    #[arg(long, value_parser = units::parse_size)]
+    simulate_bandwidth: Option<u64>,
+
+    /// Experimental: WASM module that transforms each entry's decompressed data before upload
+    #[cfg(feature = "wasm-plugins")]
+    #[arg(long)]
+    plugin: Option<PathBuf>,
+
+    /// Template for uploaded file names, e.g. '{stem}.{run_id}.{ext}' (variables: name, stem, ext, run_id, date)
+    #[arg(long)]
+    name_template: Option<String>,
+
+    /// Identifier of this delivery run, recorded in the report, logs, temp files and --name-template's {run_id}.
+    /// Defaults to a hash of the manifest and --dst/--dst-resolver, so retries of the same delivery share it
+    #[arg(long)]
+    run_id: Option<String>,
+
+    /// Read past each tar's EOF marker and warn if anything but zero padding follows
+    #[arg(long)]
+    check_trailing_data: bool,
 
-    /// Parallel workers
-    #[arg(short, long, default_value_t = 10)]
-    threads: usize,
+    /// Split files larger than this (e.g. 50g) into numbered `.part-NNNNN` files on HDFS
+    #[arg(long, value_parser = units::parse_size)]
+    split_size: Option<u64>,
+
+    /// Pick each file's HDFS block size from its manifest size, so files just over a multiple
+    /// of the block size do not end with a tiny block
+    #[arg(long)]
+    size_hints: bool,
+
+    /// The cluster's dfs.blocksize, used by --size-hints and --checksums
+    #[arg(long, value_parser = units::parse_size, default_value = "128m")]
+    dfs_block_size: u64,
+
+    /// Set a writer option for every file created (repeatable): block_size=SIZE,
+    /// replication=N, permission=OCTAL or create_parent=BOOL
+    #[arg(long = "write-opt", value_name = "KEY=VALUE", value_parser = WriteOpt::parse)]
+    write_opts: Vec<WriteOpt>,
+
+    /// Write a CHECKSUMS file to each destination root listing every uploaded file's checksum
+    /// as `hdfs dfs -checksum` prints it (MD5-of-MD5-of-CRC32C)
+    #[arg(long)]
+    checksums: bool,
+
+    /// Compressed member data read from the tar ahead of the decompress workers (e.g. 256m),
+    /// so reading moves on to the next members while earlier ones are decompressed; 64m by
+    /// default, or a quarter of --max-memory if that is less
+    #[arg(long, value_parser = units::parse_size)]
+    read_ahead: Option<u64>,
+
+    /// Bytes of decompressed data handed to each HDFS writer at a time (e.g. 1m), for every file;
+    /// by default each file's chunk size follows its manifest size
+    #[arg(long, value_parser = units::parse_size, conflicts_with_all = ["min_chunk_size", "max_chunk_size"])]
+    chunk_size: Option<u64>,
+
+    /// Smallest chunk picked for a file from its manifest size (e.g. 64k)
+    #[arg(long, value_parser = units::parse_size, default_value = "64k")]
+    min_chunk_size: u64,
+
+    /// Largest chunk picked for a file from its manifest size (e.g. 4m)
+    #[arg(long, value_parser = units::parse_size, default_value = "1m")]
+    max_chunk_size: u64,
+
+    /// Decompressed chunks kept in memory at once across all files; decompression
+    /// waits for uploads to free one, so memory use stays bounded
+    #[arg(long, default_value_t = 256)]
+    max_buffers: usize,
+
+    /// Decompressed chunks queued for each file's HDFS writer before its decompression waits
+    /// (or spills, with --spill-max-bytes); raise it when the sink is bursty, lower it to share
+    /// memory between more files
+    #[arg(long, default_value_t = 16)]
+    queue_depth: usize,
+
+    /// Decompressed bytes queued for all HDFS writers together (e.g. 128m); no limit beyond
+    /// --queue-depth and --max-buffers by default
+    #[arg(long, value_parser = units::parse_size)]
+    max_in_flight: Option<u64>,
+
+    /// Memory for buffered data in total (e.g. 2g): the read-ahead plus every decompressed
+    /// chunk, queued or being written; keep it below the container limit minus the ~10m base
+    #[arg(long, value_parser = units::parse_size)]
+    max_memory: Option<u64>,
+
+    /// Store files compressed as gzip or zstd, optionally with a level (e.g. zstd:19), adding
+    /// .gz or .zst to their names; content and size are still verified decompressed
+    #[arg(long, value_parser = Recompression::parse)]
+    recompress: Option<Recompression>,
+
+    /// Files uploaded to HDFS at once; the tar is read ahead only while a slot is free
+    #[arg(long, alias = "threads", default_value_t = 10)]
+    upload_workers: usize,
+
+    /// Files open for writing at once, each holding a DataNode pipeline, whatever the upload
+    /// workers, second copies and receipts; others wait for a free one (unlimited by default)
+    #[arg(long)]
+    max_connections: Option<usize>,
+
+    /// Archives read at once, each on its own thread; upload and decompress workers are shared.
+    /// Above 1 needs --on-collision error or suffix, as which archive reaches a name first varies
+    #[arg(long, default_value_t = 1)]
+    archive_workers: usize,
+
+    /// Members decompressed at once, each on its own thread (defaults to the number of CPUs)
+    #[arg(long)]
+    decompress_workers: Option<usize>,
+}
+
+/// Parses a `--dst-map` value of the form `group=path`.
+fn parse_dst_map(value: &str) -> Result<(String, String), String> {
+    match value.split_once('=') {
+        Some((group, path)) if !group.is_empty() && !path.is_empty() => {
+            Ok((group.to_string(), path.trim_end_matches('/').to_string()))
+        }
+        _ => Err(format!("invalid --dst-map '{}', expected group=path", value)),
+    }
+}
+
+impl ExtractionArgs {
+    /// Adds a line to `problems` for each flag set to something unusable.
+    fn check(&self, problems: &mut Vec<String>) {
+        if let Some(path) = &self.exclude_manifest
+            && !Path::new(path).is_file()
+        {
+            problems.push(format!("--exclude-manifest {}: no such file", path));
+        }
+        if let Some(dict) = &self.zstd_dict
+            && !dict.is_file()
+        {
+            problems.push(format!("--zstd-dict {}: no such file", dict.display()));
+        }
+        if self.match_regex.is_some() && self.match_strategy != MatchStrategy::Regex {
+            problems.push("--match-regex requires --match regex".to_string());
+        }
+        if let Err(e) = matcher::matcher(self.match_strategy, self.match_regex.as_deref(), &self.match_replace) {
+            problems.push(e.to_string());
+        }
+        let mut suffixes = HashSet::new();
+        for decompressor in &self.decompressors {
+            if !suffixes.insert(&decompressor.suffix) {
+                problems.push(format!("--decompressor lists {} more than once", decompressor.suffix));
+            }
+        }
+        let mut suffixes = HashSet::new();
+        for rule in &self.suffix_map {
+            if !suffixes.insert(&rule.suffix) {
+                problems.push(format!("--suffix-map lists {} more than once", rule.suffix));
+            }
+        }
+        if self.decompress_threads == Some(0) {
+            problems.push("--decompress-threads must be at least 1".to_string());
+        }
+        if !(MIN_BUFFER_SIZE as u64..=1 << 30).contains(&self.read_buffer_size) {
+            problems.push(format!("--read-buffer-size must be between 4k and 1g, got {}", self.read_buffer_size));
+        }
+    }
+
+    /// `processor` matching and decoding entries as these flags say; buffer
+    /// sizes are left to the caller, which also picks the chunk sizes.
+    fn configure(&self, processor: Processor) -> Result<Processor> {
+        let excluded = match &self.exclude_manifest {
+            Some(path) => config::load_filenames(path).context("Failed to load exclusion manifest")?,
+            None => HashSet::new(),
+        };
+        let zstd_dict = self
+            .zstd_dict
+            .as_ref()
+            .map(|path| std::fs::read(path).with_context(|| format!("Failed to read zstd dictionary {}", path.display())))
+            .transpose()?;
+        let matcher = matcher::matcher(self.match_strategy, self.match_regex.as_deref(), &self.match_replace)?;
+        Ok(processor
+            .with_matcher(matcher)
+            .with_exclusions(excluded)
+            .with_strict_paths(self.strict_paths)
+            .with_relocate_misplaced(self.relocate_misplaced)
+            .with_recurse_archives(self.recurse_archives)
+            .with_decompress_threads(self.decompress_threads)
+            .with_decompressors(self.decompressors.clone())
+            .with_format_map(FormatMap::default().with_rules(self.suffix_map.clone()))
+            .with_zstd_dict(zstd_dict.as_deref())
+            .with_lenient_z_header(self.lenient_z_header))
+    }
+}
+
+/// Checks the options against each other before anything is read or
+/// connected, so every problem is reported at once.
+fn validate(args: &Args) -> Result<()> {
+    let mut problems = Vec::new();
+
+    match expand_tars(&args.tar) {
+        Ok(tars) => {
+            for tar in tars {
+                if !Path::new(&tar).is_file() {
+                    problems.push(format!("--tar {}: no such file", tar));
+                }
+            }
+        }
+        Err(e) => problems.push(e.to_string()),
+    }
+    if !Path::new(&args.xml).is_file() {
+        problems.push(format!("--xml {}: no such file", args.xml));
+    }
+    if let Some(path) = &args.previous_xml
+        && !Path::new(path).is_file()
+    {
+        problems.push(format!("--previous-xml {}: no such file", path));
+    }
+    args.extraction.check(&mut problems);
+    #[cfg(feature = "wasm-plugins")]
+    if let Some(plugin) = &args.plugin
+        && !plugin.is_file()
+    {
+        problems.push(format!("--plugin {}: no such file", plugin.display()));
+    }
+    if args.skip_existing && args.recompress.is_some() {
+        problems.push("--skip-existing cannot compare sizes of files stored with --recompress".to_string());
+    }
+    #[cfg(feature = "wasm-plugins")]
+    if args.skip_existing && args.plugin.is_some() {
+        problems.push("--skip-existing cannot compare sizes of files transformed by --plugin".to_string());
+    }
+    if args.append {
+        let conflicting = [
+            (args.split_size.is_some(), "--split-size"),
+            (args.atomic, "--atomic"),
+            (args.skip_existing, "--skip-existing"),
+            (args.if_exists != IfExists::Overwrite, "--if-exists"),
+            (args.retries_per_file > 0, "--retries-per-file"),
+            (args.speculate_below.is_some(), "--speculate-below"),
+            (args.checksums, "--checksums"),
+            // A run killed mid-append leaves part of an increment that a
+            // rerun skipping by record would never notice, or append again.
+            (args.journal, "--journal"),
+            (args.state_file.is_some(), "--state-file"),
+            // An entry at the manifest path would be appended after the relocated copy.
+            (args.extraction.relocate_misplaced, "--relocate-misplaced"),
+        ];
+        for (_, flag) in conflicting.iter().filter(|(set, _)| *set) {
+            problems.push(format!("--append cannot be combined with {}", flag));
+        }
+    }
+
+    if args.simulate_bandwidth.is_some() && args.sink != SinkKind::Null {
+        problems.push("--simulate-bandwidth requires --sink null".to_string());
+    }
+    if args.order != MemberOrder::Archive && !args.indexed {
+        problems.push("--order requires --indexed".to_string());
+    }
+    if let Some(share) = args.speculate_below {
+        if !args.indexed {
+            problems.push("--speculate-below requires --indexed".to_string());
+        }
+        if !(share > 0.0 && share <= 1.0) {
+            problems.push(format!("--speculate-below must be above 0 and at most 1, got {}", share));
+        }
+    }
+    if args.file_timeout.is_some_and(|limit| limit.is_zero()) {
+        problems.push("--file-timeout must be greater than 0".to_string());
+    }
+    if args.resource_interval.is_zero() {
+        problems.push("--resource-interval must be greater than 0".to_string());
+    }
+    if args.stall_timeout.is_some_and(|limit| limit.is_zero()) {
+        problems.push("--stall-timeout must be greater than 0".to_string());
+    }
+    if args.max_runtime.is_some_and(|limit| limit.is_zero()) {
+        problems.push("--max-runtime must be greater than 0".to_string());
+    }
+    if args.retries_per_file > 0 && !args.indexed {
+        problems.push("--retries-per-file requires --indexed".to_string());
+    }
+    if args.coordinate && args.lease_ttl.is_zero() {
+        problems.push("--lease-ttl must be greater than 0".to_string());
+    }
+    if args.store_and_forward && args.spill_max_bytes.is_none() {
+        problems.push("--store-and-forward requires --spill-max-bytes".to_string());
+    }
+    if args.trash_dir.is_some() && !args.apply_deletes {
+        problems.push("--trash-dir has no effect without --apply-deletes".to_string());
+    }
+    if let Some(template) = &args.name_template
+        && let Err(e) = NameTemplate::parse(template)
+    {
+        problems.push(e.to_string());
+    }
+
+    let mut groups = HashSet::new();
+    for (group, _) in &args.dst_map {
+        if !groups.insert(group) {
+            problems.push(format!("--dst-map lists group '{}' more than once", group));
+        }
+    }
+
+    if args.upload_workers == 0 {
+        problems.push("--upload-workers must be at least 1".to_string());
+    }
+    if args.max_connections == Some(0) {
+        problems.push("--max-connections must be at least 1".to_string());
+    }
+    if args.archive_workers == 0 {
+        problems.push("--archive-workers must be at least 1".to_string());
+    }
+    if args.archive_workers > 1 && args.indexed {
+        problems.push("--archive-workers has no effect with --indexed".to_string());
+    }
+    if args.archive_workers > 1 && !matches!(args.on_collision, CollisionPolicy::Error | CollisionPolicy::Suffix) {
+        problems.push(format!(
+            "--archive-workers above 1 needs --on-collision error or suffix: with {:?} the entry kept would depend on which archive gets there first",
+            args.on_collision
+        ));
+    }
+    if args.archive_workers > 1 && args.extraction.relocate_misplaced {
+        problems.push("--relocate-misplaced cannot be combined with --archive-workers above 1".to_string());
+    }
+    if args.async_read && args.indexed {
+        problems.push("--async-read has no effect with --indexed".to_string());
+    }
+    if args.max_buffers == 0 {
+        problems.push("--max-buffers must be at least 1".to_string());
+    }
+    if args.queue_depth == 0 {
+        problems.push("--queue-depth must be at least 1".to_string());
+    }
+    if args.max_in_flight == Some(0) {
+        problems.push("--max-in-flight must be greater than 0".to_string());
+    }
+    if args.decompress_workers == Some(0) {
+        problems.push("--decompress-workers must be at least 1".to_string());
+    }
+    if read_ahead(args) < args.extraction.read_buffer_size {
+        problems.push(format!(
+            "--read-ahead {} must be at least --read-buffer-size {}",
+            read_ahead(args),
+            args.extraction.read_buffer_size
+        ));
+    }
+    if let Some(max_memory) = args.max_memory
+        && read_ahead(args) >= max_memory
+    {
+        problems.push(format!(
+            "--read-ahead {} leaves none of --max-memory {} for decompressed data",
+            read_ahead(args),
+            max_memory
+        ));
+    }
+    for (flag, size) in [
+        ("--chunk-size", args.chunk_size),
+        ("--min-chunk-size", Some(args.min_chunk_size)),
+        ("--max-chunk-size", Some(args.max_chunk_size)),
+    ] {
+        if let Some(size) = size
+            && !(1..=1 << 30).contains(&size)
+        {
+            problems.push(format!("{} must be between 1 byte and 1g, got {}", flag, size));
+        }
+    }
+    if args.min_chunk_size > args.max_chunk_size {
+        problems.push(format!(
+            "--min-chunk-size {} is larger than --max-chunk-size {}",
+            args.min_chunk_size, args.max_chunk_size
+        ));
+    }
+    if args.split_size == Some(0) {
+        problems.push("--split-size must be greater than 0".to_string());
+    }
+    if (args.checksums || args.size_hints) && args.write_opts.iter().any(|opt| matches!(opt, WriteOpt::BlockSize(_))) {
+        problems.push("--write-opt block_size cannot be combined with --checksums or --size-hints; set --dfs-block-size".to_string());
+    }
+    if (args.checksums || args.size_hints) && (args.dfs_block_size == 0 || !args.dfs_block_size.is_multiple_of(512)) {
+        problems.push(format!(
+            "--dfs-block-size {} must be a non-zero multiple of 512 (the HDFS checksum chunk)",
+            args.dfs_block_size
+        ));
+    }
+    for (flag, value) in [
+        ("--max-expansion-ratio", args.max_expansion_ratio),
+        ("--ratio-warn-max", args.ratio_warn_max),
+        ("--min-file-throughput", args.min_file_throughput),
+        ("--sla-min-throughput", args.sla_min_throughput),
+    ] {
+        if let Some(value) = value
+            && !(value.is_finite() && value > 0.0)
+        {
+            problems.push(format!("{} must be a positive number, got {}", flag, value));
+        }
+    }
+
+    if !(args.ratio_warn_tolerance.is_finite() && args.ratio_warn_tolerance >= 0.0) {
+        problems.push(format!("--ratio-warn-tolerance must not be negative, got {}", args.ratio_warn_tolerance));
+    }
+
+    invalid_if_any(&problems)
+}
+
+/// Fails listing `problems`, if there are any.
+fn invalid_if_any(problems: &[String]) -> Result<()> {
+    match problems {
+        [] => Ok(()),
+        [problem] => bail!("Invalid configuration: {}", problem),
+        _ => bail!("Invalid configuration:\n  - {}", problems.join("\n  - ")),
+    }
+}
+
+const DEFAULT_READ_AHEAD: u64 = 64 << 20;
+
+/// Exit status of a run stopped by --deadline or --max-runtime.
+const EXIT_DEADLINE: i32 = 3;
+
+/// Exit status of a run that delivered every file but missed its SLA.
+const EXIT_SLA_BREACHED: i32 = 4;
+
+/// Time left until the earlier of `--deadline` and `--max-runtime`.
+fn time_to_deadline(args: &Args) -> Option<Duration> {
+    let until_deadline = args.deadline.map(|deadline| {
+        let now = time::OffsetDateTime::now_utc().to_offset(local_offset());
+        let mut at = now.replace_time(deadline);
+        if at <= now {
+            at += time::Duration::days(1);
+        }
+        Duration::try_from(at - now).unwrap_or_default()
+    });
+    [until_deadline, args.max_runtime].into_iter().flatten().min()
+}
+
+/// `--read-ahead`, defaulting to a quarter of `--max-memory` when that is
+/// below the usual 64m.
+fn read_ahead(args: &Args) -> u64 {
+    args.read_ahead.unwrap_or_else(|| args.max_memory.map_or(DEFAULT_READ_AHEAD, |bytes| (bytes / 4).min(DEFAULT_READ_AHEAD)))
+}
+
+/// A fixed `--chunk-size`, or chunks sized per file within the bounds.
+fn chunk_sizing(args: &Args) -> ChunkSizing {
+    match args.chunk_size {
+        Some(size) => ChunkSizing::fixed(size as usize),
+        None => ChunkSizing { min: args.min_chunk_size as usize, max: args.max_chunk_size as usize },
+    }
 }
 
-#[tokio::main]
-async fn main() -> Result<()> {
+/// The HDFS trash of the current user, as `hdfs dfs -rm` uses it.
+fn default_trash_dir() -> String {
+    let user = std::env::var("HADOOP_USER_NAME")
+        .or_else(|_| std::env::var("USER"))
+        .unwrap_or_else(|_| "hdfs".to_string());
+    format!("/user/{}/.Trash/Current", user)
+}
+
+/// Offset of the system timezone, looked up once at startup.
+static LOCAL_OFFSET: OnceLock<time::UtcOffset> = OnceLock::new();
+
+/// Timezone used for log timestamps, run dates, `--deadline` and `schedule --cron`.
+fn local_offset() -> time::UtcOffset {
+    LOCAL_OFFSET.get().copied().unwrap_or(time::UtcOffset::UTC)
+}
+
+fn main() -> Result<()> {
+    // The offset can only be read while the process is single-threaded,
+    // so before the runtime starts its workers.
+    let offset = time::UtcOffset::current_local_offset();
+    LOCAL_OFFSET.get_or_init(|| offset.unwrap_or(time::UtcOffset::UTC));
     fmt()
         .with_env_filter(EnvFilter::from_default_env())
         .with_timer(tracing_subscriber::fmt::time::OffsetTime::new(
-            time::UtcOffset::from_hms(8, 0, 0).unwrap(),
+            local_offset(),
             time::format_description::well_known::Rfc3339,
         ))
         .init();
+    if let Err(e) = offset {
+        warn!("Cannot determine the local timezone ({}), using UTC", e);
+    }
 
-    let args = Args::parse();
+    tokio::runtime::Builder::new_multi_thread().enable_all().build()?.block_on(run_command())
+}
+
+async fn run_command() -> Result<()> {
+    let cli = Cli::parse();
+    let result = match cli.command {
+        Some(Command::ExtractOne(args)) => extract_one(args).await,
+        Some(Command::Schedule(args)) => schedule(args).await,
+        Some(Command::Test(args)) => test(args).await,
+        None => match cli.run {
+            Some(args) => run(args).await,
+            None => Cli::command()
+                .error(ErrorKind::MissingRequiredArgument, "--tar and --xml are required")
+                .exit(),
+        },
+    };
+    if let Err(e) = &result {
+        let code = if e.is::<DeadlinePassed>() {
+            EXIT_DEADLINE
+        } else if e.is::<SlaBreached>() {
+            EXIT_SLA_BREACHED
+        } else {
+            return result;
+        };
+        eprintln!("Error: {:?}", e);
+        std::process::exit(code);
+    }
+    result
+}
+
+async fn run(args: Args) -> Result<()> {
+    validate(&args)?;
+    let started = Instant::now();
+
+    let run_id = match &args.run_id {
+        Some(run_id) => run_id.clone(),
+        None => {
+            let dst = args.dst_resolver.as_deref().or(args.dst.as_deref()).unwrap_or_default();
+            digest::default_run_id(&args.xml, dst).context("Failed to read XML file")?
+        }
+    };
+    info!("Starting run {}", run_id);
+
+    let template_context = TemplateContext {
+        run_id: run_id.clone(),
+        // The date the operator sees on the wall clock, not the UTC one.
+        date: time::OffsetDateTime::now_utc()
+            .to_offset(local_offset())
+            .format(time::macros::format_description!("[year][month][day]"))
+            .context("Failed to format run date")?,
+    };
+    let name_template = args
+        .name_template
+        .as_deref()
+        .map(NameTemplate::parse)
+        .transpose()?;
 
     // 1. Load XML Config (Local Manifest)
-    let config = Config::from_xml_file(&args.xml)
+    let work_dir = WorkDir::new(
+        args.work_dir.clone().unwrap_or_else(std::env::temp_dir),
+        args.min_free_disk,
+        run_id.clone(),
+    );
+    let config = Config::from_xml_file_indexed(&args.xml, args.manifest_index_threshold, &work_dir)
         .context("Failed to load XML manifest")?;
+    let state = args.state_file.as_deref().map(|path| StateFile::open(path, &run_id)).transpose()?;
+
+    let dst = match &args.dst_resolver {
+        Some(url) => {
+            let resolver = DstResolver::new(url.clone(), work_dir.path(), args.dst_cache_ttl);
+            let request = ResolveRequest {
+                manifest: PathBuf::from(&args.xml)
+                    .file_name()
+                    .map(|name| name.to_string_lossy().to_string())
+                    .unwrap_or_else(|| args.xml.clone()),
+                files: config.entries,
+                bytes: config.total_size,
+                run_id: run_id.clone(),
+            };
+            let fallback = args.dst.clone();
+            tokio::task::spawn_blocking(move || resolver.resolve(&request, fallback.as_deref()))
+                .await
+                .context("Destination resolver panicked")??
+        }
+        None => args.dst.clone().expect("clap requires --dst without --dst-resolver"),
+    };
+
+    if let Some(previous_xml) = &args.previous_xml {
+        let previous = Config::from_xml_file(previous_xml)
+            .context("Failed to load previous XML manifest")?;
+        config.diff(&previous)?.print();
+    }
 
     // 2. Initialize HDFS Client
     // hdfs-native will automatically check HADOOP_CONF_DIR 
     // for hdfs-site.xml and core-site.xml.
-    let client = if let Some(url) = args.namenode {
-        ClientBuilder::new().with_url(&url).build().context("Failed to create HDFS client")?
-    } else {
-        ClientBuilder::new().build().context("Failed to create HDFS client from config")?
+    let throttle = args.simulate_bandwidth.map(|bps| Arc::new(Throttle::new(bps)));
+    let sink = match args.sink {
+        SinkKind::Hdfs => {
+            let options = ConnectOptions {
+                retries: args.connect_retries,
+                backoff: args.connect_backoff,
+                pin: args.pin_namenode,
+            };
+            Sink::Hdfs(Arc::new(namenode::connect(args.namenode.as_deref(), &options).await?))
+        }
+        SinkKind::Null => {
+            info!("Using null sink, nothing will be written to HDFS");
+            Sink::Null(throttle.clone())
+        }
     };
 
     // Note: To support Kerberos:
@@ -66,15 +1052,465 @@ async fn main() -> Result<()> {
     // 2. Ensure HADOOP_CONF_DIR environment variable is set.
     // 3. Ensure a valid TGT existed (run kinit before executing).
 
-    // 3. Initialize Processor
-    let processor = Processor::new(client, config, args.dst, args.xml);
+    // 3. Open TARs and detect a wrapping top-level directory
+    let mut sources = expand_tars(&args.tar)?
+        .iter()
+        .map(|path| open_tar(path, args.extraction.keep_top_dir, &config))
+        .collect::<Result<Vec<_>>>()?;
+    // Streamed tars pick up after the last member an earlier attempt delivered.
+    if let Some(state) = &state
+        && !args.indexed
+    {
+        for source in &mut sources {
+            if let Some(offset) = state.resume_offset(&source.name) {
+                info!("Resuming {} at byte {}", source.name, offset);
+                source.reader
+                    .seek(SeekFrom::Start(offset))
+                    .with_context(|| format!("Failed to seek {} to byte {}", source.name, offset))?;
+            }
+        }
+    }
+
+    // 4. Initialize Processor
+    let resources = ResourceMonitor::new(ResourceLimits {
+        max_rss: args.max_rss,
+        max_fds: args.max_open_files.or_else(telemetry::fd_limit),
+        recycle_writers: args.recycle_writers,
+    });
+    // Dropped when the run ends, however it ends.
+    let _sampler = resources.start(args.resource_interval);
+    let control = Control::default().with_resources(resources.clone());
+    let mut background = BackgroundTasks(Vec::new());
+    if let Some(socket) = &args.control_socket {
+        background.0.push(control.serve(socket)?);
+    }
+
+    let chunk_sizing = chunk_sizing(&args);
+    let read_ahead = read_ahead(&args);
+    let stages = Arc::new(StageCounters::default());
+    let expected_bytes = match args.shard {
+        Some(shard) => config.file_map.iter().try_fold(0u64, |total, item| {
+            let (name, size) = item?;
+            Ok::<_, anyhow::Error>(if shard.claims(&name) { total + size } else { total })
+        })?,
+        None => config.total_size,
+    };
+    let leases = args.coordinate.then(|| Arc::new(Leases::new(sink.clone(), &dst, args.lease_ttl)));
+    let journal = args.journal.then(|| Journal::new(sink.clone(), &dst, &run_id));
+    let profiler = args.profile.map(|spec| Arc::new(Profiler::new(spec)));
+    let processor = args.extraction.configure(Processor::new(sink, config, dst, args.xml.clone()))?
+        .with_stage_counters(stages.clone())
+        .with_profiler(profiler.clone())
+        .with_leases(leases.clone())
+        .with_journal(journal)
+        .with_atomic_uploads(args.atomic.then(|| run_id.clone()))
+        .with_dst_map(args.dst_map.iter().cloned().collect())
+        .with_apply_deletes(args.apply_deletes.then(|| args.trash_dir.clone().unwrap_or_else(default_trash_dir)))
+        .with_collision_policy(args.on_collision)
+        .with_if_exists(args.if_exists)
+        .with_append(args.append)
+        .with_buffer_sizes(args.extraction.read_buffer_size as usize, chunk_sizing)
+        .with_recompression(args.recompress)
+        .with_control(control.clone())
+        .with_upload_concurrency(args.upload_workers)
+        .with_max_connections(args.max_connections)
+        .with_write_retries(RetryPolicy { retries: args.write_retries, backoff: args.write_backoff })
+        .with_archive_workers(args.archive_workers)
+        .with_decompress_workers(args.decompress_workers)
+        .with_read_ahead(read_ahead as usize)
+        .with_max_buffers(args.max_buffers, args.max_memory.map(|bytes| (bytes - read_ahead) as usize))
+        .with_queue_depth(args.queue_depth, args.max_in_flight.map(|bytes| bytes as usize))
+        .with_expansion_limit(ExpansionLimit {
+            max_ratio: args.max_expansion_ratio,
+            max_size: args.max_decompressed_size,
+        })
+        .with_ratio_check(RatioCheck {
+            tolerance: args.ratio_warn_tolerance,
+            max_ratio: args.ratio_warn_max,
+        })
+        .with_skip_existing(args.skip_existing)
+        .with_state_file(state)
+        .with_shard(args.shard)
+        .with_name_template(name_template, template_context)
+        .with_split_size(args.split_size)
+        .with_checksums(args.checksums.then_some(args.dfs_block_size))
+        .with_write_opts(args.write_opts.clone())
+        .with_block_sizing(args.size_hints.then_some(BlockSizing { default_block_size: args.dfs_block_size }))
+        .with_spill(args.spill_max_bytes.map(|max_bytes| Spill::new(work_dir.clone(), max_bytes)))
+        .with_store_and_forward(args.store_and_forward)
+        .with_resource_monitor(Some(resources.clone()))
+        .with_check_trailing_data(args.check_trailing_data)
+        .with_mmap(args.mmap)
+        .with_member_order(args.order)
+        .with_throughput_floor(args.min_file_throughput.map(|min_mb_per_sec| ThroughputFloor {
+            min_mb_per_sec,
+            window: args.throughput_window,
+        }))
+        .with_file_timeout(args.file_timeout.map(|limit| FileTimeout { limit, policy: args.on_file_timeout }))
+        .with_stall_timeout(args.stall_timeout.map(|limit| FileTimeout { limit, policy: args.on_file_timeout }))
+        .with_speculation(args.speculate_below.map(|min_share| Speculation {
+            min_share,
+            window: args.throughput_window,
+        }))
+        .with_retries_per_file(args.retries_per_file);
+
+    #[cfg(feature = "wasm-plugins")]
+    let processor = processor.with_plugin(args.plugin.as_ref().map(untar::plugin::Plugin::load).transpose()?);
+
+    if args.dry_run {
+        let plan = processor.plan().await?;
+        plan.print();
+        if plan.conflicts() > 0 {
+            bail!("{} destination path(s) conflict with this delivery", plan.conflicts());
+        }
+        return Ok(());
+    }
+
+    // Streamed tars are still files here: refuse colliding names before anything is uploaded.
+    if !args.indexed {
+        processor.check_collisions(&mut sources)?;
+    }
+
+    // 5. Run untar
+    let run = async {
+        if args.indexed {
+            processor.process_indexed_tars(sources).await
+        } else if args.mmap {
+            processor.process_tars(sources.into_iter().map(map_tar).collect::<Result<Vec<_>>>()?).await
+        } else if args.async_read {
+            let chunk_size = args.extraction.read_buffer_size as usize;
+            processor.process_tars(sources.into_iter().map(|source| async_tar(source, chunk_size)).collect()).await
+        } else {
+            processor.process_tars(sources).await
+        }
+    };
+    let progress_log = (!args.progress_interval.is_zero())
+        .then(|| stages.start_progress_log(expected_bytes, args.progress_interval));
+    let deadline = time_to_deadline(&args).map(|remaining| {
+        info!("Run deadline in {}s", remaining.as_secs());
+        control.expire_after(remaining, args.deadline_grace)
+    });
+    let lease_renewal = leases.as_ref().map(|leases| {
+        info!("Coordinating through leases as {}", leases.owner());
+        leases.start_renewal()
+    });
+    background.0.extend([progress_log, lease_renewal, deadline].into_iter().flatten());
+    let result = run.instrument(info_span!("run", run_id = %run_id)).await;
+    drop(background);
+    if let Some(profiler) = &profiler {
+        let path = match &args.report {
+            Some(report_path) => Path::new(report_path).with_extension("cpu.folded"),
+            None => PathBuf::from(format!("untar-{}.cpu.folded", run_id)),
+        };
+        if let Err(e) = profiler.write_folded(&path) {
+            warn!("Failed to write CPU accounting to {}: {:#}", path.display(), e);
+        }
+    }
+    let mut report = match result {
+        Ok(report) => report,
+        Err(mut e) => {
+            // Keep per-file failures, panic backtraces and misplaced entries in the report.
+            if let (Some(failed), Some(report_path)) = (e.downcast_mut::<RunFailed>(), &args.report) {
+                failed.report.run_id = run_id;
+                failed.report.duration_ms = started.elapsed().as_millis() as u64;
+                failed.report.record_resources(&resources.status());
+                failed.report.stages = stages.timings();
+                failed.report.write_to_file(report_path)?;
+                info!("Run report written to {}", report_path);
+            }
+            return Err(e);
+        }
+    };
+    report.run_id = run_id;
+    report.duration_ms = started.elapsed().as_millis() as u64;
+    report.record_resources(&resources.status());
+    report.check_sla(&Sla {
+        max_duration: args.sla_max_duration,
+        min_throughput: args.sla_min_throughput,
+    });
 
-    // 4. Run untar
-    let tar_file = File::open(&args.tar)
-        .context(format!("Failed to open TAR file: {}", args.tar))?;
-    
-    processor.process_tar(tar_file).await?;
+    if let Some(report_path) = &args.report {
+        report.write_to_file(report_path)?;
+        info!("Run report written to {}", report_path);
+    }
 
+    if let Some(throttle) = &throttle {
+        println!(
+            "Simulated {} bytes at {}/s: projected run time {}s",
+            report.bytes_written,
+            throttle.bytes_per_sec(),
+            report.duration_ms / 1000
+        );
+    }
+
+    for stage in &report.stages {
+        println!(
+            "{:<10} {:>15} bytes in {:>9} ms: {:>10.2} MB/s",
+            stage.name, stage.bytes, stage.busy_ms, stage.mb_per_sec
+        );
+    }
+
+    if !report.sla_breaches.is_empty() {
+        println!("All files processed and verified, but the SLA was breached.");
+        return Err(SlaBreached(report.sla_breaches).into());
+    }
     println!("Success! All files processed and verified.");
     Ok(())
 }
+
+/// Aborts a run's background tasks when dropped, so they stop with it even
+/// if it panics.
+struct BackgroundTasks(Vec<tokio::task::JoinHandle<()>>);
+
+impl Drop for BackgroundTasks {
+    fn drop(&mut self) {
+        for task in &self.0 {
+            task.abort();
+        }
+    }
+}
+
+/// Parses and validates the arguments of every job in the `--jobs` file.
+fn load_batch(path: &Path) -> Result<Vec<(String, Args)>> {
+    schedule::load_jobs(path)?
+        .into_iter()
+        .map(|job| {
+            let cli = Cli::try_parse_from(std::iter::once("untar".to_string()).chain(job.args))
+                .map_err(|e| anyhow!("Invalid arguments for job {}: {}", job.name, e.render()))?;
+            let mut args = match (cli.command, cli.run) {
+                (None, Some(args)) => args,
+                _ => bail!("Job {} must be a plain run, not a subcommand", job.name),
+            };
+            args.sla_max_duration = args.sla_max_duration.or(job.sla.max_duration);
+            args.sla_min_throughput = args.sla_min_throughput.or(job.sla.min_throughput);
+            validate(&args).with_context(|| format!("Invalid job {}", job.name))?;
+            Ok((job.name, args))
+        })
+        .collect()
+}
+
+async fn schedule(args: ScheduleArgs) -> Result<()> {
+    let cron = CronSchedule::parse(&args.cron)?;
+    let jobs = load_batch(&args.jobs)?;
+    info!("Scheduled {} job(s) from {} at '{}'", jobs.len(), args.jobs.display(), args.cron);
+    if let Some(dir) = &args.receipts_dir {
+        std::fs::create_dir_all(dir).with_context(|| format!("Failed to create {}", dir.display()))?;
+    }
+
+    let mut last = time::OffsetDateTime::now_utc().to_offset(local_offset());
+    loop {
+        let next = cron.next_after(last).context("Cron expression never fires")?;
+        info!("Next batch at {}", next);
+        let now = time::OffsetDateTime::now_utc().to_offset(local_offset());
+        if let Ok(wait) = Duration::try_from(next - now) {
+            tokio::time::sleep(wait).await;
+        }
+
+        // Batches run one at a time: fire times that pass while one runs are skipped.
+        run_batch(&args, next).await;
+        let finished = time::OffsetDateTime::now_utc().to_offset(local_offset());
+        let mut missed = 0;
+        last = next;
+        while let Some(due) = cron.next_after(last)
+            && due <= finished
+        {
+            missed += 1;
+            last = due;
+        }
+        if missed > 0 {
+            warn!("Batch started at {} ran past {} later start time(s), skipping them", next, missed);
+        }
+    }
+}
+
+/// Runs every job of the batch in order; a failed or panicking job is
+/// logged and the next one still runs. Jobs share this process, so each
+/// stops the background tasks it started as it ends.
+async fn run_batch(args: &ScheduleArgs, started: time::OffsetDateTime) {
+    let jobs = match load_batch(&args.jobs) {
+        Ok(jobs) => jobs,
+        Err(e) => {
+            error!("Skipping batch at {}: {:#}", started, e);
+            return;
+        }
+    };
+    let stamp = started
+        .format(time::macros::format_description!("[year][month][day]T[hour][minute]"))
+        .unwrap_or_default();
+    let mut failed = 0;
+    let mut breached = 0;
+    for (name, mut job) in jobs {
+        if let Some(dir) = &args.receipts_dir
+            && job.report.is_none()
+        {
+            job.report = Some(dir.join(format!("{}-{}.xml", name, stamp)).to_string_lossy().to_string());
+        }
+        info!("Starting job {}", name);
+        match catch_panics(run(job)).await {
+            Ok(()) => info!("Job {} finished", name),
+            Err(e) if e.is::<SlaBreached>() => {
+                breached += 1;
+                warn!(target: "untar::sla", "Job {} finished but breached its SLA: {:#}", name, e);
+            }
+            Err(e) => {
+                failed += 1;
+                error!("Job {} failed: {:#}", name, e);
+            }
+        }
+    }
+    if failed > 0 || breached > 0 {
+        warn!(
+            "Batch started at {} finished with {} failed job(s) and {} SLA breach(es)",
+            started, failed, breached
+        );
+    } else {
+        info!("Batch started at {} finished", started);
+    }
+}
+
+async fn extract_one(args: ExtractOneArgs) -> Result<()> {
+    let config = Config::from_xml_file(&args.xml).context("Failed to load XML manifest")?;
+
+    let (sink, out_dir) = match args.out.split_once("://") {
+        Some((scheme, rest)) => {
+            let (authority, path) = rest.split_once('/').unwrap_or((rest, ""));
+            let url = format!("{}://{}", scheme, authority);
+            let client = namenode::connect(Some(&url), &ConnectOptions::default()).await?;
+            (Sink::Hdfs(Arc::new(client)), format!("/{}", path.trim_end_matches('/')))
+        }
+        None => (Sink::Local, args.out.trim_end_matches('/').to_string()),
+    };
+
+    let mut source = open_tar(&args.tar, args.keep_top_dir, &config)?;
+    let offset = archive::find_entry(&source.reader, &args.entry, source.top_dir.as_deref())?
+        .with_context(|| format!("Entry {} not found in {}", args.entry, args.tar))?;
+    source.reader.seek(SeekFrom::Start(offset)).context("Failed to seek TAR file")?;
+
+    let processor = Processor::new(sink, config, out_dir, args.xml);
+    let report = processor.extract_one(source, &args.entry).await?;
+    for file in &report.files {
+        println!("Restored {} to {} ({} bytes, sha256 {})", file.entry, file.target, file.size, file.sha256);
+    }
+    Ok(())
+}
+
+async fn test(args: TestArgs) -> Result<()> {
+    let started = Instant::now();
+    let mut problems = Vec::new();
+    args.extraction.check(&mut problems);
+    invalid_if_any(&problems)?;
+    let config = Config::from_xml_file(&args.xml).context("Failed to load XML manifest")?;
+    let sources = expand_tars(&args.tar)?
+        .iter()
+        .map(|path| open_tar(path, args.extraction.keep_top_dir, &config))
+        .collect::<Result<Vec<_>>>()?;
+
+    // The null sink discards the data, and the manifest receipt, once verified.
+    // Nothing is written, so decompressed data is passed on as it is read.
+    let read_buffer_size = args.extraction.read_buffer_size as usize;
+    let processor = args.extraction.configure(Processor::new(Sink::Null(None), config, String::new(), args.xml))?
+        .with_buffer_sizes(read_buffer_size, ChunkSizing::fixed(read_buffer_size));
+    let mut report = match processor.process_tars(sources).await {
+        Ok(report) => report,
+        Err(mut e) => {
+            if let (Some(failed), Some(report_path)) = (e.downcast_mut::<RunFailed>(), &args.report) {
+                failed.report.duration_ms = started.elapsed().as_millis() as u64;
+                failed.report.write_to_file(report_path)?;
+            }
+            return Err(e);
+        }
+    };
+    report.duration_ms = started.elapsed().as_millis() as u64;
+    if let Some(report_path) = &args.report {
+        report.write_to_file(report_path)?;
+    }
+
+    for file in &report.files {
+        println!("OK {} ({} bytes, sha256 {})", file.entry, file.size, file.sha256);
+    }
+    println!("All {} files decompressed and verified against the manifest.", report.files.len());
+    Ok(())
+}
+
+/// `--tar` values with a `*`, `?` or `[...]` in their file name replaced by
+/// the files they match, in name order; other values are kept as given.
+fn expand_tars(patterns: &[String]) -> Result<Vec<String>> {
+    let mut paths = Vec::new();
+    for pattern in patterns {
+        let path = Path::new(pattern);
+        let name = path.file_name().map(|name| name.to_string_lossy()).unwrap_or_default();
+        if !name.contains(['*', '?', '[']) {
+            paths.push(pattern.clone());
+            continue;
+        }
+        let dir = path.parent().filter(|dir| !dir.as_os_str().is_empty());
+        let regex = archive::glob_regex(&name).with_context(|| format!("--tar {}: invalid pattern", pattern))?;
+        let mut matches = Vec::new();
+        let entries = std::fs::read_dir(dir.unwrap_or(Path::new(".")))
+            .with_context(|| format!("--tar {}: cannot list its directory", pattern))?;
+        for entry in entries {
+            let entry = entry.with_context(|| format!("--tar {}: cannot list its directory", pattern))?;
+            let file_name = entry.file_name().to_string_lossy().to_string();
+            // Like a shell, only a pattern starting with a dot matches hidden files.
+            if (file_name.starts_with('.') && !name.starts_with('.')) || !regex.is_match(&file_name) {
+                continue;
+            }
+            if entry.file_type().is_ok_and(|file_type| !file_type.is_dir()) {
+                matches.push(match dir {
+                    Some(dir) => dir.join(&file_name).to_string_lossy().to_string(),
+                    None => file_name,
+                });
+            }
+        }
+        if matches.is_empty() {
+            bail!("--tar {}: no files match", pattern);
+        }
+        matches.sort();
+        paths.extend(matches);
+    }
+    Ok(paths)
+}
+
+/// `source` read from a memory mapping of its file.
+fn map_tar(source: TarSource<File>) -> Result<TarSource<Cursor<archive::MappedFile>>> {
+    let mut reader = source.reader;
+    let mapped = archive::MappedFile::map(&reader).with_context(|| format!("Failed to map TAR file: {}", source.name))?;
+    // The whole file is mapped; keep reading from where the file was.
+    let mut cursor = Cursor::new(mapped);
+    cursor.set_position(reader.stream_position().with_context(|| format!("Failed to read position in {}", source.name))?);
+    Ok(TarSource { name: source.name, reader: cursor, top_dir: source.top_dir })
+}
+
+fn async_tar(source: TarSource<File>, chunk_size: usize) -> TarSource<archive::AsyncTarReader> {
+    TarSource { name: source.name, reader: archive::AsyncTarReader::open(source.reader, chunk_size), top_dir: source.top_dir }
+}
+
+fn open_tar(path: &str, keep_top_dir: bool, config: &Config) -> Result<TarSource<File>> {
+    let mut tar_file = File::open(path)
+        .context(format!("Failed to open TAR file: {}", path))?;
+
+    let mut top_dir = None;
+    if !keep_top_dir {
+        top_dir = archive::detect_top_dir(&tar_file)?;
+        tar_file.rewind().context("Failed to rewind TAR file")?;
+
+        if let Some(dir) = &top_dir {
+            let prefix = format!("{}/", dir);
+            let mut listed = false;
+            for name in config.file_map.keys() {
+                if name?.starts_with(&prefix) {
+                    listed = true;
+                    break;
+                }
+            }
+            if listed {
+                info!("Manifest already includes top-level directory {}, keeping it", dir);
+                top_dir = None;
+            } else {
+                info!("Stripping top-level directory {} from {}", dir, path);
+            }
+        }
+    }
+
+    Ok(TarSource { name: path.to_string(), reader: tar_file, top_dir })
+}
@@ -0,0 +1,128 @@
+use std::collections::HashMap;
+use std::sync::OnceLock;
+use anyhow::{anyhow, Result};
+use regex::Regex;
+
+use crate::config::Config;
+
+/// How tar entry names are looked up in the manifest.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, clap::ValueEnum)]
+pub enum MatchStrategy {
+    /// The entry path must equal the manifest path
+    Exact,
+    /// Ignore case, `./` and repeated or back slashes
+    Normalized,
+    /// Rewrite the entry path with --match-regex/--match-replace, then match exactly
+    Regex,
+    /// Match on the file name alone, if it is unique in the manifest
+    Basename,
+}
+
+/// Maps a tar entry to the manifest entry it delivers. The names matchers
+/// return are what the coverage check counts as delivered.
+pub trait ManifestMatcher: Send + Sync {
+    /// Manifest path for the entry `name` (compression suffix already
+    /// removed), if the manifest lists it.
+    fn find(&self, name: &str, manifest: &Config) -> Result<Option<String>>;
+}
+
+/// Builds the matcher for `strategy`; `regex` and `replace` are only used
+/// (and then required) by `MatchStrategy::Regex`.
+pub fn matcher(strategy: MatchStrategy, regex: Option<&str>, replace: &str) -> Result<Box<dyn ManifestMatcher>> {
+    Ok(match strategy {
+        MatchStrategy::Exact => Box::new(ExactMatcher),
+        MatchStrategy::Normalized => Box::new(NormalizedMatcher::default()),
+        MatchStrategy::Regex => {
+            let pattern = regex.ok_or_else(|| anyhow!("--match regex requires --match-regex"))?;
+            let regex = Regex::new(pattern).map_err(|e| anyhow!("Invalid --match-regex '{}': {}", pattern, e))?;
+            Box::new(RegexMatcher { regex, replacement: replace.to_string() })
+        }
+        MatchStrategy::Basename => Box::new(BasenameMatcher::default()),
+    })
+}
+
+pub struct ExactMatcher;
+
+impl ManifestMatcher for ExactMatcher {
+    fn find(&self, name: &str, manifest: &Config) -> Result<Option<String>> {
+        Ok(manifest.file_map.contains_key(name)?.then(|| name.to_string()))
+    }
+}
+
+/// Lower-cased, with `\` as `/` and empty and `.` components dropped.
+fn normalize(name: &str) -> String {
+    name.replace('\\', "/")
+        .split('/')
+        .filter(|component| !component.is_empty() && *component != ".")
+        .collect::<Vec<_>>()
+        .join("/")
+        .to_lowercase()
+}
+
+/// Compares normalized paths. Names that normalize alike in the manifest are
+/// ambiguous and only match exactly.
+#[derive(Default)]
+pub struct NormalizedMatcher {
+    /// Built on the first lookup that is not an exact match.
+    index: OnceLock<HashMap<String, Option<String>>>,
+}
+
+impl ManifestMatcher for NormalizedMatcher {
+    fn find(&self, name: &str, manifest: &Config) -> Result<Option<String>> {
+        if manifest.file_map.contains_key(name)? {
+            return Ok(Some(name.to_string()));
+        }
+        let index = match self.index.get() {
+            Some(index) => index,
+            None => {
+                let mut index: HashMap<String, Option<String>> = HashMap::new();
+                for filename in manifest.file_map.keys() {
+                    let filename = filename?;
+                    index
+                        .entry(normalize(&filename))
+                        .and_modify(|path| *path = None)
+                        .or_insert(Some(filename));
+                }
+                self.index.get_or_init(|| index)
+            }
+        };
+        Ok(index.get(&normalize(name)).cloned().flatten())
+    }
+}
+
+/// Rewrites entry paths matching `regex` (e.g. to drop a vendor prefix)
+/// before an exact lookup; other paths are looked up unchanged.
+pub struct RegexMatcher {
+    regex: Regex,
+    /// May refer to capture groups as `$1` or `${name}`.
+    replacement: String,
+}
+
+impl ManifestMatcher for RegexMatcher {
+    fn find(&self, name: &str, manifest: &Config) -> Result<Option<String>> {
+        let rewritten = self.regex.replace(name, self.replacement.as_str());
+        Ok(manifest.file_map.contains_key(&rewritten)?.then(|| rewritten.into_owned()))
+    }
+}
+
+/// Matches on the last path component, when exactly one manifest entry has
+/// that file name.
+#[derive(Default)]
+pub struct BasenameMatcher {
+    /// Built on first use.
+    paths_by_file_name: OnceLock<HashMap<String, Option<String>>>,
+}
+
+impl ManifestMatcher for BasenameMatcher {
+    fn find(&self, name: &str, manifest: &Config) -> Result<Option<String>> {
+        let file_name = name.rsplit('/').next().unwrap_or(name);
+        let paths = match self.paths_by_file_name.get() {
+            Some(paths) => paths,
+            None => {
+                let paths = manifest.paths_by_file_name()?;
+                self.paths_by_file_name.get_or_init(|| paths)
+            }
+        };
+        Ok(paths.get(file_name).cloned().flatten())
+    }
+}
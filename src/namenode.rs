@@ -0,0 +1,80 @@
+use std::net::{SocketAddr, ToSocketAddrs};
+use std::time::Duration;
+use anyhow::{anyhow, bail, Context, Result};
+use hdfs_native::client::{Client, ClientBuilder};
+use tracing::info;
+
+use crate::retry::with_retries;
+
+/// How the HDFS client is established at run start.
+#[derive(Debug, Clone)]
+pub struct ConnectOptions {
+    /// Retries of name resolution and the first NameNode round trip.
+    pub retries: u32,
+    /// First retry delay; doubles per attempt, with jitter.
+    pub backoff: Duration,
+    /// Resolve the NameNode host once and use the address for the whole run.
+    pub pin: bool,
+}
+
+impl Default for ConnectOptions {
+    fn default() -> Self {
+        Self { retries: 5, backoff: Duration::from_secs(1), pin: false }
+    }
+}
+
+/// Builds an HDFS client for `namenode` (or the site-xml defaults) and checks
+/// that the NameNode answers, retrying transient DNS/connection failures.
+pub async fn connect(namenode: Option<&str>, options: &ConnectOptions) -> Result<Client> {
+    let url = match (namenode, options.pin) {
+        (Some(url), true) => {
+            let (scheme, authority, path) = split_url(url)?;
+            let address = with_retries("Resolving NameNode", options.retries, options.backoff, || async {
+                resolve(authority)
+            })
+            .await?;
+            let pinned = format!("{}://{}{}", scheme, address, path);
+            info!("Pinned NameNode {} to {}", url, pinned);
+            Some(pinned)
+        }
+        (None, true) => bail!("--pin-namenode requires --namenode with a host:port URL"),
+        (url, false) => url.map(str::to_string),
+    };
+
+    with_retries("Connecting to HDFS", options.retries, options.backoff, || async {
+        let client = match &url {
+            Some(url) => ClientBuilder::new().with_url(url).build().context("Failed to create HDFS client")?,
+            None => ClientBuilder::new().build().context("Failed to create HDFS client from config")?,
+        };
+        // The client connects lazily; a cheap RPC surfaces DNS and connection errors now.
+        client
+            .get_file_info("/")
+            .await
+            .map_err(|e| anyhow!("Failed to reach the NameNode: {}", e))?;
+        Ok::<_, anyhow::Error>(client)
+    })
+    .await
+}
+
+/// Splits `scheme://host:port[/path]`; a nameservice without a port cannot be pinned.
+fn split_url(url: &str) -> Result<(&str, &str, &str)> {
+    let (scheme, rest) = url
+        .split_once("://")
+        .ok_or_else(|| anyhow!("NameNode URL {} has no scheme", url))?;
+    let (authority, path) = match rest.find('/') {
+        Some(i) => rest.split_at(i),
+        None => (rest, ""),
+    };
+    if authority.rsplit_once(':').is_none() {
+        bail!("NameNode URL {} has no port; pinning needs host:port, not a nameservice", url);
+    }
+    Ok((scheme, authority, path))
+}
+
+fn resolve(authority: &str) -> Result<SocketAddr> {
+    authority
+        .to_socket_addrs()
+        .map_err(|e| anyhow!("Failed to resolve {}: {}", authority, e))?
+        .next()
+        .ok_or_else(|| anyhow!("{} resolved to no addresses", authority))
+}
@@ -0,0 +1,79 @@
+use anyhow::{bail, Result};
+
+const VARIABLES: &[&str] = &["name", "stem", "ext", "run_id", "date"];
+
+/// Template for HDFS file names, e.g. `{stem}.{run_id}.{ext}`.
+///
+/// Variables: `{name}` (original file name), `{stem}` (name without its last
+/// extension), `{ext}` (last extension, empty if none), `{run_id}` and
+/// `{date}` (run start date in the system timezone, `YYYYMMDD`). The entry's
+/// directory is kept as is.
+#[derive(Debug, Clone)]
+pub struct NameTemplate {
+    template: String,
+}
+
+/// Per-run values available to a name template.
+#[derive(Debug, Clone, Default)]
+pub struct TemplateContext {
+    pub run_id: String,
+    /// `YYYYMMDD`, in the timezone the caller picks.
+    pub date: String,
+}
+
+impl NameTemplate {
+    pub fn parse(template: &str) -> Result<Self> {
+        let mut rest = template;
+        let mut per_file = false;
+        while let Some(start) = rest.find('{') {
+            let Some(len) = rest[start..].find('}') else {
+                bail!("Unclosed '{{' in name template '{}'", template);
+            };
+            let variable = &rest[start + 1..start + len];
+            if !VARIABLES.contains(&variable) {
+                bail!(
+                    "Unknown variable {{{}}} in name template '{}' (expected one of {})",
+                    variable,
+                    template,
+                    VARIABLES.join(", ")
+                );
+            }
+            per_file |= variable == "name" || variable == "stem";
+            rest = &rest[start + len + 1..];
+        }
+        if !per_file {
+            bail!(
+                "Name template '{}' contains neither {{name}} nor {{stem}}, so every file would get the same name",
+                template
+            );
+        }
+        Ok(Self { template: template.to_string() })
+    }
+
+    /// Applies the template to the file name part of `target_name`.
+    pub fn render(&self, target_name: &str, context: &TemplateContext) -> String {
+        let (dir, name) = match target_name.rsplit_once('/') {
+            Some((dir, name)) => (Some(dir), name),
+            None => (None, target_name),
+        };
+        let (stem, ext) = match name.rsplit_once('.') {
+            Some((stem, ext)) if !stem.is_empty() => (stem, ext),
+            _ => (name, ""),
+        };
+
+        let rendered = self
+            .template
+            .replace("{name}", name)
+            .replace("{stem}", stem)
+            .replace("{ext}", ext)
+            .replace("{run_id}", &context.run_id)
+            .replace("{date}", &context.date);
+        // A template like `{stem}.{ext}` on a file without extension leaves a dangling dot.
+        let rendered = rendered.trim_end_matches('.');
+
+        match dir {
+            Some(dir) => format!("{}/{}", dir, rendered),
+            None => rendered.to_string(),
+        }
+    }
+}
@@ -0,0 +1,59 @@
+//! Turns panics in worker tasks into errors that carry their backtrace.
+
+use std::any::Any;
+use std::backtrace::Backtrace;
+use std::cell::RefCell;
+use std::future::Future;
+use std::panic::AssertUnwindSafe;
+use std::sync::Once;
+use anyhow::Result;
+use futures_util::FutureExt;
+
+thread_local! {
+    /// Backtrace of the last panic on this thread, captured by the hook.
+    static LAST_BACKTRACE: RefCell<Option<String>> = const { RefCell::new(None) };
+}
+
+static INSTALL_HOOK: Once = Once::new();
+
+#[derive(Debug, thiserror::Error)]
+#[error("worker panicked: {message}")]
+pub struct WorkerPanic {
+    pub message: String,
+    pub backtrace: String,
+}
+
+/// Records a backtrace for every panic, then runs the previous hook.
+fn install_hook() {
+    INSTALL_HOOK.call_once(|| {
+        let previous = std::panic::take_hook();
+        std::panic::set_hook(Box::new(move |info| {
+            let backtrace = Backtrace::force_capture().to_string();
+            LAST_BACKTRACE.with(|last| *last.borrow_mut() = Some(backtrace));
+            previous(info);
+        }));
+    });
+}
+
+fn panic_message(payload: &(dyn Any + Send)) -> String {
+    if let Some(message) = payload.downcast_ref::<&str>() {
+        message.to_string()
+    } else if let Some(message) = payload.downcast_ref::<String>() {
+        message.clone()
+    } else {
+        "non-string panic payload".to_string()
+    }
+}
+
+/// Runs `future`, converting a panic into a `WorkerPanic` error.
+pub async fn catch_panics<T, F: Future<Output = Result<T>>>(future: F) -> Result<T> {
+    install_hook();
+    match AssertUnwindSafe(future).catch_unwind().await {
+        Ok(result) => result,
+        Err(payload) => Err(WorkerPanic {
+            message: panic_message(payload.as_ref()),
+            backtrace: LAST_BACKTRACE.with(|last| last.borrow_mut().take()).unwrap_or_default(),
+        }
+        .into()),
+    }
+}
@@ -0,0 +1,247 @@
+use std::io::{self, Cursor, Read};
+use std::sync::mpsc::{self, Receiver, SyncSender, TryRecvError, TrySendError};
+use std::sync::{Arc, Mutex};
+use std::thread;
+
+use crate::decompress::GzipDecoder;
+
+/// Compressed bytes handed to the background threads at a time.
+const INPUT_CHUNK: usize = 256 * 1024;
+/// Decompressed bytes per block of sequentially inflated output.
+const OUTPUT_BLOCK: usize = 256 * 1024;
+/// Fixed header, XLEN and the `BC` subfield of a BGZF block.
+const BGZF_HEADER_LEN: usize = 18;
+
+/// Decompressed output in stream order; filled by whichever thread inflates it.
+type Block = Receiver<io::Result<Vec<u8>>>;
+type Job = (Vec<u8>, SyncSender<io::Result<Vec<u8>>>);
+
+/// Gzip decoder that inflates on background threads while the caller keeps
+/// reading (and hashing) the compressed input.
+///
+/// Members that record their own compressed size, as BGZF files written by
+/// bgzip do, are inflated in parallel on `threads` workers. Any other gzip
+/// data is inflated on one background thread, in order. CRCs and sizes are
+/// checked either way.
+pub struct ParallelGzipDecoder<R: Read> {
+    inner: R,
+    input: Option<SyncSender<Vec<u8>>>,
+    /// Input read but not yet accepted by the background threads.
+    pending: Option<Vec<u8>>,
+    blocks: Receiver<Block>,
+    block: Vec<u8>,
+    pos: usize,
+}
+
+impl<R: Read> ParallelGzipDecoder<R> {
+    pub fn new(inner: R, threads: usize) -> Self {
+        let threads = threads.max(1);
+        let (input, input_rx) = mpsc::sync_channel(4);
+        let (blocks_tx, blocks) = mpsc::sync_channel(threads * 2);
+        thread::spawn(move || dispatch(ChannelReader::new(input_rx), threads, blocks_tx));
+        Self { inner, input: Some(input), pending: None, blocks, block: Vec::new(), pos: 0 }
+    }
+
+    /// Hands the next chunk of input to the background threads; false once
+    /// the input is exhausted or they cannot take more right now.
+    fn feed(&mut self) -> io::Result<bool> {
+        let Some(input) = &self.input else {
+            return Ok(false);
+        };
+        let chunk = match self.pending.take() {
+            Some(chunk) => chunk,
+            None => {
+                let mut chunk = vec![0u8; INPUT_CHUNK];
+                let n = self.inner.read(&mut chunk)?;
+                if n == 0 {
+                    // Closing the channel marks the end of the input.
+                    self.input = None;
+                    return Ok(false);
+                }
+                chunk.truncate(n);
+                chunk
+            }
+        };
+        match input.try_send(chunk) {
+            Ok(()) => Ok(true),
+            Err(TrySendError::Full(chunk)) => {
+                self.pending = Some(chunk);
+                Ok(false)
+            }
+            Err(TrySendError::Disconnected(_)) => {
+                self.input = None;
+                Ok(false)
+            }
+        }
+    }
+}
+
+impl<R: Read> Read for ParallelGzipDecoder<R> {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        if buf.is_empty() {
+            return Ok(0);
+        }
+        loop {
+            if self.pos < self.block.len() {
+                let n = buf.len().min(self.block.len() - self.pos);
+                buf[..n].copy_from_slice(&self.block[self.pos..self.pos + n]);
+                self.pos += n;
+                return Ok(n);
+            }
+            let next = match self.blocks.try_recv() {
+                Ok(next) => next,
+                Err(TryRecvError::Disconnected) => return Ok(0),
+                Err(TryRecvError::Empty) => {
+                    if self.feed()? {
+                        continue;
+                    }
+                    // The background threads have all the input they can take.
+                    match self.blocks.recv() {
+                        Ok(next) => next,
+                        Err(_) => return Ok(0),
+                    }
+                }
+            };
+            self.block = next
+                .recv()
+                .map_err(|_| io::Error::other("gzip worker thread exited"))??;
+            self.pos = 0;
+        }
+    }
+}
+
+/// Reads the chunks sent by `ParallelGzipDecoder::feed`.
+struct ChannelReader {
+    chunks: Receiver<Vec<u8>>,
+    chunk: Vec<u8>,
+    pos: usize,
+}
+
+impl ChannelReader {
+    fn new(chunks: Receiver<Vec<u8>>) -> Self {
+        Self { chunks, chunk: Vec::new(), pos: 0 }
+    }
+}
+
+impl Read for ChannelReader {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        while self.pos == self.chunk.len() {
+            match self.chunks.recv() {
+                Ok(chunk) => {
+                    self.chunk = chunk;
+                    self.pos = 0;
+                }
+                Err(_) => return Ok(0),
+            }
+        }
+        let n = buf.len().min(self.chunk.len() - self.pos);
+        buf[..n].copy_from_slice(&self.chunk[self.pos..self.pos + n]);
+        self.pos += n;
+        Ok(n)
+    }
+}
+
+/// Fills `buf` unless the input ends first; returns how much was read.
+fn read_full(reader: &mut impl Read, buf: &mut [u8]) -> io::Result<usize> {
+    let mut filled = 0;
+    while filled < buf.len() {
+        match reader.read(&mut buf[filled..])? {
+            0 => break,
+            n => filled += n,
+        }
+    }
+    Ok(filled)
+}
+
+/// Total size of the member starting with `head`, if it is a BGZF block.
+fn bgzf_block_size(head: &[u8]) -> Option<usize> {
+    let is_bgzf = head.len() >= BGZF_HEADER_LEN
+        && head[..3] == [0x1f, 0x8b, 8]
+        && head[3] & 0x04 != 0
+        && head[10..16] == [6, 0, b'B', b'C', 2, 0];
+    is_bgzf.then(|| u16::from_le_bytes([head[16], head[17]]) as usize + 1)
+}
+
+fn ready(result: io::Result<Vec<u8>>) -> Block {
+    let (tx, rx) = mpsc::sync_channel(1);
+    let _ = tx.send(result);
+    rx
+}
+
+/// Splits the input into members, queueing BGZF blocks for the workers and
+/// inflating anything else itself. Stops when the decoder is dropped.
+fn dispatch(mut input: ChannelReader, threads: usize, blocks: SyncSender<Block>) {
+    let (jobs, job_rx) = mpsc::sync_channel::<Job>(threads * 2);
+    let job_rx = Arc::new(Mutex::new(job_rx));
+    for _ in 0..threads {
+        let job_rx = job_rx.clone();
+        thread::spawn(move || inflate_members(job_rx));
+    }
+
+    let mut members = 0u64;
+    loop {
+        let mut head = vec![0u8; BGZF_HEADER_LEN];
+        let n = match read_full(&mut input, &mut head) {
+            Ok(n) => n,
+            Err(e) => {
+                let _ = blocks.send(ready(Err(e)));
+                return;
+            }
+        };
+        if n == 0 && members > 0 {
+            return;
+        }
+        head.truncate(n);
+
+        match bgzf_block_size(&head) {
+            Some(size) if size >= BGZF_HEADER_LEN => {
+                let mut member = head;
+                member.resize(size, 0);
+                match read_full(&mut input, &mut member[BGZF_HEADER_LEN..]) {
+                    Ok(n) if n == size - BGZF_HEADER_LEN => {}
+                    Ok(_) => {
+                        let truncated = io::Error::new(io::ErrorKind::UnexpectedEof, "truncated BGZF block");
+                        let _ = blocks.send(ready(Err(truncated)));
+                        return;
+                    }
+                    Err(e) => {
+                        let _ = blocks.send(ready(Err(e)));
+                        return;
+                    }
+                }
+                let (result, block) = mpsc::sync_channel(1);
+                if jobs.send((member, result)).is_err() || blocks.send(block).is_err() {
+                    return;
+                }
+                members += 1;
+            }
+            _ => {
+                // Not BGZF: inflate the rest of the stream here, in order.
+                let mut decoder = GzipDecoder::new(Cursor::new(head).chain(input)).after_members(members);
+                loop {
+                    let mut block = vec![0u8; OUTPUT_BLOCK];
+                    let result = read_full(&mut decoder, &mut block).map(|n| {
+                        block.truncate(n);
+                        block
+                    });
+                    let end = !matches!(&result, Ok(block) if !block.is_empty());
+                    if blocks.send(ready(result)).is_err() || end {
+                        return;
+                    }
+                }
+            }
+        }
+    }
+}
+
+fn inflate_members(jobs: Arc<Mutex<Receiver<Job>>>) {
+    loop {
+        let job = jobs.lock().unwrap().recv();
+        let Ok((member, result)) = job else {
+            return;
+        };
+        let mut out = Vec::new();
+        let inflated = GzipDecoder::new(&member[..]).read_to_end(&mut out).map(|_| out);
+        let _ = result.send(inflated);
+    }
+}
@@ -0,0 +1,75 @@
+use crate::sink::PathStatus;
+
+/// What a delivery would do to one destination path.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum PlanAction {
+    Create,
+    /// An existing file of a different size would be replaced.
+    Overwrite { existing: u64 },
+    /// A file of the expected size is already there.
+    SkipIdentical,
+    /// A directory is in the way.
+    Conflict,
+    /// Moved to the trash by a manifest `<deleted-file>` with `--apply-deletes`.
+    Delete,
+}
+
+impl PlanAction {
+    /// Compares a file this delivery writes with what is at its path now.
+    /// Only sizes are compared; reading the data back is left to the audit.
+    pub fn for_write(size: u64, existing: Option<PathStatus>) -> Self {
+        match existing {
+            None => PlanAction::Create,
+            Some(status) if status.is_dir => PlanAction::Conflict,
+            Some(status) if status.len == size => PlanAction::SkipIdentical,
+            Some(status) => PlanAction::Overwrite { existing: status.len },
+        }
+    }
+}
+
+#[derive(Debug)]
+pub struct PlannedFile {
+    pub path: String,
+    /// Manifest size, or the existing size for deletes.
+    pub size: u64,
+    pub action: PlanAction,
+}
+
+/// Result of `--dry-run`: every destination path the delivery touches.
+#[derive(Debug, Default)]
+pub struct Plan {
+    pub files: Vec<PlannedFile>,
+}
+
+impl Plan {
+    fn count(&self, matches: impl Fn(&PlanAction) -> bool) -> usize {
+        self.files.iter().filter(|file| matches(&file.action)).count()
+    }
+
+    pub fn conflicts(&self) -> usize {
+        self.count(|action| *action == PlanAction::Conflict)
+    }
+
+    /// Prints a `git status`-like listing; identical files are only counted.
+    pub fn print(&self) {
+        println!(
+            "Dry run: {} to create, {} to overwrite, {} identical, {} conflicting, {} to delete",
+            self.count(|action| *action == PlanAction::Create),
+            self.count(|action| matches!(action, PlanAction::Overwrite { .. })),
+            self.count(|action| *action == PlanAction::SkipIdentical),
+            self.conflicts(),
+            self.count(|action| *action == PlanAction::Delete),
+        );
+        for file in &self.files {
+            match file.action {
+                PlanAction::Create => println!("  A {} ({} bytes)", file.path, file.size),
+                PlanAction::Overwrite { existing } => {
+                    println!("  M {} ({} -> {} bytes)", file.path, existing, file.size)
+                }
+                PlanAction::SkipIdentical => {}
+                PlanAction::Conflict => println!("  U {} (a directory is in the way)", file.path),
+                PlanAction::Delete => println!("  D {} ({} bytes)", file.path, file.size),
+            }
+        }
+    }
+}
@@ -0,0 +1,95 @@
+//! Experimental per-entry transformation stage backed by a WASM module.
+//!
+//! A plugin module must export:
+//! - `memory`
+//! - `alloc(len: i32) -> i32`: returns a buffer the host copies each input chunk into
+//! - `transform(ptr: i32, len: i32) -> i64`: transforms one decompressed chunk and
+//!   returns the output location packed as `(out_ptr << 32) | out_len`
+//!
+//! and may export `finish() -> i64` to flush buffered output at the end of an entry.
+//! Every tar entry gets a fresh instance, so plugins may keep per-entry state.
+
+use std::path::Path;
+use anyhow::{anyhow, Context, Result};
+use wasmtime::{Engine, Instance, Memory, Module, Store, TypedFunc};
+
+pub struct Plugin {
+    engine: Engine,
+    module: Module,
+}
+
+impl Plugin {
+    pub fn load<P: AsRef<Path>>(path: P) -> Result<Self> {
+        let path = path.as_ref();
+        let engine = Engine::default();
+        let module = Module::from_file(&engine, path)
+            .map_err(|e| anyhow!("Failed to load WASM plugin {}: {}", path.display(), e))?;
+        Ok(Self { engine, module })
+    }
+
+    pub fn instantiate(&self) -> Result<PluginInstance> {
+        let mut store = Store::new(&self.engine, ());
+        let instance = Instance::new(&mut store, &self.module, &[])
+            .map_err(|e| anyhow!("Failed to instantiate WASM plugin: {}", e))?;
+
+        let memory = instance
+            .get_memory(&mut store, "memory")
+            .context("WASM plugin does not export `memory`")?;
+        let alloc = instance
+            .get_typed_func(&mut store, "alloc")
+            .map_err(|e| anyhow!("WASM plugin `alloc` export: {}", e))?;
+        let transform = instance
+            .get_typed_func(&mut store, "transform")
+            .map_err(|e| anyhow!("WASM plugin `transform` export: {}", e))?;
+        let finish = instance.get_typed_func(&mut store, "finish").ok();
+
+        Ok(PluginInstance { store, memory, alloc, transform, finish })
+    }
+}
+
+pub struct PluginInstance {
+    store: Store<()>,
+    memory: Memory,
+    alloc: TypedFunc<i32, i32>,
+    transform: TypedFunc<(i32, i32), i64>,
+    finish: Option<TypedFunc<(), i64>>,
+}
+
+impl PluginInstance {
+    pub fn transform(&mut self, chunk: &[u8]) -> Result<Vec<u8>> {
+        let len = i32::try_from(chunk.len()).context("Chunk too large for WASM plugin")?;
+        let ptr = self
+            .alloc
+            .call(&mut self.store, len)
+            .map_err(|e| anyhow!("WASM plugin alloc failed: {}", e))?;
+        self.memory
+            .write(&mut self.store, ptr as u32 as usize, chunk)
+            .map_err(|e| anyhow!("WASM plugin returned an invalid buffer: {}", e))?;
+
+        let packed = self
+            .transform
+            .call(&mut self.store, (ptr, len))
+            .map_err(|e| anyhow!("WASM plugin transform failed: {}", e))?;
+        self.read_output(packed)
+    }
+
+    pub fn finish(&mut self) -> Result<Vec<u8>> {
+        let Some(finish) = &self.finish else {
+            return Ok(Vec::new());
+        };
+        let packed = finish
+            .call(&mut self.store, ())
+            .map_err(|e| anyhow!("WASM plugin finish failed: {}", e))?;
+        self.read_output(packed)
+    }
+
+    fn read_output(&mut self, packed: i64) -> Result<Vec<u8>> {
+        let ptr = (packed as u64 >> 32) as usize;
+        let len = (packed as u64 & 0xffff_ffff) as usize;
+        let mut out = vec![0u8; len];
+        self.memory
+            .read(&self.store, ptr, &mut out)
+            .map_err(|e| anyhow!("WASM plugin returned an invalid output range: {}", e))?;
+        Ok(out)
+    }
+}
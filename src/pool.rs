@@ -0,0 +1,139 @@
+use std::ops::{Deref, DerefMut};
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::{Arc, Mutex};
+use bytes::Bytes;
+use tokio::sync::{OwnedSemaphorePermit, Semaphore};
+
+/// Chunk buffers shared by every decode of a run. At most `max_buffers`
+/// exist at once, so decompressed data in flight stays bounded, and each
+/// one is reused once the upload is done with it.
+pub struct BufferPool {
+    free: Mutex<Vec<Idle>>,
+    slots: Arc<Semaphore>,
+    /// One permit per byte the buffers may take, idle ones included.
+    memory: Option<(Arc<Semaphore>, usize)>,
+    /// Gets waiting for memory; idle buffers are freed rather than kept for them.
+    waiting: AtomicUsize,
+}
+
+/// Counts a get as waiting until it is done or cancelled.
+struct Waiting<'a>(&'a AtomicUsize);
+
+impl<'a> Waiting<'a> {
+    fn new(waiting: &'a AtomicUsize) -> Self {
+        waiting.fetch_add(1, Ordering::SeqCst);
+        Self(waiting)
+    }
+}
+
+impl Drop for Waiting<'_> {
+    fn drop(&mut self) {
+        self.0.fetch_sub(1, Ordering::SeqCst);
+    }
+}
+
+struct Idle {
+    data: Vec<u8>,
+    memory: Option<OwnedSemaphorePermit>,
+}
+
+impl BufferPool {
+    pub fn new(max_buffers: usize) -> Self {
+        Self {
+            free: Mutex::new(Vec::new()),
+            slots: Arc::new(Semaphore::new(max_buffers)),
+            memory: None,
+            waiting: AtomicUsize::new(0),
+        }
+    }
+
+    /// Also keep the buffers within `max_bytes` in total. A buffer larger
+    /// than that takes the whole budget.
+    pub fn with_max_bytes(mut self, max_bytes: Option<usize>) -> Self {
+        self.memory = max_bytes.map(|bytes| (Arc::new(Semaphore::new(bytes)), bytes));
+        self
+    }
+
+    /// A buffer of `size` bytes, waiting while every buffer (or all of the
+    /// memory) is in use.
+    pub async fn get(self: &Arc<Self>, size: usize) -> PooledBuffer {
+        let slot = self.slots.clone().acquire_owned().await.expect("buffer pool is never closed");
+        // Under a memory budget an idle buffer must already be big enough,
+        // since its memory was reserved for its capacity.
+        let idle = self.free.lock().unwrap().pop().filter(|idle| self.memory.is_none() || idle.data.capacity() >= size);
+        let (mut data, memory) = match idle {
+            Some(idle) => (idle.data, idle.memory),
+            None => (Vec::new(), self.reserve(size).await),
+        };
+        data.resize(size, 0);
+        PooledBuffer { data, memory, pool: self.clone(), _slot: slot }
+    }
+
+    async fn reserve(&self, size: usize) -> Option<OwnedSemaphorePermit> {
+        let (memory, max_bytes) = self.memory.as_ref()?;
+        let permits = size.min(*max_bytes) as u32;
+        if let Ok(permit) = memory.clone().try_acquire_many_owned(permits) {
+            return Some(permit);
+        }
+        // Idle buffers give their memory back before waiting for busy ones.
+        let _waiting = Waiting::new(&self.waiting);
+        self.free.lock().unwrap().clear();
+        Some(memory.clone().acquire_many_owned(permits).await.expect("buffer pool is never closed"))
+    }
+
+    /// Buffers that may still be handed out without waiting.
+    pub fn available(&self) -> usize {
+        self.slots.available_permits()
+    }
+}
+
+pub struct PooledBuffer {
+    data: Vec<u8>,
+    memory: Option<OwnedSemaphorePermit>,
+    pool: Arc<BufferPool>,
+    _slot: OwnedSemaphorePermit,
+}
+
+impl PooledBuffer {
+    /// Hands on the first `len` bytes without copying them; the buffer goes
+    /// back to the pool when the last clone of the `Bytes` is dropped.
+    pub fn freeze(self, len: usize) -> Bytes {
+        Bytes::from_owner(Filled { buffer: self, len })
+    }
+}
+
+impl Deref for PooledBuffer {
+    type Target = [u8];
+
+    fn deref(&self) -> &[u8] {
+        &self.data
+    }
+}
+
+impl DerefMut for PooledBuffer {
+    fn deref_mut(&mut self) -> &mut [u8] {
+        &mut self.data
+    }
+}
+
+impl Drop for PooledBuffer {
+    fn drop(&mut self) {
+        // Back on the free list before the slot is released to a waiter.
+        let idle = Idle { data: std::mem::take(&mut self.data), memory: self.memory.take() };
+        let mut free = self.pool.free.lock().unwrap();
+        if self.pool.waiting.load(Ordering::SeqCst) == 0 {
+            free.push(idle);
+        }
+    }
+}
+
+struct Filled {
+    buffer: PooledBuffer,
+    len: usize,
+}
+
+impl AsRef<[u8]> for Filled {
+    fn as_ref(&self) -> &[u8] {
+        &self.buffer[..self.len]
+    }
+}
@@ -1,156 +1,3254 @@
-use std::io::Read;
-use std::sync::Arc;
+use std::borrow::Cow;
+use std::collections::{HashMap, HashSet, VecDeque};
+use std::fs::File;
+use std::future::Future;
+use std::io::{self, BufReader, Cursor, Read, Seek, SeekFrom};
+use std::sync::mpsc::{channel, Receiver};
+use std::pin::Pin;
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
 use anyhow::{anyhow, Context, Result};
 use bytes::Bytes;
-use hdfs_native::client::{Client, WriteOptions};
+use clap::ValueEnum;
+use futures_util::{stream, StreamExt, TryStreamExt};
+use hdfs_native::client::WriteOptions;
+use sha2::{Digest, Sha256};
 use tar::Archive;
-use tokio::sync::mpsc;
-use tracing::{info, warn, error};
+use tokio::runtime::{Handle, RuntimeFlavor};
+use tokio::sync::{Notify, OwnedSemaphorePermit, Semaphore};
+use tokio::task::{AbortHandle, JoinHandle};
+use tracing::{info, warn, error, Instrument};
+use zstd::dict::DecoderDictionary;
 
-use crate::config::Config;
-use crate::decompress::{get_format, wrap_decoder};
+use crate::archive::{
+    count_non_zero, index_entries, is_garbage_header, strip_top_dir, CountingReader, EntryReader, LocalTar, MappedFile,
+};
+use crate::compress::{Recompression, Recompressor};
+use crate::config::{self, Config};
+use crate::control::{Control, DeadlinePassed, RunState};
+use crate::decompress::{
+    DecoderOptions, DecompressionFormat, DEFAULT_BUFFER_SIZE, ExternalDecoder, ExternalDecompressor, FormatMap, sniff_format,
+    wrap_decoder_with, MAGIC_LEN,
+};
+use crate::digest::{FileChecksum, HashingReader, HdfsChecksum};
+use crate::journal::{Journal, Recorded};
+use crate::lease::{Claim, Leases};
+use crate::matcher::{BasenameMatcher, ExactMatcher, ManifestMatcher};
+use crate::naming::{NameTemplate, TemplateContext};
+use crate::panics::{catch_panics, WorkerPanic};
+use crate::parallel_gzip::ParallelGzipDecoder;
+use crate::plan::{Plan, PlanAction, PlannedFile};
+use crate::pool::BufferPool;
+use crate::report::{Collision, Deletion, FileFailure, FileRecord, Misplaced, PartRecord, PeerDelivery, RatioAnomaly, RootRecord, RunReport, TrailingData};
+use crate::retry::{jittered_backoff, with_retries_if, RetryPolicy, MAX_BACKOFF};
+use crate::sink::{is_transient, BlockSizing, Sink, SinkWriter, WriteOpt};
+use crate::spill::{self, ChunkReceiver, ChunkSender, InFlight, Spill};
+use crate::state::StateFile;
+use crate::telemetry::{Profiled, Profiler, ResourceMonitor, Stage, StageCounters};
+#[cfg(feature = "wasm-plugins")]
+use crate::plugin::{Plugin, PluginInstance};
+
+/// How to handle tar entries that map to an HDFS path already used in this run.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, clap::ValueEnum)]
+pub enum CollisionPolicy {
+    /// Keep the first entry and skip later ones
+    Skip,
+    /// Fail the run, before anything is uploaded
+    Error,
+    /// Upload later entries as `<name>.1`, `<name>.2`, ...
+    Suffix,
+    /// Upload later entries over the earlier ones
+    LastWins,
+}
+
+/// What to do when a target is already on HDFS from before this run.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, clap::ValueEnum)]
+pub enum IfExists {
+    /// Replace it
+    Overwrite,
+    /// Leave it and skip the entry
+    Skip,
+    /// Fail the run
+    Fail,
+    /// Upload the entry as `<name>.1`, `<name>.2`, ... instead
+    Version,
+}
+
+/// The order `process_indexed_tars` schedules the members of a tar in.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, clap::ValueEnum)]
+pub enum MemberOrder {
+    /// The order they are stored in
+    Archive,
+    /// The largest first, so no single big member is left to finish last
+    Size,
+    /// By path
+    Name,
+    /// The order the manifest lists them in; members it does not list come last
+    Manifest,
+}
+
+/// What a run does once a file's upload has timed out.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, clap::ValueEnum)]
+pub enum TimeoutPolicy {
+    /// Start no new files, as after any other failure
+    Stop,
+    /// Go on with the other files; the run still fails at the end
+    Continue,
+}
+
+/// One archive of a run. Several archives may share one manifest.
+pub struct TarSource<R> {
+    pub name: String,
+    pub reader: R,
+    /// Top-level directory to strip from every entry path before matching.
+    pub top_dir: Option<String>,
+}
+
+/// Returned when uploads failed or the archives did not cover the manifest;
+/// carries the report so it can still be written.
+#[derive(Debug, thiserror::Error)]
+#[error("{reason}")]
+pub struct RunFailed {
+    pub reason: String,
+    pub report: RunReport,
+}
+
+/// An upload aborted for taking longer than `FileTimeout::limit`.
+#[derive(Debug, thiserror::Error)]
+#[error("Upload of {target} timed out after {limit:?}")]
+pub struct FileTimedOut {
+    pub target: String,
+    pub limit: Duration,
+}
+
+/// An upload aborted for moving no data for `StallWatch::limit`.
+#[derive(Debug, thiserror::Error)]
+#[error("Upload of {target} made no progress for {idle:?}")]
+pub struct FileStalled {
+    pub target: String,
+    pub idle: Duration,
+}
+
+/// A member still being decompressed or uploaded.
+struct PendingUpload {
+    entry: String,
+    target: String,
+    /// The target the manifest lists, which `target` adds a version to
+    /// under `--if-exists version`; leases, the journal and the state file
+    /// go by it.
+    listed: String,
+    decode: JoinHandle<Result<Decoded>>,
+    handle: JoinHandle<Result<u64>>,
+    /// Held on `listed` until the upload is finished.
+    lease: Option<Arc<Leases>>,
+    /// For members of local tars, which can be extracted again.
+    retries: Option<FileRetries>,
+    /// Where the file is recorded once delivered.
+    state: Option<Arc<StateFile>>,
+    journal: Option<Arc<Journal>>,
+    /// Where the file is written until it is verified, with atomic uploads.
+    staged: Option<Staged>,
+    /// What the upload has written to, taken back if the member is bad.
+    opened: Opened,
+    /// Archive and offset of the member after this one, recorded once
+    /// everything before it is delivered.
+    resume_at: Option<(String, u64)>,
+}
+
+/// Extracts a member again from its local tar when its upload fails.
+struct FileRetries {
+    reextract: Reextract,
+    retries: u32,
+}
+
+/// Why an attempt at a member failed.
+enum Failure {
+    /// Reading, decompressing or verifying it, which fails again the same way.
+    Member(anyhow::Error),
+    /// Writing it out, which another attempt may get through.
+    Upload(anyhow::Error),
+}
+
+impl Failure {
+    fn into_error(self) -> anyhow::Error {
+        match self {
+            Failure::Member(e) | Failure::Upload(e) => e,
+        }
+    }
+}
+
+/// A file another run held the lease on when its member was reached.
+struct Deferred {
+    entry: String,
+    target: String,
+    holder: String,
+}
+
+/// A file written under `STAGING_DIR`, moved to its target once verified.
+struct Staged {
+    sink: Sink,
+    path: String,
+    split: bool,
+    /// Replace a file already in place, rather than fail on it.
+    overwrite: bool,
+}
+
+impl Staged {
+    /// Moves the file, or each of its parts, into place.
+    async fn publish(&self, record: &FileRecord) -> Result<()> {
+        let moves: Vec<(String, &str)> = match self.split {
+            true => record.parts
+                .iter()
+                .enumerate()
+                .map(|(index, part)| (part_file_name(&self.path, index), part.path.as_str()))
+                .collect(),
+            false => vec![(self.path.clone(), record.target.as_str())],
+        };
+        for (from, to) in moves {
+            self.sink.rename(&from, to, self.overwrite)
+                .await
+                .map_err(|e| anyhow!("Failed to move {} into place as {}: {}", from, to, e))?;
+        }
+        Ok(())
+    }
+
+    /// Removes whatever a failed upload left behind.
+    async fn discard(&self) {
+        let mut paths = vec![self.path.clone()];
+        if self.split {
+            // Parts are written in order, so the first missing one ends them.
+            paths.clear();
+            for index in 0.. {
+                let part = part_file_name(&self.path, index);
+                match self.sink.status(&part).await {
+                    Ok(Some(_)) => paths.push(part),
+                    Ok(None) => break,
+                    Err(e) => {
+                        warn!("Failed to look for staged part {}: {}", part, e);
+                        break;
+                    }
+                }
+            }
+        }
+        for path in paths {
+            if let Err(e) = self.sink.delete(&path).await {
+                warn!("Failed to remove staged file {}: {}", path, e);
+            }
+        }
+    }
+}
+
+/// State shared across all archives of one run.
+#[derive(Default)]
+struct RunProgress {
+    upload_handles: Vec<PendingUpload>,
+    processed_files: HashSet<String>,
+    /// Target path -> tar entry delivering it; shared by archives extracted at once.
+    targets: Arc<Mutex<HashMap<String, String>>>,
+    /// Manifest name -> misplaced entry relocated to it, until an entry at
+    /// the manifest path turns up.
+    relocated: HashMap<String, String>,
+    /// Files left to the runs holding their leases.
+    deferred: Vec<Deferred>,
+    /// Failures that were uploads timing out or stalling.
+    timeouts: usize,
+    /// Archive and offset just past the member being extracted, for members
+    /// read straight from a streamed tar.
+    resume_point: Option<(String, u64)>,
+    report: RunReport,
+}
+
+impl RunProgress {
+    /// Records the uploads at the front of the queue that have completed,
+    /// so the report stays in tar order.
+    async fn finish_completed(&mut self) {
+        let done = self
+            .upload_handles
+            .iter()
+            .take_while(|pending| pending.decode.is_finished() && pending.handle.is_finished())
+            .count();
+        for pending in self.upload_handles.drain(..done).collect::<Vec<_>>() {
+            self.finish(pending).await;
+        }
+    }
+
+    async fn finish_all(&mut self) {
+        for pending in std::mem::take(&mut self.upload_handles) {
+            self.finish(pending).await;
+        }
+    }
+
+    /// Takes back the copy of `name` relocated from a misplaced entry, now
+    /// that `entry` turned up at the manifest path. Its upload is finished
+    /// first, so the two never write the target at once. False if `name`
+    /// was not relocated.
+    async fn supersede_relocated(&mut self, name: &str, entry: &str) -> bool {
+        let Some(misplaced) = self.relocated.remove(name) else {
+            return false;
+        };
+        warn!("{} is at the manifest path, replacing {} delivered there", entry, misplaced);
+        self.finish_all().await;
+        self.report.files.retain(|file| file.entry != misplaced);
+        self.report.failures.retain(|failure| failure.entry != misplaced);
+        for relocated in self.report.misplaced.iter_mut().filter(|m| m.entry == misplaced) {
+            relocated.action = "superseded".to_string();
+        }
+        self.targets.lock().unwrap().retain(|_, delivering| *delivering != misplaced);
+        true
+    }
+
+    async fn finish(&mut self, mut pending: PendingUpload) {
+        let lease = pending.lease.take();
+        let listed = pending.listed.clone();
+        self.record(pending).await;
+        if let Some(leases) = lease {
+            leases.release(&listed).await;
+        }
+    }
+
+    async fn record(&mut self, pending: PendingUpload) {
+        let PendingUpload { entry, target, listed, decode, mut handle, retries, state, journal, staged, opened, resume_at, .. } = pending;
+        let decoded = match decode.await {
+            Ok(decoded) => decoded,
+            Err(e) => Err(anyhow!("Decompression of {} did not complete: {}", entry, e)),
+        };
+        let mut outcome = settle(decoded, &mut handle, &target, &opened).await;
+        if let Some(FileRetries { reextract, retries }) = retries {
+            let mut attempt = 0;
+            while attempt < retries
+                && let Err(Failure::Upload(e)) = &outcome
+            {
+                attempt += 1;
+                warn!("Upload of {} failed, extracting {} again ({}/{}): {:#}", target, entry, attempt, retries, e);
+                let write_path = staged.as_ref().map_or(&target, |staged| &staged.path);
+                outcome = match reextract(write_path.clone()).await {
+                    Ok(outcome) => outcome,
+                    Err(e) => Err(Failure::Upload(anyhow!("Retry of {} did not complete: {}", target, e))),
+                };
+            }
+        }
+        if let Some(staged) = &staged {
+            if let Ok((decoded, _)) = &outcome
+                && let Err(e) = staged.publish(&decoded.record).await
+            {
+                outcome = Err(Failure::Upload(e));
+            }
+            if outcome.is_err() {
+                staged.discard().await;
+            }
+        }
+        match outcome {
+            Ok((decoded, bytes)) => {
+                // Left uncommitted, the file is only written again.
+                if let Some(journal) = &journal
+                    && let Err(e) = journal.commit(&listed, &decoded.record).await
+                {
+                    warn!("{:#}", e);
+                }
+                if let Some(state) = &state {
+                    state.complete(&listed, &decoded.record);
+                    // Uploads finish in tar order, so every earlier member
+                    // is settled; a rerun may seek past them unless one was
+                    // left to another run or failed.
+                    if let Some((archive, offset)) = &resume_at
+                        && self.report.failures.is_empty()
+                        && self.deferred.is_empty()
+                    {
+                        state.reached(archive, *offset);
+                    }
+                }
+                self.report.bytes_written += bytes;
+                self.report.files.push(decoded.record);
+                self.report.ratio_anomalies.extend(decoded.ratio_anomaly);
+            }
+            // A failed decode has already logged its error.
+            Err(Failure::Member(e)) => self.fail(entry, target, e),
+            Err(Failure::Upload(e)) => {
+                error!("Upload of {} failed: {:#}", target, e);
+                self.fail(entry, target, e);
+            }
+        }
+    }
+
+    /// Adds what an archive extracted alongside others did, once its
+    /// uploads are finished.
+    fn absorb(&mut self, archive: RunProgress) {
+        self.processed_files.extend(archive.processed_files);
+        self.deferred.extend(archive.deferred);
+        self.timeouts += archive.timeouts;
+        self.report.merge(archive.report);
+    }
+
+    fn fail(&mut self, entry: String, target: String, e: anyhow::Error) {
+        if e.is::<FileTimedOut>() || e.is::<FileStalled>() {
+            self.timeouts += 1;
+        }
+        self.report.failures.push(FileFailure {
+            entry,
+            target,
+            error: format!("{:#}", e),
+            backtrace: e.downcast_ref::<WorkerPanic>().map(|panic| panic.backtrace.clone()),
+        });
+    }
+
+    /// Fails with the report attached if any upload failed.
+    fn check_failures(&mut self) -> Result<()> {
+        if self.report.failures.is_empty() {
+            return Ok(());
+        }
+        let first = &self.report.failures[0];
+        let reason = format!(
+            "{} file(s) failed, first {}: {}",
+            self.report.failures.len(),
+            first.target,
+            first.error
+        );
+        Err(RunFailed { reason, report: std::mem::take(&mut self.report) }.into())
+    }
+}
 
 pub struct Processor {
-    client: Arc<Client>,
+    sink: Sink,
     config: Arc<Config>,
     hdfs_base_path: String,
+    /// Manifest group -> destination root, overriding `hdfs_base_path`.
+    dst_map: HashMap<String, String>,
+    /// Set when deletes are applied.
+    trash_root: Option<String>,
     xml_file_path: String,
+    collision_policy: CollisionPolicy,
+    if_exists: IfExists,
+    append: bool,
+    control: Control,
+    /// One permit per upload that may run at once.
+    upload_slots: Arc<Semaphore>,
+    /// One permit per file that may be open for writing at once, if limited.
+    connections: Option<Arc<Semaphore>>,
+    /// For creating and closing files.
+    retry: RetryPolicy,
+    throughput_floor: Option<ThroughputFloor>,
+    file_timeout: Option<FileTimeout>,
+    stall_timeout: Option<FileTimeout>,
+    speculation: Option<Speculation>,
+    upload_rates: Arc<UploadRates>,
+    retries_per_file: u32,
+    shard: Option<Shard>,
+    leases: Option<Arc<Leases>>,
+    expansion_limit: ExpansionLimit,
+    ratio_check: RatioCheck,
+    excluded: HashSet<String>,
+    skip_existing: bool,
+    state: Option<Arc<StateFile>>,
+    journal: Option<Arc<Journal>>,
+    /// Run id naming staged files, with atomic uploads.
+    atomic_uploads: Option<String>,
+    split_size: Option<u64>,
+    block_sizing: Option<BlockSizing>,
+    /// Writer settings for every file created.
+    write_opts: Arc<[WriteOpt]>,
+    recurse_archives: bool,
+    /// How members are decompressed, shared with the decode workers.
+    decoders: Arc<Decoders>,
+    /// One permit per member that may be decompressed at once.
+    decode_slots: Arc<Semaphore>,
+    /// One permit per byte of raw member data read ahead of the decode workers.
+    read_ahead: Arc<Semaphore>,
+    read_ahead_bytes: usize,
+    /// Buffers for decompressed chunks, shared by all decode workers.
+    buffers: Arc<BufferPool>,
+    chunk_sizing: ChunkSizing,
+    /// Decompressed chunks each upload may have queued in memory.
+    queue_depth: usize,
+    /// Bytes of decompressed chunks queued across all uploads.
+    in_flight: Option<Arc<InFlight>>,
+    recompression: Option<Recompression>,
+    /// Set when CHECKSUMS files are written: the block size of files
+    /// `block_sizing` leaves at the cluster default.
+    checksum_block_size: Option<u64>,
+    strict_paths: bool,
+    relocate_misplaced: bool,
+    matcher: Box<dyn ManifestMatcher>,
+    /// Finds entries the matcher missed that the manifest lists under
+    /// another directory.
+    misplaced: BasenameMatcher,
+    spill: Option<Arc<Spill>>,
+    /// Start files without waiting for an upload slot, spilling their data
+    /// until one is free.
+    store_and_forward: bool,
+    resources: Option<ResourceMonitor>,
+    stages: Arc<StageCounters>,
+    profiler: Option<Arc<Profiler>>,
+    check_trailing_data: bool,
+    /// Archives `process_tars` extracts at once.
+    archive_workers: usize,
+    /// Whether indexed tars are mapped into memory rather than read.
+    mmap: bool,
+    member_order: MemberOrder,
+    name_template: Option<(NameTemplate, TemplateContext)>,
+    #[cfg(feature = "wasm-plugins")]
+    plugin: Option<Arc<Plugin>>,
+}
+
+/// Longest a single file's upload may take.
+#[derive(Debug, Clone, Copy)]
+pub struct FileTimeout {
+    pub limit: Duration,
+    pub policy: TimeoutPolicy,
+}
+
+/// Minimum sustained write throughput for a single file.
+#[derive(Debug, Clone, Copy)]
+pub struct ThroughputFloor {
+    /// MB/s measured over time spent inside HDFS writes.
+    pub min_mb_per_sec: f64,
+    pub window: Duration,
+}
+
+/// When to start a second copy of an upload that falls far behind the
+/// others, e.g. because its HDFS pipeline degraded.
+#[derive(Debug, Clone, Copy)]
+pub struct Speculation {
+    /// An upload straggles once its throughput over a window is below this
+    /// share of the median over the run's uploads.
+    pub min_share: f64,
+    /// Throughput is measured like for `ThroughputFloor`, over this long.
+    pub window: Duration,
+}
+
+/// The share of a manifest one of several hosts delivers: the files whose
+/// name hashes to `index` modulo `count`, so hosts given the same tars and
+/// manifest never write the same file.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Shard {
+    pub index: u64,
+    pub count: u64,
+}
+
+impl Shard {
+    /// Parses `i/N`, with `i` counted from 0.
+    pub fn parse(value: &str) -> Result<Self, String> {
+        let invalid = || format!("invalid --shard '{}', expected i/N with 0 <= i < N", value);
+        let (index, count) = value.split_once('/').ok_or_else(invalid)?;
+        let index: u64 = index.trim().parse().map_err(|_| invalid())?;
+        let count: u64 = count.trim().parse().map_err(|_| invalid())?;
+        if index >= count {
+            return Err(invalid());
+        }
+        Ok(Self { index, count })
+    }
+
+    /// Whether this shard delivers the manifest file `filename`. Hashed
+    /// with SHA-256 so every host and build agrees.
+    pub fn claims(&self, filename: &str) -> bool {
+        let digest = Sha256::digest(filename.as_bytes());
+        let hash = u64::from_be_bytes(digest[..8].try_into().expect("8 bytes"));
+        hash % self.count == self.index
+    }
+
+    /// Appended to the receipts and CHECKSUMS files the shard writes, so
+    /// shards do not overwrite each other's.
+    fn suffix(&self) -> String {
+        format!(".shard-{}-of-{}", self.index, self.count)
+    }
+}
+
+/// Guard against compressed members that expand far beyond their size.
+#[derive(Debug, Clone, Copy, Default)]
+pub struct ExpansionLimit {
+    /// Decompressed bytes per compressed byte of the tar entry.
+    pub max_ratio: Option<f64>,
+    pub max_size: Option<u64>,
+}
+
+impl ExpansionLimit {
+    fn check(&self, path: &str, compressed: u64, decompressed: u64) -> Result<()> {
+        if let Some(max_size) = self.max_size
+            && decompressed > max_size
+        {
+            return Err(anyhow!(
+                "Decompressed size of {} exceeds the {} byte limit; aborting file",
+                path, max_size
+            ));
+        }
+        if let Some(max_ratio) = self.max_ratio
+            && decompressed as f64 > compressed.max(1) as f64 * max_ratio
+        {
+            return Err(anyhow!(
+                "Decompressed size of {} exceeds {}x its compressed size of {} bytes; aborting file",
+                path, max_ratio, compressed
+            ));
+        }
+        Ok(())
+    }
+}
+
+/// Flags compressed members whose decompressed size looks wrong, without
+/// failing them: a corrupt feed may store data uncompressed under a
+/// compression suffix, so it expands by a ratio of about 1.
+#[derive(Debug, Clone, Copy)]
+pub struct RatioCheck {
+    /// How far from 1.0 a ratio may be and still be flagged.
+    pub tolerance: f64,
+    /// Also flag members that expand more than this many times.
+    pub max_ratio: Option<f64>,
+}
+
+impl Default for RatioCheck {
+    fn default() -> Self {
+        Self { tolerance: 0.001, max_ratio: None }
+    }
+}
+
+impl RatioCheck {
+    /// The ratio of `decompressed` to `compressed` if it is an anomaly.
+    fn check(&self, compressed: u64, decompressed: u64) -> Option<f64> {
+        // Empty members have no meaningful ratio.
+        if compressed == 0 || decompressed == 0 {
+            return None;
+        }
+        let ratio = decompressed as f64 / compressed as f64;
+        let near_one = (ratio - 1.0).abs() <= self.tolerance;
+        let too_high = self.max_ratio.is_some_and(|max_ratio| ratio > max_ratio);
+        (near_one || too_high).then_some(ratio)
+    }
+}
+
+/// Size of the decompressed chunks handed to an upload, picked per file:
+/// about `CHUNKS_PER_FILE` chunks of whole HDFS packets, within bounds.
+#[derive(Debug, Clone, Copy)]
+pub struct ChunkSizing {
+    pub min: usize,
+    pub max: usize,
+}
+
+impl ChunkSizing {
+    /// The same chunk size for every file.
+    pub fn fixed(size: usize) -> Self {
+        Self { min: size, max: size }
+    }
+
+    pub fn chunk_size(&self, expected_size: u64) -> usize {
+        let target = (expected_size / CHUNKS_PER_FILE).next_multiple_of(HDFS_PACKET_SIZE);
+        usize::try_from(target).unwrap_or(usize::MAX).clamp(self.min, self.max)
+    }
+}
+
+impl Processor {
+    pub fn new(sink: Sink, config: Config, hdfs_base_path: String, xml_file_path: String) -> Self {
+        Self {
+            sink,
+            config: Arc::new(config),
+            hdfs_base_path,
+            dst_map: HashMap::new(),
+            trash_root: None,
+            xml_file_path,
+            collision_policy: CollisionPolicy::Skip,
+            if_exists: IfExists::Overwrite,
+            append: false,
+            control: Control::default(),
+            upload_slots: Arc::new(Semaphore::new(DEFAULT_UPLOAD_CONCURRENCY)),
+            connections: None,
+            retry: RetryPolicy::default(),
+            throughput_floor: None,
+            file_timeout: None,
+            stall_timeout: None,
+            speculation: None,
+            upload_rates: Arc::default(),
+            retries_per_file: 0,
+            shard: None,
+            leases: None,
+            expansion_limit: ExpansionLimit::default(),
+            ratio_check: RatioCheck::default(),
+            excluded: HashSet::new(),
+            skip_existing: false,
+            state: None,
+            journal: None,
+            atomic_uploads: None,
+            split_size: None,
+            block_sizing: None,
+            write_opts: Arc::new([]),
+            recurse_archives: false,
+            decoders: Arc::new(Decoders::default()),
+            decode_slots: Arc::new(Semaphore::new(default_decompress_workers())),
+            read_ahead: Arc::new(Semaphore::new(DEFAULT_READ_AHEAD)),
+            read_ahead_bytes: DEFAULT_READ_AHEAD,
+            buffers: Arc::new(BufferPool::new(DEFAULT_MAX_BUFFERS)),
+            chunk_sizing: ChunkSizing::fixed(DEFAULT_CHUNK_SIZE),
+            queue_depth: DEFAULT_QUEUE_DEPTH,
+            in_flight: None,
+            recompression: None,
+            checksum_block_size: None,
+            strict_paths: false,
+            relocate_misplaced: false,
+            matcher: Box::new(ExactMatcher),
+            misplaced: BasenameMatcher::default(),
+            spill: None,
+            store_and_forward: false,
+            resources: None,
+            stages: Arc::default(),
+            profiler: None,
+            check_trailing_data: false,
+            archive_workers: 1,
+            mmap: false,
+            member_order: MemberOrder::Archive,
+            name_template: None,
+            #[cfg(feature = "wasm-plugins")]
+            plugin: None,
+        }
+    }
+
+    /// Send files of these manifest groups to their own roots; files of other
+    /// groups, or of none, go to the base path.
+    pub fn with_dst_map(mut self, dst_map: HashMap<String, String>) -> Self {
+        self.dst_map = dst_map;
+        self
+    }
+
+    /// Destination root for the manifest entry `filename`.
+    fn root_for(&self, filename: &str) -> Result<&str> {
+        Ok(self.root_for_group(self.config.group(filename)?.as_deref()))
+    }
+
+    fn root_for_group(&self, group: Option<&str>) -> &str {
+        group
+            .and_then(|group| self.dst_map.get(group))
+            .unwrap_or(&self.hdfs_base_path)
+    }
+
+    /// Apply the manifest's `<deleted-file>` entries after a successful run,
+    /// moving the files under `trash_root`.
+    pub fn with_apply_deletes(mut self, trash_root: Option<String>) -> Self {
+        self.trash_root = trash_root;
+        self
+    }
+
+    pub fn with_collision_policy(mut self, policy: CollisionPolicy) -> Self {
+        self.collision_policy = policy;
+        self
+    }
+
+    /// What to do with targets already on HDFS. Anything but `Overwrite`
+    /// also creates files without replacing what another writer puts
+    /// there in the meantime.
+    pub fn with_if_exists(mut self, policy: IfExists) -> Self {
+        self.if_exists = policy;
+        self
+    }
+
+    /// Add every file to the end of the existing file at its target, which
+    /// must be there. Manifest sizes and digests are the appended data's.
+    /// Not for split, staged or retried uploads, which would write the
+    /// data somewhere else or append it twice.
+    pub fn with_append(mut self, append: bool) -> Self {
+        self.append = append;
+        self
+    }
+
+    pub fn with_control(mut self, control: Control) -> Self {
+        self.control = control;
+        self
+    }
+
+    /// Upload at most this many files at once; reading waits for a free
+    /// slot before starting the next file.
+    pub fn with_upload_concurrency(mut self, uploads: usize) -> Self {
+        self.upload_slots = Arc::new(Semaphore::new(uploads));
+        self
+    }
+
+    /// Keep at most `max` files open for writing, each a DataNode
+    /// pipeline, counting second copies and receipts as well as uploads.
+    pub fn with_max_connections(mut self, max: Option<usize>) -> Self {
+        self.connections = max.map(|max| Arc::new(Semaphore::new(max.max(1))));
+        self
+    }
+
+    /// Retry creating and closing files on transient HDFS errors.
+    pub fn with_write_retries(mut self, retry: RetryPolicy) -> Self {
+        self.retry = retry;
+        self
+    }
+
+    pub fn with_throughput_floor(mut self, floor: Option<ThroughputFloor>) -> Self {
+        self.throughput_floor = floor;
+        self
+    }
+
+    /// Abort uploads that take longer than `timeout.limit`.
+    pub fn with_file_timeout(mut self, timeout: Option<FileTimeout>) -> Self {
+        self.file_timeout = timeout;
+        self
+    }
+
+    /// Abort uploads that move no data for `timeout.limit`.
+    pub fn with_stall_timeout(mut self, timeout: Option<FileTimeout>) -> Self {
+        self.stall_timeout = timeout;
+        self
+    }
+
+    /// Race a second copy against uploads of `process_indexed_tars` that
+    /// straggle, keeping whichever finishes first.
+    pub fn with_speculation(mut self, speculation: Option<Speculation>) -> Self {
+        self.speculation = speculation;
+        self
+    }
+
+    /// Extract a member of `process_indexed_tars` again from its tar, up to
+    /// `retries` times, when its upload fails.
+    pub fn with_retries_per_file(mut self, retries: u32) -> Self {
+        self.retries_per_file = retries;
+        self
+    }
+
+    /// Abort files whose compressed data expands past these limits.
+    pub fn with_expansion_limit(mut self, limit: ExpansionLimit) -> Self {
+        self.expansion_limit = limit;
+        self
+    }
+
+    /// Warn about, and report, compressed members with suspicious ratios.
+    pub fn with_ratio_check(mut self, check: RatioCheck) -> Self {
+        self.ratio_check = check;
+        self
+    }
+
+    /// Manifest names that must never land on HDFS. They are skipped and
+    /// do not count as missing.
+    pub fn with_exclusions(mut self, excluded: HashSet<String>) -> Self {
+        self.excluded = excluded;
+        self
+    }
+
+    /// Leave targets alone that already exist with the manifest size, so a
+    /// failed run can be resumed. Split files are always written.
+    pub fn with_skip_existing(mut self, skip_existing: bool) -> Self {
+        self.skip_existing = skip_existing;
+        self
+    }
+
+    /// Record every delivered file in `state`, and skip the ones it lists
+    /// from an earlier attempt at the run without looking at HDFS.
+    pub fn with_state_file(mut self, state: Option<StateFile>) -> Self {
+        self.state = state.map(Arc::new);
+        self
+    }
+
+    /// Record starting and committing every file in `journal`, and skip the
+    /// files it has committed from an earlier attempt at the run.
+    pub fn with_journal(mut self, journal: Option<Journal>) -> Self {
+        self.journal = journal.map(Arc::new);
+        self
+    }
+
+    /// Write every file to `<root>/.untar-tmp/<name>.<run_id>` and move it
+    /// to its target only once it is verified, so readers of the target
+    /// never see it half-written. Failed uploads are removed.
+    pub fn with_atomic_uploads(mut self, run_id: Option<String>) -> Self {
+        self.atomic_uploads = run_id;
+        self
+    }
+
+    /// Lease every file before writing it, leaving files another run holds
+    /// the lease on to that run.
+    pub fn with_leases(mut self, leases: Option<Arc<Leases>>) -> Self {
+        self.leases = leases;
+        self
+    }
+
+    /// Deliver only the manifest files `shard` claims. The others are
+    /// skipped and do not count as missing.
+    pub fn with_shard(mut self, shard: Option<Shard>) -> Self {
+        self.shard = shard;
+        self
+    }
+
+    /// Create every file with these writer settings. A block size picked
+    /// by `with_block_sizing` still wins over `WriteOpt::BlockSize`.
+    pub fn with_write_opts(mut self, write_opts: Vec<WriteOpt>) -> Self {
+        self.write_opts = write_opts.into();
+        self
+    }
+
+    /// Write files larger than this as `<name>.part-00000`, `<name>.part-00001`, ...
+    pub fn with_split_size(mut self, split_size: Option<u64>) -> Self {
+        self.split_size = split_size;
+        self
+    }
+
+    /// Choose each file's block size from its manifest size.
+    pub fn with_block_sizing(mut self, block_sizing: Option<BlockSizing>) -> Self {
+        self.block_sizing = block_sizing;
+        self
+    }
+
+    /// Open tars nested in the archive that the manifest does not list and
+    /// extract their members instead of uploading the tar as one file.
+    pub fn with_recurse_archives(mut self, recurse_archives: bool) -> Self {
+        self.recurse_archives = recurse_archives;
+        self
+    }
+
+    /// Inflate gzip members of at least `PARALLEL_GZIP_MIN_SIZE` on this
+    /// many background threads.
+    pub fn with_decompress_threads(mut self, threads: Option<usize>) -> Self {
+        Arc::make_mut(&mut self.decoders).decompress_threads = threads;
+        self
+    }
+
+    /// Decompress at most this many members at once, each on its own
+    /// blocking thread; by default one per CPU.
+    pub fn with_decompress_workers(mut self, workers: Option<usize>) -> Self {
+        self.decode_slots = Arc::new(Semaphore::new(workers.unwrap_or_else(default_decompress_workers)));
+        self
+    }
+
+    /// Pipe entries with these suffixes through external commands instead
+    /// of the built-in decoders.
+    pub fn with_decompressors(mut self, decompressors: Vec<ExternalDecompressor>) -> Self {
+        Arc::make_mut(&mut self.decoders).decompressors = decompressors;
+        self
+    }
+
+    /// Let the tar reader queue up to this many bytes of members for the
+    /// decode workers, so it moves on to the next member while they catch up.
+    pub fn with_read_ahead(mut self, bytes: usize) -> Self {
+        self.read_ahead = Arc::new(Semaphore::new(bytes));
+        self.read_ahead_bytes = bytes;
+        self
+    }
+
+    /// Keep at most this many decompressed chunks, and `max_bytes` of them,
+    /// in memory across all files; decoding waits for an upload to release
+    /// one.
+    pub fn with_max_buffers(mut self, max_buffers: usize, max_bytes: Option<usize>) -> Self {
+        self.buffers = Arc::new(BufferPool::new(max_buffers).with_max_bytes(max_bytes));
+        self
+    }
+
+    /// Let each upload queue up to `depth` decompressed chunks, and all of
+    /// them together at most `max_in_flight` bytes, before decoding waits
+    /// (or spills, with `with_spill`).
+    pub fn with_queue_depth(mut self, depth: usize, max_in_flight: Option<usize>) -> Self {
+        self.queue_depth = depth;
+        self.in_flight = max_in_flight.map(|bytes| Arc::new(InFlight::new(bytes)));
+        self
+    }
+
+    /// Suffixes mapped to formats, for picking decoders and naming the
+    /// decompressed files.
+    pub fn with_format_map(mut self, formats: FormatMap) -> Self {
+        Arc::make_mut(&mut self.decoders).formats = formats;
+        self
+    }
+
+    /// Decompress `.zst` members with this shared dictionary.
+    pub fn with_zstd_dict(mut self, dict: Option<&[u8]>) -> Self {
+        Arc::make_mut(&mut self.decoders).zstd_dict = dict.map(|dict| Arc::new(DecoderDictionary::copy(dict)));
+        self
+    }
+
+    /// Read the tar and feed decoders `read_buffer_size` bytes at a time, and
+    /// hand decompressed data to the uploads in chunks sized by `chunk_sizing`.
+    pub fn with_buffer_sizes(mut self, read_buffer_size: usize, chunk_sizing: ChunkSizing) -> Self {
+        Arc::make_mut(&mut self.decoders).read_buffer_size = read_buffer_size;
+        self.chunk_sizing = chunk_sizing;
+        self
+    }
+
+    /// Upload `.Z` members without the compress magic as empty files instead
+    /// of failing them.
+    pub fn with_lenient_z_header(mut self, lenient: bool) -> Self {
+        Arc::make_mut(&mut self.decoders).lenient_z_header = lenient;
+        self
+    }
+
+    /// Store files compressed in this format, with its suffix added to their
+    /// names; the decompressed content is still verified against the manifest.
+    pub fn with_recompression(mut self, recompression: Option<Recompression>) -> Self {
+        self.recompression = recompression;
+        self
+    }
+
+    /// Write a `CHECKSUMS` file with the HDFS file checksum of every upload
+    /// to each root; `default_block_size` is the cluster's `dfs.blocksize`.
+    pub fn with_checksums(mut self, default_block_size: Option<u64>) -> Self {
+        self.checksum_block_size = default_block_size;
+        self
+    }
+
+    /// Fail the run on an entry whose file name is unique in the manifest
+    /// but listed under another directory; by default it is skipped.
+    pub fn with_strict_paths(mut self, strict_paths: bool) -> Self {
+        self.strict_paths = strict_paths;
+        self
+    }
+
+    /// Deliver such an entry to the path the manifest lists instead, unless
+    /// an entry at that path turns up too, which replaces it.
+    pub fn with_relocate_misplaced(mut self, relocate_misplaced: bool) -> Self {
+        self.relocate_misplaced = relocate_misplaced;
+        self
+    }
+
+    /// How tar entries are matched to manifest entries; exact by default.
+    pub fn with_matcher(mut self, matcher: Box<dyn ManifestMatcher>) -> Self {
+        self.matcher = matcher;
+        self
+    }
+
+    /// Let decompression run ahead of a slow sink by spilling chunks to disk.
+    pub fn with_spill(mut self, spill: Option<Spill>) -> Self {
+        self.spill = spill.map(Arc::new);
+        self
+    }
+
+    /// Spool new files through the spill while every upload slot is taken (needs `with_spill`).
+    pub fn with_store_and_forward(mut self, store_and_forward: bool) -> Self {
+        self.store_and_forward = store_and_forward;
+        self
+    }
+
+    /// Drain in-flight uploads before starting new files while the monitor
+    /// reports memory or open files near their limits.
+    pub fn with_resource_monitor(mut self, monitor: Option<ResourceMonitor>) -> Self {
+        self.resources = monitor;
+        self
+    }
+
+    /// Extract up to `workers` archives of `process_tars` at once. Which of
+    /// them first reaches a name used by several varies from run to run, so
+    /// only the `Error` and `Suffix` collision policies lose no file to it.
+    pub fn with_archive_workers(mut self, workers: usize) -> Self {
+        self.archive_workers = workers.max(1);
+        self
+    }
+
+    /// Map tars into memory for `process_indexed_tars` instead of reading
+    /// them with syscalls.
+    pub fn with_mmap(mut self, mmap: bool) -> Self {
+        self.mmap = mmap;
+        self
+    }
+
+    /// Counts what each stage of the run handles in `stages`, e.g. to log
+    /// progress while it runs.
+    pub fn with_stage_counters(mut self, stages: Arc<StageCounters>) -> Self {
+        self.stages = stages;
+        self
+    }
+
+    /// Charge the CPU time of reading, decompressing and writing to a profile.
+    pub fn with_profiler(mut self, profiler: Option<Arc<Profiler>>) -> Self {
+        self.profiler = profiler;
+        self
+    }
+
+    /// Order in which `process_indexed_tars` matches and reads members.
+    pub fn with_member_order(mut self, member_order: MemberOrder) -> Self {
+        self.member_order = member_order;
+        self
+    }
+
+    /// Read past each archive's EOF marker and warn about anything but zero padding.
+    pub fn with_check_trailing_data(mut self, check_trailing_data: bool) -> Self {
+        self.check_trailing_data = check_trailing_data;
+        self
+    }
+
+    pub fn with_name_template(mut self, template: Option<NameTemplate>, context: TemplateContext) -> Self {
+        self.name_template = template.map(|template| (template, context));
+        self
+    }
+
+    /// Pass every decompressed chunk through this WASM plugin before upload.
+    #[cfg(feature = "wasm-plugins")]
+    pub fn with_plugin(mut self, plugin: Option<Plugin>) -> Self {
+        self.plugin = plugin.map(Arc::new);
+        self
+    }
+
+    /// Extracts every archive, then checks that the manifest is covered by
+    /// the union of their entries and uploads the manifest itself.
+    pub async fn process_tars<R: Read + Send + 'static>(&self, sources: Vec<TarSource<R>>) -> Result<RunReport> {
+        let mut progress = RunProgress::default();
+
+        if self.archive_workers > 1
+            && sources.len() > 1
+            && Handle::current().runtime_flavor() == RuntimeFlavor::MultiThread
+        {
+            if let Err(e) = self.extract_concurrently(sources, &mut progress) {
+                return self.cut_short(progress, e).await;
+            }
+            return self.complete_run(progress).await;
+        }
+        for source in sources {
+            info!("Extracting archive {}", source.name);
+            progress.report.archives.push(source.name.clone());
+            if let Err(e) = self.extract_tar_blocking(source, &mut progress, None).await {
+                return self.cut_short(progress, e).await;
+            }
+            if self.stopped(&progress) {
+                break;
+            }
+        }
+        self.complete_run(progress).await
+    }
+
+    /// Extracts up to `archive_workers` archives at once, each read on its
+    /// own thread while decode and upload workers stay shared, then adds
+    /// what each did to `progress` in archive order. An archive that fails
+    /// does not stop the others.
+    fn extract_concurrently<R: Read + Send + 'static>(
+        &self,
+        sources: Vec<TarSource<R>>,
+        progress: &mut RunProgress,
+    ) -> Result<()> {
+        let count = sources.len();
+        let queue = Mutex::new(sources.into_iter().enumerate());
+        let done: Mutex<Vec<Option<(RunProgress, Result<()>)>>> = Mutex::new((0..count).map(|_| None).collect());
+        let targets = progress.targets.clone();
+        let handle = Handle::current();
+        tokio::task::block_in_place(|| {
+            std::thread::scope(|scope| {
+                for _ in 0..self.archive_workers.min(count) {
+                    scope.spawn(|| loop {
+                        let Some((index, source)) = queue.lock().unwrap().next() else {
+                            break;
+                        };
+                        let mut archive = RunProgress { targets: targets.clone(), ..Default::default() };
+                        info!("Extracting archive {}", source.name);
+                        archive.report.archives.push(source.name.clone());
+                        let result = handle.block_on(async {
+                            let extract = self.extract_tar(source, &mut archive, None);
+                            let result = Profiled::new(extract, self.profiler.as_ref(), || "untar;read".to_string()).await;
+                            archive.finish_all().await;
+                            result
+                        });
+                        done.lock().unwrap()[index] = Some((archive, result));
+                    });
+                }
+            });
+        });
+
+        let mut first_error = None;
+        for (archive, result) in done.into_inner().unwrap().into_iter().flatten() {
+            progress.absorb(archive);
+            if let Err(e) = result {
+                first_error.get_or_insert(e);
+            }
+        }
+        first_error.map_or(Ok(()), Err)
+    }
+
+    /// Like `process_tars`, for uncompressed tars on local disk: each tar's
+    /// headers are indexed first, then every member is read by its own
+    /// decode worker at its offset, so members are read in parallel rather
+    /// than one after another.
+    pub async fn process_indexed_tars(&self, mut sources: Vec<TarSource<File>>) -> Result<RunReport> {
+        self.check_collisions(&mut sources)?;
+        let mut progress = RunProgress::default();
+
+        let positions = match self.member_order {
+            MemberOrder::Manifest => config::load_positions(&self.xml_file_path)?,
+            _ => HashMap::new(),
+        };
+        for source in sources {
+            info!("Indexing archive {}", source.name);
+            progress.report.archives.push(source.name.clone());
+            if let Err(e) = self.extract_indexed(source, &positions, &mut progress).await {
+                return self.cut_short(progress, e).await;
+            }
+            if self.stopped(&progress) {
+                break;
+            }
+        }
+        self.complete_run(progress).await
+    }
+
+    /// Under `CollisionPolicy::Error`, fails before anything is uploaded if
+    /// two members of `sources` map to the same target. Members of nested
+    /// archives and misplaced ones are only checked as they are extracted.
+    /// Each source is left where it was read from.
+    pub fn check_collisions(&self, sources: &mut [TarSource<File>]) -> Result<()> {
+        if self.collision_policy != CollisionPolicy::Error {
+            return Ok(());
+        }
+        let mut targets: HashMap<String, String> = HashMap::new();
+        for source in sources {
+            let position = source.reader
+                .stream_position()
+                .with_context(|| format!("Failed to read position in {}", source.name))?;
+            source.reader.rewind().with_context(|| format!("Failed to rewind {}", source.name))?;
+            let index = index_entries(&source.reader)?;
+            source.reader
+                .seek(SeekFrom::Start(position))
+                .with_context(|| format!("Failed to seek {}", source.name))?;
+            for member in index.entries {
+                let path = match &source.top_dir {
+                    Some(top_dir) => strip_top_dir(&member.path, top_dir),
+                    None => member.path,
+                };
+                if path.is_empty() || self.is_nested_archive(&path)? {
+                    continue;
+                }
+                let Some(name) = self.matcher.find(&self.decompressed_name(&path), &self.config)? else {
+                    continue;
+                };
+                if self.excluded.contains(&name) || !self.in_shard(&name) {
+                    continue;
+                }
+                let target_name = match &self.name_template {
+                    Some((template, context)) => template.render(&name, context),
+                    None => name.clone(),
+                };
+                let root = self.root_for(&name)?;
+                if let Some(first) = targets.insert(format!("{}/{}", root, target_name), path.clone()) {
+                    return Err(anyhow!("Name collision on {}: {} and {}", target_name, first, path));
+                }
+            }
+        }
+        Ok(())
+    }
+
+    /// Waits for the runs holding the leases on deferred files to finish
+    /// them, and checks that they landed.
+    async fn await_deferred(&self, progress: &mut RunProgress) -> Result<()> {
+        let Some(leases) = &self.leases else {
+            return Ok(());
+        };
+        for deferred in std::mem::take(&mut progress.deferred) {
+            info!("Waiting for {} to finish {}", deferred.holder, deferred.target);
+            let released = leases.wait_released(&deferred.target).await?;
+            let landed = self.sink.status(&deferred.target)
+                .await
+                .map_err(|e| anyhow!("Failed to stat {}: {}", deferred.target, e))?
+                .is_some_and(|status| !status.is_dir);
+            if released && landed {
+                info!("{} was delivered by {}", deferred.target, deferred.holder);
+                progress.report.peer_deliveries.push(PeerDelivery { target: deferred.target, holder: deferred.holder });
+            } else {
+                let e = match released {
+                    true => anyhow!("{} released its lease without delivering the file", deferred.holder),
+                    false => anyhow!("the lease of {} expired before it delivered the file", deferred.holder),
+                };
+                error!("{} was not delivered: {:#}", deferred.target, e);
+                progress.fail(deferred.entry, deferred.target, e);
+            }
+        }
+        Ok(())
+    }
+
+    /// Fails a run that `e` stopped. Past the deadline, the uploads in flight
+    /// are waited for first, so the report lists what was delivered.
+    async fn cut_short(&self, mut progress: RunProgress, e: anyhow::Error) -> Result<RunReport> {
+        if !e.is::<DeadlinePassed>() {
+            return Err(e);
+        }
+        progress.finish_all().await;
+        let mut report = std::mem::take(&mut progress.report);
+        if let Some(spill) = &self.spill {
+            report.spilled_bytes = spill.spilled_bytes();
+        }
+        let reason = format!(
+            "{} file(s) delivered and {} failed before the deadline, no receipt written",
+            report.files.len(),
+            report.failures.len()
+        );
+        Err(anyhow::Error::new(RunFailed { reason, report }).context(DeadlinePassed))
+    }
+
+    /// Whether the failures so far stop new files from being started.
+    fn stopped(&self, progress: &RunProgress) -> bool {
+        let continues = [self.file_timeout, self.stall_timeout]
+            .into_iter()
+            .flatten()
+            .any(|timeout| timeout.policy == TimeoutPolicy::Continue);
+        let tolerated = if continues { progress.timeouts } else { 0 };
+        progress.report.failures.len() > tolerated
+    }
+
+    /// Whether this run delivers the manifest file `filename`.
+    fn in_shard(&self, filename: &str) -> bool {
+        self.shard.is_none_or(|shard| shard.claims(filename))
+    }
+
+    /// Waits for the uploads of a run, checks the manifest is covered, and
+    /// applies deletes and receipts.
+    async fn complete_run(&self, mut progress: RunProgress) -> Result<RunReport> {
+        // Wait for remaining uploads
+        progress.finish_all().await;
+        self.await_deferred(&mut progress).await?;
+        progress.check_failures()?;
+        let RunProgress { processed_files, mut report, .. } = progress;
+        if let Some(spill) = &self.spill {
+            report.spilled_bytes = spill.spilled_bytes();
+        }
+        report.stages = self.stages.timings();
+
+        // Final validation: check if all XML entries were found in TAR, per root
+        let xml_filename = std::path::Path::new(&self.xml_file_path)
+            .file_name()
+            .and_then(|name| name.to_str())
+            .ok_or_else(|| anyhow!("Invalid XML file path"))?;
+        let shard_suffix = self.shard.map(|shard| shard.suffix()).unwrap_or_default();
+        let receipt_name = format!("{}{}", xml_filename, shard_suffix);
+        let xml_filename = receipt_name.as_str();
+        let mut roots: Vec<RootRecord> = Vec::new();
+        for filename in self.config.file_map.keys() {
+            let filename = filename?;
+            if !self.in_shard(&filename) {
+                continue;
+            }
+            let root = self.root_for(&filename)?;
+            root_record(&mut roots, root, xml_filename).expected += 1;
+            if !processed_files.contains(&filename)
+                && !self.excluded.contains(&filename)
+                && !self.delivered_before_resume(&filename).await?
+            {
+                error!("File {} listed in XML was not found in TAR (destined for {})", filename, root);
+                let reason = match report.misplaced.iter().find(|m| m.expected == filename) {
+                    Some(misplaced) => format!(
+                        "Missing file in TAR: {} (found as {}, under an unexpected directory)",
+                        filename, misplaced.entry
+                    ),
+                    None => format!("Missing file in TAR: {}", filename),
+                };
+                return Err(RunFailed { reason, report }.into());
+            }
+        }
+        // Root path -> lines of its CHECKSUMS file.
+        let mut checksums: HashMap<String, Vec<String>> = HashMap::new();
+        for file in &report.files {
+            // Longest match, in case one root is nested inside another.
+            if let Some(root) = roots
+                .iter_mut()
+                .filter(|r| file.target.starts_with(&format!("{}/", r.path)))
+                .max_by_key(|r| r.path.len())
+            {
+                root.delivered += 1;
+                root.bytes += file.size;
+                let lines = checksums.entry(root.path.clone()).or_default();
+                lines.extend(checksum_line(&file.target, file.checksum.as_ref()));
+                for part in &file.parts {
+                    lines.extend(checksum_line(&part.path, part.checksum.as_ref()));
+                }
+            }
+        }
+        root_record(&mut roots, &self.hdfs_base_path, xml_filename);
+
+        // Deletes only once every addition has landed
+        if let Some(trash_root) = &self.trash_root {
+            for deleted in self.config.deleted.iter().filter(|deleted| self.in_shard(&deleted.filename)) {
+                if processed_files.contains(&deleted.filename) {
+                    warn!("{} is both delivered and deleted by the manifest, keeping it", deleted.filename);
+                    continue;
+                }
+                let root = self.root_for_group(deleted.group.as_deref());
+                let path = format!("{}/{}", root, deleted.filename);
+                // Manifests delete files; a directory is never trashed whole.
+                match self.sink.status(&path).await {
+                    Ok(Some(status)) if status.is_dir => {
+                        let reason = format!("Refusing to delete {}: it is a directory", path);
+                        return Err(RunFailed { reason, report }.into());
+                    }
+                    Ok(_) => {}
+                    Err(e) => {
+                        let reason = format!("Failed to stat {} before deleting it: {}", path, e);
+                        return Err(RunFailed { reason, report }.into());
+                    }
+                }
+                match self.sink.trash(&path, trash_root).await {
+                    Ok(Some(trash)) => {
+                        info!("Deleted {} (moved to {})", path, trash);
+                        root_record(&mut roots, root, xml_filename).deleted += 1;
+                        report.deletions.push(Deletion { path, trash: Some(trash) });
+                    }
+                    Ok(None) => {
+                        info!("{} is deleted by the manifest but already absent", path);
+                        report.deletions.push(Deletion { path, trash: None });
+                    }
+                    Err(e) => {
+                        let reason = format!("Failed to delete {}: {}", path, e);
+                        return Err(RunFailed { reason, report }.into());
+                    }
+                }
+            }
+        }
+
+        roots.sort_by(|a, b| a.path.cmp(&b.path));
+
+        // Upload the XML file to every root as its receipt
+        let xml_content = Bytes::from(
+            std::fs::read(&self.xml_file_path)
+                .map_err(|e| anyhow!("Failed to read XML file {}: {}", self.xml_file_path, e))?,
+        );
+        for root in &roots {
+            if self.checksum_block_size.is_some() {
+                let mut lines = checksums.remove(&root.path).unwrap_or_default();
+                lines.sort();
+                let path = format!("{}/{}{}", root.path, CHECKSUMS_FILE, shard_suffix);
+                self.put(&path, Bytes::from(lines.concat())).await?;
+                info!("Wrote {} checksums to {}", lines.len(), path);
+            }
+
+            info!(
+                "Verified {} of {} manifest files under {}, uploading XML file",
+                root.delivered, root.expected, root.path
+            );
+            self.put(&root.receipt, xml_content.clone()).await?;
+            info!("XML file uploaded successfully to {}", root.receipt);
+        }
+        report.roots = roots;
+
+        Ok(report)
+    }
+
+    /// Where the manifest file `filename` is delivered, barring collisions.
+    fn target_for(&self, filename: &str) -> Result<String> {
+        let mut target_name = filename.to_string();
+        if let Some((template, context)) = &self.name_template {
+            target_name = template.render(&target_name, context);
+        }
+        Ok(format!("{}/{}{}", self.root_for(filename)?, target_name, self.stored_suffix()))
+    }
+
+    /// Gives up the lease taken on `target` for a file not written after all.
+    async fn release(&self, target: &str) {
+        if let Some(leases) = &self.leases {
+            leases.release(target).await;
+        }
+    }
+
+    /// Whether anything is at `target`, or at its first part if it is split.
+    async fn occupied(&self, target: &str, split: bool) -> Result<bool> {
+        let path = match split {
+            true => part_file_name(target, 0),
+            false => target.to_string(),
+        };
+        let existing = self.sink.status(&path)
+            .await
+            .map_err(|e| anyhow!("Failed to stat {}: {}", path, e))?;
+        Ok(existing.is_some())
+    }
+
+    /// Whether `filename` was delivered by an earlier attempt at the run,
+    /// from the part of a tar this one sought past: listed in the state file
+    /// or, if it was skipped as already present then, found at the target.
+    async fn delivered_before_resume(&self, filename: &str) -> Result<bool> {
+        let Some(state) = self.state.as_ref().filter(|state| state.resumes()) else {
+            return Ok(false);
+        };
+        let target = self.target_for(filename)?;
+        let size = self.config.get_expected_size(filename)?;
+        if state.completed_size(&target).is_some_and(|completed| Some(completed) == size) {
+            return Ok(true);
+        }
+        let existing = self.sink.status(&target)
+            .await
+            .map_err(|e| anyhow!("Failed to stat {}: {}", target, e))?;
+        Ok(existing.is_some_and(|status| !status.is_dir && Some(status.len) == size))
+    }
+
+    /// Classifies every file this delivery writes, and every file it
+    /// deletes, against what is at the destination now. Writes nothing.
+    pub async fn plan(&self) -> Result<Plan> {
+        let mut paths: Vec<(String, Option<u64>)> = Vec::new();
+        for item in self.config.file_map.iter() {
+            let (filename, size) = item?;
+            if self.excluded.contains(&filename) || !self.in_shard(&filename) {
+                continue;
+            }
+            paths.push((self.target_for(&filename)?, Some(size)));
+        }
+        if self.trash_root.is_some() {
+            for deleted in self.config.deleted.iter().filter(|deleted| self.in_shard(&deleted.filename)) {
+                let root = self.root_for_group(deleted.group.as_deref());
+                paths.push((format!("{}/{}", root, deleted.filename), None));
+            }
+        }
+        paths.sort();
+
+        let files: Vec<Option<PlannedFile>> = stream::iter(paths)
+            .map(|(path, size)| async move {
+                let existing = self.sink.status(&path)
+                    .await
+                    .map_err(|e| anyhow!("Failed to stat {}: {}", path, e))?;
+                Ok::<_, anyhow::Error>(match (size, existing) {
+                    (Some(size), existing) => {
+                        Some(PlannedFile { action: PlanAction::for_write(size, existing), path, size })
+                    }
+                    (None, Some(existing)) => {
+                        Some(PlannedFile { path, size: existing.len, action: PlanAction::Delete })
+                    }
+                    // Nothing to delete.
+                    (None, None) => None,
+                })
+            })
+            .buffered(PLAN_CONCURRENCY)
+            .try_collect()
+            .await?;
+        Ok(Plan { files: files.into_iter().flatten().collect() })
+    }
+
+    /// Writes a small file in one go, replacing any existing one.
+    async fn put(&self, path: &str, data: Bytes) -> Result<()> {
+        let _connection = connect(self.connections.as_ref()).await;
+        let write_options = WriteOpt::apply_all(&self.write_opts, WriteOptions::default().overwrite(true));
+        // Small enough to write again from the start whatever step failed.
+        with_retries_if(&format!("Writing {}", path), self.retry, is_transient, || {
+            let (write_options, data) = (write_options.clone(), data.clone());
+            async move {
+                let mut writer = self.sink.create(path, write_options).await?;
+                writer.write(data).await?;
+                writer.close().await
+            }
+        })
+        .await
+        .map_err(|e| anyhow!("Failed to write HDFS file {}: {}", path, e))
+    }
+
+    /// Extracts the single member `entry` (with or without its compression
+    /// suffix) and verifies it against the manifest, for spot restores.
+    pub async fn extract_one<R: Read + Send + 'static>(&self, source: TarSource<R>, entry: &str) -> Result<RunReport> {
+        let mut progress = RunProgress::default();
+        let archive_name = source.name.clone();
+        progress.report.archives.push(archive_name.clone());
+        self.extract_tar_blocking(source, &mut progress, Some(entry)).await?;
+
+        progress.finish_all().await;
+        progress.check_failures()?;
+        if progress.report.files.is_empty() {
+            return Err(anyhow!("Entry {} was not found in {} or is not listed in the manifest", entry, archive_name));
+        }
+        Ok(progress.report)
+    }
+
+    /// Runs `extract_tar` on a thread that may block, so reading and
+    /// decompressing do not hold up the runtime's workers, and the uploads
+    /// on them, while a member is inflated. Uploads still run as tasks fed
+    /// through their chunk channels.
+    async fn extract_tar_blocking<R: Read + Send + 'static>(
+        &self,
+        source: TarSource<R>,
+        progress: &mut RunProgress,
+        only: Option<&str>,
+    ) -> Result<()> {
+        let extract = Profiled::new(self.extract_tar(source, progress, only), self.profiler.as_ref(), || "untar;read".to_string());
+        match Handle::current().runtime_flavor() {
+            RuntimeFlavor::MultiThread => tokio::task::block_in_place(|| Handle::current().block_on(extract)),
+            // A current-thread runtime has no worker to hand its tasks to.
+            _ => extract.await,
+        }
+    }
+
+    /// Extracts every manifest entry of `source`, or only `only` if given.
+    async fn extract_tar<R: Read + Send + 'static>(&self, source: TarSource<R>, progress: &mut RunProgress, only: Option<&str>) -> Result<()> {
+        let mut archive = Archive::new(CountingReader::new(BufReader::with_capacity(self.decoders.read_buffer_size, source.reader)));
+        let entries = archive.entries().context("Failed to read tar entries")?;
+        // Offset just past the last member's data, where the EOF marker should start.
+        let mut end_of_members = 0u64;
+        // The caller has already sought past what an earlier attempt delivered.
+        let base = self.state.as_ref().and_then(|state| state.resume_offset(&source.name)).unwrap_or(0);
+        let mut garbage = false;
+
+        for entry_res in entries {
+            self.control.wait_to_start().await?;
+            let mut entry = match entry_res {
+                Ok(entry) => entry,
+                // Junk where the EOF marker should be: stop and report it below.
+                Err(e) if end_of_members > 0 && is_garbage_header(&e) => {
+                    garbage = true;
+                    break;
+                }
+                Err(e) => return Err(e).context("Failed to get tar entry"),
+            };
+            end_of_members = entry.raw_file_position() + entry.size().div_ceil(512) * 512;
+            let mut path = entry.path()?.to_string_lossy().to_string();
+            if let Some(top_dir) = &source.top_dir {
+                path = strip_top_dir(&path, top_dir);
+                if path.is_empty() {
+                    continue;
+                }
+            }
+
+            if self.is_nested_archive(&path)? {
+                let nested = format!("{}!{}", source.name, path);
+                let reader = self.decoders.decoder(&path, &mut entry)?;
+                // Only offsets between members of this tar can be resumed from.
+                progress.resume_point = None;
+                if self.extract_nested(reader, &nested, &path, progress, only).await? {
+                    return Ok(());
+                }
+                continue;
+            }
+            progress.resume_point = Some((source.name.clone(), base + end_of_members));
+            let entry_size = entry.size();
+            // Start no new files once one has failed.
+            if self.extract_entry(MemberData::Stream(&mut entry), entry_size, path, progress, only).await?
+                && (self.stopped(progress) || only.is_some())
+            {
+                return Ok(());
+            }
+        }
+        if garbage || self.check_trailing_data {
+            let mut reader = archive.into_inner();
+            let non_zero = count_non_zero(&mut reader)
+                .map_err(|e| anyhow!("Failed to read past the end of {}: {}", source.name, e))?;
+            let trailing = reader.position().saturating_sub(end_of_members);
+            record_trailing_data(&source.name, garbage || non_zero > 0, trailing, progress);
+        }
+
+        Ok(())
+    }
+
+    /// Extracts every manifest entry of a local tar from an index of its
+    /// members. Members are matched in the configured `MemberOrder`, which
+    /// is also the order of the report and the one collisions are resolved
+    /// in; reading them is left to the decode workers. `positions` are the
+    /// manifest positions of its files, for `MemberOrder::Manifest`.
+    async fn extract_indexed(
+        &self,
+        source: TarSource<File>,
+        positions: &HashMap<String, usize>,
+        progress: &mut RunProgress,
+    ) -> Result<()> {
+        let mut file = source.reader;
+        file.rewind().with_context(|| format!("Failed to rewind {}", source.name))?;
+        let mut index = index_entries(&file)?;
+        if let Some(top_dir) = &source.top_dir {
+            for member in &mut index.entries {
+                member.path = strip_top_dir(&member.path, top_dir);
+            }
+            index.entries.retain(|member| !member.path.is_empty());
+        }
+        match self.member_order {
+            MemberOrder::Archive => {}
+            MemberOrder::Size => index.entries.sort_by_key(|member| std::cmp::Reverse(member.size)),
+            MemberOrder::Name => index.entries.sort_by(|a, b| a.path.cmp(&b.path)),
+            MemberOrder::Manifest => {
+                let mut keyed = index.entries
+                    .drain(..)
+                    .map(|member| {
+                        let position = self.matcher
+                            .find(&self.decompressed_name(&member.path), &self.config)?
+                            .and_then(|name| positions.get(&name).copied())
+                            .unwrap_or(usize::MAX);
+                        Ok((position, member))
+                    })
+                    .collect::<Result<Vec<_>>>()?;
+                keyed.sort_by_key(|(position, _)| *position);
+                index.entries = keyed.into_iter().map(|(_, member)| member).collect();
+            }
+        }
+        let tar = Arc::new(if self.mmap {
+            LocalTar::Mapped(MappedFile::map(&file).with_context(|| format!("Failed to map {}", source.name))?)
+        } else {
+            LocalTar::File(file)
+        });
+        info!("Indexed {} members of {}", index.entries.len(), source.name);
+
+        for member in index.entries {
+            self.control.wait_to_start().await?;
+            let path = member.path;
+            let reader = BufReader::with_capacity(
+                self.decoders.read_buffer_size,
+                EntryReader::new(tar.clone(), member.offset, member.size),
+            );
+
+            if self.is_nested_archive(&path)? {
+                let nested = format!("{}!{}", source.name, path);
+                let reader = self.decoders.decoder(&path, reader)?;
+                if self.extract_nested(reader, &nested, &path, progress, None).await? {
+                    return Ok(());
+                }
+                continue;
+            }
+            if self.extract_entry(MemberData::At(reader), member.size, path, progress, None).await?
+                && self.stopped(progress)
+            {
+                return Ok(());
+            }
+        }
+        if index.garbage || self.check_trailing_data {
+            let len = tar.size()
+                .map_err(|e| anyhow!("Failed to read past the end of {}: {}", source.name, e))?;
+            let trailing = len.saturating_sub(index.end_of_members);
+            let non_zero = count_non_zero(EntryReader::new(tar.clone(), index.end_of_members, trailing))
+                .map_err(|e| anyhow!("Failed to read past the end of {}: {}", source.name, e))?;
+            record_trailing_data(&source.name, index.garbage || non_zero > 0, trailing, progress);
+        }
+
+        Ok(())
+    }
+
+    /// A member `--recurse-archives` opens: a tar, possibly compressed, that
+    /// the manifest does not list itself.
+    fn is_nested_archive(&self, path: &str) -> Result<bool> {
+        Ok(self.recurse_archives
+            && self.decompressed_name(path).ends_with(".tar")
+            && self.matcher.find(&self.decompressed_name(path), &self.config)?.is_none())
+    }
+
+    /// `path` without its compression suffix, built-in or external.
+    fn decompressed_name<'p>(&self, path: &'p str) -> Cow<'p, str> {
+        match self.decoders.decompressors.iter().find_map(|external| external.strip(path)) {
+            Some(name) => Cow::Borrowed(name),
+            None => self.decoders.formats.decompressed_name(path),
+        }
+    }
+
+    /// Added to target names by `--recompress`.
+    fn stored_suffix(&self) -> &'static str {
+        self.recompression.as_ref().map_or("", Recompression::suffix)
+    }
+
+    /// Extracts the members of a tar found at `path` inside another archive,
+    /// as if it had been unpacked where it sits. True once the run should stop.
+    fn extract_nested<'a>(
+        &'a self,
+        reader: Box<dyn Read + 'a>,
+        name: &'a str,
+        path: &'a str,
+        progress: &'a mut RunProgress,
+        only: Option<&'a str>,
+    ) -> Pin<Box<dyn Future<Output = Result<bool>> + 'a>> {
+        Box::pin(async move {
+            info!("Extracting nested archive {}", name);
+            progress.report.archives.push(name.to_string());
+            let dir = path.rsplit_once('/').map(|(dir, _)| format!("{}/", dir)).unwrap_or_default();
+            let mut archive = Archive::new(reader);
+            let entries = archive.entries().with_context(|| format!("Failed to read nested archive {}", name))?;
+
+            for entry_res in entries {
+                self.control.wait_to_start().await?;
+                let mut entry = entry_res.with_context(|| format!("Failed to get tar entry of {}", name))?;
+                let entry_path = format!("{}{}", dir, entry.path()?.to_string_lossy());
+                if self.is_nested_archive(&entry_path)? {
+                    let nested = format!("{}!{}", name, entry_path);
+                    let reader = self.decoders.decoder(&entry_path, &mut entry)?;
+                    if self.extract_nested(reader, &nested, &entry_path, progress, only).await? {
+                        return Ok(true);
+                    }
+                    continue;
+                }
+                let entry_size = entry.size();
+                if self.extract_entry(MemberData::Stream(&mut entry), entry_size, entry_path, progress, only).await?
+                    && (self.stopped(progress) || only.is_some())
+                {
+                    return Ok(true);
+                }
+            }
+            Ok(false)
+        })
+    }
+
+    /// Matches one tar member against the manifest and, if it is listed,
+    /// decompresses and verifies it and starts its upload. False if skipped.
+    async fn extract_entry(
+        &self,
+        data: MemberData<'_>,
+        entry_size: u64,
+        path: String,
+        progress: &mut RunProgress,
+        only: Option<&str>,
+    ) -> Result<bool> {
+        let mut lookup_name = self.decompressed_name(&path).to_string();
+        if let Some(only) = only
+            && path != only
+            && lookup_name != only
+        {
+            return Ok(false);
+        }
+
+        let matched = self.matcher.find(&lookup_name, &self.config)?;
+        if let Some(name) = &matched {
+            lookup_name.clone_from(name);
+        }
+        if self.excluded.contains(&lookup_name) {
+            info!("Skipping {} (excluded by policy)", path);
+            progress.report.excluded.push(path);
+            return Ok(false);
+        }
+        if matched.is_some() && !self.in_shard(&lookup_name) {
+            info!("Skipping {} (another shard delivers it)", path);
+            return Ok(false);
+        }
+
+        let exact = matched.is_some();
+        let expected_size = match matched {
+            Some(_) => {
+                progress.processed_files.insert(lookup_name.clone());
+                self.config.get_expected_size(&lookup_name)?.expect("matched name from the manifest")
+            },
+            None => match self.misplaced.find(&lookup_name, &self.config)? {
+                Some(expected) if self.excluded.contains(&expected) => {
+                    info!("Skipping {} (excluded by policy as {})", path, expected);
+                    progress.report.excluded.push(path);
+                    return Ok(false);
+                }
+                Some(expected) if !self.in_shard(&expected) => {
+                    info!("Skipping {} (another shard delivers it as {})", path, expected);
+                    return Ok(false);
+                }
+                Some(expected) if self.strict_paths => {
+                    progress.report.misplaced.push(Misplaced {
+                        entry: path.clone(),
+                        expected: expected.clone(),
+                        action: "rejected".to_string(),
+                    });
+                    return Err(anyhow!("{} is listed in the manifest as {} (--strict-paths)", path, expected));
+                }
+                Some(expected) if self.relocate_misplaced && !progress.processed_files.contains(&expected) => {
+                    warn!("{} is listed in the manifest as {}, delivering it there", path, expected);
+                    progress.report.misplaced.push(Misplaced {
+                        entry: path.clone(),
+                        expected: expected.clone(),
+                        action: "relocated".to_string(),
+                    });
+                    progress.processed_files.insert(expected.clone());
+                    progress.relocated.insert(expected.clone(), path.clone());
+                    lookup_name = expected;
+                    self.config.get_expected_size(&lookup_name)?.expect("manifest path from the manifest")
+                }
+                Some(expected) => {
+                    warn!("{} is listed in the manifest as {}, skipping it", path, expected);
+                    progress.report.misplaced.push(Misplaced {
+                        entry: path.clone(),
+                        expected,
+                        action: "skipped".to_string(),
+                    });
+                    return Ok(false);
+                }
+                None => {
+                    warn!("File {} (from tar: {}) not found in XML manifest, skipping", lookup_name, path);
+                    return Ok(false);
+                }
+            },
+        };
+
+        // An entry at its manifest path always wins over a relocated copy,
+        // whose file here is then this run's own to overwrite.
+        let mut replacing = exact && progress.supersede_relocated(&lookup_name, &path).await;
+
+        info!("Processing: {} (Expected size: {})", path, expected_size);
+
+        // 2. Prepare decompression
+        let mut target_name = lookup_name.clone();
+        if let Some((template, context)) = &self.name_template {
+            target_name = template.render(&target_name, context);
+        }
+
+        // Files routed to different roots by --dst-map never collide.
+        let root = self.root_for(&lookup_name)?;
+        let first = progress.targets.lock().unwrap().get(&format!("{}/{}", root, target_name)).cloned();
+        if let Some(first) = first {
+            let renamed = suffixed_name(&progress.targets.lock().unwrap(), root, &target_name);
+            let action = match self.collision_policy {
+                CollisionPolicy::Skip => "skipped".to_string(),
+                CollisionPolicy::Error => "failed".to_string(),
+                CollisionPolicy::LastWins => "overwritten".to_string(),
+                CollisionPolicy::Suffix => format!("renamed to {}", renamed),
+            };
+            warn!("Tar entries {} and {} both map to {}, {}", first, path, target_name, action);
+            progress.report.collisions.push(Collision {
+                target: format!("{}/{}", root, target_name),
+                first: first.clone(),
+                duplicate: path.clone(),
+                action,
+            });
+
+            match self.collision_policy {
+                CollisionPolicy::Skip => return Ok(false),
+                CollisionPolicy::Error => {
+                    return Err(anyhow!("Name collision on {}: {} and {}", target_name, first, path));
+                }
+                CollisionPolicy::LastWins => {
+                    // The earlier upload is finished first, so the two never
+                    // write the target at once, and its file is then this
+                    // run's own to overwrite.
+                    progress.finish_all().await;
+                    progress.report.files.retain(|file| file.entry != first);
+                    progress.report.failures.retain(|failure| failure.entry != first);
+                    replacing = true;
+                }
+                CollisionPolicy::Suffix => target_name = renamed,
+            }
+        }
+        progress.targets.lock().unwrap().insert(format!("{}/{}", root, target_name), path.clone());
+
+        let listed = format!("{}/{}{}", root, target_name, self.stored_suffix());
+        if !replacing && self.state.as_ref().is_some_and(|state| state.completed_size(&listed) == Some(expected_size)) {
+            info!("Skipping {} (completed in an earlier attempt)", path);
+            progress.report.already_present.push(listed);
+            return Ok(false);
+        }
+        if self.skip_existing && !replacing && self.split_size.is_none_or(|limit| expected_size <= limit) {
+            let existing = self.sink.status(&listed)
+                .await
+                .map_err(|e| anyhow!("Failed to stat {}: {}", listed, e))?;
+            if existing.is_some_and(|status| !status.is_dir && status.len == expected_size) {
+                info!("Skipping {} (already present as {})", path, listed);
+                progress.report.already_present.push(listed);
+                return Ok(false);
+            }
+        }
+        if let Some(leases) = &self.leases
+            && let Claim::HeldBy(holder) = leases.acquire(&listed).await?
+        {
+            info!("Skipping {} ({} holds the lease on {})", path, holder, listed);
+            progress.deferred.push(Deferred { entry: path, target: listed, holder });
+            return Ok(false);
+        }
+        // Where an earlier attempt at the run left the file partly written.
+        let mut partial = None;
+        if let Some(journal) = &self.journal {
+            match journal.lookup(&listed).await? {
+                Recorded::Committed { size, .. } if size == expected_size && !replacing => {
+                    info!("Skipping {} (committed in the journal)", path);
+                    self.release(&listed).await;
+                    progress.report.already_present.push(listed);
+                    return Ok(false);
+                }
+                Recorded::Partial { path: written } => {
+                    warn!("An earlier attempt left {} partly written, writing it again", written);
+                    progress.report.partial.push(written.clone());
+                    partial = Some(written);
+                }
+                Recorded::Committed { .. } | Recorded::Absent => {}
+            }
+        }
+        // This run's own files are rewritten whatever the policy.
+        let own = replacing || partial.is_some();
+        let mut target_path = partial.unwrap_or_else(|| listed.clone());
+        if self.if_exists != IfExists::Overwrite && !own {
+            let split = self.split_size.is_some_and(|limit| expected_size > limit);
+            if self.occupied(&target_path, split).await? {
+                match self.if_exists {
+                    IfExists::Overwrite => {}
+                    IfExists::Skip => {
+                        info!("Skipping {} ({} already exists)", path, target_path);
+                        self.release(&listed).await;
+                        progress.report.already_present.push(target_path);
+                        return Ok(false);
+                    }
+                    IfExists::Fail => {
+                        self.release(&listed).await;
+                        return Err(anyhow!("{} already exists (--if-exists fail)", target_path));
+                    }
+                    IfExists::Version => {
+                        let mut version = 1;
+                        while self.occupied(&format!("{}.{}", target_path, version), split).await? {
+                            version += 1;
+                        }
+                        let versioned = format!("{}.{}", target_path, version);
+                        warn!("{} already exists, writing {} to {}", target_path, path, versioned);
+                        target_path = versioned;
+                    }
+                }
+            }
+        }
+        if let Some(journal) = &self.journal {
+            journal.start(&listed, &target_path).await?;
+        }
+
+        // 3. Setup HDFS upload
+        let (tx, rx) = spill::channel(self.queue_depth, self.in_flight.clone(), self.spill.clone());
+
+        // A plugin or recompression changes the length of the data, so what
+        // is written is not checked against the manifest; the decompressed
+        // input is instead.
+        let recompressor = self.recompression
+            .map(|recompression| recompression.compressor())
+            .transpose()
+            .map_err(|e| anyhow!("Failed to start recompression for {}: {}", path, e))?;
+        #[cfg(feature = "wasm-plugins")]
+        let plugin = self.plugin.as_ref().map(|p| p.instantiate()).transpose()?;
+        #[cfg(feature = "wasm-plugins")]
+        let expected_written = (plugin.is_none() && recompressor.is_none()).then_some(expected_size);
+        #[cfg(not(feature = "wasm-plugins"))]
+        let expected_written = recompressor.is_none().then_some(expected_size);
+
+        let split_size = self.split_size.filter(|&limit| expected_size > limit);
+        let staged = self.atomic_uploads.as_ref().map(|run_id| Staged {
+            sink: self.sink.clone(),
+            path: format!(
+                "{}/{}/{}{}.{}",
+                root, STAGING_DIR, target_name, self.stored_suffix(), run_id
+            ),
+            split: split_size.is_some(),
+            overwrite: self.if_exists == IfExists::Overwrite || own,
+        });
+        // Split files are sized for their full parts.
+        let block_size = self.block_sizing
+            .and_then(|sizing| sizing.block_size(split_size.unwrap_or(expected_size)));
+        let mut upload = Upload {
+            sink: self.sink.clone(),
+            target_path: staged.as_ref().map_or_else(|| target_path.clone(), |staged| staged.path.clone()),
+            entry: path.clone(),
+            expected_written,
+            split_size,
+            block_size,
+            // A staged file's path is this run's own.
+            overwrite: self.if_exists == IfExists::Overwrite || staged.is_some() || own,
+            append: self.append,
+            write_opts: self.write_opts.clone(),
+            connections: self.connections.clone(),
+            retry: self.retry,
+            control: self.control.clone(),
+            throughput_floor: self.throughput_floor,
+            timeout: self.file_timeout.map(|timeout| timeout.limit),
+            stall: self.stall_timeout.map(|timeout| Arc::new(StallWatch::new(timeout.limit))),
+            stages: self.stages.clone(),
+            second_copy: None,
+            opened: Opened::new(self.sink.clone()),
+        };
+        let opened = upload.opened.clone();
+        // Members of a local tar can be read again, for a second copy or a retry.
+        let reextract = match &data {
+            MemberData::At(reader) if self.speculation.is_some() || self.retries_per_file > 0 => {
+                Some(self.reextract(reader.get_ref().clone(), &upload, &target_path, entry_size, expected_size))
+            }
+            _ => None,
+        };
+        if let (Some(reextract), Some(speculation)) = (&reextract, self.speculation)
+            && split_size.is_none()
+        {
+            upload.second_copy = Some(SecondCopy {
+                min_share: speculation.min_share,
+                window: speculation.window,
+                rates: self.upload_rates.clone(),
+                start: reextract.clone(),
+                path: format!("{}/{}/{}{}.second-copy", root, STAGING_DIR, target_name, self.stored_suffix()),
+            });
+        }
+        let upload_slots = self.upload_slots.clone();
+        let slot = match self.store_and_forward {
+            true => None,
+            false => Some(upload_slots.clone().acquire_owned().await.expect("upload slots are never closed")),
+        };
+        let upload_handle = tokio::spawn(
+            Profiled::new(catch_panics(async move {
+                // Freed when the upload ends, fails or is aborted. Spooled
+                // files queue for theirs in tar order.
+                let _slot = match slot {
+                    Some(slot) => slot,
+                    None => upload_slots.acquire_owned().await.expect("upload slots are never closed"),
+                };
+                upload.run(rx).await
+            }), self.profiler.as_ref(), || "untar;write".to_string())
+            .in_current_span(),
+        );
+
+        let decode = Decode {
+            decoders: self.decoders.clone(),
+            path: path.clone(),
+            target_path: target_path.clone(),
+            entry_size,
+            expected_size,
+            check_size: expected_written.is_none(),
+            split_size,
+            checksum_block_size: self.checksum_block_size.map(|default| block_size.unwrap_or(default)),
+            buffers: self.buffers.clone(),
+            chunk_size: self.chunk_sizing.chunk_size(expected_size),
+            expansion_limit: self.expansion_limit,
+            ratio_check: self.ratio_check,
+            recompressor,
+            #[cfg(feature = "wasm-plugins")]
+            plugin,
+            upload: Some(upload_handle.abort_handle()),
+            stages: self.stages.clone(),
+        };
+        let decode_handle = match (Handle::current().runtime_flavor(), data) {
+            (RuntimeFlavor::MultiThread, MemberData::At(reader)) => self.spawn_decode(decode, reader, tx).await,
+            (RuntimeFlavor::MultiThread, MemberData::Stream(entry)) => {
+                let (raw_tx, raw_rx) = channel();
+                let decode_handle = self.spawn_decode(decode, EntryFeed::new(raw_rx), tx).await;
+                // Only reading the tar happens here; the worker decompresses
+                // while the next members are read.
+                let mut buffer = vec![0u8; self.decoders.read_buffer_size];
+                loop {
+                    let chunk = match entry.read(&mut buffer) {
+                        Ok(0) => break,
+                        Ok(n) => {
+                            let budget = self.read_ahead
+                                .clone()
+                                .acquire_many_owned(n.min(self.read_ahead_bytes) as u32)
+                                .await
+                                .expect("read-ahead budget is never closed");
+                            Ok(RawChunk { data: buffer[..n].to_vec(), _budget: budget })
+                        }
+                        Err(e) => Err(e),
+                    };
+                    let failed = chunk.is_err();
+                    // The worker hangs up once it has given up on the member.
+                    if raw_tx.send(chunk).is_err() || failed {
+                        break;
+                    }
+                }
+                decode_handle
+            }
+            // A current-thread runtime cannot wait on a worker while it reads.
+            (_, data) => {
+                let decoded = match data {
+                    MemberData::Stream(entry) => catch_panics(decode.run(entry, tx)).await,
+                    MemberData::At(reader) => catch_panics(decode.run(reader, tx)).await,
+                };
+                tokio::spawn(std::future::ready(decoded))
+            }
+        };
+
+        progress.upload_handles.push(PendingUpload {
+            entry: path.clone(),
+            target: target_path.clone(),
+            listed,
+            decode: decode_handle,
+            handle: upload_handle,
+            lease: self.leases.clone(),
+            retries: reextract
+                .filter(|_| self.retries_per_file > 0)
+                .map(|reextract| FileRetries { reextract, retries: self.retries_per_file }),
+            state: self.state.clone(),
+            journal: self.journal.clone(),
+            staged,
+            opened,
+            resume_at: progress.resume_point.clone(),
+        });
+
+        // Pick up failures early so no new files are started after them.
+        progress.finish_completed().await;
+        // Close every open writer to release their sockets and buffers.
+        if self.resources.as_ref().is_some_and(ResourceMonitor::should_recycle) {
+            info!("Resource usage near limits, draining {} uploads", progress.upload_handles.len());
+            progress.finish_all().await;
+        }
+        Ok(true)
+    }
+
+    /// Runs `decode` on a decode worker once one is free.
+    async fn spawn_decode<R: Read + Send + 'static>(
+        &self,
+        decode: Decode,
+        input: R,
+        tx: ChunkSender,
+    ) -> JoinHandle<Result<Decoded>> {
+        let worker = self.decode_slots.clone().acquire_owned().await.expect("decode slots are never closed");
+        let handle = Handle::current();
+        let span = tracing::Span::current();
+        let profiler = self.profiler.clone();
+        let frame = self.decoders.frame(&decode.path);
+        tokio::task::spawn_blocking(move || {
+            let _worker = worker;
+            let _span = span.enter();
+            let run = catch_panics(decode.run(input, tx));
+            handle.block_on(Profiled::new(run, profiler.as_ref(), || format!("untar;decompress;{}", frame)))
+        })
+    }
+
+    /// How to extract the indexed `member` once more, as `upload` does, for
+    /// a second copy of the upload or a retry of it.
+    fn reextract(&self, member: EntryReader, upload: &Upload, target: &str, entry_size: u64, expected_size: u64) -> Reextract {
+        let sink = upload.sink.clone();
+        let target = target.to_string();
+        let path = upload.entry.clone();
+        let expected_written = upload.expected_written;
+        let split_size = upload.split_size;
+        let block_size = upload.block_size;
+        let append = upload.append;
+        let write_opts = upload.write_opts.clone();
+        let connections = upload.connections.clone();
+        let retry = upload.retry;
+        let timeout = upload.timeout;
+        let stall_timeout = upload.stall.as_ref().map(|stall| stall.limit);
+        let control = upload.control.clone();
+        let decoders = self.decoders.clone();
+        let decode_slots = self.decode_slots.clone();
+        let buffers = self.buffers.clone();
+        let queue_depth = self.queue_depth;
+        let in_flight = self.in_flight.clone();
+        let checksum_block_size = self.checksum_block_size.map(|default| block_size.unwrap_or(default));
+        let chunk_size = self.chunk_sizing.chunk_size(expected_size);
+        let expansion_limit = self.expansion_limit;
+        let ratio_check = self.ratio_check;
+        let recompression = self.recompression;
+        #[cfg(feature = "wasm-plugins")]
+        let plugin = self.plugin.clone();
+        Arc::new(move |target_path: String| {
+            let sink = sink.clone();
+            let target = target.clone();
+            let path = path.clone();
+            let write_opts = write_opts.clone();
+            let connections = connections.clone();
+            let control = control.clone();
+            let decoders = decoders.clone();
+            let decode_slots = decode_slots.clone();
+            let buffers = buffers.clone();
+            let in_flight = in_flight.clone();
+            let member = member.clone();
+            #[cfg(feature = "wasm-plugins")]
+            let plugin = plugin.clone();
+            tokio::spawn(
+                async move {
+                    let recompressor = recompression
+                        .map(|recompression| recompression.compressor())
+                        .transpose()
+                        .map_err(|e| Failure::Member(anyhow!("Failed to start recompression for {}: {}", path, e)))?;
+                    #[cfg(feature = "wasm-plugins")]
+                    let plugin = plugin.as_ref().map(|p| p.instantiate()).transpose().map_err(Failure::Member)?;
+                    let (tx, rx) = spill::channel(queue_depth, in_flight, None);
+                    // Only the first attempt counts towards the stage timings.
+                    let opened = Opened::new(sink.clone());
+                    let upload = Upload {
+                        sink,
+                        target_path: target_path.clone(),
+                        entry: path.clone(),
+                        expected_written,
+                        split_size,
+                        block_size,
+                        // Whatever is there is left by an earlier attempt.
+                        overwrite: true,
+                        append,
+                        write_opts,
+                        connections,
+                        retry,
+                        control,
+                        throughput_floor: None,
+                        timeout,
+                        stall: stall_timeout.map(|limit| Arc::new(StallWatch::new(limit))),
+                        stages: Arc::default(),
+                        second_copy: None,
+                        opened: opened.clone(),
+                    };
+                    // Stops with this task, should a second copy lose its race.
+                    let mut uploading = AbortOnDrop(tokio::spawn(catch_panics(upload.run(rx)).in_current_span()));
+                    let input = BufReader::with_capacity(decoders.read_buffer_size, member);
+                    let decode = Decode {
+                        decoders,
+                        path,
+                        // Recorded as the target wherever this copy is written.
+                        target_path: target,
+                        entry_size,
+                        expected_size,
+                        check_size: expected_written.is_none(),
+                        split_size,
+                        checksum_block_size,
+                        buffers,
+                        chunk_size,
+                        expansion_limit,
+                        ratio_check,
+                        recompressor,
+                        #[cfg(feature = "wasm-plugins")]
+                        plugin,
+                        upload: Some(uploading.0.abort_handle()),
+                        stages: Arc::default(),
+                    };
+                    let worker = decode_slots.acquire_owned().await.expect("decode slots are never closed");
+                    let handle = Handle::current();
+                    let span = tracing::Span::current();
+                    let decoded = tokio::task::spawn_blocking(move || {
+                        let _worker = worker;
+                        let _span = span.enter();
+                        handle.block_on(catch_panics(decode.run(input, tx)))
+                    })
+                    .await
+                    .unwrap_or_else(|e| Err(anyhow!("Decompression for {} did not complete: {}", target_path, e)));
+                    settle(decoded, &mut uploading.0, &target_path, &opened).await
+                }
+                .in_current_span(),
+            )
+        })
+    }
 }
 
-impl Processor {
-    pub fn new(client: Client, config: Config, hdfs_base_path: String, xml_file_path: String) -> Self {
+/// Where a member's data comes from.
+enum MemberData<'e> {
+    /// The tar stream, positioned at the member.
+    Stream(&'e mut dyn Read),
+    /// The member's own reader into a local tar, for the decode worker to read.
+    At(BufReader<EntryReader>),
+}
+
+/// Records (and logs) unexpected bytes after the last member of `archive`.
+fn record_trailing_data(archive: &str, found: bool, trailing: u64, progress: &mut RunProgress) {
+    if found {
+        warn!("{} has {} bytes of unexpected data after its last member", archive, trailing);
+        progress.report.trailing_data.push(TrailingData { archive: archive.to_string(), bytes: trailing });
+    }
+}
+
+/// Everything needed to pick and set up a member's decoder.
+#[derive(Clone)]
+struct Decoders {
+    decompress_threads: Option<usize>,
+    decompressors: Vec<ExternalDecompressor>,
+    formats: FormatMap,
+    zstd_dict: Option<Arc<DecoderDictionary<'static>>>,
+    read_buffer_size: usize,
+    lenient_z_header: bool,
+}
+
+impl Default for Decoders {
+    fn default() -> Self {
         Self {
-            client: Arc::new(client),
-            config: Arc::new(config),
-            hdfs_base_path,
-            xml_file_path,
+            decompress_threads: None,
+            decompressors: Vec::new(),
+            formats: FormatMap::default(),
+            zstd_dict: None,
+            read_buffer_size: DEFAULT_BUFFER_SIZE,
+            lenient_z_header: false,
         }
     }
+}
 
-    pub async fn process_tar<R: Read + Send + 'static>(&self, reader: R) -> Result<()> {
-        let mut archive = Archive::new(reader);
-        let entries = archive.entries().context("Failed to read tar entries")?;
-        
-        let mut upload_handles = Vec::new();
-        let mut processed_files = std::collections::HashSet::new();
+impl Decoders {
+    /// The `--decompressor` command for `path`, if one claims its suffix.
+    fn external(&self, path: &str) -> Option<&ExternalDecompressor> {
+        self.decompressors.iter().find(|external| external.strip(path).is_some())
+    }
 
-        for entry_res in entries {
-            let mut entry = entry_res.context("Failed to get tar entry")?;
-            let path = entry.path()?.to_string_lossy().to_string();
-            
-            let lookup_name = path.trim_end_matches(".gz").trim_end_matches(".Z").to_string();
-            
-            let expected_size = match self.config.get_expected_size(&lookup_name) {
-                Some(size) => {
-                    processed_files.insert(lookup_name.clone());
-                    size
-                },
-                None => {
-                    warn!("File {} (from tar: {}) not found in XML manifest, skipping", lookup_name, path);
-                    continue;
-                }
+    /// Names how `path` is decompressed, for profiles.
+    fn frame(&self, path: &str) -> String {
+        match self.external(path) {
+            Some(external) => format!("external{}", external.suffix),
+            None => match self.formats.format(path).to_possible_value() {
+                Some(value) => value.get_name().to_string(),
+                None => "unknown".to_string(),
+            },
+        }
+    }
+
+    fn options(&self) -> DecoderOptions<'_> {
+        DecoderOptions {
+            zstd_dict: self.zstd_dict.as_deref(),
+            buffer_size: self.read_buffer_size,
+            lenient_z_header: self.lenient_z_header,
+        }
+    }
+
+    /// Decoder for a member `path` found inside an archive.
+    fn decoder<'r, R: Read + 'r>(&'r self, path: &str, reader: R) -> Result<Box<dyn Read + 'r>> {
+        match self.external(path) {
+            Some(external) => Ok(Box::new(
+                ExternalDecoder::new(&external.command, reader)
+                    .map_err(|e| anyhow!("Failed to start decompressor for {}: {}", path, e))?,
+            )),
+            None => wrap_decoder_with(self.formats.format(path), reader, self.options())
+                .map_err(|e| anyhow!("Failed to start decompression for {}: {}", path, e)),
+        }
+    }
+}
+
+/// What decompressing one member found out about it.
+struct Decoded {
+    record: FileRecord,
+    ratio_anomaly: Option<RatioAnomaly>,
+}
+
+/// Decompresses and verifies one member, handing the data to its upload.
+struct Decode {
+    decoders: Arc<Decoders>,
+    path: String,
+    target_path: String,
+    /// Size of the member in the tar, and of its content per the manifest.
+    entry_size: u64,
+    expected_size: u64,
+    /// Check the decompressed size here, as the upload sees transformed data.
+    check_size: bool,
+    split_size: Option<u64>,
+    /// Block size for HDFS checksums, with `--checksums`.
+    checksum_block_size: Option<u64>,
+    buffers: Arc<BufferPool>,
+    chunk_size: usize,
+    expansion_limit: ExpansionLimit,
+    ratio_check: RatioCheck,
+    recompressor: Option<Recompressor>,
+    #[cfg(feature = "wasm-plugins")]
+    plugin: Option<PluginInstance>,
+    /// Stops the upload before it closes a file cut short by a decompression
+    /// error; `None` where the upload waits for the decode anyway.
+    upload: Option<AbortHandle>,
+    stages: Arc<StageCounters>,
+}
+
+impl Decode {
+    async fn run<R: Read>(mut self, input: R, tx: ChunkSender) -> Result<Decoded> {
+        let result = self.decode(input, &tx).await;
+        if let Err(e) = &result {
+            if let Some(upload) = &self.upload {
+                upload.abort();
+            }
+            error!("{:#}", e);
+        }
+        result
+    }
+
+    async fn decode<R: Read>(&mut self, input: R, tx: &ChunkSender) -> Result<Decoded> {
+        let path = &self.path;
+        let decoders = &*self.decoders;
+        let mut raw = HashingReader::new(TimedReader::new(input, self.stages.clone()));
+        let mut head = Vec::with_capacity(MAGIC_LEN);
+        (&mut raw).take(MAGIC_LEN as u64).read_to_end(&mut head)
+            .map_err(|e| anyhow!("Read error for {}: {}", path, e))?;
+        // The magic bytes win over the extension, unless an entry without
+        // a compression suffix already has the manifest size as stored.
+        let external = decoders.external(path);
+        let named_format = decoders.formats.format(path);
+        let format = match sniff_format(&head) {
+            Some(sniffed)
+                if external.is_none()
+                    && sniffed != named_format
+                    && !(named_format == DecompressionFormat::None && self.entry_size == self.expected_size) =>
+            {
+                warn!("{} contains {:?} data, decompressing it as such", path, sniffed);
+                sniffed
+            }
+            _ => named_format,
+        };
+        let input = Cursor::new(head).chain(&mut raw);
+        let compressed = external.is_some() || format != DecompressionFormat::None;
+        let mut decoder: Box<dyn Read + '_> = match (external, decoders.decompress_threads) {
+            (Some(_), _) => decoders.decoder(path, input)?,
+            (None, Some(threads)) if format == DecompressionFormat::Gzip && self.entry_size >= PARALLEL_GZIP_MIN_SIZE => {
+                Box::new(ParallelGzipDecoder::new(input, threads))
+            }
+            _ => wrap_decoder_with(format, input, decoders.options())
+                .map_err(|e| anyhow!("Failed to start decompression for {}: {}", path, e))?,
+        };
+        let mut decompressed = 0u64;
+        let mut hasher = Sha256::new();
+        let mut checksum = self.checksum_block_size
+            .filter(|_| self.split_size.is_none())
+            .map(HdfsChecksum::new);
+        let mut parts = self.split_size.map(|limit| PartHasher::new(limit, self.checksum_block_size));
+        let mut stored = 0u64;
+        // Time spent decompressing and checking, reads of the raw data included.
+        let mut busy = Duration::ZERO;
+        loop {
+            let mut buffer = self.buffers.get(self.chunk_size).await;
+            let started = Instant::now();
+            let n = decoder.read(&mut buffer).map_err(|e| anyhow!("Decompression error for {}: {}", path, e))?;
+            if n == 0 {
+                busy += started.elapsed();
+                break;
+            }
+            let chunk = buffer.freeze(n);
+            decompressed += n as u64;
+            self.stages.record(Stage::Decompress, n as u64, Duration::ZERO);
+            if compressed {
+                self.expansion_limit.check(path, self.entry_size, decompressed)?;
+            }
+            hasher.update(&chunk);
+            #[cfg(feature = "wasm-plugins")]
+            let chunk = match &mut self.plugin {
+                Some(plugin) => Bytes::from(plugin.transform(&chunk)
+                    .with_context(|| format!("Plugin error for {}", path))?),
+                None => chunk,
+            };
+            let chunk = match &mut self.recompressor {
+                Some(recompressor) => Bytes::from(recompressor.compress(&chunk)
+                    .map_err(|e| anyhow!("Recompression error for {}: {}", path, e))?),
+                None => chunk,
+            };
+            busy += started.elapsed();
+            if chunk.is_empty() {
+                continue;
+            }
+            stored += chunk.len() as u64;
+            if let Some(parts) = &mut parts {
+                parts.update(&chunk);
+            }
+            if let Some(checksum) = &mut checksum {
+                checksum.update(&chunk);
+            }
+            if tx.send(chunk).await.is_err() {
+                break;
+            }
+        }
+        #[cfg(feature = "wasm-plugins")]
+        if let Some(plugin) = &mut self.plugin {
+            let tail = plugin.finish().with_context(|| format!("Plugin error for {}", path))?;
+            let tail = match &mut self.recompressor {
+                Some(recompressor) => recompressor.compress(&tail)
+                    .map_err(|e| anyhow!("Recompression error for {}: {}", path, e))?,
+                None => tail,
             };
+            if !tail.is_empty() {
+                stored += tail.len() as u64;
+                if let Some(parts) = &mut parts {
+                    parts.update(&tail);
+                }
+                if let Some(checksum) = &mut checksum {
+                    checksum.update(&tail);
+                }
+                let _ = tx.send(Bytes::from(tail)).await;
+            }
+        }
+        let recompressed = self.recompressor.is_some();
+        if let Some(recompressor) = self.recompressor.take() {
+            let tail = recompressor.finish()
+                .map_err(|e| anyhow!("Recompression error for {}: {}", path, e))?;
+            stored += tail.len() as u64;
+            if let Some(parts) = &mut parts {
+                parts.update(&tail);
+            }
+            if let Some(checksum) = &mut checksum {
+                checksum.update(&tail);
+            }
+            let _ = tx.send(Bytes::from(tail)).await;
+        }
+        drop(decoder);
+        // The bytes were counted as they came.
+        self.stages.record(Stage::Decompress, 0, busy.saturating_sub(raw.get_ref().elapsed));
 
-            info!("Processing: {} (Expected size: {})", path, expected_size);
-
-            // 2. Prepare decompression
-            let format = get_format(&path);
-            let target_name = path.trim_end_matches(".gz").trim_end_matches(".Z").to_string();
-            let target_path = format!("{}/{}", self.hdfs_base_path, target_name);
-            
-            // 3. Setup HDFS upload
-            let (tx, mut rx) = mpsc::channel::<Vec<u8>>(16);
-            let client = self.client.clone();
-            let target_path_clone = target_path.clone();
-            let path_clone = path.clone();
-            
-            let upload_handle = tokio::spawn(async move {
-                let write_options = WriteOptions::default().overwrite(true);
-                let mut writer = client.create(&target_path_clone, write_options)
-                    .await
-                    .map_err(|e| anyhow!("Failed to create HDFS file {}: {}", target_path_clone, e))?;
-                let mut total_written = 0u64;
-                
-                while let Some(chunk) = rx.recv().await {
-                    total_written += chunk.len() as u64;
-                    writer.write(Bytes::from(chunk)).await
-                        .map_err(|e| anyhow!("Write error to HDFS for {}: {}", target_path_clone, e))?;
-                }
-                
-                writer.close().await
-                    .map_err(|e| anyhow!("Close error for HDFS file {}: {}", target_path_clone, e))?;
-                
-                if total_written != expected_size {
-                    return Err(anyhow!("Size mismatch for {}: expected {}, got {}", path_clone, expected_size, total_written));
+        let (raw_size, raw_sha256) = raw.finish()
+            .map_err(|e| anyhow!("Read error for {}: {}", path, e))?;
+        if self.check_size && decompressed != self.expected_size {
+            return Err(anyhow!("Size mismatch for {}: expected {}, got {}", path, self.expected_size, decompressed));
+        }
+        let ratio_anomaly = compressed
+            .then(|| self.ratio_check.check(raw_size, decompressed))
+            .flatten()
+            .map(|ratio| {
+                warn!(
+                    "{} decompressed from {} to {} bytes (ratio {:.4}), it may be corrupt",
+                    path, raw_size, decompressed, ratio
+                );
+                RatioAnomaly { entry: path.clone(), compressed: raw_size, decompressed, ratio }
+            });
+        let record = FileRecord {
+            entry: path.clone(),
+            target: self.target_path.clone(),
+            raw_size,
+            raw_sha256,
+            size: decompressed,
+            sha256: hex::encode(hasher.finalize()),
+            stored_size: recompressed.then_some(stored),
+            checksum: checksum.map(HdfsChecksum::finish),
+            parts: parts
+                .map(|parts| parts.finish(&self.target_path))
+                .unwrap_or_default(),
+        };
+        Ok(Decoded { record, ratio_anomaly })
+    }
+}
+
+/// Records the reads through it as the read stage, and adds up their time.
+struct TimedReader<R> {
+    inner: R,
+    stages: Arc<StageCounters>,
+    elapsed: Duration,
+}
+
+impl<R: Read> TimedReader<R> {
+    fn new(inner: R, stages: Arc<StageCounters>) -> Self {
+        Self { inner, stages, elapsed: Duration::ZERO }
+    }
+}
+
+impl<R: Read> Read for TimedReader<R> {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        let started = Instant::now();
+        let n = self.inner.read(buf)?;
+        let elapsed = started.elapsed();
+        self.stages.record(Stage::Read, n as u64, elapsed);
+        self.elapsed += elapsed;
+        Ok(n)
+    }
+}
+
+/// Member data read ahead of its decode worker, holding its share of the
+/// read-ahead budget until the worker is done with it.
+struct RawChunk {
+    data: Vec<u8>,
+    _budget: OwnedSemaphorePermit,
+}
+
+/// Reads the raw member data the tar reader sends a decode worker; a read
+/// error on the tar side is passed on as the worker's read error.
+struct EntryFeed {
+    chunks: Receiver<io::Result<RawChunk>>,
+    chunk: Option<RawChunk>,
+    pos: usize,
+}
+
+impl EntryFeed {
+    fn new(chunks: Receiver<io::Result<RawChunk>>) -> Self {
+        Self { chunks, chunk: None, pos: 0 }
+    }
+}
+
+impl Read for EntryFeed {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        loop {
+            if let Some(chunk) = &self.chunk
+                && self.pos < chunk.data.len()
+            {
+                let n = buf.len().min(chunk.data.len() - self.pos);
+                buf[..n].copy_from_slice(&chunk.data[self.pos..self.pos + n]);
+                self.pos += n;
+                return Ok(n);
+            }
+            // Returns the previous chunk's budget before waiting for the next.
+            self.chunk = None;
+            match self.chunks.recv() {
+                Ok(chunk) => {
+                    self.chunk = Some(chunk?);
+                    self.pos = 0;
                 }
-                
-                Ok::<(), anyhow::Error>(())
+                // The whole member has been sent.
+                Err(_) => return Ok(0),
+            }
+        }
+    }
+}
+
+/// One decode worker per CPU, unless `--decompress-workers` says otherwise.
+fn default_decompress_workers() -> usize {
+    std::thread::available_parallelism().map_or(1, usize::from)
+}
+
+/// Decompressed chunks held in memory at once, unless `--max-buffers` says otherwise.
+const DEFAULT_MAX_BUFFERS: usize = 256;
+
+/// Decompressed chunks each upload may queue, unless `--queue-depth` says otherwise.
+const DEFAULT_QUEUE_DEPTH: usize = 16;
+
+/// Raw member bytes the tar reader may queue for the decode workers, unless
+/// `--read-ahead` says otherwise.
+const DEFAULT_READ_AHEAD: usize = 64 << 20;
+
+/// Smaller gzip members are not worth the threads of `--decompress-threads`.
+const PARALLEL_GZIP_MIN_SIZE: u64 = 64 << 20;
+
+/// Decompressed bytes handed to an upload at a time, unless `--chunk-size` says otherwise.
+const DEFAULT_CHUNK_SIZE: usize = 64 * 1024;
+
+/// Data bytes in an HDFS write packet (`dfs.client-write-packet-size`).
+const HDFS_PACKET_SIZE: u64 = 64 * 1024;
+
+/// Chunks adaptive sizing aims to split a file into.
+const CHUNKS_PER_FILE: u64 = 64;
+
+/// Files uploaded at once, unless `--threads` says otherwise.
+const DEFAULT_UPLOAD_CONCURRENCY: usize = 10;
+
+/// Destination paths looked up at once by a dry run.
+const PLAN_CONCURRENCY: usize = 32;
+
+/// Written to each root with `--checksums`.
+const CHECKSUMS_FILE: &str = "CHECKSUMS";
+
+/// Directory under a root that atomic uploads are written to first.
+pub const STAGING_DIR: &str = ".untar-tmp";
+
+/// A `CHECKSUMS` line in `hdfs dfs -checksum` format: path, algorithm and digest, tab-separated.
+fn checksum_line(path: &str, checksum: Option<&FileChecksum>) -> Option<String> {
+    checksum.map(|checksum| format!("{}\t{}\t{}\n", path, checksum.algorithm, checksum.checksum))
+}
+
+/// The record for `root`, added on first use.
+fn root_record<'a>(roots: &'a mut Vec<RootRecord>, root: &str, xml_filename: &str) -> &'a mut RootRecord {
+    let index = match roots.iter().position(|r| r.path == root) {
+        Some(index) => index,
+        None => {
+            roots.push(RootRecord {
+                path: root.to_string(),
+                expected: 0,
+                delivered: 0,
+                bytes: 0,
+                deleted: 0,
+                receipt: format!("{}/{}", root, xml_filename),
             });
+            roots.len() - 1
+        }
+    };
+    &mut roots[index]
+}
+
+/// One file's upload, fed decompressed chunks by the tar reader.
+struct Upload {
+    sink: Sink,
+    target_path: String,
+    /// Tar member being uploaded, for error messages.
+    entry: String,
+    /// Bytes the upload must add up to, unless a plugin changes the length.
+    expected_written: Option<u64>,
+    /// Roll over to a new part file after this many bytes.
+    split_size: Option<u64>,
+    /// HDFS block size, if not the cluster default.
+    block_size: Option<u64>,
+    /// Whether to replace a file already at the path.
+    overwrite: bool,
+    /// Whether to add to the end of the file at the path instead.
+    append: bool,
+    write_opts: Arc<[WriteOpt]>,
+    connections: Option<Arc<Semaphore>>,
+    retry: RetryPolicy,
+    control: Control,
+    throughput_floor: Option<ThroughputFloor>,
+    /// Aborts the upload, second copy included, once it has run this long.
+    timeout: Option<Duration>,
+    stall: Option<Arc<StallWatch>>,
+    stages: Arc<StageCounters>,
+    /// Raced against this upload once it straggles.
+    second_copy: Option<SecondCopy>,
+    /// Files opened for writing so far.
+    opened: Opened,
+}
+
+/// Files an upload has opened for writing, each with its length before if
+/// it was appended to, so they can be taken back when the member turns
+/// out to be bad after the upload has started.
+#[derive(Clone)]
+struct Opened {
+    sink: Sink,
+    files: Arc<Mutex<Vec<(String, Option<u64>)>>>,
+}
+
+impl Opened {
+    fn new(sink: Sink) -> Self {
+        Self { sink, files: Arc::default() }
+    }
+
+    fn push(&self, path: &str, appended_to: Option<u64>) {
+        self.files.lock().unwrap().push((path.to_string(), appended_to));
+    }
 
-            // Reading and Decompressing (Streaming into channel)
-            let mut decoder = wrap_decoder(format, &mut entry);
-            let mut buffer = vec![0u8; 65536];
-            loop {
-                match decoder.read(&mut buffer) {
-                    Ok(0) => break,
-                    Ok(n) => {
-                        if tx.send(buffer[..n].to_vec()).await.is_err() {
-                            break; 
+    /// Leaves `path` alone from now on.
+    fn forget(&self, path: &str) {
+        self.files.lock().unwrap().retain(|(opened, _)| opened != path);
+    }
+
+    /// Removes the files the upload created and cuts the one it appended to
+    /// back to its length before, so no partial or unverified data is left
+    /// at the target. Only called once the upload has stopped.
+    async fn take_back(&self) {
+        let files = std::mem::take(&mut *self.files.lock().unwrap());
+        for (path, appended_to) in files {
+            match appended_to {
+                Some(len) => cut_back(&self.sink, &path, len).await,
+                None => match self.sink.delete(&path).await {
+                    Ok(()) => info!("Removed {}, left partly written by a failed member", path),
+                    Err(e) => error!("Failed to remove {}, left partly written by a failed member: {}", path, e),
+                },
+            }
+        }
+    }
+}
+
+/// Starts extracting a member once more into the given path, resolving to
+/// what was decoded and the bytes written there.
+type Reextract = Arc<dyn Fn(String) -> JoinHandle<Result<(Decoded, u64), Failure>> + Send + Sync>;
+
+/// A second copy of an upload, started if the upload falls behind.
+struct SecondCopy {
+    /// Share of the median throughput below which the upload straggles.
+    min_share: f64,
+    window: Duration,
+    rates: Arc<UploadRates>,
+    start: Reextract,
+    /// Where the copy is written, under `STAGING_DIR` so it cannot clash with a member.
+    path: String,
+}
+
+/// Most windows `UploadRates` keeps.
+const UPLOAD_RATE_SAMPLES: usize = 1024;
+/// Windows needed before a median is trusted to tell stragglers.
+const MIN_UPLOAD_RATE_SAMPLES: usize = 8;
+
+/// Throughput of the latest windows of a run's uploads, in MB/s.
+#[derive(Default)]
+struct UploadRates {
+    rates: Mutex<VecDeque<f64>>,
+}
+
+impl UploadRates {
+    fn record(&self, rate: f64) {
+        let mut rates = self.rates.lock().unwrap();
+        if rates.len() == UPLOAD_RATE_SAMPLES {
+            rates.pop_front();
+        }
+        rates.push_back(rate);
+    }
+
+    fn median(&self) -> Option<f64> {
+        let mut rates: Vec<f64> = self.rates.lock().unwrap().iter().copied().collect();
+        if rates.len() < MIN_UPLOAD_RATE_SAMPLES {
+            return None;
+        }
+        rates.sort_by(f64::total_cmp);
+        Some(rates[rates.len() / 2])
+    }
+}
+
+/// Where an upload with a second copy reports its throughput, and says
+/// when it straggles.
+struct Straggling<'a> {
+    min_share: f64,
+    window: Duration,
+    rates: &'a UploadRates,
+    stalled: &'a Notify,
+    /// Tells the upload to close its file and stop: a second copy finished first.
+    superseded: &'a Notify,
+}
+
+impl Straggling<'_> {
+    fn record(&self, throughput: f64) {
+        if self.rates.median().is_some_and(|median| throughput < median * self.min_share) {
+            self.stalled.notify_one();
+        }
+        self.rates.record(throughput);
+    }
+}
+
+/// When an upload last moved data, for its stall watchdog.
+struct StallWatch {
+    limit: Duration,
+    last: Mutex<Instant>,
+}
+
+impl StallWatch {
+    fn new(limit: Duration) -> Self {
+        Self { limit, last: Mutex::new(Instant::now()) }
+    }
+
+    fn touch(&self) {
+        *self.last.lock().unwrap() = Instant::now();
+    }
+
+    /// Resolves to how long the upload has been idle once that reaches the
+    /// limit. Time paused with frozen writes does not count.
+    async fn stalled(&self, control: &Control) -> Duration {
+        loop {
+            let idle = self.last.lock().unwrap().elapsed();
+            if idle < self.limit {
+                tokio::time::sleep(self.limit - idle).await;
+            } else if matches!(control.state(), RunState::Paused { freeze_writes: true }) {
+                self.touch();
+            } else {
+                return idle;
+            }
+        }
+    }
+}
+
+/// Aborts the task when dropped, so a second copy stops with its upload.
+struct AbortOnDrop<T>(JoinHandle<T>);
+
+impl<T> Drop for AbortOnDrop<T> {
+    fn drop(&mut self) {
+        self.0.abort();
+    }
+}
+
+/// Takes a failed increment back off the file at `path` it was appended to,
+/// so a rerun appends it whole rather than after a partial copy.
+async fn cut_back(sink: &Sink, path: &str, len: u64) {
+    match sink.truncate(path, len).await {
+        Ok(()) => info!("Cut {} back to its {} bytes before the failed append", path, len),
+        Err(e) => error!(
+            "Failed to cut {} back to {} bytes after a failed append; it holds part of the increment: {}",
+            path, len, e
+        ),
+    }
+}
+
+/// Waits for the upload of a member whose decode ended with `decoded`. A
+/// failed decode has stopped its upload, unless the upload failed first,
+/// and what the upload had written is then taken back out of `opened`.
+async fn settle(
+    decoded: Result<Decoded>,
+    upload: &mut JoinHandle<Result<u64>>,
+    target: &str,
+    opened: &Opened,
+) -> Result<(Decoded, u64), Failure> {
+    if decoded.is_err() {
+        upload.abort();
+    }
+    let uploaded = upload.await;
+    // Whatever the upload made of it, a member that failed to decode leaves nothing behind.
+    if decoded.is_err() {
+        opened.take_back().await;
+    }
+    match (decoded, uploaded) {
+        (Ok(decoded), Ok(Ok(written))) => Ok((decoded, written)),
+        (_, Ok(Err(e))) => Err(Failure::Upload(e)),
+        (Err(e), _) => Err(Failure::Member(e)),
+        (Ok(_), Err(e)) => Err(Failure::Upload(anyhow!("Upload task for {} did not complete: {}", target, e))),
+    }
+}
+
+impl Upload {
+    /// Writes the chunks from `rx`, giving up once that takes longer than
+    /// the timeout or stalls.
+    async fn run(self, rx: ChunkReceiver) -> Result<u64> {
+        let target = self.target_path.clone();
+        let timeout = self.timeout;
+        let stall = self.stall.clone();
+        let control = self.control.clone();
+        let watched = async {
+            let Some(stall) = &stall else {
+                return self.deliver(rx).await;
+            };
+            tokio::select! {
+                written = self.deliver(rx) => written,
+                idle = stall.stalled(&control) => {
+                    warn!("Upload of {} made no progress for {:?}, aborting it", target, idle);
+                    Err(FileStalled { target: target.clone(), idle }.into())
+                }
+            }
+        };
+        let Some(limit) = timeout else {
+            return watched.await;
+        };
+        let result = tokio::time::timeout(limit, watched).await;
+        result.unwrap_or_else(|_| {
+            warn!("Upload of {} timed out after {:?}, aborting it", target, limit);
+            Err(FileTimedOut { target: target.clone(), limit }.into())
+        })
+    }
+
+    /// Writes the chunks from `rx`. With a second copy, starts it once the
+    /// upload straggles and keeps whichever copy finishes first.
+    async fn deliver(mut self, mut rx: ChunkReceiver) -> Result<u64> {
+        let Some(SecondCopy { min_share, window, rates, start, path: copy_path }) = self.second_copy.take() else {
+            return self.write(&mut rx, None).await;
+        };
+        let stalled = Notify::new();
+        let superseded = Notify::new();
+        let straggling = Straggling { min_share, window, rates: &rates, stalled: &stalled, superseded: &superseded };
+        let mut write = Box::pin(self.write(&mut rx, Some(straggling)));
+        tokio::select! {
+            written = &mut write => return written,
+            _ = stalled.notified() => {}
+        }
+
+        warn!("Upload of {} is straggling, racing a second copy to {}", self.target_path, copy_path);
+        // Taken back with the rest should the member turn out bad.
+        self.opened.push(&copy_path, None);
+        let mut copy = AbortOnDrop(start(copy_path.clone()));
+        let copied = tokio::select! {
+            written = &mut write => {
+                copy.0.abort();
+                let _ = (&mut copy.0).await;
+                self.remove_copy(&copy_path).await;
+                return written;
+            }
+            copied = &mut copy.0 => match copied {
+                Ok(copied) => copied.map(|(_, written)| written).map_err(Failure::into_error),
+                Err(e) => Err(anyhow!("Second copy of {} did not complete: {}", self.target_path, e)),
+            },
+        };
+        match copied {
+            Ok(written) => {
+                // The first upload closes its file before it is replaced.
+                superseded.notify_one();
+                if let Ok(first) = write.await {
+                    self.remove_copy(&copy_path).await;
+                    return Ok(first);
+                }
+                if let Err(e) = self.sink.delete(&self.target_path).await {
+                    warn!("Failed to remove {}, left partly written by its first upload: {}", self.target_path, e);
+                }
+                self.sink.rename(&copy_path, &self.target_path, true).await
+                    .map_err(|e| anyhow!("Failed to move {} to {}: {}", copy_path, self.target_path, e))?;
+                // The target now holds the verified copy, not the first upload's data.
+                self.opened.forget(&self.target_path);
+                info!("Second copy of {} finished first, keeping it", self.target_path);
+                // The decode worker still hashes the rest of the member.
+                while rx.recv().await.is_some() {}
+                Ok(written)
+            }
+            Err(e) => {
+                warn!("Second copy of {} failed, keeping the first: {:#}", self.target_path, e);
+                self.remove_copy(&copy_path).await;
+                write.await
+            }
+        }
+    }
+
+    async fn remove_copy(&self, copy_path: &str) {
+        if let Err(e) = self.sink.delete(copy_path).await {
+            warn!("Failed to remove second copy {}: {}", copy_path, e);
+        }
+    }
+
+    async fn write(&self, rx: &mut ChunkReceiver, straggling: Option<Straggling<'_>>) -> Result<u64> {
+        // Length of the file appended to, to check only this data landed.
+        let appended_to = match self.append {
+            true => self.length(&self.target_path).await?,
+            false => None,
+        };
+        let written = self.write_parts(rx, straggling, appended_to).await;
+        if written.is_err()
+            && let Some(before) = appended_to
+        {
+            cut_back(&self.sink, &self.target_path, before).await;
+        }
+        written
+    }
+
+    async fn write_parts(
+        &self,
+        rx: &mut ChunkReceiver,
+        straggling: Option<Straggling<'_>>,
+        appended_to: Option<u64>,
+    ) -> Result<u64> {
+        // Held until the last part is closed, so the parts of a split file
+        // take over each other's connection instead of queueing again.
+        let _connection = connect(self.connections.as_ref()).await;
+        let mut part = 0usize;
+        let mut part_path = match self.split_size {
+            Some(_) => part_file_name(&self.target_path, part),
+            None => self.target_path.clone(),
+        };
+        self.made_progress();
+        let mut writer = self.create(&part_path).await?;
+        self.opened.push(&part_path, appended_to);
+        let mut part_written = 0u64;
+        let mut total_written = 0u64;
+        let mut window_bytes = 0u64;
+        let mut window_time = Duration::ZERO;
+
+        loop {
+            let chunk = match &straggling {
+                Some(straggling) => tokio::select! {
+                    biased;
+                    _ = straggling.superseded.notified() => {
+                        // Closed rather than dropped, so its lease is released.
+                        if let Err(e) = writer.close().await {
+                            warn!("Failed to close {} after its second copy won: {}", part_path, e);
                         }
+                        return Err(anyhow!("Upload of {} gave way to its second copy", self.target_path));
                     }
-                    Err(e) => {
-                        return Err(anyhow!("Decompression error for {}: {}", path, e));
-                    }
+                    chunk = rx.recv() => chunk,
+                },
+                None => rx.recv().await,
+            };
+            let Some(chunk) = chunk else {
+                break;
+            };
+            let mut chunk = chunk.map_err(|e| anyhow!("Failed to read spilled data for {}: {}", self.target_path, e))?;
+            self.made_progress();
+            self.control.wait_to_write().await?;
+            let written = chunk.len() as u64;
+            total_written += written;
+            window_bytes += written;
+            let write_started = Instant::now();
+
+            while !chunk.is_empty() {
+                if let Some(limit) = self.split_size
+                    && part_written == limit
+                {
+                    self.close(&mut writer, &part_path, part_written).await?;
+                    part += 1;
+                    part_written = 0;
+                    part_path = part_file_name(&self.target_path, part);
+                    writer = self.create(&part_path).await?;
+                    self.opened.push(&part_path, None);
                 }
+                let take = match self.split_size {
+                    Some(limit) => chunk.len().min((limit - part_written) as usize),
+                    None => chunk.len(),
+                };
+                writer.write(chunk.split_to(take)).await
+                    .map_err(|e| anyhow!("Write error to HDFS for {}: {}", part_path, e))?;
+                self.made_progress();
+                part_written += take as u64;
             }
-            drop(tx); 
+            let write_time = write_started.elapsed();
+            self.stages.record(Stage::Write, written, write_time);
+            window_time += write_time;
 
-            upload_handles.push(upload_handle);
-            
-            // Optional: throttle number of concurrent uploads if needed
-            if upload_handles.len() >= 10 {
-                // Wait for the oldest one to finish to keep concurrency manageable
-                upload_handles.remove(0).await??;
+            let window = self.throughput_floor
+                .map(|floor| floor.window)
+                .or(straggling.as_ref().map(|straggling| straggling.window));
+            if let Some(window) = window
+                && window_time >= window
+            {
+                let throughput = window_bytes as f64 / 1_000_000.0 / window_time.as_secs_f64();
+                if let Some(floor) = self.throughput_floor
+                    && throughput < floor.min_mb_per_sec
+                {
+                    return Err(anyhow!(
+                        "Throughput for {} fell to {:.2} MB/s, below the {:.2} MB/s floor; aborting file",
+                        self.target_path, throughput, floor.min_mb_per_sec
+                    ));
+                }
+                if let Some(straggling) = &straggling {
+                    straggling.record(throughput);
+                }
+                window_bytes = 0;
+                window_time = Duration::ZERO;
             }
         }
 
-        // Wait for remaining uploads
-        for handle in upload_handles {
-            handle.await??;
-        }
+        self.close(&mut writer, &part_path, appended_to.unwrap_or_default() + part_written).await?;
 
-        // Final validation: check if all XML entries were found in TAR
-        for filename in self.config.file_map.keys() {
-            if !processed_files.contains(filename) {
-                error!("File {} listed in XML was not found in TAR", filename);
-                return Err(anyhow!("Missing file in TAR: {}", filename));
+        if let Some(expected_size) = self.expected_written
+            && total_written != expected_size
+        {
+            return Err(anyhow!("Size mismatch for {}: expected {}, got {}", self.entry, expected_size, total_written));
+        }
+        if let Some(before) = appended_to {
+            let len = self.length(&part_path).await?.unwrap_or_default();
+            if len != before + total_written {
+                return Err(anyhow!(
+                    "{} is {} bytes after appending {} to its {}",
+                    part_path, len, total_written, before
+                ));
             }
         }
 
-        // Upload XML file to HDFS
-        info!("Uploading XML file to HDFS");
-        let xml_content = std::fs::read(&self.xml_file_path)
-            .map_err(|e| anyhow!("Failed to read XML file {}: {}", self.xml_file_path, e))?;
-        
-        let xml_filename = std::path::Path::new(&self.xml_file_path)
-            .file_name()
-            .and_then(|name| name.to_str())
-            .ok_or_else(|| anyhow!("Invalid XML file path"))?;
-        
-        let xml_target_path = format!("{}/{}", self.hdfs_base_path, xml_filename);
-        let write_options = WriteOptions::default().overwrite(true);
-        let mut writer = self.client.create(&xml_target_path, write_options)
+        Ok(total_written)
+    }
+
+    fn made_progress(&self) {
+        if let Some(stall) = &self.stall {
+            stall.touch();
+        }
+    }
+
+    async fn create(&self, path: &str) -> Result<SinkWriter> {
+        if self.append {
+            return with_retries_if(&format!("Opening {} to append", path), self.retry, is_transient, || self.sink.append(path))
+                .await
+                .map_err(|e| anyhow!("Failed to open HDFS file {} to append to it: {}", path, e));
+        }
+        let mut write_options = WriteOpt::apply_all(&self.write_opts, WriteOptions::default().overwrite(self.overwrite));
+        if let Some(block_size) = self.block_size {
+            write_options = write_options.block_size(block_size);
+        }
+        with_retries_if(&format!("Creating {}", path), self.retry, is_transient, || self.sink.create(path, write_options.clone()))
+            .await
+            .map_err(|e| anyhow!("Failed to create HDFS file {}: {}", path, e))
+    }
+
+    /// Length of the file at `path`, if there is one.
+    async fn length(&self, path: &str) -> Result<Option<u64>> {
+        let status = self.sink.status(path)
             .await
-            .map_err(|e| anyhow!("Failed to create HDFS file {}: {}", xml_target_path, e))?;
-        
-        writer.write(Bytes::from(xml_content)).await
-            .map_err(|e| anyhow!("Write error to HDFS for {}: {}", xml_target_path, e))?;
-        
-        writer.close().await
-            .map_err(|e| anyhow!("Close error for HDFS file {}: {}", xml_target_path, e))?;
-        
-        info!("XML file uploaded successfully to {}", xml_target_path);
+            .map_err(|e| anyhow!("Failed to stat {}: {}", path, e))?;
+        Ok(status.map(|status| status.len))
+    }
 
+    /// Closes `writer`, retrying transient failures such as a NameNode that
+    /// is slow to complete the last block. A close that had to be retried
+    /// may have lost data it was flushing, so the length is checked after
+    /// against `expected_len`. Writes are not retried: data a broken writer
+    /// had buffered is gone.
+    async fn close(&self, writer: &mut SinkWriter, path: &str, expected_len: u64) -> Result<()> {
+        let mut attempt = 0;
+        loop {
+            match writer.close().await {
+                Ok(()) => break,
+                Err(e) if attempt < self.retry.retries && is_transient(&e) => {
+                    let delay = jittered_backoff(attempt, self.retry.backoff, MAX_BACKOFF);
+                    warn!(
+                        "Closing {} failed ({}), retrying in {:.1}s ({}/{})",
+                        path, e, delay.as_secs_f64(), attempt + 1, self.retry.retries
+                    );
+                    tokio::time::sleep(delay).await;
+                    attempt += 1;
+                }
+                Err(e) => return Err(anyhow!("Close error for HDFS file {}: {}", path, e)),
+            }
+        }
+        if attempt > 0 {
+            let len = self.sink.status(path)
+                .await
+                .map_err(|e| anyhow!("Failed to stat {} after closing it: {}", path, e))?
+                .map(|status| status.len);
+            if len != Some(expected_len) {
+                return Err(anyhow!(
+                    "{} holds {} of the {} bytes expected after its close was retried",
+                    path, len.unwrap_or_default(), expected_len
+                ));
+            }
+        }
         Ok(())
     }
 }
+
+/// Waits for a free connection, if they are limited.
+async fn connect(connections: Option<&Arc<Semaphore>>) -> Option<OwnedSemaphorePermit> {
+    match connections {
+        Some(connections) => Some(connections.clone().acquire_owned().await.expect("connections are never closed")),
+        None => None,
+    }
+}
+
+/// Name of part `index` of a split file.
+fn part_file_name(target_path: &str, index: usize) -> String {
+    format!("{}.part-{:05}", target_path, index)
+}
+
+/// Digests the data of a split file part by part, rolling over at the same
+/// boundaries as `Upload`.
+struct PartHasher {
+    split_size: u64,
+    hasher: Sha256,
+    /// Block size of the parts, when their HDFS checksums are wanted.
+    checksum_block_size: Option<u64>,
+    checksum: Option<HdfsChecksum>,
+    len: u64,
+    done: Vec<(u64, String, Option<FileChecksum>)>,
+}
+
+impl PartHasher {
+    fn new(split_size: u64, checksum_block_size: Option<u64>) -> Self {
+        Self {
+            split_size,
+            hasher: Sha256::new(),
+            checksum_block_size,
+            checksum: checksum_block_size.map(HdfsChecksum::new),
+            len: 0,
+            done: Vec::new(),
+        }
+    }
+
+    fn update(&mut self, mut data: &[u8]) {
+        while !data.is_empty() {
+            if self.len == self.split_size {
+                self.finish_part();
+            }
+            let take = data.len().min((self.split_size - self.len) as usize);
+            self.hasher.update(&data[..take]);
+            if let Some(checksum) = &mut self.checksum {
+                checksum.update(&data[..take]);
+            }
+            self.len += take as u64;
+            data = &data[take..];
+        }
+    }
+
+    fn finish_part(&mut self) {
+        let hasher = std::mem::take(&mut self.hasher);
+        let checksum = std::mem::replace(&mut self.checksum, self.checksum_block_size.map(HdfsChecksum::new));
+        self.done.push((self.len, hex::encode(hasher.finalize()), checksum.map(HdfsChecksum::finish)));
+        self.len = 0;
+    }
+
+    fn finish(mut self, target_path: &str) -> Vec<PartRecord> {
+        self.finish_part();
+        self.done
+            .into_iter()
+            .enumerate()
+            .map(|(index, (size, sha256, checksum))| PartRecord {
+                path: part_file_name(target_path, index),
+                size,
+                sha256,
+                checksum,
+            })
+            .collect()
+    }
+}
+
+/// First free `<name>.<n>` under `root` among the targets already used in
+/// this run.
+fn suffixed_name(targets: &HashMap<String, String>, root: &str, name: &str) -> String {
+    let mut index = 1;
+    while targets.contains_key(&format!("{}/{}.{}", root, name, index)) {
+        index += 1;
+    }
+    format!("{}.{}", name, index)
+}
@@ -0,0 +1,266 @@
+use std::path::Path;
+use std::time::Duration;
+use anyhow::{Context, Result};
+use quick_xml::se::to_string;
+use serde::{Deserialize, Deserializer, Serialize};
+use tracing::warn;
+
+use crate::digest::FileChecksum;
+use crate::telemetry::ResourceStatus;
+
+/// Summary of a run, optionally written next to the logs as XML.
+#[derive(Debug, Default, Serialize)]
+#[serde(rename = "untar-report")]
+pub struct RunReport {
+    pub run_id: String,
+    #[serde(rename = "archive", default)]
+    pub archives: Vec<String>,
+    pub duration_ms: u64,
+    pub bytes_written: u64,
+    /// Bytes that went through the disk spill because the sink fell behind.
+    pub spilled_bytes: u64,
+    /// Highest resident memory and open file descriptor counts sampled during the run.
+    pub peak_rss_bytes: u64,
+    pub peak_open_fds: u64,
+    /// Throughput of reading, decompressing and writing, to tell which one limits the run.
+    #[serde(rename = "stage", default)]
+    pub stages: Vec<StageTiming>,
+    #[serde(rename = "sla-breach", default)]
+    pub sla_breaches: Vec<String>,
+    /// Entries skipped because an exclusion manifest lists them.
+    #[serde(rename = "excluded", default)]
+    pub excluded: Vec<String>,
+    #[serde(rename = "collision", default)]
+    pub collisions: Vec<Collision>,
+    /// Entries whose file name is in the manifest under a different directory.
+    #[serde(rename = "misplaced", default)]
+    pub misplaced: Vec<Misplaced>,
+    /// Archives with junk after their last member.
+    #[serde(rename = "trailing-data", default)]
+    pub trailing_data: Vec<TrailingData>,
+    /// Compressed members whose decompressed size is implausible for their compressed size.
+    #[serde(rename = "ratio-anomaly", default)]
+    pub ratio_anomalies: Vec<RatioAnomaly>,
+    /// Destination roots, one per `--dst-map` group in use plus `--dst`.
+    #[serde(rename = "root", default)]
+    pub roots: Vec<RootRecord>,
+    /// Manifest `<deleted-file>` entries applied with `--apply-deletes`.
+    #[serde(rename = "deleted", default)]
+    pub deletions: Vec<Deletion>,
+    #[serde(rename = "file", default)]
+    pub files: Vec<FileRecord>,
+    /// Files another run held the lease on, and delivered, under `--coordinate`.
+    #[serde(rename = "peer-delivery", default)]
+    pub peer_deliveries: Vec<PeerDelivery>,
+    /// Targets left alone as already delivered: found with the manifest size
+    /// under `--skip-existing`, found at all under `--if-exists skip`, or
+    /// listed in the `--state-file` or `--journal`.
+    #[serde(rename = "already-present", default)]
+    pub already_present: Vec<String>,
+    /// Targets the `--journal` shows an earlier attempt started but never
+    /// committed; they were written again.
+    #[serde(rename = "partial", default)]
+    pub partial: Vec<String>,
+    /// Files whose upload failed; they are not listed under `files`.
+    #[serde(rename = "failure", default)]
+    pub failures: Vec<FileFailure>,
+}
+
+/// An upload that returned an error or panicked.
+#[derive(Debug, Serialize)]
+pub struct FileFailure {
+    pub entry: String,
+    pub target: String,
+    pub error: String,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub backtrace: Option<String>,
+}
+
+/// Provenance of one extracted file.
+#[derive(Debug, Serialize)]
+pub struct FileRecord {
+    /// Member name inside the tar.
+    pub entry: String,
+    pub target: String,
+    /// Size and SHA-256 of the member as stored in the tar (before decompression).
+    pub raw_size: u64,
+    pub raw_sha256: String,
+    /// Size and SHA-256 of the decompressed content.
+    pub size: u64,
+    pub sha256: String,
+    /// Size written to HDFS, when `--recompress` stored the content compressed.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub stored_size: Option<u64>,
+    /// HDFS file checksum of what was written, with `--checksums`; split files have one per part.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub checksum: Option<FileChecksum>,
+    /// Set when the file was split into part files; `sha256` above covers all parts.
+    #[serde(rename = "part", default)]
+    pub parts: Vec<PartRecord>,
+}
+
+#[derive(Debug, Serialize)]
+pub struct Misplaced {
+    /// Path of the entry in the tar.
+    pub entry: String,
+    /// Path the manifest lists for that file name.
+    pub expected: String,
+    /// `skipped`, `relocated` to the manifest path (`superseded` once an
+    /// entry at that path replaced it), or `rejected` under `--strict-paths`.
+    pub action: String,
+}
+
+/// Unexpected bytes after the last member of an archive.
+#[derive(Debug, Serialize)]
+pub struct TrailingData {
+    pub archive: String,
+    /// Bytes after the last member, including the EOF marker if there is one.
+    pub bytes: u64,
+}
+
+/// A compressed member that expanded suspiciously little, or a lot; the
+/// file is still delivered.
+#[derive(Debug, Serialize)]
+pub struct RatioAnomaly {
+    pub entry: String,
+    /// Size of the member in the tar and after decompression.
+    pub compressed: u64,
+    pub decompressed: u64,
+    /// Decompressed bytes per compressed byte.
+    pub ratio: f64,
+}
+
+/// What one destination root received, checked against the manifest.
+#[derive(Debug, Serialize)]
+pub struct RootRecord {
+    pub path: String,
+    /// Manifest entries routed to this root, and how many of them were delivered.
+    pub expected: usize,
+    pub delivered: usize,
+    pub bytes: u64,
+    /// Files moved to the trash under this root.
+    pub deleted: usize,
+    /// Where the manifest was uploaded as the root's receipt.
+    pub receipt: String,
+}
+
+#[derive(Debug, Serialize)]
+pub struct Deletion {
+    pub path: String,
+    /// Where the file was moved; absent if it was already gone.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub trash: Option<String>,
+}
+
+/// One part file of a split upload.
+#[derive(Debug, Serialize)]
+pub struct PartRecord {
+    pub path: String,
+    pub size: u64,
+    pub sha256: String,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub checksum: Option<FileChecksum>,
+}
+
+/// A file left to the concurrent run that held its lease.
+#[derive(Debug, Serialize)]
+pub struct PeerDelivery {
+    pub target: String,
+    /// The holding run, as `host:pid`.
+    pub holder: String,
+}
+
+/// Two tar entries that mapped to the same HDFS target after name normalization.
+#[derive(Debug, Serialize)]
+pub struct Collision {
+    pub target: String,
+    pub first: String,
+    pub duplicate: String,
+    pub action: String,
+}
+
+/// Service level a run is expected to meet, e.g. the `sla` section of a
+/// scheduled job: `sla: { max-duration: 45m, min-throughput: 20 }`.
+#[derive(Debug, Clone, Default, Deserialize)]
+#[serde(rename_all = "kebab-case", deny_unknown_fields)]
+pub struct Sla {
+    #[serde(default, deserialize_with = "duration")]
+    pub max_duration: Option<Duration>,
+    /// Minimum average throughput in MB/s.
+    #[serde(default)]
+    pub min_throughput: Option<f64>,
+}
+
+fn duration<'de, D: Deserializer<'de>>(deserializer: D) -> Result<Option<Duration>, D::Error> {
+    let value: Option<String> = Option::deserialize(deserializer)?;
+    value
+        .map(|value| crate::units::parse_duration(&value))
+        .transpose()
+        .map_err(serde::de::Error::custom)
+}
+
+/// Returned by a run that delivered every file but missed its SLA.
+#[derive(Debug, thiserror::Error)]
+#[error("SLA breached: {}", .0.join("; "))]
+pub struct SlaBreached(pub Vec<String>);
+
+impl RunReport {
+    /// Average write throughput of the run in MB/s.
+    pub fn throughput(&self) -> f64 {
+        let secs = (self.duration_ms as f64 / 1000.0).max(0.001);
+        self.bytes_written as f64 / 1_000_000.0 / secs
+    }
+
+    /// Adds what another part of the same run delivered, e.g. an archive
+    /// extracted alongside this one.
+    pub fn merge(&mut self, other: RunReport) {
+        self.archives.extend(other.archives);
+        self.bytes_written += other.bytes_written;
+        self.excluded.extend(other.excluded);
+        self.collisions.extend(other.collisions);
+        self.misplaced.extend(other.misplaced);
+        self.trailing_data.extend(other.trailing_data);
+        self.ratio_anomalies.extend(other.ratio_anomalies);
+        self.files.extend(other.files);
+        self.peer_deliveries.extend(other.peer_deliveries);
+        self.already_present.extend(other.already_present);
+        self.partial.extend(other.partial);
+        self.failures.extend(other.failures);
+    }
+
+    pub fn record_resources(&mut self, status: &ResourceStatus) {
+        self.peak_rss_bytes = status.peak_rss_bytes;
+        self.peak_open_fds = status.peak_open_fds;
+    }
+
+    /// Records (and logs) every way this run missed its SLA.
+    pub fn check_sla(&mut self, sla: &Sla) {
+        if let Some(max_duration) = sla.max_duration
+            && self.duration_ms > max_duration.as_millis() as u64
+        {
+            self.sla_breaches.push(format!(
+                "run took {}s, SLA is {}s",
+                self.duration_ms / 1000,
+                max_duration.as_secs()
+            ));
+        }
+        if let Some(min_throughput) = sla.min_throughput {
+            let throughput = self.throughput();
+            if throughput < min_throughput {
+                self.sla_breaches.push(format!(
+                    "throughput {:.2} MB/s, SLA is {:.2} MB/s",
+                    throughput, min_throughput
+                ));
+            }
+        }
+        for breach in &self.sla_breaches {
+            warn!(target: "untar::sla", "SLA BREACHED: {}", breach);
+        }
+    }
+
+    pub fn write_to_file<P: AsRef<Path>>(&self, path: P) -> Result<()> {
+        let xml = to_string(self).context("Failed to serialize run report")?;
+        std::fs::write(path, xml).context("Failed to write run report")?;
+        Ok(())
+    }
+}
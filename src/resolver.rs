@@ -0,0 +1,115 @@
+use std::collections::HashMap;
+use std::path::{Path, PathBuf};
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+use anyhow::{anyhow, bail, Context, Result};
+use serde::{Deserialize, Serialize};
+use tracing::{info, warn};
+
+/// Manifest metadata sent to the routing service, which picks the tenant/feed destination.
+#[derive(Debug, Serialize)]
+pub struct ResolveRequest {
+    pub manifest: String,
+    pub files: usize,
+    pub bytes: u64,
+    pub run_id: String,
+}
+
+#[derive(Debug, Deserialize)]
+struct ResolveResponse {
+    dst: String,
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+struct CachedDst {
+    dst: String,
+    resolved_at: u64,
+}
+
+/// Looks up the HDFS destination from an HTTP routing service at run start.
+///
+/// Answers are cached in `cache_path` for `ttl`; when the service is down a
+/// stale cached answer is used before falling back to `--dst`.
+pub struct DstResolver {
+    url: String,
+    cache_path: PathBuf,
+    ttl: Duration,
+    timeout: Duration,
+}
+
+impl DstResolver {
+    pub fn new(url: String, cache_dir: &Path, ttl: Duration) -> Self {
+        Self {
+            url,
+            cache_path: cache_dir.join("untar-dst-cache.json"),
+            ttl,
+            timeout: Duration::from_secs(30),
+        }
+    }
+
+    /// Resolves the destination for `request`, using `fallback` if neither the
+    /// service nor the cache can answer.
+    pub fn resolve(&self, request: &ResolveRequest, fallback: Option<&str>) -> Result<String> {
+        // The run id differs between retries, so it is not part of the cache key.
+        let key = format!("{}|{}|{}", request.manifest, request.files, request.bytes);
+        let mut cache = self.load_cache();
+        let now = SystemTime::now().duration_since(UNIX_EPOCH).unwrap_or_default().as_secs();
+
+        if let Some(cached) = cache.get(&key)
+            && now.saturating_sub(cached.resolved_at) < self.ttl.as_secs()
+        {
+            info!("Using cached destination {} for {}", cached.dst, request.manifest);
+            return Ok(cached.dst.clone());
+        }
+
+        match self.query(request) {
+            Ok(dst) => {
+                info!("Routing service resolved {} to {}", request.manifest, dst);
+                cache.insert(key, CachedDst { dst: dst.clone(), resolved_at: now });
+                if let Err(e) = self.save_cache(&cache) {
+                    warn!("Failed to write destination cache {}: {}", self.cache_path.display(), e);
+                }
+                Ok(dst)
+            }
+            Err(e) => {
+                if let Some(cached) = cache.get(&key) {
+                    warn!("{}; using stale cached destination {}", e, cached.dst);
+                    Ok(cached.dst.clone())
+                } else if let Some(fallback) = fallback {
+                    warn!("{}; falling back to --dst {}", e, fallback);
+                    Ok(fallback.to_string())
+                } else {
+                    Err(e.context("No cached destination and no --dst fallback"))
+                }
+            }
+        }
+    }
+
+    fn query(&self, request: &ResolveRequest) -> Result<String> {
+        let response: ResolveResponse = ureq::AgentBuilder::new()
+            .timeout(self.timeout)
+            .build()
+            .post(&self.url)
+            .send_json(request)
+            .map_err(|e| anyhow!("Failed to query routing service: {}", e))?
+            .into_json()
+            .map_err(|e| anyhow!("Failed to parse routing service response from {}: {}", self.url, e))?;
+        if !response.dst.starts_with('/') && !response.dst.contains("://") {
+            bail!("Routing service {} returned a relative destination '{}'", self.url, response.dst);
+        }
+        Ok(response.dst)
+    }
+
+    fn load_cache(&self) -> HashMap<String, CachedDst> {
+        std::fs::read(&self.cache_path)
+            .ok()
+            .and_then(|data| serde_json::from_slice(&data).ok())
+            .unwrap_or_default()
+    }
+
+    fn save_cache(&self, cache: &HashMap<String, CachedDst>) -> Result<()> {
+        let tmp = self.cache_path.with_extension("json.tmp");
+        std::fs::write(&tmp, serde_json::to_vec(cache)?).context("Failed to write cache")?;
+        std::fs::rename(&tmp, &self.cache_path).context("Failed to replace cache")?;
+        Ok(())
+    }
+}
@@ -0,0 +1,61 @@
+use std::future::Future;
+use std::time::Duration;
+use rand::Rng;
+use tracing::warn;
+
+/// Exponential backoff for retry `attempt` (0-based), capped at `max` and
+/// jittered by ±50% so many jobs retrying at once do not stampede.
+pub fn jittered_backoff(attempt: u32, base: Duration, max: Duration) -> Duration {
+    let exponential = base.saturating_mul(1u32 << attempt.min(16)).min(max);
+    exponential.mul_f64(rand::thread_rng().gen_range(0.5..1.5))
+}
+
+/// Longest delay between two retries.
+pub const MAX_BACKOFF: Duration = Duration::from_secs(60);
+
+/// How often, and starting how patiently, failed operations are retried.
+#[derive(Debug, Clone, Copy, Default)]
+pub struct RetryPolicy {
+    pub retries: u32,
+    /// First delay; doubles per attempt, with jitter.
+    pub backoff: Duration,
+}
+
+/// Runs `operation` until it succeeds or `retries` retries have failed,
+/// sleeping with `jittered_backoff` in between.
+pub async fn with_retries<T, E, F, Fut>(what: &str, retries: u32, base: Duration, operation: F) -> Result<T, E>
+where
+    E: std::fmt::Display,
+    F: FnMut() -> Fut,
+    Fut: Future<Output = Result<T, E>>,
+{
+    with_retries_if(what, RetryPolicy { retries, backoff: base }, |_: &E| true, operation).await
+}
+
+/// Like `with_retries`, but gives up at once on errors `retryable` rejects.
+pub async fn with_retries_if<T, E, F, Fut>(
+    what: &str,
+    policy: RetryPolicy,
+    retryable: impl Fn(&E) -> bool,
+    mut operation: F,
+) -> Result<T, E>
+where
+    E: std::fmt::Display,
+    F: FnMut() -> Fut,
+    Fut: Future<Output = Result<T, E>>,
+{
+    let RetryPolicy { retries, backoff: base } = policy;
+    let mut attempt = 0;
+    loop {
+        match operation().await {
+            Ok(value) => return Ok(value),
+            Err(e) if attempt < retries && retryable(&e) => {
+                let delay = jittered_backoff(attempt, base, MAX_BACKOFF);
+                warn!("{} failed ({}), retrying in {:.1}s ({}/{})", what, e, delay.as_secs_f64(), attempt + 1, retries);
+                tokio::time::sleep(delay).await;
+                attempt += 1;
+            }
+            Err(e) => return Err(e),
+        }
+    }
+}
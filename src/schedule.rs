@@ -0,0 +1,163 @@
+use std::path::Path;
+use anyhow::{anyhow, bail, Context, Result};
+use serde::Deserialize;
+use time::{Date, Duration, OffsetDateTime, Time};
+
+use crate::report::Sla;
+
+/// A standard five-field cron expression (minute, hour, day of month,
+/// month, day of week), e.g. `0 2 * * *`, or one of `@hourly`, `@daily`,
+/// `@weekly` and `@monthly`.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct CronSchedule {
+    minutes: u64,
+    hours: u64,
+    days: u64,
+    months: u64,
+    weekdays: u64,
+    /// With both day fields restricted, a day matching either one fires (as in cron).
+    any_day: bool,
+}
+
+impl CronSchedule {
+    pub fn parse(expr: &str) -> Result<Self> {
+        let expr = match expr.trim() {
+            "@hourly" => "0 * * * *",
+            "@daily" | "@midnight" => "0 0 * * *",
+            "@weekly" => "0 0 * * 0",
+            "@monthly" => "0 0 1 * *",
+            expr => expr,
+        };
+        let fields: Vec<&str> = expr.split_whitespace().collect();
+        let [minute, hour, day, month, weekday] = fields[..] else {
+            bail!("Invalid cron expression '{}': expected 5 fields, got {}", expr, fields.len());
+        };
+        let parse = |field: &str, min: u32, max: u32| {
+            parse_field(field, min, max).map_err(|e| anyhow!("Invalid cron expression '{}': {}", expr, e))
+        };
+        // Sunday is both 0 and 7.
+        let mut weekdays = parse(weekday, 0, 7)?;
+        if weekdays & (1 << 7) != 0 {
+            weekdays |= 1;
+        }
+        Ok(Self {
+            minutes: parse(minute, 0, 59)?,
+            hours: parse(hour, 0, 23)?,
+            days: parse(day, 1, 31)?,
+            months: parse(month, 1, 12)?,
+            weekdays,
+            any_day: !day.starts_with('*') && !weekday.starts_with('*'),
+        })
+    }
+
+    fn matches_day(&self, date: Date) -> bool {
+        let day = self.days & (1 << date.day()) != 0;
+        let weekday = self.weekdays & (1 << date.weekday().number_days_from_sunday()) != 0;
+        if self.any_day { day || weekday } else { day && weekday }
+    }
+
+    /// The first minute after `after` the schedule fires at, in the same offset.
+    pub fn next_after(&self, after: OffsetDateTime) -> Option<OffsetDateTime> {
+        let mut next = after.replace_second(0).ok()?.replace_nanosecond(0).ok()? + Duration::minutes(1);
+        // Every valid schedule fires within four years (February 29th).
+        let limit = after + Duration::days(4 * 366);
+        while next <= limit {
+            if self.months & (1 << next.month() as u8) == 0 {
+                let (year, month) = match next.month().next() {
+                    time::Month::January => (next.year() + 1, time::Month::January),
+                    month => (next.year(), month),
+                };
+                next = next.replace_date(Date::from_calendar_date(year, month, 1).ok()?).replace_time(Time::MIDNIGHT);
+            } else if !self.matches_day(next.date()) {
+                next = next.replace_date(next.date().next_day()?).replace_time(Time::MIDNIGHT);
+            } else if self.hours & (1 << next.hour()) == 0 {
+                next = next.replace_minute(0).ok()? + Duration::hours(1);
+            } else if self.minutes & (1 << next.minute()) == 0 {
+                next += Duration::minutes(1);
+            } else {
+                return Some(next);
+            }
+        }
+        None
+    }
+}
+
+/// Bitmask of the values a field such as `*/15`, `1-5` or `0,30` allows.
+fn parse_field(field: &str, min: u32, max: u32) -> Result<u64, String> {
+    let mut mask = 0u64;
+    for part in field.split(',') {
+        let (range, step) = match part.split_once('/') {
+            Some((range, step)) => {
+                let step: u32 = step.parse().map_err(|_| format!("invalid step in '{}'", part))?;
+                if step == 0 {
+                    return Err(format!("zero step in '{}'", part));
+                }
+                (range, step)
+            }
+            None => (part, 1),
+        };
+        let value = |s: &str| -> Result<u32, String> {
+            let value: u32 = s.parse().map_err(|_| format!("invalid value '{}'", s))?;
+            if value < min || value > max {
+                return Err(format!("{} is outside {}-{}", value, min, max));
+            }
+            Ok(value)
+        };
+        let (start, end) = match range {
+            "*" => (min, max),
+            range => match range.split_once('-') {
+                Some((start, end)) => (value(start)?, value(end)?),
+                // `5/10` means from 5 to the end in steps of 10.
+                None if step > 1 => (value(range)?, max),
+                None => (value(range)?, value(range)?),
+            },
+        };
+        if start > end {
+            return Err(format!("empty range '{}'", range));
+        }
+        for value in (start..=end).step_by(step as usize) {
+            mask |= 1 << value;
+        }
+    }
+    Ok(mask)
+}
+
+/// One run of a scheduled batch: the arguments `untar` would be started with.
+#[derive(Debug, Clone, Deserialize)]
+pub struct Job {
+    /// Names the job in logs and its receipts.
+    pub name: String,
+    pub args: Vec<String>,
+    /// SLA of the feed the job delivers, for the thresholds its `args` leave unset.
+    #[serde(default)]
+    pub sla: Sla,
+}
+
+#[derive(Debug, Deserialize)]
+struct JobFile {
+    jobs: Vec<Job>,
+}
+
+/// Reads a `--jobs` file:
+///
+/// ```yaml
+/// jobs:
+///   - name: logs
+///     sla: { max-duration: 45m, min-throughput: 20 }
+///     args: [--tar, /incoming/logs.tar, --xml, /incoming/logs.xml, --dst, /raw/logs]
+/// ```
+pub fn load_jobs(path: &Path) -> Result<Vec<Job>> {
+    let content = std::fs::read_to_string(path)
+        .with_context(|| format!("Failed to read jobs file {}", path.display()))?;
+    let file: JobFile = serde_yaml::from_str(&content)
+        .map_err(|e| anyhow!("Failed to parse jobs file {}: {}", path.display(), e))?;
+    for (i, job) in file.jobs.iter().enumerate() {
+        if job.name.is_empty() || job.name.contains('/') {
+            bail!("Job {} in {} needs a name without '/'", i + 1, path.display());
+        }
+        if file.jobs[..i].iter().any(|other| other.name == job.name) {
+            bail!("Job name {} appears twice in {}", job.name, path.display());
+        }
+    }
+    Ok(file.jobs)
+}
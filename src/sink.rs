@@ -0,0 +1,496 @@
+use std::collections::HashMap;
+use std::sync::{Arc, Mutex as StdMutex};
+use std::time::Duration;
+use bytes::Bytes;
+use hdfs_native::client::{Client, WriteOptions};
+use hdfs_native::file::FileWriter;
+use hdfs_native::HdfsError;
+use tokio::fs::{File, OpenOptions};
+use tokio::io::AsyncWriteExt;
+use tokio::sync::Mutex;
+use tokio::time::Instant;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, clap::ValueEnum)]
+pub enum SinkKind {
+    /// Write to HDFS
+    Hdfs,
+    /// Discard output after it has been read, decompressed and verified
+    Null,
+}
+
+/// HDFS block sizes must be a multiple of the checksum chunk; whole MiB keeps them tidy.
+const BLOCK_SIZE_ALIGN: u64 = 1 << 20;
+
+/// Bytes copied at a time when truncating an HDFS file.
+const TRUNCATE_CHUNK: u64 = 8 << 20;
+
+/// Picks a per-file HDFS block size from the file's expected size, so a file
+/// just over a block boundary is not left with a tiny last block.
+#[derive(Debug, Clone, Copy)]
+pub struct BlockSizing {
+    /// The cluster's `dfs.blocksize`.
+    pub default_block_size: u64,
+}
+
+impl BlockSizing {
+    /// Spreads `size` over as many default-sized blocks as it fills
+    /// completely, so every block ends up between one and two default
+    /// blocks long. `None` when the default already fits.
+    pub fn block_size(&self, size: u64) -> Option<u64> {
+        let blocks = size / self.default_block_size.max(1);
+        if blocks == 0 {
+            return None;
+        }
+        let block_size = size.div_ceil(blocks).next_multiple_of(BLOCK_SIZE_ALIGN);
+        (block_size != self.default_block_size).then_some(block_size)
+    }
+}
+
+/// One `--write-opt key=value` setting, applied to the `WriteOptions` of
+/// every file a run creates.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum WriteOpt {
+    BlockSize(u64),
+    Replication(u32),
+    /// Octal, like `hdfs dfs -chmod`.
+    Permission(u32),
+    CreateParent(bool),
+}
+
+impl WriteOpt {
+    const KEYS: &'static str = "block_size, replication, permission or create_parent";
+
+    /// Parses `key=value`, e.g. `replication=2` or `permission=640`.
+    pub fn parse(value: &str) -> Result<Self, String> {
+        let Some((key, setting)) = value.split_once('=') else {
+            return Err(format!("invalid --write-opt '{}', expected key=value with key {}", value, Self::KEYS));
+        };
+        let invalid = |expected: &str| format!("invalid --write-opt {} '{}', expected {}", key, setting, expected);
+        match key {
+            "block_size" => match crate::units::parse_size(setting) {
+                Ok(size) if size > 0 => Ok(WriteOpt::BlockSize(size)),
+                _ => Err(invalid("a size such as 256m")),
+            },
+            "replication" => match setting.parse() {
+                Ok(replication) if replication > 0 => Ok(WriteOpt::Replication(replication)),
+                _ => Err(invalid("a positive number")),
+            },
+            "permission" => match u32::from_str_radix(setting, 8) {
+                Ok(mode) if mode <= 0o7777 => Ok(WriteOpt::Permission(mode)),
+                _ => Err(invalid("an octal mode such as 640")),
+            },
+            "create_parent" => match setting.parse() {
+                Ok(create_parent) => Ok(WriteOpt::CreateParent(create_parent)),
+                Err(_) => Err(invalid("true or false")),
+            },
+            _ => Err(format!("unknown --write-opt key '{}', expected {}", key, Self::KEYS)),
+        }
+    }
+
+    /// `options` with every setting of `opts` applied, later ones winning.
+    pub fn apply_all(opts: &[WriteOpt], options: WriteOptions) -> WriteOptions {
+        opts.iter().fold(options, |options, opt| match *opt {
+            WriteOpt::BlockSize(size) => options.block_size(size),
+            WriteOpt::Replication(replication) => options.replication(replication),
+            WriteOpt::Permission(mode) => options.permission(mode),
+            WriteOpt::CreateParent(create_parent) => options.create_parent(create_parent),
+        })
+    }
+}
+
+/// Files written to a `Sink::Memory`, keyed by target path.
+pub type MemoryFiles = Arc<StdMutex<HashMap<String, Vec<u8>>>>;
+
+/// Destination for extracted files.
+#[derive(Clone)]
+pub enum Sink {
+    Hdfs(Arc<Client>),
+    Null(Option<Arc<Throttle>>),
+    /// Keeps closed files in memory; used by tests and tooling.
+    Memory(MemoryFiles),
+    /// Writes to the local filesystem, e.g. for `extract-one` restores.
+    Local,
+}
+
+impl Sink {
+    pub async fn create(&self, path: &str, write_options: WriteOptions) -> hdfs_native::Result<SinkWriter> {
+        match self {
+            Sink::Hdfs(client) => Ok(SinkWriter::Hdfs(Box::new(client.create(path, write_options).await?))),
+            Sink::Null(throttle) => Ok(SinkWriter::Null(throttle.clone())),
+            Sink::Memory(files) => Ok(SinkWriter::Memory {
+                files: files.clone(),
+                path: path.to_string(),
+                data: Vec::new(),
+            }),
+            Sink::Local => {
+                if let Some(parent) = std::path::Path::new(path).parent() {
+                    tokio::fs::create_dir_all(parent).await?;
+                }
+                let file = OpenOptions::new()
+                    .write(true)
+                    .create(true)
+                    .truncate(true)
+                    .create_new(!write_options.overwrite)
+                    .open(path)
+                    .await?;
+                Ok(SinkWriter::Local(file))
+            }
+        }
+    }
+
+    /// Opens the existing file at `path` to add to its end.
+    pub async fn append(&self, path: &str) -> hdfs_native::Result<SinkWriter> {
+        match self {
+            Sink::Hdfs(client) => Ok(SinkWriter::Hdfs(Box::new(client.append(path).await?))),
+            Sink::Null(throttle) => Ok(SinkWriter::Null(throttle.clone())),
+            Sink::Memory(files) => {
+                let data = files.lock().unwrap().get(path).cloned();
+                match data {
+                    Some(data) => Ok(SinkWriter::Memory { files: files.clone(), path: path.to_string(), data }),
+                    None => Err(HdfsError::FileNotFound(path.to_string())),
+                }
+            }
+            Sink::Local => Ok(SinkWriter::Local(OpenOptions::new().append(true).open(path).await?)),
+        }
+    }
+
+    /// Cuts the file at `path` back to its first `len` bytes, e.g. to take
+    /// off an increment whose append failed. hdfs-native has no truncate
+    /// call, so on HDFS the kept bytes are copied to a file that replaces it.
+    pub async fn truncate(&self, path: &str, len: u64) -> hdfs_native::Result<()> {
+        match self {
+            Sink::Hdfs(client) => {
+                let kept = format!("{}.untar-truncate", path);
+                let mut reader = client.read(path).await?;
+                let mut writer = client.create(&kept, WriteOptions::default().overwrite(true)).await?;
+                let mut left = len.min(reader.file_length() as u64);
+                while left > 0 {
+                    let chunk = reader.read(left.min(TRUNCATE_CHUNK) as usize).await?;
+                    if chunk.is_empty() {
+                        break;
+                    }
+                    left -= chunk.len() as u64;
+                    writer.write(chunk).await?;
+                }
+                writer.close().await?;
+                client.rename(&kept, path, true).await
+            }
+            Sink::Null(_) => Ok(()),
+            Sink::Memory(files) => {
+                if let Some(data) = files.lock().unwrap().get_mut(path) {
+                    data.truncate(len as usize);
+                }
+                Ok(())
+            }
+            Sink::Local => {
+                let file = OpenOptions::new().write(true).open(path).await?;
+                Ok(file.set_len(len).await?)
+            }
+        }
+    }
+
+    /// What is at `path` now, or `None` if nothing is (always, for the null sink).
+    pub async fn status(&self, path: &str) -> hdfs_native::Result<Option<PathStatus>> {
+        match self {
+            Sink::Hdfs(client) => match client.get_file_info(path).await {
+                Ok(status) => Ok(Some(PathStatus { is_dir: status.isdir, len: status.length as u64 })),
+                Err(HdfsError::FileNotFound(_)) => Ok(None),
+                Err(e) => Err(e),
+            },
+            Sink::Null(_) => Ok(None),
+            Sink::Memory(files) => {
+                let files = files.lock().unwrap();
+                if let Some(data) = files.get(path) {
+                    return Ok(Some(PathStatus { is_dir: false, len: data.len() as u64 }));
+                }
+                let prefix = format!("{}/", path);
+                Ok(files.keys().any(|key| key.starts_with(&prefix)).then_some(PathStatus { is_dir: true, len: 0 }))
+            }
+            Sink::Local => match tokio::fs::metadata(path).await {
+                Ok(metadata) => Ok(Some(PathStatus { is_dir: metadata.is_dir(), len: metadata.len() })),
+                Err(e) if e.kind() == std::io::ErrorKind::NotFound => Ok(None),
+                Err(e) => Err(e.into()),
+            },
+        }
+    }
+
+    /// Moves `path` to `<trash_root><path>` like `hdfs dfs -rm` does, adding
+    /// a timestamp if that is taken. Returns where it went, or `None` if
+    /// `path` does not exist.
+    pub async fn trash(&self, path: &str, trash_root: &str) -> hdfs_native::Result<Option<String>> {
+        let mut trashed = format!("{}{}", trash_root.trim_end_matches('/'), path);
+        match self {
+            Sink::Hdfs(client) => {
+                match client.get_file_info(path).await {
+                    Ok(_) => {}
+                    Err(HdfsError::FileNotFound(_)) => return Ok(None),
+                    Err(e) => return Err(e),
+                }
+                if client.get_file_info(&trashed).await.is_ok() {
+                    trashed = format!("{}{}", trashed, trash_suffix());
+                }
+                if let Some((parent, _)) = trashed.rsplit_once('/') {
+                    client.mkdirs(parent, 0o700, true).await?;
+                }
+                client.rename(path, &trashed, false).await?;
+                Ok(Some(trashed))
+            }
+            Sink::Null(_) => Ok(Some(trashed)),
+            Sink::Memory(files) => {
+                let mut files = files.lock().unwrap();
+                let Some(data) = files.remove(path) else {
+                    return Ok(None);
+                };
+                if files.contains_key(&trashed) {
+                    trashed = format!("{}{}", trashed, trash_suffix());
+                }
+                files.insert(trashed.clone(), data);
+                Ok(Some(trashed))
+            }
+            Sink::Local => {
+                if tokio::fs::metadata(path).await.is_err() {
+                    return Ok(None);
+                }
+                if tokio::fs::metadata(&trashed).await.is_ok() {
+                    trashed = format!("{}{}", trashed, trash_suffix());
+                }
+                if let Some(parent) = std::path::Path::new(&trashed).parent() {
+                    tokio::fs::create_dir_all(parent).await?;
+                }
+                tokio::fs::rename(path, &trashed).await?;
+                Ok(Some(trashed))
+            }
+        }
+    }
+
+    /// Writes `data` to `path` unless something is there already, in one
+    /// step where the backend allows, so only one of several racing
+    /// writers succeeds. False if `path` was taken.
+    pub async fn create_new(&self, path: &str, data: Bytes) -> hdfs_native::Result<bool> {
+        match self {
+            Sink::Hdfs(client) => {
+                let mut writer = match client.create(path, WriteOptions::default().overwrite(false)).await {
+                    Ok(writer) => writer,
+                    Err(HdfsError::AlreadyExists(_)) => return Ok(false),
+                    Err(e) => return Err(e),
+                };
+                writer.write(data).await?;
+                writer.close().await?;
+                Ok(true)
+            }
+            Sink::Null(_) => Ok(true),
+            Sink::Memory(files) => {
+                let mut files = files.lock().unwrap();
+                if files.contains_key(path) {
+                    return Ok(false);
+                }
+                files.insert(path.to_string(), data.to_vec());
+                Ok(true)
+            }
+            Sink::Local => {
+                if let Some(parent) = std::path::Path::new(path).parent() {
+                    tokio::fs::create_dir_all(parent).await?;
+                }
+                let mut file = match OpenOptions::new().write(true).create_new(true).open(path).await {
+                    Ok(file) => file,
+                    Err(e) if e.kind() == std::io::ErrorKind::AlreadyExists => return Ok(false),
+                    Err(e) => return Err(e.into()),
+                };
+                file.write_all(&data).await?;
+                file.sync_all().await?;
+                Ok(true)
+            }
+        }
+    }
+
+    /// The contents of the file at `path`, or `None` if there is none
+    /// (always, for the null sink). For small files such as leases.
+    pub async fn read(&self, path: &str) -> hdfs_native::Result<Option<Bytes>> {
+        match self {
+            Sink::Hdfs(client) => {
+                let mut reader = match client.read(path).await {
+                    Ok(reader) => reader,
+                    Err(HdfsError::FileNotFound(_)) => return Ok(None),
+                    Err(e) => return Err(e),
+                };
+                let len = reader.file_length();
+                Ok(Some(reader.read(len).await?))
+            }
+            Sink::Null(_) => Ok(None),
+            Sink::Memory(files) => Ok(files.lock().unwrap().get(path).map(|data| Bytes::copy_from_slice(data))),
+            Sink::Local => match tokio::fs::read(path).await {
+                Ok(data) => Ok(Some(Bytes::from(data))),
+                Err(e) if e.kind() == std::io::ErrorKind::NotFound => Ok(None),
+                Err(e) => Err(e.into()),
+            },
+        }
+    }
+
+    /// Moves the file at `from` to `to`, creating the directories above
+    /// `to`. Whatever is at `to` is replaced only with `overwrite`; without
+    /// it the move fails instead.
+    pub async fn rename(&self, from: &str, to: &str, overwrite: bool) -> hdfs_native::Result<()> {
+        match self {
+            Sink::Hdfs(client) => {
+                if let Some((parent, _)) = to.rsplit_once('/')
+                    && !parent.is_empty()
+                {
+                    client.mkdirs(parent, 0o755, true).await?;
+                }
+                client.rename(from, to, overwrite).await
+            }
+            Sink::Null(_) => Ok(()),
+            Sink::Memory(files) => {
+                let mut files = files.lock().unwrap();
+                if !overwrite && files.contains_key(to) {
+                    return Err(HdfsError::AlreadyExists(to.to_string()));
+                }
+                if let Some(data) = files.remove(from) {
+                    files.insert(to.to_string(), data);
+                }
+                Ok(())
+            }
+            Sink::Local => {
+                if let Some(parent) = std::path::Path::new(to).parent() {
+                    tokio::fs::create_dir_all(parent).await?;
+                }
+                if overwrite {
+                    return Ok(tokio::fs::rename(from, to).await?);
+                }
+                // Linking fails if `to` exists, where a rename would replace it.
+                tokio::fs::hard_link(from, to).await?;
+                Ok(tokio::fs::remove_file(from).await?)
+            }
+        }
+    }
+
+    /// Removes the file at `path`, if there is one.
+    pub async fn delete(&self, path: &str) -> hdfs_native::Result<()> {
+        match self {
+            Sink::Hdfs(client) => match client.delete(path, false).await {
+                Ok(_) | Err(HdfsError::FileNotFound(_)) => Ok(()),
+                Err(e) => Err(e),
+            },
+            Sink::Null(_) => Ok(()),
+            Sink::Memory(files) => {
+                files.lock().unwrap().remove(path);
+                Ok(())
+            }
+            Sink::Local => match tokio::fs::remove_file(path).await {
+                Err(e) if e.kind() != std::io::ErrorKind::NotFound => Err(e.into()),
+                _ => Ok(()),
+            },
+        }
+    }
+}
+
+/// NameNode exceptions that clear up by themselves, e.g. during a failover.
+const TRANSIENT_EXCEPTIONS: [&str; 5] = [
+    "RetriableException",
+    "StandbyException",
+    "SafeModeException",
+    "NotReplicatedYetException",
+    "RecoveryInProgressException",
+];
+
+/// Whether an HDFS error may go away on retry, like a dropped connection
+/// or a restarting DataNode, rather than being permanent, like a denied
+/// permission or a missing parent directory.
+pub fn is_transient(e: &HdfsError) -> bool {
+    match e {
+        HdfsError::IOError(_) | HdfsError::DataTransferError(_) => true,
+        HdfsError::RPCError(class, _) => TRANSIENT_EXCEPTIONS.iter().any(|exception| class.ends_with(exception)),
+        _ => false,
+    }
+}
+
+/// An existing file or directory at the destination.
+#[derive(Debug, Clone, Copy)]
+pub struct PathStatus {
+    pub is_dir: bool,
+    pub len: u64,
+}
+
+/// Suffix that keeps a trashed file from replacing an earlier one of the same name.
+fn trash_suffix() -> String {
+    let millis = std::time::SystemTime::now()
+        .duration_since(std::time::UNIX_EPOCH)
+        .unwrap_or_default()
+        .as_millis();
+    format!(".{}", millis)
+}
+
+pub enum SinkWriter {
+    Hdfs(Box<FileWriter>),
+    Null(Option<Arc<Throttle>>),
+    Memory { files: MemoryFiles, path: String, data: Vec<u8> },
+    Local(File),
+}
+
+impl SinkWriter {
+    pub async fn write(&mut self, buf: Bytes) -> hdfs_native::Result<usize> {
+        match self {
+            SinkWriter::Hdfs(writer) => writer.write(buf).await,
+            SinkWriter::Null(throttle) => {
+                if let Some(throttle) = throttle {
+                    throttle.consume(buf.len() as u64).await;
+                }
+                Ok(buf.len())
+            }
+            SinkWriter::Memory { data, .. } => {
+                data.extend_from_slice(&buf);
+                Ok(buf.len())
+            }
+            SinkWriter::Local(file) => {
+                file.write_all(&buf).await?;
+                Ok(buf.len())
+            }
+        }
+    }
+
+    pub async fn close(&mut self) -> hdfs_native::Result<()> {
+        match self {
+            SinkWriter::Hdfs(writer) => writer.close().await,
+            SinkWriter::Null(_) => Ok(()),
+            SinkWriter::Memory { files, path, data } => {
+                files.lock().unwrap().insert(path.clone(), std::mem::take(data));
+                Ok(())
+            }
+            SinkWriter::Local(file) => {
+                file.flush().await?;
+                file.sync_all().await?;
+                Ok(())
+            }
+        }
+    }
+}
+
+/// Bandwidth limit shared by every writer of a run, simulating one network link.
+pub struct Throttle {
+    bytes_per_sec: u64,
+    next_free: Mutex<Instant>,
+}
+
+impl Throttle {
+    pub fn new(bytes_per_sec: u64) -> Self {
+        Self {
+            bytes_per_sec: bytes_per_sec.max(1),
+            next_free: Mutex::new(Instant::now()),
+        }
+    }
+
+    pub fn bytes_per_sec(&self) -> u64 {
+        self.bytes_per_sec
+    }
+
+    /// Reserves the link for `bytes` and sleeps until the transfer would have finished.
+    pub async fn consume(&self, bytes: u64) {
+        let done = {
+            let mut next_free = self.next_free.lock().await;
+            let start = (*next_free).max(Instant::now());
+            *next_free = start + Duration::from_secs_f64(bytes as f64 / self.bytes_per_sec as f64);
+            *next_free
+        };
+        tokio::time::sleep_until(done).await;
+    }
+}
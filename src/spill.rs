@@ -0,0 +1,247 @@
+use std::fs::{File, OpenOptions};
+use std::io;
+use std::os::unix::fs::FileExt;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::{Arc, Mutex};
+use bytes::Bytes;
+use tokio::sync::{mpsc, OwnedSemaphorePermit, Semaphore};
+use tracing::{info, warn};
+
+use crate::workdir::WorkDir;
+
+/// Disk budget for chunks that do not fit in memory while the sink is slow,
+/// shared by every upload of a run.
+pub struct Spill {
+    work_dir: WorkDir,
+    max_bytes: u64,
+    /// Current size of all spill files.
+    used: AtomicU64,
+    /// Bytes ever spilled, for the report.
+    spilled: AtomicU64,
+    files: AtomicU64,
+}
+
+impl Spill {
+    pub fn new(work_dir: WorkDir, max_bytes: u64) -> Self {
+        Self {
+            work_dir,
+            max_bytes,
+            used: AtomicU64::new(0),
+            spilled: AtomicU64::new(0),
+            files: AtomicU64::new(0),
+        }
+    }
+
+    pub fn spilled_bytes(&self) -> u64 {
+        self.spilled.load(Ordering::Relaxed)
+    }
+
+    fn try_reserve(&self, len: u64) -> bool {
+        self.used
+            .fetch_update(Ordering::AcqRel, Ordering::Acquire, |used| {
+                (used + len <= self.max_bytes).then_some(used + len)
+            })
+            .is_ok()
+    }
+
+    fn release(&self, len: u64) {
+        self.used.fetch_sub(len, Ordering::AcqRel);
+    }
+
+    /// Opens an anonymous spill file: it is unlinked right away, so it
+    /// disappears with the process even after a crash.
+    fn open_file(&self) -> io::Result<File> {
+        self.work_dir.ensure_free_space().map_err(io::Error::other)?;
+        let n = self.files.fetch_add(1, Ordering::Relaxed);
+        let path = self.work_dir.temp_path(&format!("spill-{}", n));
+        let file = OpenOptions::new().read(true).write(true).create_new(true).open(&path)?;
+        std::fs::remove_file(&path)?;
+        Ok(file)
+    }
+}
+
+/// One upload's spill file. It only grows while the upload lags, and is
+/// truncated (returning its budget) whenever the receiver catches up.
+struct SpillFile {
+    spill: Arc<Spill>,
+    file: File,
+    state: Mutex<SpillState>,
+}
+
+#[derive(Default)]
+struct SpillState {
+    /// End of written data, i.e. the file size charged to the budget.
+    write_pos: u64,
+    /// Spilled bytes not yet read back.
+    pending: u64,
+}
+
+impl SpillFile {
+    fn write(&self, data: &[u8]) -> io::Result<u64> {
+        let mut state = self.state.lock().unwrap();
+        let offset = state.write_pos;
+        self.file.write_all_at(data, offset)?;
+        state.write_pos += data.len() as u64;
+        state.pending += data.len() as u64;
+        Ok(offset)
+    }
+
+    fn read(&self, offset: u64, len: usize) -> io::Result<Bytes> {
+        let mut data = vec![0u8; len];
+        self.file.read_exact_at(&mut data, offset)?;
+        let mut state = self.state.lock().unwrap();
+        state.pending -= len as u64;
+        if state.pending == 0 {
+            self.file.set_len(0)?;
+            self.spill.release(state.write_pos);
+            state.write_pos = 0;
+        }
+        Ok(Bytes::from(data))
+    }
+}
+
+impl Drop for SpillFile {
+    fn drop(&mut self) {
+        self.spill.release(self.state.lock().unwrap().write_pos);
+    }
+}
+
+/// Byte budget for chunks queued in memory, shared by every channel of a
+/// run. A chunk larger than the whole budget takes all of it.
+pub struct InFlight {
+    bytes: Arc<Semaphore>,
+    max_bytes: usize,
+}
+
+impl InFlight {
+    pub fn new(max_bytes: usize) -> Self {
+        Self { bytes: Arc::new(Semaphore::new(max_bytes)), max_bytes }
+    }
+
+    fn permits(&self, len: usize) -> u32 {
+        len.min(self.max_bytes) as u32
+    }
+
+    fn try_reserve(&self, len: usize) -> Option<OwnedSemaphorePermit> {
+        self.bytes.clone().try_acquire_many_owned(self.permits(len)).ok()
+    }
+
+    async fn reserve(&self, len: usize) -> OwnedSemaphorePermit {
+        self.bytes.clone().acquire_many_owned(self.permits(len)).await.expect("in-flight budget is never closed")
+    }
+}
+
+enum Chunk {
+    /// The budget permit, if any, is returned when the receiver takes the chunk.
+    Memory(Bytes, Option<OwnedSemaphorePermit>),
+    Spilled { offset: u64, len: usize },
+}
+
+/// The receiving upload task has gone away.
+#[derive(Debug)]
+pub struct Closed;
+
+/// Ordered chunk channel holding up to `depth` chunks in memory, and no
+/// more than `in_flight` allows; with a `Spill`, further chunks go to disk
+/// instead of blocking the sender.
+pub fn channel(
+    depth: usize,
+    in_flight: Option<Arc<InFlight>>,
+    spill: Option<Arc<Spill>>,
+) -> (ChunkSender, ChunkReceiver) {
+    let (tx, rx) = mpsc::unbounded_channel();
+    let in_memory = Arc::new(Semaphore::new(depth));
+    let file = Arc::new(Mutex::new(None));
+    (
+        ChunkSender { tx, in_memory: in_memory.clone(), in_flight, spill, file: file.clone() },
+        ChunkReceiver { rx, in_memory, file },
+    )
+}
+
+pub struct ChunkSender {
+    tx: mpsc::UnboundedSender<Chunk>,
+    in_memory: Arc<Semaphore>,
+    in_flight: Option<Arc<InFlight>>,
+    spill: Option<Arc<Spill>>,
+    file: Arc<Mutex<Option<Arc<SpillFile>>>>,
+}
+
+impl ChunkSender {
+    pub async fn send(&self, data: Bytes) -> Result<(), Closed> {
+        if let Ok(permit) = self.in_memory.try_acquire() {
+            let budget = match &self.in_flight {
+                Some(in_flight) => in_flight.try_reserve(data.len()).map(Some),
+                None => Some(None),
+            };
+            if let Some(budget) = budget {
+                permit.forget();
+                return self.tx.send(Chunk::Memory(data, budget)).map_err(|_| Closed);
+            }
+        }
+        if let Some(spill) = &self.spill
+            && spill.try_reserve(data.len() as u64)
+        {
+            match self.spill_file(spill).and_then(|file| file.write(&data)) {
+                Ok(offset) => {
+                    spill.spilled.fetch_add(data.len() as u64, Ordering::Relaxed);
+                    return self.tx.send(Chunk::Spilled { offset, len: data.len() }).map_err(|_| Closed);
+                }
+                Err(e) => {
+                    spill.release(data.len() as u64);
+                    warn!("Failed to spill to disk, waiting for the upload instead: {}", e);
+                }
+            }
+        }
+        let permit = self.in_memory.acquire().await.map_err(|_| Closed)?;
+        let budget = match &self.in_flight {
+            Some(in_flight) => Some(in_flight.reserve(data.len()).await),
+            None => None,
+        };
+        permit.forget();
+        self.tx.send(Chunk::Memory(data, budget)).map_err(|_| Closed)
+    }
+
+    fn spill_file(&self, spill: &Arc<Spill>) -> io::Result<Arc<SpillFile>> {
+        let mut file = self.file.lock().unwrap();
+        if let Some(file) = &*file {
+            return Ok(file.clone());
+        }
+        info!("Upload is falling behind, spilling chunks to {}", spill.work_dir.path().display());
+        let spill_file = Arc::new(SpillFile {
+            spill: spill.clone(),
+            file: spill.open_file()?,
+            state: Mutex::new(SpillState::default()),
+        });
+        *file = Some(spill_file.clone());
+        Ok(spill_file)
+    }
+}
+
+pub struct ChunkReceiver {
+    rx: mpsc::UnboundedReceiver<Chunk>,
+    in_memory: Arc<Semaphore>,
+    file: Arc<Mutex<Option<Arc<SpillFile>>>>,
+}
+
+impl ChunkReceiver {
+    /// Next chunk in send order, read back from disk if it was spilled.
+    pub async fn recv(&mut self) -> Option<io::Result<Bytes>> {
+        match self.rx.recv().await? {
+            Chunk::Memory(data, _budget) => {
+                self.in_memory.add_permits(1);
+                Some(Ok(data))
+            }
+            Chunk::Spilled { offset, len } => {
+                let file = self.file.lock().unwrap().clone().expect("spilled chunk without a spill file");
+                Some(file.read(offset, len))
+            }
+        }
+    }
+}
+
+impl Drop for ChunkReceiver {
+    fn drop(&mut self) {
+        // Wakes a sender waiting for memory, which then sees `Closed`.
+        self.in_memory.close();
+    }
+}
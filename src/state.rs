@@ -0,0 +1,120 @@
+use std::collections::HashMap;
+use std::fs::{File, OpenOptions};
+use std::io::Write;
+use std::path::{Path, PathBuf};
+use std::sync::Mutex;
+use anyhow::{Context, Result};
+use tracing::{info, warn};
+
+use crate::report::FileRecord;
+
+/// Local record of the files a run has delivered, appended to as each one
+/// completes, so a rerun after a crash skips them without asking HDFS.
+///
+/// The first line names the run; a file written for another run is started
+/// over. Every other line is `done<TAB>target<TAB>size<TAB>sha256`, or
+/// `offset<TAB>archive<TAB>bytes` once every entry of a tar before that
+/// offset is delivered, so a rerun can seek past them.
+pub struct StateFile {
+    path: PathBuf,
+    file: Mutex<File>,
+    /// Target -> size, as completed before this run started.
+    completed: HashMap<String, u64>,
+    /// Archive -> offset of its first entry not known to be delivered, as
+    /// of the start of this run.
+    resume: HashMap<String, u64>,
+}
+
+impl StateFile {
+    /// Opens `path`, keeping the files it lists as completed if it was
+    /// written for `run_id`.
+    pub fn open(path: &Path, run_id: &str) -> Result<Self> {
+        let header = format!("untar-state v1 run={}\n", run_id);
+        let contents = match std::fs::read_to_string(path) {
+            Ok(contents) => contents,
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => String::new(),
+            Err(e) => return Err(e).with_context(|| format!("Failed to read state file {}", path.display())),
+        };
+        let mut completed = HashMap::new();
+        let mut resume = HashMap::new();
+        let mut kept = header.clone();
+        match contents.strip_prefix(&header) {
+            Some(lines) => {
+                // A line without its newline was cut short by a crash.
+                for line in lines.split_inclusive('\n').filter(|line| line.ends_with('\n')) {
+                    match line.trim_end_matches('\n').split('\t').collect::<Vec<_>>()[..] {
+                        ["done", target, size, sha256] if sha256.len() == 64 => {
+                            if let Ok(size) = size.parse() {
+                                completed.insert(target.to_string(), size);
+                                kept.push_str(line);
+                            }
+                        }
+                        ["offset", archive, offset] => {
+                            if let Ok(offset) = offset.parse() {
+                                resume.insert(archive.to_string(), offset);
+                            }
+                        }
+                        _ => {}
+                    }
+                }
+            }
+            None if contents.is_empty() => {}
+            None => warn!("State file {} belongs to another run, starting it over", path.display()),
+        }
+        // Only the latest offset of each archive matters.
+        for (archive, offset) in &resume {
+            kept.push_str(&format!("offset\t{}\t{}\n", archive, offset));
+        }
+        // Rewritten without any torn line, so appends start on a fresh one.
+        let temp = path.with_extension("tmp");
+        std::fs::write(&temp, &kept)
+            .and_then(|()| std::fs::rename(&temp, path))
+            .with_context(|| format!("Failed to write state file {}", path.display()))?;
+        let file = OpenOptions::new()
+            .append(true)
+            .open(path)
+            .with_context(|| format!("Failed to open state file {}", path.display()))?;
+        if !completed.is_empty() {
+            info!("State file {} lists {} completed file(s)", path.display(), completed.len());
+        }
+        Ok(Self { path: path.to_path_buf(), file: Mutex::new(file), completed, resume })
+    }
+
+    /// Where an earlier attempt at the run left off reading `archive`.
+    pub fn resume_offset(&self, archive: &str) -> Option<u64> {
+        self.resume.get(archive).copied()
+    }
+
+    /// Whether an earlier attempt left off partway through any archive.
+    pub fn resumes(&self) -> bool {
+        !self.resume.is_empty()
+    }
+
+    /// Size `target` was delivered with by an earlier attempt at the run.
+    pub fn completed_size(&self, target: &str) -> Option<u64> {
+        self.completed.get(target).copied()
+    }
+
+    /// Files completed before this run started.
+    pub fn completed(&self) -> usize {
+        self.completed.len()
+    }
+
+    /// Records `target` as delivered as `file`, whose path differs from it
+    /// for a versioned file. A record that cannot be written only
+    /// costs a rerun that file, so it is logged rather than failing it.
+    pub fn complete(&self, target: &str, file: &FileRecord) {
+        self.append(&format!("done\t{}\t{}\t{}\n", target, file.size, file.sha256));
+    }
+
+    /// Records that every entry of `archive` before `offset` is delivered.
+    pub fn reached(&self, archive: &str, offset: u64) {
+        self.append(&format!("offset\t{}\t{}\n", archive, offset));
+    }
+
+    fn append(&self, line: &str) {
+        if let Err(e) = self.file.lock().unwrap().write_all(line.as_bytes()) {
+            warn!("Failed to write {:?} to state file {}: {}", line.trim_end(), self.path.display(), e);
+        }
+    }
+}
@@ -0,0 +1,400 @@
+use std::collections::HashMap;
+use std::future::Future;
+use std::io::Write;
+use std::path::Path;
+use std::pin::Pin;
+use std::sync::atomic::{AtomicBool, AtomicU64, Ordering};
+use std::sync::{Arc, Mutex};
+use std::task::{Context, Poll};
+use std::time::{Duration, Instant};
+use serde::Serialize;
+use tokio::task::JoinHandle;
+use tracing::{debug, info, warn};
+
+/// Warn (and start recycling writers, if enabled) at this share of a limit.
+const WARN_RATIO: f64 = 0.8;
+
+/// Memory and file descriptor use of this process.
+#[derive(Debug, Clone, Copy, Default)]
+pub struct ResourceUsage {
+    pub rss_bytes: u64,
+    pub open_fds: u64,
+}
+
+impl ResourceUsage {
+    /// Reads the current usage from `/proc`; `None` where that is unavailable.
+    pub fn sample() -> Option<Self> {
+        let statm = std::fs::read_to_string("/proc/self/statm").ok()?;
+        let resident_pages: u64 = statm.split_whitespace().nth(1)?.parse().ok()?;
+        // SAFETY: sysconf has no preconditions.
+        let page_size = unsafe { libc::sysconf(libc::_SC_PAGESIZE) }.max(1) as u64;
+        // Minus the descriptor read_dir itself holds.
+        let open_fds = std::fs::read_dir("/proc/self/fd").ok()?.count().saturating_sub(1) as u64;
+        Some(Self { rss_bytes: resident_pages * page_size, open_fds })
+    }
+}
+
+/// Soft limit on open files (RLIMIT_NOFILE), if it is finite.
+pub fn fd_limit() -> Option<u64> {
+    let mut limit = libc::rlimit { rlim_cur: 0, rlim_max: 0 };
+    // SAFETY: `limit` is a valid, writable rlimit.
+    if unsafe { libc::getrlimit(libc::RLIMIT_NOFILE, &mut limit) } != 0 || limit.rlim_cur == libc::RLIM_INFINITY {
+        return None;
+    }
+    Some(limit.rlim_cur)
+}
+
+#[derive(Debug, Clone, Copy, Default)]
+pub struct ResourceLimits {
+    pub max_rss: Option<u64>,
+    pub max_fds: Option<u64>,
+    /// Drain in-flight uploads, closing their writers, before starting more
+    /// files while usage is above the warning level.
+    pub recycle_writers: bool,
+}
+
+/// Latest and peak resource usage, with limit checks.
+#[derive(Default)]
+struct MonitorState {
+    limits: ResourceLimits,
+    rss: AtomicU64,
+    fds: AtomicU64,
+    peak_rss: AtomicU64,
+    peak_fds: AtomicU64,
+    rss_warned: AtomicBool,
+    fds_warned: AtomicBool,
+}
+
+/// Samples RSS and open descriptors periodically for long runs, warning as
+/// limits approach. Cheap to clone.
+#[derive(Clone, Default)]
+pub struct ResourceMonitor {
+    state: Arc<MonitorState>,
+}
+
+/// Snapshot for the control socket and the run report.
+#[derive(Debug, Clone, Copy, Serialize)]
+pub struct ResourceStatus {
+    pub rss_bytes: u64,
+    pub open_fds: u64,
+    pub peak_rss_bytes: u64,
+    pub peak_open_fds: u64,
+}
+
+impl ResourceMonitor {
+    pub fn new(limits: ResourceLimits) -> Self {
+        Self { state: Arc::new(MonitorState { limits, ..Default::default() }) }
+    }
+
+    /// Samples every `interval`, which must be above zero, until the
+    /// returned [`Sampler`] is dropped.
+    #[must_use = "sampling stops when the Sampler is dropped"]
+    pub fn start(&self, interval: Duration) -> Sampler {
+        self.record_sample();
+        let monitor = self.clone();
+        Sampler(tokio::spawn(async move {
+            let mut ticker = tokio::time::interval(interval);
+            ticker.tick().await;
+            loop {
+                ticker.tick().await;
+                monitor.record_sample();
+            }
+        }))
+    }
+
+    fn record_sample(&self) {
+        if let Some(usage) = ResourceUsage::sample() {
+            self.record(usage);
+        }
+    }
+
+    /// Takes `usage` as the latest sample, warning as it nears the limits.
+    pub fn record(&self, usage: ResourceUsage) {
+        let state = &self.state;
+        state.rss.store(usage.rss_bytes, Ordering::Relaxed);
+        state.fds.store(usage.open_fds, Ordering::Relaxed);
+        state.peak_rss.fetch_max(usage.rss_bytes, Ordering::Relaxed);
+        state.peak_fds.fetch_max(usage.open_fds, Ordering::Relaxed);
+        debug!("Resource usage: {} bytes RSS, {} open files", usage.rss_bytes, usage.open_fds);
+
+        check_limit("RSS", "bytes", usage.rss_bytes, state.limits.max_rss, &state.rss_warned);
+        check_limit("Open files", "descriptors", usage.open_fds, state.limits.max_fds, &state.fds_warned);
+    }
+
+    /// True while usage is high enough that uploads should be drained
+    /// before new files start.
+    pub fn should_recycle(&self) -> bool {
+        let state = &self.state;
+        state.limits.recycle_writers
+            && (above_warning(state.rss.load(Ordering::Relaxed), state.limits.max_rss)
+                || above_warning(state.fds.load(Ordering::Relaxed), state.limits.max_fds))
+    }
+
+    /// True while the latest sample is above the warning level of a limit.
+    pub fn near_limit(&self) -> bool {
+        self.state.rss_warned.load(Ordering::Relaxed) || self.state.fds_warned.load(Ordering::Relaxed)
+    }
+
+    pub fn status(&self) -> ResourceStatus {
+        let state = &self.state;
+        ResourceStatus {
+            rss_bytes: state.rss.load(Ordering::Relaxed),
+            open_fds: state.fds.load(Ordering::Relaxed),
+            peak_rss_bytes: state.peak_rss.load(Ordering::Relaxed),
+            peak_open_fds: state.peak_fds.load(Ordering::Relaxed),
+        }
+    }
+}
+
+/// Stops the sampling [`ResourceMonitor::start`] started when dropped.
+pub struct Sampler(JoinHandle<()>);
+
+impl Drop for Sampler {
+    fn drop(&mut self) {
+        self.0.abort();
+    }
+}
+
+fn above_warning(value: u64, limit: Option<u64>) -> bool {
+    limit.is_some_and(|limit| value as f64 >= limit as f64 * WARN_RATIO)
+}
+
+/// Warns once when `value` crosses the warning level, and logs when it drops back.
+fn check_limit(what: &str, unit: &str, value: u64, limit: Option<u64>, warned: &AtomicBool) {
+    let Some(limit) = limit else {
+        return;
+    };
+    if above_warning(value, limit.into()) {
+        if !warned.swap(true, Ordering::Relaxed) {
+            warn!("{} at {} of {} {} limit", what, value, limit, unit);
+        }
+    } else if warned.swap(false, Ordering::Relaxed) {
+        info!("{} back to {} of {} {} limit", what, value, limit, unit);
+    }
+}
+
+/// A step every extracted file goes through.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Stage {
+    /// Reading the member from the tar
+    Read,
+    /// Decompressing, hashing and checking it
+    Decompress,
+    /// Writing it to the sink
+    Write,
+}
+
+impl Stage {
+    const ALL: [Stage; 3] = [Stage::Read, Stage::Decompress, Stage::Write];
+
+    fn name(self) -> &'static str {
+        match self {
+            Stage::Read => "read",
+            Stage::Decompress => "decompress",
+            Stage::Write => "write",
+        }
+    }
+}
+
+#[derive(Default)]
+struct StageCounter {
+    bytes: AtomicU64,
+    nanos: AtomicU64,
+}
+
+/// Bytes each stage handled and the time spent in it, summed over all
+/// workers, so a run shows which stage holds it back. Shared by the workers.
+#[derive(Default)]
+pub struct StageCounters {
+    stages: [StageCounter; 3],
+}
+
+/// Throughput of one stage, for the run report.
+#[derive(Debug, Clone, Serialize)]
+pub struct StageTiming {
+    pub name: &'static str,
+    pub bytes: u64,
+    /// Time spent in the stage, summed over workers working at once.
+    pub busy_ms: u64,
+    /// Bytes per busy second, in MB/s: what one worker sustains.
+    pub mb_per_sec: f64,
+}
+
+impl StageCounters {
+    pub fn record(&self, stage: Stage, bytes: u64, elapsed: Duration) {
+        let counter = &self.stages[stage as usize];
+        counter.bytes.fetch_add(bytes, Ordering::Relaxed);
+        counter.nanos.fetch_add(elapsed.as_nanos() as u64, Ordering::Relaxed);
+    }
+
+    fn bytes(&self, stage: Stage) -> u64 {
+        self.stages[stage as usize].bytes.load(Ordering::Relaxed)
+    }
+
+    /// Logs every `interval`, until aborted, what each stage handled per
+    /// second since the last log, and an ETA from the decompressed bytes so
+    /// far against `expected_bytes` (the manifest's total size).
+    pub fn start_progress_log(self: &Arc<Self>, expected_bytes: u64, interval: Duration) -> JoinHandle<()> {
+        let stages = self.clone();
+        tokio::spawn(async move {
+            let started = Instant::now();
+            let mut ticker = tokio::time::interval(interval);
+            ticker.tick().await;
+            let mut last = [0u64; 3];
+            let mut last_tick = started;
+            loop {
+                ticker.tick().await;
+                let now = Instant::now();
+                let secs = (now - last_tick).as_secs_f64().max(0.001);
+                let bytes = Stage::ALL.map(|stage| stages.bytes(stage));
+                let rate = |stage: Stage| (bytes[stage as usize] - last[stage as usize]) as f64 / 1_000_000.0 / secs;
+                let done = bytes[Stage::Decompress as usize];
+                let eta = match done {
+                    0 => "unknown".to_string(),
+                    _ => {
+                        let left = expected_bytes.saturating_sub(done) as f64 / done as f64;
+                        format!("{}s", (now - started).mul_f64(left).as_secs())
+                    }
+                };
+                info!(
+                    "Progress: {} of {} bytes ({:.1}%); read {:.2} MB/s, decompressed {:.2} MB/s, written {:.2} MB/s; ETA {}",
+                    done,
+                    expected_bytes,
+                    done as f64 * 100.0 / expected_bytes.max(1) as f64,
+                    rate(Stage::Read),
+                    rate(Stage::Decompress),
+                    rate(Stage::Write),
+                    eta
+                );
+                last = bytes;
+                last_tick = now;
+            }
+        })
+    }
+
+    pub fn timings(&self) -> Vec<StageTiming> {
+        Stage::ALL
+            .into_iter()
+            .map(|stage| {
+                let counter = &self.stages[stage as usize];
+                let bytes = counter.bytes.load(Ordering::Relaxed);
+                let busy = Duration::from_nanos(counter.nanos.load(Ordering::Relaxed));
+                StageTiming {
+                    name: stage.name(),
+                    bytes,
+                    busy_ms: busy.as_millis() as u64,
+                    mb_per_sec: bytes as f64 / 1_000_000.0 / busy.as_secs_f64().max(0.001),
+                }
+            })
+            .collect()
+    }
+}
+
+/// What `--profile` captures: `cpu`, optionally limited to the first
+/// seconds of the run, e.g. `cpu:30`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct ProfileSpec {
+    pub duration: Option<Duration>,
+}
+
+impl ProfileSpec {
+    pub fn parse(value: &str) -> Result<Self, String> {
+        let invalid = || format!("invalid --profile '{}', expected cpu or cpu:SECONDS", value);
+        match value.split_once(':') {
+            None if value == "cpu" => Ok(Self { duration: None }),
+            Some(("cpu", seconds)) => match seconds.parse::<u64>() {
+                Ok(seconds) if seconds > 0 => Ok(Self { duration: Some(Duration::from_secs(seconds)) }),
+                _ => Err(invalid()),
+            },
+            _ => Err(invalid()),
+        }
+    }
+}
+
+/// CPU time of the pipeline's tasks, by the stage and format they work
+/// on, measured with the thread CPU clock around every poll. Written in
+/// the folded-stack format flamegraph.pl, inferno and speedscope read.
+///
+/// This accounts time to stages rather than sampling call stacks, so a
+/// stack is only as deep as the stage it names: it tells whether gzip
+/// decoding or HDFS writes took the CPU, not which functions inside them.
+pub struct Profiler {
+    started: Instant,
+    until: Option<Instant>,
+    /// Folded stack -> CPU microseconds.
+    stacks: Mutex<HashMap<String, u64>>,
+}
+
+impl Profiler {
+    pub fn new(spec: ProfileSpec) -> Self {
+        let started = Instant::now();
+        Self { started, until: spec.duration.map(|duration| started + duration), stacks: Mutex::default() }
+    }
+
+    fn record(&self, stack: &str, cpu: Duration) {
+        if self.until.is_some_and(|until| Instant::now() > until) || cpu.is_zero() {
+            return;
+        }
+        let mut stacks = self.stacks.lock().unwrap();
+        *stacks.entry(stack.to_string()).or_default() += cpu.as_micros() as u64;
+    }
+
+    /// Writes one `frame;frame;frame microseconds` line per stack, sorted.
+    pub fn write_folded(&self, path: &Path) -> anyhow::Result<()> {
+        let stacks = self.stacks.lock().unwrap();
+        let mut lines: Vec<(&String, &u64)> = stacks.iter().collect();
+        lines.sort();
+        let mut file = std::io::BufWriter::new(std::fs::File::create(path)?);
+        for (stack, micros) in lines {
+            writeln!(file, "{} {}", stack, micros)?;
+        }
+        file.flush()?;
+        info!(
+            "Wrote per-stage CPU time of {:?} to {} ({} stacks, in microseconds)",
+            self.until.map_or(self.started.elapsed(), |until| until - self.started),
+            path.display(),
+            stacks.len()
+        );
+        Ok(())
+    }
+}
+
+/// CPU time the calling thread has used.
+fn thread_cpu_time() -> Duration {
+    let mut time = libc::timespec { tv_sec: 0, tv_nsec: 0 };
+    // SAFETY: `time` is a valid, writable timespec.
+    if unsafe { libc::clock_gettime(libc::CLOCK_THREAD_CPUTIME_ID, &mut time) } != 0 {
+        return Duration::ZERO;
+    }
+    Duration::new(time.tv_sec as u64, time.tv_nsec as u32)
+}
+
+/// A future whose polls are charged to `stack` in a `Profiler`, if there
+/// is one. Tasks move between threads only between polls, so each poll
+/// is timed on one thread's clock.
+pub struct Profiled<F> {
+    inner: Pin<Box<F>>,
+    profile: Option<(Arc<Profiler>, String)>,
+}
+
+impl<F: Future> Profiled<F> {
+    pub fn new(inner: F, profiler: Option<&Arc<Profiler>>, stack: impl FnOnce() -> String) -> Self {
+        Self { inner: Box::pin(inner), profile: profiler.map(|profiler| (profiler.clone(), stack())) }
+    }
+}
+
+impl<F: Future> Future for Profiled<F> {
+    type Output = F::Output;
+
+    fn poll(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<F::Output> {
+        if self.profile.is_none() {
+            return self.inner.as_mut().poll(cx);
+        }
+        let started = thread_cpu_time();
+        let poll = self.inner.as_mut().poll(cx);
+        if let Some((profiler, stack)) = &self.profile {
+            profiler.record(stack, thread_cpu_time().saturating_sub(started));
+        }
+        poll
+    }
+}
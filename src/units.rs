@@ -0,0 +1,49 @@
+use std::time::Duration;
+
+/// Parses a duration such as `90`, `90s`, `15m` or `2h` (plain numbers are seconds).
+pub fn parse_duration(value: &str) -> Result<Duration, String> {
+    let value = value.trim();
+    let (number, multiplier) = match value.char_indices().last() {
+        Some((i, 's')) => (&value[..i], 1),
+        Some((i, 'm')) => (&value[..i], 60),
+        Some((i, 'h')) => (&value[..i], 3600),
+        Some((i, 'd')) => (&value[..i], 86400),
+        _ => (value, 1),
+    };
+    let number: u64 = number
+        .parse()
+        .map_err(|_| format!("invalid duration '{}', expected e.g. 90s, 15m or 2h", value))?;
+    number
+        .checked_mul(multiplier)
+        .map(Duration::from_secs)
+        .ok_or_else(|| format!("duration '{}' is too large", value))
+}
+
+/// Parses a time of day such as `06:00` or `23:30`.
+pub fn parse_time_of_day(value: &str) -> Result<time::Time, String> {
+    let invalid = || format!("invalid time of day '{}', expected e.g. 06:00", value);
+    let (hour, minute) = value.trim().split_once(':').ok_or_else(invalid)?;
+    let hour: u8 = hour.parse().map_err(|_| invalid())?;
+    let minute: u8 = minute.parse().map_err(|_| invalid())?;
+    time::Time::from_hms(hour, minute, 0).map_err(|_| invalid())
+}
+
+/// Parses a byte size such as `4096`, `64k`, `500m` or `50g` (binary multiples).
+pub fn parse_size(value: &str) -> Result<u64, String> {
+    let value = value.trim();
+    let lower = value.to_ascii_lowercase();
+    let lower = lower.trim_end_matches('b').trim_end_matches('i');
+    let (number, shift) = match lower.char_indices().last() {
+        Some((i, 'k')) => (&lower[..i], 10),
+        Some((i, 'm')) => (&lower[..i], 20),
+        Some((i, 'g')) => (&lower[..i], 30),
+        Some((i, 't')) => (&lower[..i], 40),
+        _ => (lower, 0),
+    };
+    let number: u64 = number
+        .parse()
+        .map_err(|_| format!("invalid size '{}', expected e.g. 64k, 500m or 50g", value))?;
+    number
+        .checked_mul(1u64 << shift)
+        .ok_or_else(|| format!("size '{}' is too large", value))
+}
@@ -0,0 +1,58 @@
+use std::ffi::CString;
+use std::os::unix::ffi::OsStrExt;
+use std::path::{Path, PathBuf};
+use anyhow::{anyhow, bail, Context, Result};
+
+/// Local directory for temporary files (manifest index, spool, ...), with a
+/// floor on free space so the edge node's disk is never filled.
+#[derive(Debug, Clone)]
+pub struct WorkDir {
+    path: PathBuf,
+    min_free: u64,
+    run_id: String,
+}
+
+impl WorkDir {
+    pub fn new(path: PathBuf, min_free: u64, run_id: String) -> Self {
+        Self { path, min_free, run_id }
+    }
+
+    pub fn path(&self) -> &Path {
+        &self.path
+    }
+
+    /// Path for a temporary file or directory of this run, tagged with the run id.
+    pub fn temp_path(&self, name: &str) -> PathBuf {
+        self.path.join(format!("untar-{}-{}-{}", self.run_id, name, std::process::id()))
+    }
+
+    /// Free bytes available to unprivileged users on the work dir's filesystem.
+    pub fn free_bytes(&self) -> Result<u64> {
+        let c_path = CString::new(self.path.as_os_str().as_bytes())
+            .context("Work directory path contains a NUL byte")?;
+        let mut stat: libc::statvfs = unsafe { std::mem::zeroed() };
+        // SAFETY: `c_path` is a valid NUL-terminated string and `stat` is a valid out pointer.
+        if unsafe { libc::statvfs(c_path.as_ptr(), &mut stat) } != 0 {
+            return Err(anyhow!(
+                "Failed to stat work directory {}: {}",
+                self.path.display(),
+                std::io::Error::last_os_error()
+            ));
+        }
+        Ok(stat.f_bavail as u64 * stat.f_frsize as u64)
+    }
+
+    /// Fails with a clear message when free space is below `--min-free-disk`.
+    pub fn ensure_free_space(&self) -> Result<()> {
+        let free = self.free_bytes()?;
+        if free < self.min_free {
+            bail!(
+                "Only {} bytes free in work directory {}, below the --min-free-disk floor of {} bytes",
+                free,
+                self.path.display(),
+                self.min_free
+            );
+        }
+        Ok(())
+    }
+}
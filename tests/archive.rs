@@ -0,0 +1,71 @@
+use std::io::{Read, Seek, Write};
+use std::sync::Arc;
+use untar::archive::{glob_regex, AsyncTarReader, EntryReader, LocalTar, MappedFile};
+
+#[test]
+fn mapped_tars_read_like_files() {
+    let data: Vec<u8> = (0..10_000u32).map(|i| (i % 251) as u8).collect();
+    let mut file = tempfile::tempfile().unwrap();
+    file.write_all(&data).unwrap();
+
+    let mapped = MappedFile::map(&file).unwrap();
+    assert_eq!(&mapped[..], &data[..]);
+    for tar in [LocalTar::Mapped(mapped), LocalTar::File(file)] {
+        let tar = Arc::new(tar);
+        let mut member = Vec::new();
+        EntryReader::new(tar.clone(), 1_000, 5_000).read_to_end(&mut member).unwrap();
+        assert_eq!(member, &data[1_000..6_000]);
+        // A member running past the end of the tar is an error, not a short read.
+        let mut truncated = Vec::new();
+        assert!(EntryReader::new(tar, 9_000, 2_000).read_to_end(&mut truncated).is_err());
+    }
+
+    let empty = MappedFile::map(&tempfile::tempfile().unwrap()).unwrap();
+    assert!(empty.is_empty());
+}
+
+#[tokio::test(flavor = "multi_thread", worker_threads = 2)]
+async fn async_reads_deliver_the_whole_file() {
+    let data: Vec<u8> = (0..300_000u32).map(|i| (i % 251) as u8).collect();
+    let mut file = tempfile::tempfile().unwrap();
+    file.write_all(&data).unwrap();
+    file.rewind().unwrap();
+
+    // Smaller chunks than the file, so the reader waits on the task.
+    let mut reader = AsyncTarReader::open(file, 4096);
+    let read = tokio::task::block_in_place(|| {
+        let mut read = Vec::new();
+        reader.read_to_end(&mut read).unwrap();
+        read
+    });
+    assert_eq!(read, data);
+}
+
+#[test]
+fn glob_classes_match_like_a_shell() {
+    let parts = glob_regex("part-[0-9].tar").unwrap();
+    assert!(parts.is_match("part-7.tar"));
+    assert!(!parts.is_match("part-x.tar"));
+
+    let negated = glob_regex("part-[!0-9].tar").unwrap();
+    assert!(negated.is_match("part-x.tar"));
+    assert!(!negated.is_match("part-7.tar"));
+
+    // Regex syntax inside a class is taken literally.
+    let literal = glob_regex("a[]\\^].tar").unwrap();
+    for name in ["a].tar", "a\\.tar", "a^.tar"] {
+        assert!(literal.is_match(name), "{}", name);
+    }
+    assert!(!literal.is_match("ab.tar"));
+}
+
+#[test]
+fn an_unclosed_glob_bracket_matches_itself() {
+    let unclosed = glob_regex("batch[1*.tar").unwrap();
+    assert!(unclosed.is_match("batch[1.tar"));
+    assert!(unclosed.is_match("batch[12.tar"));
+    assert!(!unclosed.is_match("batch1.tar"));
+
+    assert!(glob_regex("[!").unwrap().is_match("[!"));
+    assert!(glob_regex("x[]").unwrap().is_match("x[]"));
+}
@@ -0,0 +1,29 @@
+use untar::digest::HdfsChecksum;
+
+fn checksum(data: &[u8], block_size: u64, write_size: usize) -> (String, String) {
+    let mut hdfs = HdfsChecksum::new(block_size);
+    for chunk in data.chunks(write_size.max(1)) {
+        hdfs.update(chunk);
+    }
+    let checksum = hdfs.finish();
+    (checksum.algorithm, checksum.checksum)
+}
+
+#[test]
+fn matches_hdfs_dfs_checksum() {
+    // What `hdfs dfs -checksum` prints for an empty file.
+    assert_eq!(
+        checksum(b"", 128 << 20, 1),
+        (
+            "MD5-of-0MD5-of-0CRC32".to_string(),
+            "00000000000000000000000070bc8f4b72a86921468bf8e8441dce51".to_string()
+        )
+    );
+
+    // Single-block files report no CRCs per block; multi-block files do.
+    let data: Vec<u8> = (0..5000u32).map(|i| (i * 31 % 251) as u8).collect();
+    assert_eq!(checksum(&data, 1 << 20, 777).0, "MD5-of-0MD5-of-512CRC32C");
+    let (algorithm, digest) = checksum(&data, 2048, 777);
+    assert_eq!(algorithm, "MD5-of-4MD5-of-512CRC32C");
+    assert_eq!(digest, checksum(&data, 2048, 4096).1);
+}
@@ -0,0 +1,98 @@
+use std::collections::HashMap;
+use std::io::{Cursor, Write};
+use std::sync::{Arc, Mutex};
+use untar::config::Config;
+use untar::naming::{NameTemplate, TemplateContext};
+use untar::processor::{CollisionPolicy, Processor, TarSource};
+use untar::report::RunReport;
+use untar::sink::Sink;
+
+type Written = Arc<Mutex<HashMap<String, Vec<u8>>>>;
+
+/// A processor for `dir/a.txt` and `dir/a.csv`, in manifest groups `first`
+/// and `second`, named by their stem alone so both map to `dir/a`.
+fn processor(dst_map: &[(&str, &str)], policy: CollisionPolicy) -> (Processor, Written, tempfile::NamedTempFile) {
+    let mut manifest = tempfile::NamedTempFile::new().unwrap();
+    write!(
+        manifest,
+        "<transmit-content>\
+         <file><filename>dir/a.txt</filename><filesize>3</filesize><group>first</group></file>\
+         <file><filename>dir/a.csv</filename><filesize>3</filesize><group>second</group></file>\
+         </transmit-content>"
+    )
+    .unwrap();
+    let written = Arc::new(Mutex::new(HashMap::new()));
+    let context = TemplateContext { run_id: "run-1".to_string(), date: "20260101".to_string() };
+    let processor = Processor::new(
+        Sink::Memory(written.clone()),
+        Config::from_xml_file(manifest.path()).unwrap(),
+        "/dst".to_string(),
+        manifest.path().to_string_lossy().to_string(),
+    )
+    .with_name_template(Some(NameTemplate::parse("{stem}").unwrap()), context)
+    .with_collision_policy(policy)
+    .with_dst_map(dst_map.iter().map(|(group, root)| (group.to_string(), root.to_string())).collect());
+    (processor, written, manifest)
+}
+
+/// A tar of `dir/a.txt` holding `txt`, then `dir/a.csv` holding `csv`.
+fn delivery() -> Vec<u8> {
+    let mut builder = tar::Builder::new(Vec::new());
+    for (name, data) in [("dir/a.txt", b"txt"), ("dir/a.csv", b"csv")] {
+        let mut header = tar::Header::new_gnu();
+        header.set_size(3);
+        header.set_mode(0o644);
+        header.set_cksum();
+        builder.append_data(&mut header, name, &data[..]).unwrap();
+    }
+    builder.into_inner().unwrap()
+}
+
+fn run(dst_map: &[(&str, &str)], policy: CollisionPolicy) -> (RunReport, HashMap<String, Vec<u8>>) {
+    let (processor, written, _manifest) = processor(dst_map, policy);
+    let source = TarSource { name: "delivery.tar".to_string(), reader: Cursor::new(delivery()), top_dir: None };
+
+    let runtime = tokio::runtime::Runtime::new().unwrap();
+    let report = runtime.block_on(processor.process_tars(vec![source])).unwrap();
+    let files = written.lock().unwrap().clone();
+    (report, files)
+}
+
+#[test]
+fn entries_mapped_to_one_target_collide() {
+    let (report, files) = run(&[], CollisionPolicy::Suffix);
+    assert_eq!(report.collisions.len(), 1);
+    assert_eq!(report.collisions[0].target, "/dst/dir/a");
+    assert_eq!(files.get("/dst/dir/a").map(Vec::as_slice), Some(&b"txt"[..]));
+    assert_eq!(files.get("/dst/dir/a.1").map(Vec::as_slice), Some(&b"csv"[..]));
+}
+
+#[test]
+fn entries_routed_to_different_roots_do_not_collide() {
+    let (report, files) = run(&[("first", "/logs"), ("second", "/tables")], CollisionPolicy::Suffix);
+    assert!(report.collisions.is_empty());
+    assert_eq!(files.get("/logs/dir/a").map(Vec::as_slice), Some(&b"txt"[..]));
+    assert_eq!(files.get("/tables/dir/a").map(Vec::as_slice), Some(&b"csv"[..]));
+}
+
+#[test]
+fn the_last_entry_replaces_the_record_of_the_one_it_overwrites() {
+    let (report, files) = run(&[], CollisionPolicy::LastWins);
+    assert_eq!(report.collisions.len(), 1);
+    let entries: Vec<&str> = report.files.iter().map(|file| file.entry.as_str()).collect();
+    assert_eq!(entries, ["dir/a.csv"]);
+    assert_eq!(files.get("/dst/dir/a").map(Vec::as_slice), Some(&b"csv"[..]));
+}
+
+#[test]
+fn colliding_names_fail_before_anything_is_uploaded() {
+    let (processor, written, _manifest) = processor(&[], CollisionPolicy::Error);
+    let mut tar = tempfile::tempfile().unwrap();
+    tar.write_all(&delivery()).unwrap();
+    let source = TarSource { name: "delivery.tar".to_string(), reader: tar, top_dir: None };
+
+    let runtime = tokio::runtime::Runtime::new().unwrap();
+    let e = runtime.block_on(processor.process_indexed_tars(vec![source])).unwrap_err();
+    assert!(e.to_string().contains("Name collision on dir/a"), "{:#}", e);
+    assert!(written.lock().unwrap().is_empty());
+}
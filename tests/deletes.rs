@@ -0,0 +1,76 @@
+use std::collections::HashMap;
+use std::io::{Cursor, Write};
+use std::sync::{Arc, Mutex};
+use untar::config::Config;
+use untar::processor::{Processor, RunFailed, TarSource};
+use untar::sink::Sink;
+
+/// A manifest delivering `new.txt` and deleting `deleted`.
+fn manifest(deleted: &str) -> tempfile::NamedTempFile {
+    let mut manifest = tempfile::NamedTempFile::new().unwrap();
+    write!(
+        manifest,
+        "<transmit-content>\
+         <file><filename>new.txt</filename><filesize>3</filesize></file>\
+         <deleted-file><filename>{}</filename></deleted-file>\
+         </transmit-content>",
+        deleted
+    )
+    .unwrap();
+    manifest
+}
+
+fn run(deleted: &str, existing: &[(&str, &[u8])]) -> (anyhow::Result<untar::report::RunReport>, HashMap<String, Vec<u8>>) {
+    let manifest = manifest(deleted);
+    let files: HashMap<String, Vec<u8>> =
+        existing.iter().map(|(path, data)| (path.to_string(), data.to_vec())).collect();
+    let written = Arc::new(Mutex::new(files));
+    let processor = Processor::new(
+        Sink::Memory(written.clone()),
+        Config::from_xml_file(manifest.path()).unwrap(),
+        "/dst".to_string(),
+        manifest.path().to_string_lossy().to_string(),
+    )
+    .with_apply_deletes(Some("/trash".to_string()));
+
+    let mut builder = tar::Builder::new(Vec::new());
+    let mut header = tar::Header::new_gnu();
+    header.set_size(3);
+    header.set_mode(0o644);
+    header.set_cksum();
+    builder.append_data(&mut header, "new.txt", &b"new"[..]).unwrap();
+    let source = TarSource { name: "delivery.tar".to_string(), reader: Cursor::new(builder.into_inner().unwrap()), top_dir: None };
+
+    let runtime = tokio::runtime::Runtime::new().unwrap();
+    let result = runtime.block_on(processor.process_tars(vec![source]));
+    let files = written.lock().unwrap().clone();
+    (result, files)
+}
+
+#[test]
+fn deleted_files_are_moved_to_the_trash() {
+    let (result, files) = run("old/file.txt", &[("/dst/old/file.txt", b"old"), ("/dst/old/kept.txt", b"kept")]);
+    let report = result.unwrap();
+    assert_eq!(report.deletions.len(), 1);
+    assert_eq!(report.deletions[0].trash.as_deref(), Some("/trash/dst/old/file.txt"));
+    assert!(!files.contains_key("/dst/old/file.txt"));
+    assert_eq!(files.get("/trash/dst/old/file.txt").map(Vec::as_slice), Some(&b"old"[..]));
+    assert!(files.contains_key("/dst/old/kept.txt"));
+}
+
+#[test]
+fn directories_are_never_deleted() {
+    let (result, files) = run("old", &[("/dst/old/file.txt", b"old")]);
+    let failed = result.unwrap_err();
+    assert!(failed.downcast_ref::<RunFailed>().unwrap().reason.contains("is a directory"));
+    assert!(files.contains_key("/dst/old/file.txt"));
+}
+
+#[test]
+fn deleted_names_must_stay_below_the_destination() {
+    for name in ["", ".", "..", "/etc/passwd", "./file.txt", "old/../../etc", "old//file.txt", "old/"] {
+        let manifest = manifest(name);
+        let error = Config::from_xml_file(manifest.path()).err().unwrap_or_else(|| panic!("{:?} was accepted", name));
+        assert!(error.to_string().contains("not a relative path"), "{:?}: {}", name, error);
+    }
+}
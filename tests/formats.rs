@@ -0,0 +1,136 @@
+use std::io::{Read, Write};
+use std::process::{Command, Stdio};
+use sha2::{Digest, Sha256};
+use untar::compress::ZEncoder;
+use untar::decompress::{get_format, wrap_decoder, DecompressionFormat, FormatMap, SuffixRule};
+
+#[test]
+fn suffix_map_picks_format_and_name() {
+    let formats = FormatMap::default();
+    assert_eq!(formats.format("logs.tgz"), DecompressionFormat::Gzip);
+    assert_eq!(formats.decompressed_name("logs.tgz"), "logs.tar");
+    assert_eq!(formats.decompressed_name("logs.gzip"), "logs");
+    assert_eq!(formats.decompressed_name("old.taz"), "old.tar");
+    assert_eq!(formats.decompressed_name("notes.txt"), "notes.txt");
+
+    let formats = formats.with_rules([
+        SuffixRule::parse(".z=none").unwrap(),
+        SuffixRule::parse(".raw.gz=gzip:.bin").unwrap(),
+    ]);
+    assert_eq!(formats.format("legacy.z"), DecompressionFormat::None);
+    assert_eq!(formats.decompressed_name("legacy.z"), "legacy.z");
+    // The longest suffix wins.
+    assert_eq!(formats.decompressed_name("dump.raw.gz"), "dump.bin");
+    assert_eq!(formats.decompressed_name("dump.gz"), "dump");
+
+    assert!(SuffixRule::parse(".x=rar").is_err());
+    assert!(SuffixRule::parse("tgz=gzip").is_err());
+}
+
+fn sha256_hex(data: &[u8]) -> String {
+    hex::encode(Sha256::digest(data))
+}
+
+#[test]
+fn z_files_written_by_compress_decode() {
+    // See fixtures/README.md for how it was made.
+    let compressed = std::fs::read(concat!(env!("CARGO_MANIFEST_DIR"), "/tests/fixtures/mixed.bin.Z")).unwrap();
+    let mut out = Vec::new();
+    wrap_decoder(get_format("mixed.bin.Z"), &compressed[..]).unwrap().read_to_end(&mut out).unwrap();
+    assert_eq!(out.len(), 90_000);
+    assert_eq!(sha256_hex(&out), "3371a08e9e78d9e04efbe7ea18b8ad7cfe05b1619f2a6ce1d48522dab693d5c9");
+}
+
+#[test]
+fn gzip_decodes_z_files_we_write() {
+    let mut data = Vec::new();
+    let mut state = 0x2545_f491_4f6c_dd1du64;
+    for i in 0..200_000u32 {
+        // Text-like runs with random stretches, to fill and clear the table.
+        state ^= state << 13;
+        state ^= state >> 7;
+        state ^= state << 17;
+        data.push(if (i / 10_000) % 2 == 0 { b"abcdefgh "[(state % 9) as usize] } else { state as u8 });
+    }
+    for bits in [9, 12, 16] {
+        let mut encoder = ZEncoder::new(Vec::new(), bits);
+        encoder.write_all(&data).unwrap();
+        let compressed = encoder.finish().unwrap();
+
+        let mut gzip = match Command::new("gzip").arg("-dc").stdin(Stdio::piped()).stdout(Stdio::piped()).spawn() {
+            Ok(gzip) => gzip,
+            Err(e) => {
+                eprintln!("skipping: gzip is not available: {}", e);
+                return;
+            }
+        };
+        let mut stdin = gzip.stdin.take().unwrap();
+        let writer = std::thread::spawn(move || stdin.write_all(&compressed));
+        let output = gzip.wait_with_output().unwrap();
+        writer.join().unwrap().unwrap();
+        assert!(output.status.success(), "gzip -d rejected {}-bit output: {}", bits, String::from_utf8_lossy(&output.stderr));
+        assert!(output.stdout == data, "gzip -d decoded {}-bit output differently", bits);
+    }
+}
+
+/// Adler-32, for the lzop header checksum.
+fn adler32(data: &[u8]) -> u32 {
+    let (mut a, mut b) = (1u32, 0u32);
+    for &byte in data {
+        a = (a + byte as u32) % 65521;
+        b = (b + a) % 65521;
+    }
+    (b << 16) | a
+}
+
+#[test]
+fn lzo1x_blocks_decode_every_kind_of_match() {
+    // Assembled by hand from the LZO1X instruction set (see the kernel's
+    // Documentation/staging/lzo.rst), as no lzop is at hand to write one;
+    // the pipeline tests' own compressor only emits M3 matches.
+    let mut block = vec![21];
+    block.extend(b"abcd"); // 4 initial literals
+    block.extend([110, 0]); // M2: 4 bytes from 4 back, then 2 literals
+    block.extend(b"XY");
+    block.extend([5, 0]); // M1 after 2 literals: 2 bytes from 2 back, then 1 literal
+    block.extend(b"Z");
+    block.extend([50, 0, 0]); // M3: 20 bytes from 1 back
+    block.extend([1]); // 4 literals
+    block.extend(b"0123");
+    block.push(32); // M3: 33 + 16,367 bytes from 4 back
+    block.extend([0; 64]);
+    block.extend([47, 12, 0]);
+    block.extend([18, 215, 0]); // M4: 4 bytes from 16,437 back, then 3 literals
+    block.extend(b"xyz");
+    block.extend([72, 0]); // M2: 3 bytes from 3 back
+    block.extend([1]); // 4 literals
+    block.extend(b"PQRS");
+    block.extend([0, 0]); // M1 after a literal run: 3 bytes from 2,049 back
+    block.extend([17, 0, 0]); // end of stream
+
+    let mut expected = b"abcdabcdXYXYZ".to_vec();
+    expected.extend([b'Z'; 20]);
+    expected.extend(b"0123".repeat(4101));
+    expected.extend(b"abcdxyzxyzPQRS123");
+
+    let mut header = Vec::new();
+    header.extend(0x1040u16.to_be_bytes()); // version
+    header.extend(0x2080u16.to_be_bytes()); // library version
+    header.extend(0x0940u16.to_be_bytes()); // version needed
+    header.extend([1, 5]); // LZO1X-1, level
+    header.extend(0u32.to_be_bytes()); // no checksums
+    header.extend([0u8; 12]); // mode, mtime
+    header.push(0); // no name
+    let mut lzo = vec![0x89, b'L', b'Z', b'O', 0x00, 0x0d, 0x0a, 0x1a, 0x0a];
+    lzo.extend(&header);
+    lzo.extend(adler32(&header).to_be_bytes());
+    lzo.extend((expected.len() as u32).to_be_bytes());
+    lzo.extend((block.len() as u32).to_be_bytes());
+    lzo.extend(&block);
+    lzo.extend(0u32.to_be_bytes());
+
+    let mut out = Vec::new();
+    wrap_decoder(get_format("all.lzo"), &lzo[..]).unwrap().read_to_end(&mut out).unwrap();
+    assert_eq!(out.len(), 16_454);
+    assert!(out == expected, "LZO1X matches decoded wrongly");
+}
@@ -0,0 +1,112 @@
+//! End-to-end runs of the untar binary against a single-node HDFS started
+//! with hdfs-native's MiniDFS helper (needs Java and Maven):
+//!
+//!     cargo test --features integration-tests --test integration
+
+#![cfg(feature = "integration-tests")]
+
+use std::collections::HashSet;
+use std::io::Write;
+use std::path::Path;
+use std::process::{Command, Output};
+use flate2::write::GzEncoder;
+use flate2::Compression;
+use hdfs_native::client::{Client, ClientBuilder, WriteOptions};
+use hdfs_native::minidfs::MiniDfs;
+use tokio::sync::Mutex;
+
+/// MiniDFS listens on fixed ports, so only one cluster may run at a time.
+static CLUSTER: Mutex<()> = Mutex::const_new(());
+
+/// (tar member, manifest name, decompressed content)
+const FILES: &[(&str, &str, &[u8])] = &[
+    ("a.txt", "a.txt", b"hello world\n"),
+    ("logs/b.csv.gz", "logs/b.csv", b"id,value\n1,one\n2,two\n"),
+];
+
+fn write_tar(path: &Path) {
+    let mut builder = tar::Builder::new(std::fs::File::create(path).unwrap());
+    for (member, _, content) in FILES {
+        let data = if member.ends_with(".gz") {
+            let mut encoder = GzEncoder::new(Vec::new(), Compression::default());
+            encoder.write_all(content).unwrap();
+            encoder.finish().unwrap()
+        } else {
+            content.to_vec()
+        };
+        let mut header = tar::Header::new_gnu();
+        header.set_size(data.len() as u64);
+        header.set_mode(0o644);
+        header.set_cksum();
+        builder.append_data(&mut header, member, &data[..]).unwrap();
+    }
+    builder.finish().unwrap();
+}
+
+fn write_manifest(path: &Path, extra: &[(&str, u64)]) {
+    let mut xml = String::from("<transmit-content>\n");
+    let listed = FILES.iter().map(|(_, name, content)| (*name, content.len() as u64));
+    for (name, size) in listed.chain(extra.iter().copied()) {
+        xml.push_str(&format!("<file><filename>{}</filename><filesize>{}</filesize></file>\n", name, size));
+    }
+    xml.push_str("</transmit-content>\n");
+    std::fs::write(path, xml).unwrap();
+}
+
+fn run_untar(url: &str, dir: &Path, dst: &str) -> Output {
+    Command::new(env!("CARGO_BIN_EXE_untar"))
+        .arg("--tar").arg(dir.join("data.tar"))
+        .arg("--xml").arg(dir.join("manifest.xml"))
+        .args(["--namenode", url, "--dst", dst])
+        .arg("--report").arg(dir.join("report.xml"))
+        .output()
+        .unwrap()
+}
+
+async fn read_file(client: &Client, path: &str) -> Vec<u8> {
+    let mut reader = client.read(path).await.unwrap();
+    let len = reader.file_length();
+    reader.read(len).await.unwrap().to_vec()
+}
+
+#[tokio::test]
+async fn delivers_files_and_receipt() {
+    let _cluster = CLUSTER.lock().await;
+    let dfs = MiniDfs::with_features(&HashSet::new());
+    let client = ClientBuilder::new().with_url(&dfs.url).build().unwrap();
+    let dir = tempfile::tempdir().unwrap();
+    write_tar(&dir.path().join("data.tar"));
+    write_manifest(&dir.path().join("manifest.xml"), &[]);
+
+    let output = run_untar(&dfs.url, dir.path(), "/it/delivery");
+    assert!(output.status.success(), "untar failed: {}", String::from_utf8_lossy(&output.stderr));
+
+    for (_, name, content) in FILES {
+        let path = format!("/it/delivery/{}", name);
+        let status = client.get_file_info(&path).await.unwrap();
+        assert_eq!(status.length, content.len(), "size of {}", path);
+        assert_eq!(status.permission as u32, WriteOptions::default().permission, "permission of {}", path);
+        assert_eq!(read_file(&client, &path).await, *content, "content of {}", path);
+    }
+    let receipt = read_file(&client, "/it/delivery/manifest.xml").await;
+    assert_eq!(receipt, std::fs::read(dir.path().join("manifest.xml")).unwrap());
+
+    let report = std::fs::read_to_string(dir.path().join("report.xml")).unwrap();
+    assert!(report.contains("<receipt>/it/delivery/manifest.xml</receipt>"), "{}", report);
+    assert!(report.contains("<delivered>2</delivered>"), "{}", report);
+}
+
+#[tokio::test]
+async fn incomplete_delivery_fails_without_receipt() {
+    let _cluster = CLUSTER.lock().await;
+    let dfs = MiniDfs::with_features(&HashSet::new());
+    let client = ClientBuilder::new().with_url(&dfs.url).build().unwrap();
+    let dir = tempfile::tempdir().unwrap();
+    write_tar(&dir.path().join("data.tar"));
+    write_manifest(&dir.path().join("manifest.xml"), &[("missing.bin", 10)]);
+
+    let output = run_untar(&dfs.url, dir.path(), "/it/incomplete");
+    assert!(!output.status.success());
+    assert!(String::from_utf8_lossy(&output.stderr).contains("Missing file in TAR: missing.bin"));
+    assert!(client.get_file_info("/it/incomplete/manifest.xml").await.is_err());
+}
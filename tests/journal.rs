@@ -0,0 +1,108 @@
+use std::collections::HashMap;
+use std::io::{Cursor, Write};
+use std::sync::{Arc, Mutex};
+use untar::config::Config;
+use untar::journal::{Journal, Recorded};
+use untar::processor::{IfExists, Processor, TarSource};
+use untar::report::{FileRecord, RunReport};
+use untar::sink::Sink;
+
+const TARGET: &str = "/dst/dir/file.bin";
+
+fn record(size: u64) -> FileRecord {
+    FileRecord {
+        entry: "dir/file.bin".to_string(),
+        target: TARGET.to_string(),
+        raw_size: size,
+        raw_sha256: "0".repeat(64),
+        size,
+        sha256: "f".repeat(64),
+        stored_size: None,
+        checksum: None,
+        parts: Vec::new(),
+    }
+}
+
+#[tokio::test]
+async fn reruns_on_another_host_see_what_was_committed() {
+    let sink = Sink::Memory(Arc::new(Mutex::new(Default::default())));
+    let first = Journal::new(sink.clone(), "/dst", "run-1");
+    assert_eq!(first.lookup(TARGET).await.unwrap(), Recorded::Absent);
+
+    first.start(TARGET, TARGET).await.unwrap();
+    // The host died mid-upload.
+    let second = Journal::new(sink.clone(), "/dst/", "run-1");
+    assert_eq!(second.lookup(TARGET).await.unwrap(), Recorded::Partial { path: TARGET.to_string() });
+
+    second.start(TARGET, TARGET).await.unwrap();
+    second.commit(TARGET, &record(42)).await.unwrap();
+    let third = Journal::new(sink.clone(), "/dst", "run-1");
+    assert_eq!(
+        third.lookup(TARGET).await.unwrap(),
+        Recorded::Committed { size: 42, sha256: "f".repeat(64) }
+    );
+    // Writing it again withdraws the commit until that write is committed.
+    third.start(TARGET, TARGET).await.unwrap();
+    assert_eq!(third.lookup(TARGET).await.unwrap(), Recorded::Partial { path: TARGET.to_string() });
+
+    // Other runs keep their own journals.
+    let other = Journal::new(sink, "/dst", "run-2");
+    assert_eq!(other.lookup(TARGET).await.unwrap(), Recorded::Absent);
+}
+
+/// Delivers `dir/file.bin` to `/dst` with the journal of `run-1`.
+async fn deliver(files: &Arc<Mutex<HashMap<String, Vec<u8>>>>, if_exists: IfExists) -> anyhow::Result<RunReport> {
+    let mut manifest = tempfile::NamedTempFile::new().unwrap();
+    write!(manifest, "<transmit-content><file><filename>dir/file.bin</filename><filesize>3</filesize></file></transmit-content>").unwrap();
+    let sink = Sink::Memory(files.clone());
+    let processor = Processor::new(
+        sink.clone(),
+        Config::from_xml_file(manifest.path()).unwrap(),
+        "/dst".to_string(),
+        manifest.path().to_string_lossy().to_string(),
+    )
+    .with_if_exists(if_exists)
+    .with_journal(Some(Journal::new(sink, "/dst", "run-1")));
+
+    let mut builder = tar::Builder::new(Vec::new());
+    let mut header = tar::Header::new_gnu();
+    header.set_size(3);
+    header.set_mode(0o644);
+    header.set_cksum();
+    builder.append_data(&mut header, "dir/file.bin", &b"new"[..]).unwrap();
+    let source = TarSource { name: "delivery.tar".to_string(), reader: Cursor::new(builder.into_inner().unwrap()), top_dir: None };
+    processor.process_tars(vec![source]).await
+}
+
+#[tokio::test]
+async fn reruns_do_not_version_a_file_again() {
+    let files = Arc::new(Mutex::new(HashMap::from([(TARGET.to_string(), b"old".to_vec())])));
+    let report = deliver(&files, IfExists::Version).await.unwrap();
+    assert_eq!(report.files[0].target, format!("{}.1", TARGET));
+
+    let report = deliver(&files, IfExists::Version).await.unwrap();
+    assert!(report.files.is_empty());
+    assert_eq!(report.already_present, vec![TARGET.to_string()]);
+    let files = files.lock().unwrap();
+    assert_eq!(files.get(TARGET).map(Vec::as_slice), Some(&b"old"[..]));
+    assert_eq!(files.get(&format!("{}.1", TARGET)).map(Vec::as_slice), Some(&b"new"[..]));
+    assert!(!files.contains_key(&format!("{}.2", TARGET)));
+}
+
+#[tokio::test]
+async fn partly_written_files_are_rewritten_whatever_the_policy() {
+    let versioned = format!("{}.1", TARGET);
+    let files = Arc::new(Mutex::new(HashMap::from([
+        (TARGET.to_string(), b"old".to_vec()),
+        (versioned.clone(), b"n".to_vec()),
+    ])));
+    // An earlier attempt died writing the versioned file.
+    Journal::new(Sink::Memory(files.clone()), "/dst", "run-1").start(TARGET, &versioned).await.unwrap();
+
+    let report = deliver(&files, IfExists::Fail).await.unwrap();
+    assert_eq!(report.partial, vec![versioned.clone()]);
+    assert_eq!(report.files[0].target, versioned);
+    let files = files.lock().unwrap();
+    assert_eq!(files.get(TARGET).map(Vec::as_slice), Some(&b"old"[..]));
+    assert_eq!(files.get(&versioned).map(Vec::as_slice), Some(&b"new"[..]));
+}
@@ -0,0 +1,89 @@
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+use untar::lease::{Claim, Leases};
+use untar::sink::Sink;
+
+const TARGET: &str = "/dst/dir/file.bin";
+
+#[tokio::test]
+async fn a_held_lease_keeps_other_runs_out_until_released() {
+    let sink = Sink::Memory(Arc::new(Mutex::new(Default::default())));
+    let first = Arc::new(Leases::new(sink.clone(), "/dst", Duration::from_secs(60)));
+    let second = Leases::new(sink.clone(), "/dst/", Duration::from_secs(60));
+
+    assert!(matches!(first.acquire(TARGET).await.unwrap(), Claim::Acquired));
+    match second.acquire(TARGET).await.unwrap() {
+        Claim::HeldBy(holder) => assert_eq!(holder, first.owner()),
+        Claim::Acquired => panic!("lease acquired twice"),
+    }
+
+    let releaser = first.clone();
+    let released = tokio::spawn(async move {
+        tokio::time::sleep(Duration::from_millis(50)).await;
+        releaser.release(TARGET).await;
+    });
+    assert!(second.wait_released(TARGET).await.unwrap());
+    released.await.unwrap();
+    assert!(matches!(second.acquire(TARGET).await.unwrap(), Claim::Acquired));
+}
+
+#[tokio::test]
+async fn expired_leases_are_taken_over() {
+    let sink = Sink::Memory(Arc::new(Mutex::new(Default::default())));
+    let dead = Leases::new(sink.clone(), "/dst", Duration::ZERO);
+    let live = Leases::new(sink.clone(), "/dst", Duration::from_secs(60));
+
+    assert!(matches!(dead.acquire(TARGET).await.unwrap(), Claim::Acquired));
+    tokio::time::sleep(Duration::from_millis(5)).await;
+    assert!(!live.wait_released(TARGET).await.unwrap());
+    assert!(matches!(live.acquire(TARGET).await.unwrap(), Claim::Acquired));
+}
+
+#[tokio::test(flavor = "multi_thread", worker_threads = 4)]
+async fn only_one_of_two_racing_runs_takes_over_an_expired_lease() {
+    for _ in 0..50 {
+        let sink = Sink::Memory(Arc::new(Mutex::new(Default::default())));
+        let dead = Leases::new(sink.clone(), "/dst", Duration::ZERO);
+        assert!(matches!(dead.acquire(TARGET).await.unwrap(), Claim::Acquired));
+        tokio::time::sleep(Duration::from_millis(2)).await;
+
+        let racers: Vec<_> = (0..2)
+            .map(|_| {
+                let leases = Arc::new(Leases::new(sink.clone(), "/dst", Duration::from_secs(60)));
+                let racer = leases.clone();
+                (leases, tokio::spawn(async move { racer.acquire(TARGET).await.unwrap() }))
+            })
+            .collect();
+        let mut acquired = 0;
+        for (_, claim) in racers {
+            if matches!(claim.await.unwrap(), Claim::Acquired) {
+                acquired += 1;
+            }
+        }
+        assert_eq!(acquired, 1, "an expired lease was taken over {} times", acquired);
+        let files = match &sink {
+            Sink::Memory(files) => files.lock().unwrap().keys().cloned().collect::<Vec<_>>(),
+            _ => unreachable!(),
+        };
+        assert_eq!(files.len(), 1, "leftover lease files: {:?}", files);
+    }
+}
+
+#[tokio::test]
+async fn a_run_whose_lease_was_taken_over_leaves_it_to_the_new_holder() {
+    let sink = Sink::Memory(Arc::new(Mutex::new(Default::default())));
+    let stalled = Leases::new(sink.clone(), "/dst", Duration::ZERO);
+    let live = Leases::new(sink.clone(), "/dst", Duration::from_secs(60));
+    let third = Leases::new(sink.clone(), "/dst", Duration::from_secs(60));
+
+    assert!(matches!(stalled.acquire(TARGET).await.unwrap(), Claim::Acquired));
+    tokio::time::sleep(Duration::from_millis(5)).await;
+    assert!(matches!(live.acquire(TARGET).await.unwrap(), Claim::Acquired));
+
+    assert!(!stalled.renew(TARGET).await.unwrap());
+    stalled.release(TARGET).await;
+    match third.acquire(TARGET).await.unwrap() {
+        Claim::HeldBy(holder) => assert_eq!(holder, live.owner()),
+        Claim::Acquired => panic!("a released takeover dropped the new holder's lease"),
+    }
+}
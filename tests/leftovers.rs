@@ -0,0 +1,77 @@
+use std::io::{Cursor, Write};
+use std::path::Path;
+use flate2::write::GzEncoder;
+use flate2::Compression;
+use untar::config::Config;
+use untar::processor::{Processor, RunFailed, TarSource};
+use untar::sink::Sink;
+
+const SIZE: usize = 2 << 20;
+
+/// `SIZE` bytes gzipped, with the CRC in the trailer flipped so the member
+/// only fails once all of it has been decompressed and handed on.
+fn corrupt_gzip() -> Vec<u8> {
+    let mut state = 1u32;
+    let data: Vec<u8> = (0..SIZE)
+        .map(|_| {
+            state = state.wrapping_mul(1_103_515_245).wrapping_add(12_345);
+            (state >> 16) as u8
+        })
+        .collect();
+    let mut encoder = GzEncoder::new(Vec::new(), Compression::fast());
+    encoder.write_all(&data).unwrap();
+    let mut compressed = encoder.finish().unwrap();
+    let crc = compressed.len() - 8;
+    compressed[crc] ^= 0xff;
+    compressed
+}
+
+/// Delivers `data/big.bin.gz` holding `corrupt_gzip()` into `dst` on local disk.
+fn run(dst: &Path, append: bool) -> RunFailed {
+    let mut manifest = tempfile::NamedTempFile::new().unwrap();
+    write!(
+        manifest,
+        "<transmit-content><file><filename>data/big.bin</filename><filesize>{}</filesize></file></transmit-content>",
+        SIZE
+    )
+    .unwrap();
+    let processor = Processor::new(
+        Sink::Local,
+        Config::from_xml_file(manifest.path()).unwrap(),
+        dst.to_string_lossy().to_string(),
+        manifest.path().to_string_lossy().to_string(),
+    )
+    .with_append(append);
+
+    let compressed = corrupt_gzip();
+    let mut builder = tar::Builder::new(Vec::new());
+    let mut header = tar::Header::new_gnu();
+    header.set_size(compressed.len() as u64);
+    header.set_mode(0o644);
+    header.set_cksum();
+    builder.append_data(&mut header, "data/big.bin.gz", &compressed[..]).unwrap();
+    let source = TarSource { name: "delivery.tar".to_string(), reader: Cursor::new(builder.into_inner().unwrap()), top_dir: None };
+
+    let runtime = tokio::runtime::Runtime::new().unwrap();
+    let failed = runtime.block_on(processor.process_tars(vec![source])).unwrap_err();
+    failed.downcast::<RunFailed>().unwrap()
+}
+
+#[test]
+fn a_member_that_fails_to_decompress_leaves_no_file_behind() {
+    let dst = tempfile::tempdir().unwrap();
+    let failed = run(dst.path(), false);
+    assert_eq!(failed.report.failures.len(), 1);
+    assert!(failed.report.failures[0].error.contains("CRC mismatch"), "{}", failed.report.failures[0].error);
+    assert!(!dst.path().join("data/big.bin").exists());
+}
+
+#[test]
+fn an_increment_that_fails_to_decompress_is_cut_back_off() {
+    let dst = tempfile::tempdir().unwrap();
+    std::fs::create_dir(dst.path().join("data")).unwrap();
+    std::fs::write(dst.path().join("data/big.bin"), b"earlier\n").unwrap();
+    let failed = run(dst.path(), true);
+    assert_eq!(failed.report.failures.len(), 1);
+    assert_eq!(std::fs::read(dst.path().join("data/big.bin")).unwrap(), b"earlier\n");
+}
@@ -0,0 +1,34 @@
+use std::io::Write;
+use untar::config::Config;
+use untar::matcher::{matcher, MatchStrategy};
+
+fn manifest(names: &[&str]) -> Config {
+    let mut file = tempfile::NamedTempFile::new().unwrap();
+    write!(file, "<transmit-content>").unwrap();
+    for name in names {
+        write!(file, "<file><filename>{}</filename><filesize>1</filesize></file>", name).unwrap();
+    }
+    write!(file, "</transmit-content>").unwrap();
+    Config::from_xml_file(file.path()).unwrap()
+}
+
+#[test]
+fn strategies_find_their_manifest_entry() {
+    let config = manifest(&["logs/App.log", "img/a.png", "other/a.png"]);
+    let find = |strategy, name: &str| {
+        matcher(strategy, Some(r"^export_\d+/"), "").unwrap().find(name, &config).unwrap()
+    };
+
+    assert_eq!(find(MatchStrategy::Exact, "logs/App.log").as_deref(), Some("logs/App.log"));
+    assert_eq!(find(MatchStrategy::Exact, "./logs/app.log"), None);
+    assert_eq!(find(MatchStrategy::Normalized, "./LOGS//app.log").as_deref(), Some("logs/App.log"));
+    assert_eq!(find(MatchStrategy::Regex, "export_7/img/a.png").as_deref(), Some("img/a.png"));
+    assert_eq!(find(MatchStrategy::Basename, "x/App.log").as_deref(), Some("logs/App.log"));
+    // Ambiguous file name.
+    assert_eq!(find(MatchStrategy::Basename, "x/a.png"), None);
+}
+
+#[test]
+fn regex_strategy_requires_a_pattern() {
+    assert!(matcher(MatchStrategy::Regex, None, "").is_err());
+}
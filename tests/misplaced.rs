@@ -0,0 +1,88 @@
+use std::collections::HashMap;
+use std::io::{Cursor, Write};
+use std::sync::{Arc, Mutex};
+use untar::config::Config;
+use untar::processor::{Processor, RunFailed, TarSource};
+use untar::report::RunReport;
+use untar::sink::Sink;
+
+/// Delivers a tar of `entries` against a manifest listing `data/file.txt`.
+fn run(entries: &[(&str, &[u8])], configure: impl FnOnce(Processor) -> Processor) -> (anyhow::Result<RunReport>, HashMap<String, Vec<u8>>) {
+    let mut manifest = tempfile::NamedTempFile::new().unwrap();
+    write!(
+        manifest,
+        "<transmit-content><file><filename>data/file.txt</filename><filesize>4</filesize></file></transmit-content>"
+    )
+    .unwrap();
+    let written = Arc::new(Mutex::new(HashMap::new()));
+    let processor = configure(Processor::new(
+        Sink::Memory(written.clone()),
+        Config::from_xml_file(manifest.path()).unwrap(),
+        "/dst".to_string(),
+        manifest.path().to_string_lossy().to_string(),
+    ));
+
+    let mut builder = tar::Builder::new(Vec::new());
+    for (name, data) in entries {
+        let mut header = tar::Header::new_gnu();
+        header.set_size(data.len() as u64);
+        header.set_mode(0o644);
+        header.set_cksum();
+        builder.append_data(&mut header, name, *data).unwrap();
+    }
+    let source = TarSource { name: "delivery.tar".to_string(), reader: Cursor::new(builder.into_inner().unwrap()), top_dir: None };
+
+    let runtime = tokio::runtime::Runtime::new().unwrap();
+    let result = runtime.block_on(processor.process_tars(vec![source]));
+    let files = written.lock().unwrap().clone();
+    (result, files)
+}
+
+#[test]
+fn misplaced_entries_are_skipped_and_reported_by_default() {
+    let (result, files) = run(&[("elsewhere/file.txt", b"away")], |processor| processor);
+    let failed = result.unwrap_err();
+    let failed = failed.downcast_ref::<RunFailed>().unwrap();
+    assert!(failed.reason.contains("found as elsewhere/file.txt"), "{}", failed.reason);
+    assert_eq!(failed.report.misplaced.len(), 1);
+    assert_eq!(failed.report.misplaced[0].action, "skipped");
+    assert!(files.is_empty());
+}
+
+#[test]
+fn misplaced_entries_fail_the_run_with_strict_paths() {
+    let (result, files) = run(&[("elsewhere/file.txt", b"away")], |processor| processor.with_strict_paths(true));
+    let failed = format!("{:#}", result.unwrap_err());
+    assert!(failed.contains("--strict-paths"), "{}", failed);
+    assert!(files.is_empty());
+}
+
+#[test]
+fn misplaced_entries_are_relocated_on_request() {
+    let (result, files) = run(&[("elsewhere/file.txt", b"away")], |processor| processor.with_relocate_misplaced(true));
+    let report = result.unwrap();
+    assert_eq!(report.misplaced[0].action, "relocated");
+    assert_eq!(files.get("/dst/data/file.txt").map(Vec::as_slice), Some(&b"away"[..]));
+}
+
+#[test]
+fn an_entry_at_the_manifest_path_replaces_a_relocated_copy() {
+    let entries: [(&str, &[u8]); 2] = [("elsewhere/file.txt", b"away"), ("data/file.txt", b"home")];
+    let (result, files) = run(&entries, |processor| processor.with_relocate_misplaced(true));
+    let report = result.unwrap();
+    assert_eq!(report.misplaced[0].action, "superseded");
+    assert!(report.collisions.is_empty());
+    assert_eq!(report.files.len(), 1);
+    assert_eq!(report.files[0].entry, "data/file.txt");
+    assert_eq!(files.get("/dst/data/file.txt").map(Vec::as_slice), Some(&b"home"[..]));
+}
+
+#[test]
+fn a_relocated_copy_never_replaces_an_entry_at_the_manifest_path() {
+    let entries: [(&str, &[u8]); 2] = [("data/file.txt", b"home"), ("elsewhere/file.txt", b"away")];
+    let (result, files) = run(&entries, |processor| processor.with_relocate_misplaced(true));
+    let report = result.unwrap();
+    assert_eq!(report.misplaced[0].action, "skipped");
+    assert_eq!(report.files.len(), 1);
+    assert_eq!(files.get("/dst/data/file.txt").map(Vec::as_slice), Some(&b"home"[..]));
+}
@@ -0,0 +1,978 @@
+//! Property tests for the decode -> chunk channel -> sink pipeline.
+
+use std::collections::HashMap;
+use std::io::{Cursor, Read, Write};
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+use flate2::write::GzEncoder;
+use flate2::Compression;
+use proptest::collection::vec;
+use proptest::prelude::*;
+use untar::compress::{Recompression, ZEncoder};
+use untar::config::Config;
+use untar::decompress::{get_format, wrap_decoder, ZDecoder};
+use untar::parallel_gzip::ParallelGzipDecoder;
+use untar::processor::{ChunkSizing, IfExists, MemberOrder, Processor, RunFailed, Shard, Speculation, TarSource, ThroughputFloor, STAGING_DIR};
+use untar::sink::Sink;
+use untar::spill::Spill;
+use untar::workdir::WorkDir;
+
+fn gzip(data: &[u8]) -> Vec<u8> {
+    let mut encoder = GzEncoder::new(Vec::new(), Compression::fast());
+    encoder.write_all(data).unwrap();
+    encoder.finish().unwrap()
+}
+
+/// BGZF as written by bgzip: gzip members of at most 64 KiB, each recording
+/// its own size, then an empty end-of-file member.
+fn bgzf(data: &[u8], block: usize) -> Vec<u8> {
+    let mut out = Vec::new();
+    for chunk in data.chunks(block.max(1)).chain(std::iter::once(&[][..])) {
+        let mut encoder = flate2::write::DeflateEncoder::new(Vec::new(), Compression::fast());
+        encoder.write_all(chunk).unwrap();
+        let deflated = encoder.finish().unwrap();
+        let bsize = (18 + deflated.len() + 8 - 1) as u16;
+        out.extend([0x1f, 0x8b, 8, 4, 0, 0, 0, 0, 0, 0xff, 6, 0, b'B', b'C', 2, 0]);
+        out.extend(bsize.to_le_bytes());
+        out.extend(&deflated);
+        let mut crc = flate2::Crc::new();
+        crc.update(chunk);
+        out.extend(crc.sum().to_le_bytes());
+        out.extend((chunk.len() as u32).to_le_bytes());
+    }
+    out
+}
+
+fn bzip2(data: &[u8]) -> Vec<u8> {
+    let mut encoder = bzip2::write::BzEncoder::new(Vec::new(), bzip2::Compression::fast());
+    encoder.write_all(data).unwrap();
+    encoder.finish().unwrap()
+}
+
+/// Hadoop `BlockCompressorStream` framing: per block, its uncompressed length,
+/// then length-prefixed raw Snappy chunks.
+fn hadoop_snappy(data: &[u8], block_size: usize, chunk_size: usize) -> Vec<u8> {
+    let mut encoder = snap::raw::Encoder::new();
+    let mut out = Vec::new();
+    for block in data.chunks(block_size) {
+        out.extend((block.len() as u32).to_be_bytes());
+        for chunk in block.chunks(chunk_size) {
+            let compressed = encoder.compress_vec(chunk).unwrap();
+            out.extend((compressed.len() as u32).to_be_bytes());
+            out.extend(compressed);
+        }
+    }
+    out
+}
+
+/// One lzip member: LZMA-alone data minus its 13-byte header, wrapped in
+/// the lzip header and trailer.
+fn lzip(data: &[u8]) -> Vec<u8> {
+    let mut options = xz2::stream::LzmaOptions::new_preset(1).unwrap();
+    options.dict_size(1 << 20).literal_context_bits(3).literal_position_bits(0).position_bits(2);
+    let stream = xz2::stream::Stream::new_lzma_encoder(&options).unwrap();
+    let mut encoder = xz2::write::XzEncoder::new_stream(Vec::new(), stream);
+    encoder.write_all(data).unwrap();
+    let alone = encoder.finish().unwrap();
+
+    let mut crc = flate2::Crc::new();
+    crc.update(data);
+    let mut out = b"LZIP\x01\x14".to_vec();
+    out.extend(&alone[13..]);
+    out.extend(crc.sum().to_le_bytes());
+    out.extend((data.len() as u64).to_le_bytes());
+    out.extend((out.len() as u64 + 16).to_le_bytes());
+    out
+}
+
+/// Minimal greedy LZO1X compressor: literal runs and M3 matches only.
+fn lzo1x(data: &[u8]) -> Vec<u8> {
+    fn extended(out: &mut Vec<u8>, mut rem: usize) {
+        while rem > 255 {
+            out.push(0);
+            rem -= 255;
+        }
+        out.push(rem as u8);
+    }
+    // Split into (literal run, match) sequences first, since a match carries
+    // the count of up to 3 literals that follow it.
+    let mut sequences = Vec::new();
+    let mut table = std::collections::HashMap::new();
+    let (mut pos, mut lit_start) = (0, 0);
+    while pos + 3 <= data.len() {
+        let candidate = table.insert(&data[pos..pos + 3], pos);
+        match candidate.filter(|&c| pos - c <= 0x4000) {
+            Some(c) => {
+                let mut len = 3;
+                while pos + len < data.len() && data[c + len] == data[pos + len] {
+                    len += 1;
+                }
+                sequences.push((&data[lit_start..pos], Some((pos - c, len))));
+                pos += len;
+                lit_start = pos;
+            }
+            None => pos += 1,
+        }
+    }
+    sequences.push((&data[lit_start..], None));
+
+    let mut out = Vec::new();
+    for (i, (literals, m)) in sequences.iter().enumerate() {
+        if i == 0 && !literals.is_empty() && literals.len() <= 238 {
+            out.push(literals.len() as u8 + 17);
+            out.extend_from_slice(literals);
+        } else if literals.len() >= 4 || (i == 0 && !literals.is_empty()) {
+            if literals.len() <= 18 {
+                out.push(literals.len() as u8 - 3);
+            } else {
+                out.push(0);
+                extended(&mut out, literals.len() - 18);
+            }
+            out.extend_from_slice(literals);
+        } else {
+            out.extend_from_slice(literals); // already counted by the match before
+        }
+        if let Some((dist, len)) = *m {
+            let next_literals = sequences[i + 1].0.len();
+            let next = if next_literals < 4 { next_literals } else { 0 };
+            if len <= 33 {
+                out.push(32 | (len - 2) as u8);
+            } else {
+                out.push(32);
+                extended(&mut out, len - 33);
+            }
+            out.extend_from_slice(&((((dist - 1) << 2) | next) as u16).to_le_bytes());
+        }
+    }
+    out.extend_from_slice(&[0x11, 0, 0]);
+    out
+}
+
+fn adler32(data: &[u8]) -> u32 {
+    let (mut a, mut b) = (1u32, 0u32);
+    for &byte in data {
+        a = (a + byte as u32) % 65521;
+        b = (b + a) % 65521;
+    }
+    (b << 16) | a
+}
+
+/// An lzop file with `block`-sized LZO1X blocks, an Adler-32 of the
+/// uncompressed and a CRC32 of the compressed data per block.
+fn lzop(data: &[u8], block: usize) -> Vec<u8> {
+    let crc32 = |data: &[u8]| {
+        let mut crc = flate2::Crc::new();
+        crc.update(data);
+        crc.sum()
+    };
+    let mut header = Vec::new();
+    header.extend(0x1040u16.to_be_bytes()); // version
+    header.extend(0x2080u16.to_be_bytes()); // library version
+    header.extend(0x0940u16.to_be_bytes()); // version needed
+    header.extend([1, 5]); // LZO1X-1, level
+    header.extend((0x1 | 0x200u32).to_be_bytes()); // F_ADLER32_D | F_CRC32_C
+    header.extend([0u8; 12]); // mode, mtime
+    header.push(8);
+    header.extend(b"data.tar");
+
+    let mut out = vec![0x89, b'L', b'Z', b'O', 0x00, 0x0d, 0x0a, 0x1a, 0x0a];
+    out.extend(&header);
+    out.extend(adler32(&header).to_be_bytes());
+    for chunk in data.chunks(block) {
+        let compressed = lzo1x(chunk);
+        out.extend((chunk.len() as u32).to_be_bytes());
+        if compressed.len() < chunk.len() {
+            out.extend((compressed.len() as u32).to_be_bytes());
+            out.extend(adler32(chunk).to_be_bytes());
+            out.extend(crc32(&compressed).to_be_bytes());
+            out.extend(&compressed);
+        } else {
+            out.extend((chunk.len() as u32).to_be_bytes());
+            out.extend(adler32(chunk).to_be_bytes());
+            out.extend(chunk);
+        }
+    }
+    out.extend(0u32.to_be_bytes());
+    out
+}
+
+/// A pack (`.z`) file. Uses a complete tree over the distinct bytes plus the
+/// end-of-block code rather than a frequency-based one; pack's decoder does
+/// not care how the code lengths were chosen.
+fn pack(data: &[u8]) -> Vec<u8> {
+    let mut symbols: Vec<u8> = data.to_vec();
+    symbols.sort_unstable();
+    symbols.dedup();
+    let n = symbols.len() + 1; // with end-of-block
+    let max_len = (usize::BITS - (n - 1).leading_zeros()).max(1) as usize;
+    let long = 2 * n - (1 << max_len); // leaves at max_len, the rest one shorter
+    let mut leaves = vec![0usize; max_len + 1];
+    leaves[max_len] = long;
+    leaves[max_len - 1] = n - long;
+
+    let mut parents = vec![0usize; max_len + 1];
+    let mut nodes = 0;
+    for len in (1..=max_len).rev() {
+        nodes >>= 1;
+        parents[len] = nodes;
+        nodes += leaves[len];
+    }
+    // Shorter codes go to the first symbols, end-of-block is the last long code.
+    let mut codes = std::collections::HashMap::new();
+    for (i, &symbol) in symbols.iter().enumerate() {
+        let len = if i < leaves[max_len - 1] { max_len - 1 } else { max_len };
+        let index = if len < max_len { i } else { i - leaves[max_len - 1] };
+        codes.insert(symbol, (len, parents[len] + index));
+    }
+    let eob = (max_len, parents[max_len] + long - 1);
+
+    let mut out = vec![0x1f, 0x1e];
+    out.extend((data.len() as u32).to_be_bytes());
+    out.push(max_len as u8);
+    out.extend(leaves[1..max_len].iter().map(|&count| count as u8));
+    out.push((leaves[max_len] - 2) as u8); // stored minus 2
+    out.extend(&symbols);
+    let (mut acc, mut bits) = (0u64, 0);
+    for &(len, code) in data.iter().map(|b| &codes[b]).chain(std::iter::once(&eob)) {
+        acc = (acc << len) | code as u64;
+        bits += len;
+        while bits >= 8 {
+            bits -= 8;
+            out.push((acc >> bits) as u8);
+        }
+    }
+    if bits > 0 {
+        out.push((acc << (8 - bits)) as u8);
+    }
+    out
+}
+
+/// Builds a tar of `(name, content, gzip?)` members, naming gzip members `<name>.gz`.
+fn build_tar(files: &[(String, Vec<u8>, bool)]) -> Vec<u8> {
+    let mut builder = tar::Builder::new(Vec::new());
+    for (name, data, compressed) in files {
+        let (member_name, member) = if *compressed {
+            (format!("{}.gz", name), gzip(data))
+        } else {
+            (name.clone(), data.clone())
+        };
+        let mut header = tar::Header::new_gnu();
+        header.set_size(member.len() as u64);
+        header.set_mode(0o644);
+        header.set_cksum();
+        builder.append_data(&mut header, member_name, &member[..]).unwrap();
+    }
+    builder.into_inner().unwrap()
+}
+
+fn write_manifest(files: &[(String, Vec<u8>, bool)]) -> tempfile::NamedTempFile {
+    let mut xml = String::from("<transmit-content>");
+    for (name, data, _) in files {
+        xml.push_str(&format!(
+            "<file><filename>{}</filename><filesize>{}</filesize></file>",
+            name,
+            data.len()
+        ));
+    }
+    xml.push_str("</transmit-content>");
+    let mut file = tempfile::NamedTempFile::new().unwrap();
+    file.write_all(xml.as_bytes()).unwrap();
+    file
+}
+
+fn files_strategy() -> impl Strategy<Value = Vec<(String, Vec<u8>, bool)>> {
+    vec((vec(any::<u8>(), 0..100_000), any::<bool>()), 1..6).prop_map(|members| {
+        members
+            .into_iter()
+            .enumerate()
+            .map(|(i, (data, compressed))| (format!("dir{}/file{}.bin", i % 2, i), data, compressed))
+            .collect()
+    })
+}
+
+fn if_exists_strategy() -> impl Strategy<Value = IfExists> {
+    prop_oneof![
+        Just(IfExists::Overwrite),
+        Just(IfExists::Skip),
+        Just(IfExists::Fail),
+        Just(IfExists::Version),
+    ]
+}
+
+fn order_strategy() -> impl Strategy<Value = MemberOrder> {
+    prop_oneof![
+        Just(MemberOrder::Archive),
+        Just(MemberOrder::Size),
+        Just(MemberOrder::Name),
+        Just(MemberOrder::Manifest),
+    ]
+}
+
+proptest! {
+    #[test]
+    fn gzip_round_trips(data in vec(any::<u8>(), 0..65_536), split in any::<prop::sample::Index>()) {
+        // Two members, as produced by appending gzip files.
+        let split = split.index(data.len() + 1);
+        let mut compressed = gzip(&data[..split]);
+        compressed.extend(gzip(&data[split..]));
+        let mut out = Vec::new();
+        wrap_decoder(get_format("data.gz"), &compressed[..]).unwrap().read_to_end(&mut out).unwrap();
+        prop_assert_eq!(out, data);
+    }
+
+    #[test]
+    fn parallel_gzip_round_trips(data in vec(0u8..8, 0..200_000), block in 1usize..60_000, threads in 1usize..5) {
+        let mut out = Vec::new();
+        ParallelGzipDecoder::new(&bgzf(&data, block)[..], threads).read_to_end(&mut out).unwrap();
+        prop_assert_eq!(&out, &data);
+
+        out.clear();
+        ParallelGzipDecoder::new(&gzip(&data)[..], threads).read_to_end(&mut out).unwrap();
+        prop_assert_eq!(&out, &data);
+    }
+
+    #[test]
+    fn gzip_trailer_corruption_is_detected(data in vec(any::<u8>(), 0..65_536), byte in 0usize..8, flip in 1u8..=255) {
+        let mut compressed = gzip(&data);
+        let trailer = compressed.len() - 8;
+        compressed[trailer + byte] ^= flip;
+        let mut out = Vec::new();
+        let err = wrap_decoder(get_format("data.gz"), &compressed[..]).unwrap().read_to_end(&mut out).unwrap_err();
+        let expected = if byte < 4 { "CRC mismatch" } else { "length mismatch" };
+        prop_assert!(err.to_string().contains(expected), "{}", err);
+    }
+
+    #[test]
+    fn gzip_zero_padding_is_ignored_but_other_trailing_bytes_are_not(
+        data in vec(0u8..8, 0..100_000),
+        padding in 1usize..20_000,
+        at in any::<prop::sample::Index>(),
+        garbage in 1u8..=255,
+    ) {
+        let padded = [gzip(&data), vec![0; padding]].concat();
+        let mut out = Vec::new();
+        wrap_decoder(get_format("data.gz"), &padded[..]).unwrap().read_to_end(&mut out).unwrap();
+        prop_assert_eq!(&out, &data);
+
+        out.clear();
+        let padded_bgzf = [bgzf(&data, 30_000), vec![0; padding]].concat();
+        ParallelGzipDecoder::new(&padded_bgzf[..], 2).read_to_end(&mut out).unwrap();
+        prop_assert_eq!(&out, &data);
+
+        let mut corrupt = padded;
+        let len = corrupt.len();
+        corrupt[len - padding + at.index(padding)] = garbage;
+        let err = wrap_decoder(get_format("data.gz"), &corrupt[..]).unwrap().read_to_end(&mut Vec::new()).unwrap_err();
+        prop_assert!(err.to_string().contains("trailing garbage"), "{}", err);
+    }
+
+    #[test]
+    fn z_round_trips(text in vec(0u8..4, 0..40_000), noise in vec(any::<u8>(), 0..40_000), bits in 10u8..=16) {
+        // Repetitive data, then noise that makes the encoder clear its table.
+        let data = [&text[..], &noise[..], &text[..]].concat();
+        let mut encoder = ZEncoder::new(Vec::new(), bits);
+        encoder.write_all(&data).unwrap();
+        let compressed = encoder.finish().unwrap();
+        let mut out = Vec::new();
+        wrap_decoder(get_format("data.Z"), &compressed[..]).unwrap().read_to_end(&mut out).unwrap();
+        prop_assert_eq!(out, data);
+    }
+
+    #[test]
+    fn z_without_magic_is_rejected(data in vec(any::<u8>(), 0..64)) {
+        prop_assume!(!data.starts_with(&[0x1f, 0x9d]));
+        let err = wrap_decoder(get_format("data.Z"), &data[..]).unwrap().read_to_end(&mut Vec::new()).unwrap_err();
+        prop_assert!(err.to_string().contains("invalid compress header"), "{}", err);
+
+        let mut out = Vec::new();
+        ZDecoder::new(&data[..]).lenient().read_to_end(&mut out).unwrap();
+        prop_assert!(out.is_empty());
+    }
+
+    #[test]
+    fn z_corruption_is_an_error_not_a_panic(flags in any::<u8>(), body in vec(any::<u8>(), 0..4096)) {
+        let mut data = vec![0x1f, 0x9d, flags];
+        data.extend(&body);
+        let result = ZDecoder::new(&data[..]).read_to_end(&mut Vec::new());
+        if !(9..=16).contains(&(flags & 0x1f)) {
+            let err = result.unwrap_err();
+            prop_assert!(err.to_string().contains("unsupported compress code size"), "{}", err);
+        } else if let Err(err) = result {
+            prop_assert_eq!(err.kind(), std::io::ErrorKind::InvalidData);
+        }
+    }
+
+    #[test]
+    fn bzip2_round_trips(data in vec(any::<u8>(), 0..65_536), split in any::<prop::sample::Index>()) {
+        // Two concatenated streams, as written by pbzip2.
+        let split = split.index(data.len() + 1);
+        let mut compressed = bzip2(&data[..split]);
+        compressed.extend(bzip2(&data[split..]));
+        let mut out = Vec::new();
+        wrap_decoder(get_format("data.bz2"), &compressed[..]).unwrap().read_to_end(&mut out).unwrap();
+        prop_assert_eq!(out, data);
+    }
+
+    #[test]
+    fn xz_and_lzma_round_trip(data in vec(any::<u8>(), 0..65_536)) {
+        let xz = xz2::stream::Stream::new_easy_encoder(1, xz2::stream::Check::Crc64).unwrap();
+        let lzma = xz2::stream::Stream::new_lzma_encoder(&xz2::stream::LzmaOptions::new_preset(1).unwrap()).unwrap();
+        for (name, stream) in [("data.xz", xz), ("data.lzma", lzma)] {
+            let mut encoder = xz2::write::XzEncoder::new_stream(Vec::new(), stream);
+            encoder.write_all(&data).unwrap();
+            let compressed = encoder.finish().unwrap();
+            let mut out = Vec::new();
+            wrap_decoder(get_format(name), &compressed[..]).unwrap().read_to_end(&mut out).unwrap();
+            prop_assert_eq!(&out, &data);
+        }
+    }
+
+    #[test]
+    fn zstd_round_trips(data in vec(any::<u8>(), 0..65_536)) {
+        let compressed = zstd::encode_all(&data[..], 1).unwrap();
+        let mut out = Vec::new();
+        wrap_decoder(get_format("data.zst"), &compressed[..]).unwrap().read_to_end(&mut out).unwrap();
+        prop_assert_eq!(out, data);
+    }
+
+    #[test]
+    fn lz4_round_trips(data in vec(any::<u8>(), 0..65_536)) {
+        let mut encoder = lz4_flex::frame::FrameEncoder::new(Vec::new());
+        encoder.write_all(&data).unwrap();
+        let compressed = encoder.finish().unwrap();
+        let mut out = Vec::new();
+        wrap_decoder(get_format("data.lz4"), &compressed[..]).unwrap().read_to_end(&mut out).unwrap();
+        prop_assert_eq!(out, data);
+    }
+
+    #[test]
+    fn hadoop_snappy_round_trips(data in vec(any::<u8>(), 0..200_000), block in 1usize..100_000, chunk in 1usize..50_000) {
+        let compressed = hadoop_snappy(&data, block, chunk);
+        let mut out = Vec::new();
+        wrap_decoder(get_format("data.snappy"), &compressed[..]).unwrap().read_to_end(&mut out).unwrap();
+        prop_assert_eq!(out, data);
+    }
+
+    #[test]
+    fn brotli_round_trips(data in vec(any::<u8>(), 0..65_536)) {
+        let mut compressed = Vec::new();
+        {
+            let mut encoder = brotli::CompressorWriter::new(&mut compressed, 4096, 1, 22);
+            encoder.write_all(&data).unwrap();
+        }
+        let mut out = Vec::new();
+        wrap_decoder(get_format("data.br"), &compressed[..]).unwrap().read_to_end(&mut out).unwrap();
+        prop_assert_eq!(out, data);
+    }
+
+    #[test]
+    fn lzip_round_trips(data in vec(any::<u8>(), 0..65_536), split in any::<prop::sample::Index>()) {
+        // Two members, as written by plzip.
+        let split = split.index(data.len() + 1);
+        let mut compressed = lzip(&data[..split]);
+        compressed.extend(lzip(&data[split..]));
+        let mut out = Vec::new();
+        wrap_decoder(get_format("data.lz"), &compressed[..]).unwrap().read_to_end(&mut out).unwrap();
+        prop_assert_eq!(out, data);
+    }
+
+    #[test]
+    fn lzop_round_trips(data in vec(0u8..4, 0..65_536), block in 1usize..20_000) {
+        // A small alphabet, so the LZO1X blocks contain plenty of matches.
+        let compressed = lzop(&data, block);
+        let mut out = Vec::new();
+        wrap_decoder(get_format("data.lzo"), &compressed[..]).unwrap().read_to_end(&mut out).unwrap();
+        prop_assert_eq!(out, data);
+    }
+
+    #[test]
+    fn pack_round_trips(data in vec(any::<u8>(), 1..65_536)) {
+        let mut out = Vec::new();
+        wrap_decoder(get_format("data.z"), &pack(&data)[..]).unwrap().read_to_end(&mut out).unwrap();
+        prop_assert_eq!(out, data);
+    }
+
+    #[test]
+    fn uncompressed_passes_through(data in vec(any::<u8>(), 0..65_536)) {
+        let mut out = Vec::new();
+        wrap_decoder(get_format("data.bin"), &data[..]).unwrap().read_to_end(&mut out).unwrap();
+        prop_assert_eq!(out, data);
+    }
+}
+
+proptest! {
+    #![proptest_config(ProptestConfig::with_cases(32))]
+
+    #[test]
+    fn pipeline_delivers_every_byte(files in files_strategy(), uploads in 1usize..4, decoders in 1usize..4, read_ahead in 1usize..200_000, max_buffers in 1usize..4, max_bytes in proptest::option::of(1usize..300_000), read_buffer in 1usize..100_000, min_chunk in 1usize..70_000, extra_chunk in 0usize..200_000, queue_depth in 1usize..4, max_in_flight in proptest::option::of(1usize..200_000), max_connections in proptest::option::of(1usize..3)) {
+        let manifest = write_manifest(&files);
+        let config = Config::from_xml_file(manifest.path()).unwrap();
+        let written = Arc::new(Mutex::new(Default::default()));
+        let processor = Processor::new(
+            Sink::Memory(written.clone()),
+            config,
+            "/dst".to_string(),
+            manifest.path().to_string_lossy().to_string(),
+        )
+        .with_upload_concurrency(uploads)
+        .with_decompress_workers(Some(decoders))
+        .with_read_ahead(read_ahead)
+        .with_max_buffers(max_buffers, max_bytes)
+        .with_buffer_sizes(read_buffer, ChunkSizing { min: min_chunk, max: min_chunk + extra_chunk })
+        .with_queue_depth(queue_depth, max_in_flight)
+        .with_max_connections(max_connections);
+        let source = TarSource {
+            name: "props.tar".to_string(),
+            reader: Cursor::new(build_tar(&files)),
+            top_dir: None,
+        };
+
+        let runtime = tokio::runtime::Runtime::new().unwrap();
+        let report = runtime.block_on(processor.process_tars(vec![source])).unwrap();
+
+        let written = written.lock().unwrap();
+        let total: u64 = files.iter().map(|(_, data, _)| data.len() as u64).sum();
+        prop_assert_eq!(report.bytes_written, total);
+        for (name, data, _) in &files {
+            let target = format!("/dst/{}", name);
+            prop_assert_eq!(written.get(&target), Some(data), "content of {}", target);
+        }
+        let raw: u64 = report.files.iter().map(|file| file.raw_size).sum();
+        let stage_bytes: Vec<(&str, u64)> = report.stages.iter().map(|stage| (stage.name, stage.bytes)).collect();
+        prop_assert_eq!(stage_bytes, vec![("read", raw), ("decompress", total), ("write", total)]);
+        // Every member plus the uploaded manifest itself.
+        prop_assert_eq!(written.len(), files.len() + 1);
+    }
+
+    #[test]
+    fn pipeline_extracts_nested_archives(files in files_strategy()) {
+        let manifest = write_manifest(&files);
+        let config = Config::from_xml_file(manifest.path()).unwrap();
+        let written = Arc::new(Mutex::new(Default::default()));
+        let processor = Processor::new(
+            Sink::Memory(written.clone()),
+            config,
+            "/dst".to_string(),
+            manifest.path().to_string_lossy().to_string(),
+        )
+        .with_recurse_archives(true);
+        // The inner tar is gzipped by build_tar and stored as inner.tar.gz.
+        let source = TarSource {
+            name: "outer.tar".to_string(),
+            reader: Cursor::new(build_tar(&[("inner.tar".to_string(), build_tar(&files), true)])),
+            top_dir: None,
+        };
+
+        let runtime = tokio::runtime::Runtime::new().unwrap();
+        let report = runtime.block_on(processor.process_tars(vec![source])).unwrap();
+
+        let written = written.lock().unwrap();
+        for (name, data, _) in &files {
+            let target = format!("/dst/{}", name);
+            prop_assert_eq!(written.get(&target), Some(data), "content of {}", target);
+        }
+        prop_assert_eq!(report.archives, vec!["outer.tar".to_string(), "outer.tar!inner.tar.gz".to_string()]);
+    }
+
+    #[test]
+    fn pipeline_extracts_archives_concurrently(files in files_strategy(), archives in 1usize..4, workers in 1usize..4) {
+        let manifest = write_manifest(&files);
+        let config = Config::from_xml_file(manifest.path()).unwrap();
+        let written = Arc::new(Mutex::new(Default::default()));
+        let processor = Processor::new(
+            Sink::Memory(written.clone()),
+            config,
+            "/dst".to_string(),
+            manifest.path().to_string_lossy().to_string(),
+        )
+        .with_archive_workers(workers);
+        // Member i goes into archive i % archives.
+        let sources: Vec<_> = (0..archives)
+            .map(|archive| {
+                let members: Vec<_> = files.iter().skip(archive).step_by(archives).cloned().collect();
+                TarSource { name: format!("{}.tar", archive), reader: Cursor::new(build_tar(&members)), top_dir: None }
+            })
+            .collect();
+
+        let runtime = tokio::runtime::Runtime::new().unwrap();
+        let report = runtime.block_on(processor.process_tars(sources)).unwrap();
+
+        let written = written.lock().unwrap();
+        for (name, data, _) in &files {
+            let target = format!("/dst/{}", name);
+            prop_assert_eq!(written.get(&target), Some(data), "content of {}", target);
+        }
+        prop_assert_eq!(report.files.len(), files.len());
+        // Archives are reported in the order given, whichever finished first.
+        let names: Vec<String> = (0..archives).map(|archive| format!("{}.tar", archive)).collect();
+        prop_assert_eq!(report.archives, names);
+    }
+
+    #[test]
+    fn store_and_forward_delivers_every_byte(files in files_strategy(), uploads in 1usize..3, spill_max in 1u64..300_000) {
+        let manifest = write_manifest(&files);
+        let config = Config::from_xml_file(manifest.path()).unwrap();
+        let written = Arc::new(Mutex::new(Default::default()));
+        let dir = tempfile::tempdir().unwrap();
+        let processor = Processor::new(
+            Sink::Memory(written.clone()),
+            config,
+            "/dst".to_string(),
+            manifest.path().to_string_lossy().to_string(),
+        )
+        .with_upload_concurrency(uploads)
+        .with_queue_depth(1, None)
+        .with_spill(Some(Spill::new(WorkDir::new(dir.path().to_path_buf(), 0, "props".into()), spill_max)))
+        .with_store_and_forward(true);
+        let source = TarSource { name: "props.tar".to_string(), reader: Cursor::new(build_tar(&files)), top_dir: None };
+
+        let runtime = tokio::runtime::Runtime::new().unwrap();
+        let report = runtime.block_on(processor.process_tars(vec![source])).unwrap();
+
+        let written = written.lock().unwrap();
+        for (name, data, _) in &files {
+            let target = format!("/dst/{}", name);
+            prop_assert_eq!(written.get(&target), Some(data), "content of {}", target);
+        }
+        // Still reported in tar order.
+        let targets: Vec<String> = report.files.iter().map(|file| file.target.clone()).collect();
+        let expected: Vec<String> = files.iter().map(|(name, _, _)| format!("/dst/{}", name)).collect();
+        prop_assert_eq!(targets, expected);
+    }
+
+    #[test]
+    fn shards_split_the_manifest_without_overlap(files in files_strategy(), count in 1u64..4) {
+        let manifest = write_manifest(&files);
+        let xml_filename = manifest.path().file_name().unwrap().to_string_lossy().to_string();
+        let tar = build_tar(&files);
+        let runtime = tokio::runtime::Runtime::new().unwrap();
+
+        let mut delivered: Vec<String> = Vec::new();
+        for index in 0..count {
+            let shard = Shard { index, count };
+            let written = Arc::new(Mutex::new(Default::default()));
+            let processor = Processor::new(
+                Sink::Memory(written.clone()),
+                Config::from_xml_file(manifest.path()).unwrap(),
+                "/dst".to_string(),
+                manifest.path().to_string_lossy().to_string(),
+            )
+            .with_shard(Some(shard));
+            let source = TarSource { name: "test.tar".to_string(), reader: Cursor::new(tar.clone()), top_dir: None };
+            let report = runtime.block_on(processor.process_tars(vec![source])).unwrap();
+
+            let written = written.lock().unwrap();
+            for (name, data, _) in files.iter().filter(|(name, _, _)| shard.claims(name)) {
+                let target = format!("/dst/{}", name);
+                prop_assert_eq!(written.get(&target), Some(data), "content of {}", target);
+            }
+            let receipt = format!("/dst/{}.shard-{}-of-{}", xml_filename, index, count);
+            prop_assert!(written.contains_key(&receipt), "missing receipt {}", receipt);
+            delivered.extend(report.files.into_iter().map(|file| file.target));
+        }
+
+        delivered.sort();
+        let mut expected: Vec<String> = files.iter().map(|(name, _, _)| format!("/dst/{}", name)).collect();
+        expected.sort();
+        prop_assert_eq!(delivered, expected);
+    }
+
+    #[test]
+    fn indexed_pipeline_delivers_every_byte(files in files_strategy(), decoders in 1usize..4, mmap in any::<bool>(), order in order_strategy(), speculate in any::<bool>(), junk in vec(1u8..=255, 0..1024)) {
+        let manifest = write_manifest(&files);
+        let config = Config::from_xml_file(manifest.path()).unwrap();
+        let written = Arc::new(Mutex::new(Default::default()));
+        let processor = Processor::new(
+            Sink::Memory(written.clone()),
+            config,
+            "/dst".to_string(),
+            manifest.path().to_string_lossy().to_string(),
+        )
+        .with_decompress_workers(Some(decoders))
+        .with_mmap(mmap)
+        .with_member_order(order)
+        // Every upload below the median races a second copy.
+        .with_speculation(speculate.then_some(Speculation { min_share: 1.0, window: Duration::ZERO }))
+        .with_check_trailing_data(true);
+        let mut tar = tempfile::tempfile().unwrap();
+        tar.write_all(&build_tar(&files)).unwrap();
+        tar.write_all(&junk).unwrap();
+        let source = TarSource { name: "props.tar".to_string(), reader: tar, top_dir: None };
+
+        let runtime = tokio::runtime::Runtime::new().unwrap();
+        let report = runtime.block_on(processor.process_indexed_tars(vec![source])).unwrap();
+
+        let written = written.lock().unwrap();
+        for (name, data, _) in &files {
+            let target = format!("/dst/{}", name);
+            prop_assert_eq!(written.get(&target), Some(data), "content of {}", target);
+        }
+        // Second copies are written under the staging directory and moved or removed from there.
+        prop_assert!(!written.keys().any(|path| path.contains(STAGING_DIR)));
+        // Report entries stay in scheduling order although members are read in parallel.
+        let entries: Vec<String> = report.files.iter().map(|file| file.target.clone()).collect();
+        let mut scheduled: Vec<_> = files.iter().collect();
+        match order {
+            // The manifest lists files in tar order.
+            MemberOrder::Archive | MemberOrder::Manifest => {}
+            MemberOrder::Size => scheduled.sort_by_key(|(_, data, compressed)| {
+                std::cmp::Reverse(if *compressed { gzip(data).len() } else { data.len() })
+            }),
+            MemberOrder::Name => scheduled.sort_by(|a, b| a.0.cmp(&b.0)),
+        }
+        let expected: Vec<String> = scheduled.iter().map(|(name, _, _)| format!("/dst/{}", name)).collect();
+        prop_assert_eq!(entries, expected);
+        prop_assert_eq!(report.trailing_data.len(), usize::from(!junk.is_empty()));
+    }
+
+    #[test]
+    fn failed_uploads_are_retried_from_the_tar(files in files_strategy(), mmap in any::<bool>()) {
+        let manifest = write_manifest(&files);
+        let config = Config::from_xml_file(manifest.path()).unwrap();
+        let written = Arc::new(Mutex::new(Default::default()));
+        let processor = Processor::new(
+            Sink::Memory(written.clone()),
+            config,
+            "/dst".to_string(),
+            manifest.path().to_string_lossy().to_string(),
+        )
+        .with_mmap(mmap)
+        // Fails the first attempt at every non-empty file; retries have no floor.
+        .with_throughput_floor(Some(ThroughputFloor { min_mb_per_sec: f64::INFINITY, window: Duration::ZERO }))
+        .with_retries_per_file(1);
+        let mut tar = tempfile::tempfile().unwrap();
+        tar.write_all(&build_tar(&files)).unwrap();
+        let source = TarSource { name: "props.tar".to_string(), reader: tar, top_dir: None };
+
+        let runtime = tokio::runtime::Runtime::new().unwrap();
+        let report = runtime.block_on(processor.process_indexed_tars(vec![source])).unwrap();
+
+        let written = written.lock().unwrap();
+        for (name, data, _) in &files {
+            let target = format!("/dst/{}", name);
+            prop_assert_eq!(written.get(&target), Some(data), "content of {}", target);
+        }
+        prop_assert_eq!(report.files.len(), files.len());
+        prop_assert!(report.failures.is_empty());
+    }
+
+    #[test]
+    fn atomic_uploads_leave_nothing_staged(files in files_strategy(), retry in any::<bool>()) {
+        let manifest = write_manifest(&files);
+        let config = Config::from_xml_file(manifest.path()).unwrap();
+        let written = Arc::new(Mutex::new(Default::default()));
+        let mut processor = Processor::new(
+            Sink::Memory(written.clone()),
+            config,
+            "/dst".to_string(),
+            manifest.path().to_string_lossy().to_string(),
+        )
+        .with_atomic_uploads(Some("run-1".to_string()));
+        if retry {
+            // Every first attempt fails, leaving a staged file to remove.
+            processor = processor
+                .with_throughput_floor(Some(ThroughputFloor { min_mb_per_sec: f64::INFINITY, window: Duration::ZERO }))
+                .with_retries_per_file(1);
+        }
+        let mut tar = tempfile::tempfile().unwrap();
+        tar.write_all(&build_tar(&files)).unwrap();
+        let source = TarSource { name: "props.tar".to_string(), reader: tar, top_dir: None };
+
+        let runtime = tokio::runtime::Runtime::new().unwrap();
+        let report = runtime.block_on(processor.process_indexed_tars(vec![source])).unwrap();
+
+        let written = written.lock().unwrap();
+        for (name, data, _) in &files {
+            let target = format!("/dst/{}", name);
+            prop_assert_eq!(written.get(&target), Some(data), "content of {}", target);
+        }
+        prop_assert!(!written.keys().any(|path| path.contains(STAGING_DIR)), "staged files left: {:?}", written.keys());
+        prop_assert!(report.files.iter().all(|file| !file.target.contains(STAGING_DIR)));
+    }
+
+    #[test]
+    fn skip_existing_keeps_files_of_the_manifest_size(files in files_strategy(), present in vec(any::<bool>(), 6)) {
+        let manifest = write_manifest(&files);
+        let config = Config::from_xml_file(manifest.path()).unwrap();
+        // Earlier copies: of the right size but other bytes, or a byte too long.
+        let earlier: HashMap<String, Vec<u8>> = files
+            .iter()
+            .zip(&present)
+            .map(|((name, data, _), &present)| {
+                let mut copy: Vec<u8> = data.iter().map(|byte| !byte).collect();
+                if !present {
+                    copy.push(0);
+                }
+                (format!("/dst/{}", name), copy)
+            })
+            .collect();
+        let written = Arc::new(Mutex::new(earlier.clone()));
+        let processor = Processor::new(
+            Sink::Memory(written.clone()),
+            config,
+            "/dst".to_string(),
+            manifest.path().to_string_lossy().to_string(),
+        )
+        .with_skip_existing(true);
+        let source = TarSource { name: "props.tar".to_string(), reader: Cursor::new(build_tar(&files)), top_dir: None };
+
+        let runtime = tokio::runtime::Runtime::new().unwrap();
+        let report = runtime.block_on(processor.process_tars(vec![source])).unwrap();
+
+        let written = written.lock().unwrap();
+        let mut skipped = Vec::new();
+        for ((name, data, _), &present) in files.iter().zip(&present) {
+            let target = format!("/dst/{}", name);
+            let expected = if present { &earlier[&target] } else { data };
+            prop_assert_eq!(written.get(&target), Some(expected), "content of {}", target);
+            if present {
+                skipped.push(target);
+            }
+        }
+        prop_assert_eq!(&report.already_present, &skipped);
+        prop_assert_eq!(report.files.len() + skipped.len(), files.len());
+    }
+
+    #[test]
+    fn if_exists_policies_decide_the_fate_of_earlier_files(files in files_strategy(), policy in if_exists_strategy()) {
+        let manifest = write_manifest(&files);
+        let config = Config::from_xml_file(manifest.path()).unwrap();
+        let earlier: HashMap<String, Vec<u8>> = files
+            .iter()
+            .map(|(name, data, _)| (format!("/dst/{}", name), data.iter().map(|byte| !byte).chain([0]).collect()))
+            .collect();
+        let written = Arc::new(Mutex::new(earlier.clone()));
+        let processor = Processor::new(
+            Sink::Memory(written.clone()),
+            config,
+            "/dst".to_string(),
+            manifest.path().to_string_lossy().to_string(),
+        )
+        .with_if_exists(policy);
+        let source = TarSource { name: "props.tar".to_string(), reader: Cursor::new(build_tar(&files)), top_dir: None };
+
+        let runtime = tokio::runtime::Runtime::new().unwrap();
+        let result = runtime.block_on(processor.process_tars(vec![source]));
+
+        let written = written.lock().unwrap();
+        for (name, data, _) in &files {
+            let target = format!("/dst/{}", name);
+            let (at_target, versioned) = match policy {
+                IfExists::Overwrite => (data, None),
+                IfExists::Version => (&earlier[&target], Some(data)),
+                IfExists::Skip | IfExists::Fail => (&earlier[&target], None),
+            };
+            prop_assert_eq!(written.get(&target), Some(at_target), "content of {}", target);
+            prop_assert_eq!(written.get(&format!("{}.1", target)), versioned, "content of {}.1", target);
+        }
+        match policy {
+            IfExists::Fail => prop_assert!(result.unwrap_err().to_string().contains("already exists")),
+            IfExists::Skip => prop_assert_eq!(result.unwrap().already_present.len(), files.len()),
+            IfExists::Overwrite | IfExists::Version => prop_assert_eq!(result.unwrap().files.len(), files.len()),
+        }
+    }
+
+    #[test]
+    fn append_adds_each_increment_to_its_target(files in files_strategy(), earlier in vec(vec(any::<u8>(), 0..1000), 6)) {
+        let manifest = write_manifest(&files);
+        let config = Config::from_xml_file(manifest.path()).unwrap();
+        let earlier: HashMap<String, Vec<u8>> = files
+            .iter()
+            .zip(earlier)
+            .map(|((name, _, _), earlier)| (format!("/dst/{}", name), earlier))
+            .collect();
+        let written = Arc::new(Mutex::new(earlier.clone()));
+        let processor = Processor::new(
+            Sink::Memory(written.clone()),
+            config,
+            "/dst".to_string(),
+            manifest.path().to_string_lossy().to_string(),
+        )
+        .with_append(true);
+        let source = TarSource { name: "props.tar".to_string(), reader: Cursor::new(build_tar(&files)), top_dir: None };
+
+        let runtime = tokio::runtime::Runtime::new().unwrap();
+        let report = runtime.block_on(processor.process_tars(vec![source])).unwrap();
+
+        let written = written.lock().unwrap();
+        for ((name, data, _), file) in files.iter().zip(&report.files) {
+            let target = format!("/dst/{}", name);
+            let expected = [&earlier[&target][..], &data[..]].concat();
+            prop_assert_eq!(written.get(&target), Some(&expected), "content of {}", target);
+            prop_assert_eq!(file.size, data.len() as u64);
+        }
+        prop_assert_eq!(report.files.len(), files.len());
+    }
+
+    #[test]
+    fn failed_appends_are_cut_back_off(files in files_strategy(), earlier in vec(vec(any::<u8>(), 0..1000), 6), bad in any::<prop::sample::Index>()) {
+        // One file's manifest size is off by one, so its upload fails
+        // after the increment has been written.
+        let bad = bad.index(files.len());
+        let mut listed = files.clone();
+        listed[bad].1.push(0);
+        let manifest = write_manifest(&listed);
+        let config = Config::from_xml_file(manifest.path()).unwrap();
+        let earlier: HashMap<String, Vec<u8>> = files
+            .iter()
+            .zip(earlier)
+            .map(|((name, _, _), earlier)| (format!("/dst/{}", name), earlier))
+            .collect();
+        let written = Arc::new(Mutex::new(earlier.clone()));
+        let processor = Processor::new(
+            Sink::Memory(written.clone()),
+            config,
+            "/dst".to_string(),
+            manifest.path().to_string_lossy().to_string(),
+        )
+        .with_append(true);
+        let source = TarSource { name: "props.tar".to_string(), reader: Cursor::new(build_tar(&files)), top_dir: None };
+
+        let runtime = tokio::runtime::Runtime::new().unwrap();
+        let failed = runtime.block_on(processor.process_tars(vec![source])).unwrap_err();
+        let report = &failed.downcast_ref::<RunFailed>().unwrap().report;
+
+        let written = written.lock().unwrap();
+        let bad_target = format!("/dst/{}", files[bad].0);
+        prop_assert_eq!(written.get(&bad_target), Some(&earlier[&bad_target]), "content of {}", bad_target);
+        prop_assert_eq!(report.failures.len(), 1);
+        prop_assert_eq!(&report.failures[0].target, &bad_target);
+        for (name, data, _) in &files {
+            let target = format!("/dst/{}", name);
+            if target != bad_target {
+                let expected = [&earlier[&target][..], &data[..]].concat();
+                prop_assert_eq!(written.get(&target), Some(&expected), "content of {}", target);
+            }
+        }
+    }
+
+    #[test]
+    fn pipeline_recompresses_stored_files(files in files_strategy(), zstd in any::<bool>()) {
+        let manifest = write_manifest(&files);
+        let config = Config::from_xml_file(manifest.path()).unwrap();
+        let written = Arc::new(Mutex::new(Default::default()));
+        let recompression = if zstd { Recompression::Zstd { level: 3 } } else { Recompression::Gzip { level: 1 } };
+        let processor = Processor::new(
+            Sink::Memory(written.clone()),
+            config,
+            "/dst".to_string(),
+            manifest.path().to_string_lossy().to_string(),
+        )
+        .with_recompression(Some(recompression));
+        let source = TarSource { name: "t.tar".to_string(), reader: Cursor::new(build_tar(&files)), top_dir: None };
+
+        let runtime = tokio::runtime::Runtime::new().unwrap();
+        let report = runtime.block_on(processor.process_tars(vec![source])).unwrap();
+
+        let written = written.lock().unwrap();
+        for (name, data, _) in &files {
+            let target = format!("/dst/{}{}", name, recompression.suffix());
+            let stored = written.get(&target).unwrap_or_else(|| panic!("{} was not written", target));
+            let mut out = Vec::new();
+            wrap_decoder(get_format(&target), &stored[..]).unwrap().read_to_end(&mut out).unwrap();
+            prop_assert_eq!(&out, data, "content of {}", target);
+            let record = report.files.iter().find(|file| file.target == target).unwrap();
+            prop_assert_eq!(record.stored_size, Some(stored.len() as u64));
+        }
+    }
+}
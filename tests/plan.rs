@@ -0,0 +1,54 @@
+use std::collections::HashMap;
+use std::io::Write;
+use std::sync::{Arc, Mutex};
+use untar::config::Config;
+use untar::plan::PlanAction;
+use untar::processor::Processor;
+use untar::sink::Sink;
+
+#[test]
+fn dry_run_classifies_destination_paths() {
+    let mut manifest = tempfile::NamedTempFile::new().unwrap();
+    write!(
+        manifest,
+        "<transmit-content>\
+         <file><filename>new.txt</filename><filesize>3</filesize></file>\
+         <file><filename>same.txt</filename><filesize>3</filesize></file>\
+         <file><filename>changed.txt</filename><filesize>3</filesize></file>\
+         <file><filename>dir</filename><filesize>3</filesize></file>\
+         </transmit-content>"
+    )
+    .unwrap();
+    let config = Config::from_xml_file(manifest.path()).unwrap();
+
+    let existing: HashMap<String, Vec<u8>> = [
+        ("/dst/same.txt", b"abc".to_vec()),
+        ("/dst/changed.txt", b"abcdef".to_vec()),
+        ("/dst/dir/inner.txt", b"x".to_vec()),
+    ]
+    .into_iter()
+    .map(|(path, data)| (path.to_string(), data))
+    .collect();
+    let files = Arc::new(Mutex::new(existing));
+    let processor = Processor::new(
+        Sink::Memory(files.clone()),
+        config,
+        "/dst".to_string(),
+        manifest.path().to_string_lossy().to_string(),
+    );
+
+    let plan = tokio::runtime::Runtime::new().unwrap().block_on(processor.plan()).unwrap();
+    let actions: Vec<(&str, PlanAction)> = plan.files.iter().map(|file| (file.path.as_str(), file.action)).collect();
+    assert_eq!(
+        actions,
+        vec![
+            ("/dst/changed.txt", PlanAction::Overwrite { existing: 6 }),
+            ("/dst/dir", PlanAction::Conflict),
+            ("/dst/new.txt", PlanAction::Create),
+            ("/dst/same.txt", PlanAction::SkipIdentical),
+        ]
+    );
+    assert_eq!(plan.conflicts(), 1);
+    // Nothing was written.
+    assert_eq!(files.lock().unwrap().len(), 3);
+}
@@ -0,0 +1,49 @@
+use std::sync::Arc;
+use std::time::Duration;
+use untar::pool::BufferPool;
+
+#[tokio::test]
+async fn buffers_return_when_their_bytes_are_dropped() {
+    let pool = Arc::new(BufferPool::new(2));
+    let mut buffer = pool.get(8).await;
+    buffer[..3].copy_from_slice(b"abc");
+    let chunk = buffer.freeze(3);
+    let copy = chunk.clone();
+    assert_eq!(&chunk[..], b"abc");
+    assert_eq!(pool.available(), 1);
+
+    drop(chunk);
+    assert_eq!(pool.available(), 1);
+    drop(copy);
+    assert_eq!(pool.available(), 2);
+}
+
+#[tokio::test]
+async fn get_waits_while_every_buffer_is_in_use() {
+    let pool = Arc::new(BufferPool::new(1));
+    let chunk = pool.get(4).await.freeze(4);
+    let waiting = tokio::time::timeout(Duration::from_millis(50), pool.get(4)).await;
+    assert!(waiting.is_err());
+
+    drop(chunk);
+    // The reused buffer is resized to what is asked for.
+    let buffer = tokio::time::timeout(Duration::from_secs(5), pool.get(16)).await.unwrap();
+    assert_eq!(buffer.len(), 16);
+}
+
+#[tokio::test]
+async fn get_waits_for_memory_and_frees_idle_buffers() {
+    let pool = Arc::new(BufferPool::new(4).with_max_bytes(Some(100)));
+    let chunk = pool.get(60).await.freeze(60);
+    let waiting = tokio::time::timeout(Duration::from_millis(50), pool.get(60)).await;
+    assert!(waiting.is_err());
+
+    drop(chunk);
+    // The idle 60-byte buffer is too small to reuse, so it is freed to make room.
+    let buffer = tokio::time::timeout(Duration::from_secs(5), pool.get(90)).await.unwrap();
+    assert_eq!(buffer.len(), 90);
+    // Larger than the whole budget, once the memory is back.
+    drop(buffer);
+    let buffer = tokio::time::timeout(Duration::from_secs(5), pool.get(500)).await.unwrap();
+    assert_eq!(buffer.len(), 500);
+}
@@ -0,0 +1,33 @@
+use std::sync::Arc;
+use std::time::Duration;
+use untar::telemetry::{ProfileSpec, Profiled, Profiler};
+
+#[test]
+fn profile_spec_takes_optional_seconds() {
+    assert_eq!(ProfileSpec::parse("cpu"), Ok(ProfileSpec { duration: None }));
+    assert_eq!(ProfileSpec::parse("cpu:30"), Ok(ProfileSpec { duration: Some(Duration::from_secs(30)) }));
+    assert!(ProfileSpec::parse("cpu:0").is_err());
+    assert!(ProfileSpec::parse("heap").is_err());
+}
+
+#[tokio::test]
+async fn profiled_polls_are_written_as_folded_stacks() {
+    let profiler = Arc::new(Profiler::new(ProfileSpec { duration: None }));
+    let busy = async {
+        let mut x = 0u64;
+        for i in 0..5_000_000u64 {
+            x = std::hint::black_box(x.wrapping_mul(31).wrapping_add(i));
+        }
+        x
+    };
+    Profiled::new(busy, Some(&profiler), || "untar;decompress;gzip".to_string()).await;
+    Profiled::new(async {}, None, || unreachable!()).await;
+
+    let dir = tempfile::tempdir().unwrap();
+    let path = dir.path().join("run.cpu.folded");
+    profiler.write_folded(&path).unwrap();
+    let folded = std::fs::read_to_string(&path).unwrap();
+    let (stack, micros) = folded.trim_end().rsplit_once(' ').unwrap();
+    assert_eq!(stack, "untar;decompress;gzip");
+    assert!(micros.parse::<u64>().unwrap() > 0);
+}
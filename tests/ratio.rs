@@ -0,0 +1,73 @@
+use std::io::{Cursor, Write};
+use std::sync::{Arc, Mutex};
+use flate2::write::GzEncoder;
+use flate2::Compression;
+use untar::config::Config;
+use untar::processor::{Processor, RatioCheck, TarSource};
+use untar::sink::Sink;
+
+fn gzip(data: &[u8], level: Compression) -> Vec<u8> {
+    let mut encoder = GzEncoder::new(Vec::new(), level);
+    encoder.write_all(data).unwrap();
+    encoder.finish().unwrap()
+}
+
+#[test]
+fn suspicious_ratios_are_reported() {
+    // Incompressible data stored without compression, as a corrupt feed would.
+    let mut state = 1u32;
+    let noise: Vec<u8> = (0..65_536)
+        .map(|_| {
+            state = state.wrapping_mul(1_103_515_245).wrapping_add(12_345);
+            (state >> 24) as u8
+        })
+        .collect();
+    let zeros = vec![0u8; 65_536];
+    let members = [
+        ("stored.bin.gz", gzip(&noise, Compression::none())),
+        ("zeros.bin.gz", gzip(&zeros, Compression::default())),
+        ("text.txt.gz", gzip(&b"id,value\n".repeat(1000), Compression::default())),
+        // Uncompressed members always have a ratio of 1.
+        ("plain.bin", noise.clone()),
+    ];
+
+    let mut builder = tar::Builder::new(Vec::new());
+    for (name, data) in &members {
+        let mut header = tar::Header::new_gnu();
+        header.set_size(data.len() as u64);
+        header.set_mode(0o644);
+        header.set_cksum();
+        builder.append_data(&mut header, name, &data[..]).unwrap();
+    }
+    let tar = builder.into_inner().unwrap();
+
+    let mut manifest = tempfile::NamedTempFile::new().unwrap();
+    write!(
+        manifest,
+        "<transmit-content>\
+         <file><filename>stored.bin</filename><filesize>65536</filesize></file>\
+         <file><filename>zeros.bin</filename><filesize>65536</filesize></file>\
+         <file><filename>text.txt</filename><filesize>9000</filesize></file>\
+         <file><filename>plain.bin</filename><filesize>65536</filesize></file>\
+         </transmit-content>"
+    )
+    .unwrap();
+    let config = Config::from_xml_file(manifest.path()).unwrap();
+    let processor = Processor::new(
+        Sink::Memory(Arc::new(Mutex::new(Default::default()))),
+        config,
+        "/dst".to_string(),
+        manifest.path().to_string_lossy().to_string(),
+    )
+    .with_ratio_check(RatioCheck { tolerance: 0.001, max_ratio: Some(500.0) });
+    let source = TarSource { name: "t.tar".to_string(), reader: Cursor::new(tar), top_dir: None };
+
+    let report = tokio::runtime::Runtime::new().unwrap().block_on(processor.process_tars(vec![source])).unwrap();
+    let flagged: Vec<&str> = report.ratio_anomalies.iter().map(|anomaly| anomaly.entry.as_str()).collect();
+    assert_eq!(flagged, vec!["stored.bin.gz", "zeros.bin.gz"]);
+    let stored = &report.ratio_anomalies[0];
+    assert_eq!(stored.decompressed, 65_536);
+    assert!(stored.ratio < 1.0 && stored.ratio > 0.999, "{}", stored.ratio);
+    // Everything is still delivered.
+    assert_eq!(report.files.len(), 4);
+}
@@ -0,0 +1,25 @@
+use std::process::Command;
+
+/// Tests are always built to unwind, whatever the profile says, so the
+/// release profile is checked by building and running an example with it.
+#[test]
+#[ignore = "builds the crate with the release profile; run with --ignored"]
+fn release_builds_turn_worker_panics_into_file_failures() {
+    let target_dir = std::path::Path::new(env!("CARGO_TARGET_TMPDIR")).join("release-panics");
+    let output = Command::new(env!("CARGO"))
+        .args(["run", "--release", "--example", "worker_panic"])
+        .current_dir(env!("CARGO_MANIFEST_DIR"))
+        // Its own target directory, so it does not wait on the lock of the running build.
+        .env("CARGO_TARGET_DIR", &target_dir)
+        .output()
+        .unwrap();
+    let stdout = String::from_utf8_lossy(&output.stdout);
+    assert!(
+        output.status.success(),
+        "example failed ({}):\n{}\n{}",
+        output.status,
+        stdout,
+        String::from_utf8_lossy(&output.stderr)
+    );
+    assert!(stdout.contains("worker panic reported as"), "{}", stdout);
+}
@@ -0,0 +1,48 @@
+use untar::telemetry::{ResourceLimits, ResourceMonitor, ResourceUsage};
+
+const GIB: u64 = 1 << 30;
+
+fn usage(rss_bytes: u64, open_fds: u64) -> ResourceUsage {
+    ResourceUsage { rss_bytes, open_fds }
+}
+
+#[test]
+fn warnings_start_at_four_fifths_of_a_limit_and_clear_below_it() {
+    let monitor = ResourceMonitor::new(ResourceLimits { max_rss: Some(10 * GIB), max_fds: Some(1000), recycle_writers: false });
+
+    monitor.record(usage(7 * GIB, 799));
+    assert!(!monitor.near_limit());
+    monitor.record(usage(8 * GIB, 10));
+    assert!(monitor.near_limit());
+    monitor.record(usage(GIB, 800));
+    assert!(monitor.near_limit());
+    monitor.record(usage(GIB, 10));
+    assert!(!monitor.near_limit());
+
+    let status = monitor.status();
+    assert_eq!((status.rss_bytes, status.open_fds), (GIB, 10));
+    assert_eq!((status.peak_rss_bytes, status.peak_open_fds), (8 * GIB, 800));
+}
+
+#[test]
+fn writers_are_recycled_near_a_limit_only_when_enabled() {
+    let limits = ResourceLimits { max_rss: Some(10 * GIB), max_fds: Some(1000), recycle_writers: true };
+    let recycling = ResourceMonitor::new(limits);
+    recycling.record(usage(7 * GIB, 700));
+    assert!(!recycling.should_recycle());
+    recycling.record(usage(7 * GIB, 900));
+    assert!(recycling.should_recycle());
+    recycling.record(usage(9 * GIB, 700));
+    assert!(recycling.should_recycle());
+
+    let warning_only = ResourceMonitor::new(ResourceLimits { recycle_writers: false, ..limits });
+    warning_only.record(usage(9 * GIB, 900));
+    assert!(warning_only.near_limit());
+    assert!(!warning_only.should_recycle());
+
+    let unlimited = ResourceMonitor::new(ResourceLimits { recycle_writers: true, ..Default::default() });
+    unlimited.record(usage(u64::MAX, u64::MAX));
+    assert!(!unlimited.near_limit());
+    assert!(!unlimited.should_recycle());
+}
+
@@ -0,0 +1,77 @@
+use std::io::Write;
+use std::time::Duration;
+use time::macros::datetime;
+use untar::schedule::{load_jobs, CronSchedule};
+
+#[test]
+fn cron_finds_next_fire_time() {
+    let nightly = CronSchedule::parse("0 2 * * *").unwrap();
+    assert_eq!(
+        nightly.next_after(datetime!(2024-02-28 23:15:30 +8)),
+        Some(datetime!(2024-02-29 02:00 +8))
+    );
+    assert_eq!(
+        nightly.next_after(datetime!(2024-02-29 02:00 +8)),
+        Some(datetime!(2024-03-01 02:00 +8))
+    );
+
+    // Weekdays every 15 minutes during office hours.
+    let office = CronSchedule::parse("*/15 9-17 * * 1-5").unwrap();
+    assert_eq!(
+        office.next_after(datetime!(2024-03-08 17:50 +8)),
+        Some(datetime!(2024-03-11 09:00 +8))
+    );
+
+    // Either day field matches once both are restricted.
+    let either = CronSchedule::parse("30 4 1 * 0").unwrap();
+    assert_eq!(
+        either.next_after(datetime!(2024-03-29 00:00 +8)),
+        Some(datetime!(2024-03-31 04:30 +8))
+    );
+    assert_eq!(
+        either.next_after(datetime!(2024-03-31 05:00 +8)),
+        Some(datetime!(2024-04-01 04:30 +8))
+    );
+
+    let leap = CronSchedule::parse("0 0 29 2 *").unwrap();
+    assert_eq!(leap.next_after(datetime!(2024-03-01 00:00 +0)), Some(datetime!(2028-02-29 00:00 +0)));
+    assert_eq!(CronSchedule::parse("0 0 31 2 *").unwrap().next_after(datetime!(2024-01-01 00:00 +0)), None);
+
+    for invalid in ["0 2 * *", "60 * * * *", "*/0 * * * *", "5-1 * * * *"] {
+        assert!(CronSchedule::parse(invalid).is_err(), "{}", invalid);
+    }
+}
+
+#[test]
+fn cron_fires_at_wall_clock_time_in_the_given_offset() {
+    let nightly = CronSchedule::parse("0 2 * * *").unwrap();
+    // 06:30 UTC is past 02:00 in UTC and UTC+5:30, but only 01:30 at UTC-5.
+    let instant = datetime!(2024-03-10 06:30 UTC);
+    assert_eq!(nightly.next_after(instant), Some(datetime!(2024-03-11 02:00 UTC)));
+    assert_eq!(
+        nightly.next_after(instant.to_offset(time::macros::offset!(-5))),
+        Some(datetime!(2024-03-10 02:00 -5))
+    );
+    assert_eq!(
+        nightly.next_after(instant.to_offset(time::macros::offset!(+5:30))),
+        Some(datetime!(2024-03-11 02:00 +5:30))
+    );
+}
+
+#[test]
+fn jobs_carry_their_feed_sla() {
+    let mut file = tempfile::NamedTempFile::new().unwrap();
+    write!(
+        file,
+        "jobs:\n  - name: logs\n    sla: {{ max-duration: 45m, min-throughput: 20 }}\n    args: [--tar, a.tar]\n  - name: metrics\n    args: [--tar, b.tar]\n"
+    )
+    .unwrap();
+    let jobs = load_jobs(file.path()).unwrap();
+    assert_eq!(jobs[0].sla.max_duration, Some(Duration::from_secs(45 * 60)));
+    assert_eq!(jobs[0].sla.min_throughput, Some(20.0));
+    assert_eq!(jobs[1].sla.max_duration, None);
+
+    let mut file = tempfile::NamedTempFile::new().unwrap();
+    write!(file, "jobs:\n  - name: logs\n    sla: {{ max-duration: 9999999999999999h }}\n    args: []\n").unwrap();
+    assert!(load_jobs(file.path()).unwrap_err().to_string().contains("too large"));
+}
@@ -0,0 +1,55 @@
+use std::sync::{Arc, Mutex};
+use bytes::Bytes;
+use hdfs_native::HdfsError;
+use untar::sink::{is_transient, Sink, WriteOpt};
+
+#[test]
+fn write_opts_parse_key_value_pairs() {
+    assert_eq!(WriteOpt::parse("block_size=256m"), Ok(WriteOpt::BlockSize(256 << 20)));
+    assert_eq!(WriteOpt::parse("replication=2"), Ok(WriteOpt::Replication(2)));
+    assert_eq!(WriteOpt::parse("permission=640"), Ok(WriteOpt::Permission(0o640)));
+    assert_eq!(WriteOpt::parse("create_parent=false"), Ok(WriteOpt::CreateParent(false)));
+
+    assert!(WriteOpt::parse("replication=0").is_err());
+    assert!(WriteOpt::parse("permission=999").is_err());
+    assert!(WriteOpt::parse("packet_size=1m").is_err());
+    assert!(WriteOpt::parse("replication").is_err());
+}
+
+#[test]
+fn only_transient_hdfs_errors_are_retried() {
+    let rpc = |class: &str| HdfsError::RPCError(class.to_string(), "busy".to_string());
+    assert!(is_transient(&rpc("org.apache.hadoop.ipc.StandbyException")));
+    assert!(is_transient(&rpc("org.apache.hadoop.hdfs.server.namenode.SafeModeException")));
+    assert!(is_transient(&HdfsError::IOError(std::io::ErrorKind::ConnectionReset.into())));
+
+    assert!(!is_transient(&rpc("org.apache.hadoop.security.AccessControlException")));
+    assert!(!is_transient(&HdfsError::AlreadyExists("/dst/file".to_string())));
+    assert!(!is_transient(&HdfsError::FileNotFound("/dst/file".to_string())));
+}
+
+#[tokio::test]
+async fn appends_need_a_file_to_add_to() {
+    let sink = Sink::Memory(Arc::new(Mutex::new(Default::default())));
+    assert!(matches!(sink.append("/dst/feed.log").await, Err(HdfsError::FileNotFound(_))));
+
+    assert!(sink.create_new("/dst/feed.log", Bytes::from_static(b"day 1\n")).await.unwrap());
+    let mut writer = sink.append("/dst/feed.log").await.unwrap();
+    writer.write(Bytes::from_static(b"day 2\n")).await.unwrap();
+    writer.close().await.unwrap();
+    assert_eq!(sink.read("/dst/feed.log").await.unwrap().unwrap(), Bytes::from_static(b"day 1\nday 2\n"));
+}
+
+#[tokio::test]
+async fn renames_replace_a_file_only_when_asked_to() {
+    let sink = Sink::Memory(Arc::new(Mutex::new(Default::default())));
+    assert!(sink.create_new("/dst/staged", Bytes::from_static(b"new")).await.unwrap());
+    assert!(sink.create_new("/dst/file", Bytes::from_static(b"old")).await.unwrap());
+
+    assert!(matches!(sink.rename("/dst/staged", "/dst/file", false).await, Err(HdfsError::AlreadyExists(_))));
+    assert_eq!(sink.read("/dst/file").await.unwrap().unwrap(), Bytes::from_static(b"old"));
+
+    sink.rename("/dst/staged", "/dst/file", true).await.unwrap();
+    assert_eq!(sink.read("/dst/file").await.unwrap().unwrap(), Bytes::from_static(b"new"));
+    assert!(sink.read("/dst/staged").await.unwrap().is_none());
+}
@@ -0,0 +1,85 @@
+use std::collections::HashMap;
+use std::io::{Cursor, Write};
+use std::sync::{Arc, Mutex};
+use untar::config::Config;
+use untar::decompress::{sniff_format, DecompressionFormat};
+use untar::processor::{Processor, TarSource};
+use untar::report::RunReport;
+use untar::sink::Sink;
+
+#[test]
+fn leading_magic_bytes_name_the_format() {
+    let cases: &[(&[u8], DecompressionFormat)] = &[
+        (&[0x1f, 0x8b, 0x08, 0x00], DecompressionFormat::Gzip),
+        (&[0x1f, 0x9d, 0x90], DecompressionFormat::UnixCompress),
+        (&[0x1f, 0x1e, 0x00], DecompressionFormat::Pack),
+        (b"BZh91AY&SY", DecompressionFormat::Bzip2),
+        (&[0xfd, b'7', b'z', b'X', b'Z', 0x00, 0x00], DecompressionFormat::Xz),
+        (&[0x28, 0xb5, 0x2f, 0xfd, 0x24], DecompressionFormat::Zstd),
+        (&[0x04, 0x22, 0x4d, 0x18, 0x64], DecompressionFormat::Lz4),
+        (b"LZIP\x01", DecompressionFormat::Lzip),
+        (&[0x89, b'L', b'Z', b'O', 0x00, 0x0d, 0x0a, 0x1a, 0x0a], DecompressionFormat::Lzop),
+    ];
+    for (head, format) in cases {
+        assert_eq!(sniff_format(head), Some(*format), "{:02x?}", head);
+    }
+}
+
+#[test]
+fn data_without_a_known_magic_is_not_sniffed() {
+    assert_eq!(sniff_format(b""), None);
+    assert_eq!(sniff_format(&[0x1f]), None);
+    assert_eq!(sniff_format(b"BZ"), None);
+    assert_eq!(sniff_format(b"id,value\n"), None);
+    // LZMA-alone has no magic of its own.
+    assert_eq!(sniff_format(&[0x5d, 0x00, 0x00, 0x80, 0x00]), None);
+}
+
+/// Delivers a single tar `member` against a manifest listing `name` at `size`.
+fn run(member: &str, data: &[u8], name: &str, size: usize) -> (RunReport, HashMap<String, Vec<u8>>) {
+    let mut manifest = tempfile::NamedTempFile::new().unwrap();
+    write!(
+        manifest,
+        "<transmit-content><file><filename>{}</filename><filesize>{}</filesize></file></transmit-content>",
+        name, size
+    )
+    .unwrap();
+    let written = Arc::new(Mutex::new(HashMap::new()));
+    let processor = Processor::new(
+        Sink::Memory(written.clone()),
+        Config::from_xml_file(manifest.path()).unwrap(),
+        "/dst".to_string(),
+        manifest.path().to_string_lossy().to_string(),
+    );
+
+    let mut builder = tar::Builder::new(Vec::new());
+    let mut header = tar::Header::new_gnu();
+    header.set_size(data.len() as u64);
+    header.set_mode(0o644);
+    header.set_cksum();
+    builder.append_data(&mut header, member, data).unwrap();
+    let source = TarSource { name: "delivery.tar".to_string(), reader: Cursor::new(builder.into_inner().unwrap()), top_dir: None };
+
+    let runtime = tokio::runtime::Runtime::new().unwrap();
+    let report = runtime.block_on(processor.process_tars(vec![source])).unwrap();
+    let files = written.lock().unwrap().clone();
+    (report, files)
+}
+
+#[test]
+fn a_gz_member_holding_zstd_data_is_decompressed_as_zstd() {
+    let content = b"id,value\n1,one\n2,two\n";
+    let compressed = zstd::encode_all(&content[..], 3).unwrap();
+    let (report, files) = run("logs/b.csv.gz", &compressed, "logs/b.csv", content.len());
+    assert_eq!(report.files.len(), 1);
+    assert_eq!(files.get("/dst/logs/b.csv").map(Vec::as_slice), Some(&content[..]));
+}
+
+#[test]
+fn a_stored_member_that_starts_like_a_magic_is_kept_as_is() {
+    // Starts with the bzip2 magic but is plain text of the manifest size.
+    let content = b"BZh is how the notes begin\n";
+    let (report, files) = run("notes/readme.txt", content, "notes/readme.txt", content.len());
+    assert_eq!(report.files.len(), 1);
+    assert_eq!(files.get("/dst/notes/readme.txt").map(Vec::as_slice), Some(&content[..]));
+}
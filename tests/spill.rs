@@ -0,0 +1,70 @@
+//! The spill channel must deliver chunks in order whether they stayed in memory or went to disk.
+
+use std::sync::Arc;
+use std::time::Duration;
+use bytes::Bytes;
+use untar::spill::{self, InFlight, Spill};
+use untar::workdir::WorkDir;
+
+fn chunks() -> Vec<Bytes> {
+    (0..100u8).map(|i| Bytes::from(vec![i; 1000 + i as usize])).collect()
+}
+
+#[tokio::test]
+async fn spilled_chunks_arrive_in_order() {
+    let dir = tempfile::tempdir().unwrap();
+    let spill = Arc::new(Spill::new(WorkDir::new(dir.path().to_path_buf(), 0, "test".into()), 1 << 20));
+    let (tx, mut rx) = spill::channel(4, None, Some(spill.clone()));
+
+    // Nothing is received until everything is sent, so all but 4 chunks must spill.
+    for chunk in chunks() {
+        tx.send(chunk).await.unwrap();
+    }
+    drop(tx);
+
+    let mut received = Vec::new();
+    while let Some(chunk) = rx.recv().await {
+        received.push(chunk.unwrap());
+    }
+    assert_eq!(received, chunks());
+    assert_eq!(spill.spilled_bytes(), chunks()[4..].iter().map(|c| c.len() as u64).sum::<u64>());
+    // The work dir holds no leftovers: spill files are unlinked on creation.
+    assert_eq!(std::fs::read_dir(dir.path()).unwrap().count(), 0);
+}
+
+#[tokio::test]
+async fn sender_waits_once_the_budget_is_used() {
+    let dir = tempfile::tempdir().unwrap();
+    let spill = Arc::new(Spill::new(WorkDir::new(dir.path().to_path_buf(), 0, "test".into()), 10_000));
+    let (tx, mut rx) = spill::channel(1, None, Some(spill));
+
+    let sender = tokio::spawn(async move {
+        for chunk in chunks() {
+            tx.send(chunk).await.unwrap();
+        }
+    });
+    let mut received = Vec::new();
+    while let Some(chunk) = rx.recv().await {
+        received.push(chunk.unwrap());
+    }
+    sender.await.unwrap();
+    assert_eq!(received, chunks());
+}
+
+#[tokio::test]
+async fn channels_share_the_in_flight_budget() {
+    let in_flight = Arc::new(InFlight::new(4_500));
+    let (first, mut first_rx) = spill::channel(16, Some(in_flight.clone()), None);
+    let (second, mut second_rx) = spill::channel(16, Some(in_flight), None);
+
+    for chunk in &chunks()[..4] {
+        first.send(chunk.clone()).await.unwrap();
+    }
+    // 4006 bytes are queued on the first channel, so the second one waits.
+    let blocked = tokio::time::timeout(Duration::from_millis(50), second.send(chunks()[4].clone())).await;
+    assert!(blocked.is_err());
+
+    first_rx.recv().await.unwrap().unwrap();
+    second.send(chunks()[4].clone()).await.unwrap();
+    assert_eq!(second_rx.recv().await.unwrap().unwrap(), chunks()[4]);
+}
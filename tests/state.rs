@@ -0,0 +1,115 @@
+use std::io::{Cursor, Seek, SeekFrom, Write};
+use untar::config::Config;
+use untar::processor::{Processor, TarSource};
+use untar::report::FileRecord;
+use untar::sink::Sink;
+use untar::state::StateFile;
+
+fn record(target: &str, size: u64) -> FileRecord {
+    FileRecord {
+        entry: target.trim_start_matches("/dst/").to_string(),
+        target: target.to_string(),
+        raw_size: size,
+        raw_sha256: "0".repeat(64),
+        size,
+        sha256: "f".repeat(64),
+        stored_size: None,
+        checksum: None,
+        parts: Vec::new(),
+    }
+}
+
+#[test]
+fn completed_files_carry_over_to_the_same_run() {
+    let dir = tempfile::tempdir().unwrap();
+    let path = dir.path().join("run.state");
+
+    let state = StateFile::open(&path, "run-1").unwrap();
+    assert_eq!(state.completed(), 0);
+    state.complete("/dst/a.bin", &record("/dst/a.bin", 10));
+    state.complete("/dst/b.bin", &record("/dst/b.bin", 20));
+    drop(state);
+    // A crash in the middle of the next record.
+    std::fs::OpenOptions::new().append(true).open(&path).unwrap().write_all(b"done\t/dst/c.bin\t3").unwrap();
+
+    let state = StateFile::open(&path, "run-1").unwrap();
+    assert_eq!(state.completed(), 2);
+    assert_eq!(state.completed_size("/dst/a.bin"), Some(10));
+    assert_eq!(state.completed_size("/dst/b.bin"), Some(20));
+    assert_eq!(state.completed_size("/dst/c.bin"), None);
+    state.complete("/dst/c.bin", &record("/dst/c.bin", 30));
+    drop(state);
+    assert_eq!(StateFile::open(&path, "run-1").unwrap().completed_size("/dst/c.bin"), Some(30));
+
+    // Another run starts over.
+    let state = StateFile::open(&path, "run-2").unwrap();
+    assert_eq!(state.completed(), 0);
+    drop(state);
+    assert_eq!(StateFile::open(&path, "run-1").unwrap().completed(), 0);
+}
+
+#[test]
+fn only_the_latest_offset_of_an_archive_is_kept() {
+    let dir = tempfile::tempdir().unwrap();
+    let path = dir.path().join("run.state");
+
+    let state = StateFile::open(&path, "run-1").unwrap();
+    assert!(!state.resumes());
+    state.reached("a.tar", 1024);
+    state.reached("b.tar", 512);
+    state.reached("a.tar", 4096);
+    drop(state);
+    std::fs::OpenOptions::new().append(true).open(&path).unwrap().write_all(b"offset\ta.tar\t8").unwrap();
+
+    let state = StateFile::open(&path, "run-1").unwrap();
+    assert!(state.resumes());
+    assert_eq!(state.resume_offset("a.tar"), Some(4096));
+    assert_eq!(state.resume_offset("b.tar"), Some(512));
+    assert_eq!(state.resume_offset("c.tar"), None);
+    drop(state);
+    let lines = std::fs::read_to_string(&path).unwrap();
+    assert_eq!(lines.lines().filter(|line| line.starts_with("offset\t")).count(), 2);
+}
+
+#[tokio::test(flavor = "multi_thread", worker_threads = 2)]
+async fn reruns_seek_past_delivered_members() {
+    let mut builder = tar::Builder::new(Vec::new());
+    let mut xml = String::from("<transmit-content>");
+    for (name, byte) in [("a.bin", 1u8), ("b.bin", 2)] {
+        let data = vec![byte; 1000];
+        let mut header = tar::Header::new_gnu();
+        header.set_size(data.len() as u64);
+        header.set_mode(0o644);
+        header.set_cksum();
+        builder.append_data(&mut header, name, &data[..]).unwrap();
+        xml.push_str(&format!("<file><filename>{}</filename><filesize>{}</filesize></file>", name, data.len()));
+    }
+    xml.push_str("</transmit-content>");
+    let tar = builder.into_inner().unwrap();
+    let dir = tempfile::tempdir().unwrap();
+    let manifest = dir.path().join("manifest.xml");
+    std::fs::write(&manifest, xml).unwrap();
+    let state_path = dir.path().join("run.state");
+    let run = |state: StateFile| {
+        Processor::new(
+            Sink::Null(None),
+            Config::from_xml_file(&manifest).unwrap(),
+            "/dst".to_string(),
+            manifest.to_string_lossy().to_string(),
+        )
+        .with_state_file(Some(state))
+    };
+
+    let source = TarSource { name: "big.tar".to_string(), reader: Cursor::new(tar.clone()), top_dir: None };
+    let report = run(StateFile::open(&state_path, "run-1").unwrap()).process_tars(vec![source]).await.unwrap();
+    assert_eq!(report.files.len(), 2);
+
+    // Both members and their headers: 2 * (512 + 1024).
+    let state = StateFile::open(&state_path, "run-1").unwrap();
+    assert_eq!(state.resume_offset("big.tar"), Some(3072));
+    let mut reader = Cursor::new(tar);
+    reader.seek(SeekFrom::Start(3072)).unwrap();
+    let source = TarSource { name: "big.tar".to_string(), reader, top_dir: None };
+    let report = run(state).process_tars(vec![source]).await.unwrap();
+    assert!(report.files.is_empty());
+}
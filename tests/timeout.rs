@@ -0,0 +1,77 @@
+use std::io::{Cursor, Write};
+use std::sync::Arc;
+use std::time::Duration;
+use untar::config::Config;
+use untar::control::{Control, DeadlinePassed, RunState};
+use untar::processor::{FileTimeout, Processor, RunFailed, TarSource, TimeoutPolicy};
+use untar::sink::{Sink, Throttle};
+
+const FILES: [(&str, u8); 2] = [("a.bin", 1), ("b.bin", 2)];
+
+/// A processor for a tar of `FILES`, 4 KiB each, and the tar.
+fn wedged_run(sink: Sink) -> (Processor, TarSource<Cursor<Vec<u8>>>, tempfile::NamedTempFile) {
+    let mut builder = tar::Builder::new(Vec::new());
+    let mut xml = String::from("<transmit-content>");
+    for (name, byte) in FILES {
+        let data = vec![byte; 4096];
+        let mut header = tar::Header::new_gnu();
+        header.set_size(data.len() as u64);
+        header.set_mode(0o644);
+        header.set_cksum();
+        builder.append_data(&mut header, name, &data[..]).unwrap();
+        xml.push_str(&format!("<file><filename>{}</filename><filesize>{}</filesize></file>", name, data.len()));
+    }
+    xml.push_str("</transmit-content>");
+    let mut manifest = tempfile::NamedTempFile::new().unwrap();
+    manifest.write_all(xml.as_bytes()).unwrap();
+
+    let processor = Processor::new(
+        sink,
+        Config::from_xml_file(manifest.path()).unwrap(),
+        "/dst".to_string(),
+        manifest.path().to_string_lossy().to_string(),
+    );
+    let source = TarSource { name: "wedged.tar".to_string(), reader: Cursor::new(builder.into_inner().unwrap()), top_dir: None };
+    (processor, source, manifest)
+}
+
+#[tokio::test(flavor = "multi_thread", worker_threads = 2)]
+async fn timed_out_uploads_fail_without_stopping_the_run() {
+    // A byte a second: every upload wedges.
+    let (processor, source, _manifest) = wedged_run(Sink::Null(Some(Arc::new(Throttle::new(1)))));
+    let processor = processor
+        .with_file_timeout(Some(FileTimeout { limit: Duration::from_millis(50), policy: TimeoutPolicy::Continue }));
+
+    let failed = processor.process_tars(vec![source]).await.unwrap_err();
+    let report = &failed.downcast_ref::<RunFailed>().unwrap().report;
+    assert_eq!(report.failures.len(), FILES.len());
+    assert!(report.failures.iter().all(|failure| failure.error.contains("timed out")));
+}
+
+#[tokio::test(flavor = "multi_thread", worker_threads = 2)]
+async fn runs_past_their_deadline_fail_with_a_partial_report() {
+    let control = Control::default();
+    control.set_state(RunState::Expired { abort_writes: false });
+    // Resuming does not undo the deadline.
+    control.set_state(RunState::Running);
+    let (processor, source, _manifest) = wedged_run(Sink::Null(None));
+    let processor = processor.with_control(control);
+
+    let failed = processor.process_tars(vec![source]).await.unwrap_err();
+    assert!(failed.is::<DeadlinePassed>());
+    let report = &failed.downcast_ref::<RunFailed>().unwrap().report;
+    assert!(report.files.is_empty());
+    assert!(report.failures.is_empty());
+}
+
+#[tokio::test(flavor = "multi_thread", worker_threads = 2)]
+async fn stalled_uploads_fail() {
+    let (processor, source, _manifest) = wedged_run(Sink::Null(Some(Arc::new(Throttle::new(1)))));
+    let processor = processor
+        .with_stall_timeout(Some(FileTimeout { limit: Duration::from_millis(50), policy: TimeoutPolicy::Stop }));
+
+    let failed = processor.process_tars(vec![source]).await.unwrap_err();
+    let report = &failed.downcast_ref::<RunFailed>().unwrap().report;
+    assert!(!report.failures.is_empty());
+    assert!(report.failures.iter().all(|failure| failure.error.contains("made no progress")));
+}